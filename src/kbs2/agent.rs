@@ -1,22 +1,79 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::fs;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
-use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{anyhow, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, Flock, FlockArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use nix::unistd::Uid;
 use secrecy::{ExposeSecret, Secret, SecretString};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
 
-use crate::kbs2::backend::{Backend, RageLib};
+use crate::kbs2::backend::{
+    unlock_backoff, Backend, BadPassword, RageLib, TooManyAttempts, MAX_WRAPPED_KEY_FILESIZE,
+};
+use crate::kbs2::config;
+use crate::kbs2::util;
 
 /// The version of the agent protocol.
-const PROTOCOL_VERSION: u32 = 1;
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// How long the agent refuses to unwrap a keyfile after too many incorrect passwords for it.
+const UNWRAP_LOCKOUT: Duration = Duration::from_secs(300);
+
+/// The write end of the pipe that wakes the agent when it receives `SIGHUP`, indicating
+/// that it should flush its keys. Negative until the agent installs its handler.
+static SIGHUP_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle_sighup(_: libc::c_int) {
+    let fd = SIGHUP_PIPE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // NOTE(ww): The pipe is nonblocking, so this can't hang the agent; if it's full,
+        // there's already a wakeup waiting to be handled.
+        // Safety: write(2) is async-signal-safe.
+        unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+    }
+}
+
+/// Installs the agent's `SIGHUP` handler, returning the read end of the pipe that the
+/// handler writes to (which becomes readable once `SIGHUP` arrives) along with its
+/// write end, which must be kept open for as long as the handler is installed.
+#[doc(hidden)]
+fn install_sighup_handler() -> Result<(OwnedFd, OwnedFd)> {
+    let (hup_read, hup_write) = nix::unistd::pipe()?;
+    for fd in [&hup_read, &hup_write] {
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+    }
+    SIGHUP_PIPE.store(hup_write.as_raw_fd(), Ordering::SeqCst);
+
+    let action = SigAction::new(
+        SigHandler::Handler(handle_sighup),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    // Safety: the handler only performs an atomic load and a write(2), both of which are
+    // async-signal-safe.
+    unsafe { signal::sigaction(Signal::SIGHUP, &action) }?;
+
+    Ok((hup_read, hup_write))
+}
 
 /// Represents the entire request message, including the protocol field.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
@@ -30,8 +87,9 @@ struct Request {
 #[serde(tag = "type", content = "body")]
 enum RequestBody {
     /// Unwrap a particular keyfile (second element) with a password (third element), identifying
-    /// it in the agent with a particular public key (first element).
-    UnwrapKey(String, String, String),
+    /// it in the agent with a particular public key (first element). Incorrect passwords are
+    /// throttled according to the client's `UnwrapPolicy` (fourth element).
+    UnwrapKey(String, String, String, UnwrapPolicy),
 
     /// Check whether a particular public key has an unwrapped keyfile in the agent.
     QueryUnwrappedKey(String),
@@ -39,7 +97,10 @@ enum RequestBody {
     /// Get the actual unwrapped key, by public key.
     GetUnwrappedKey(String),
 
-    /// Flush all keys from the agent.
+    /// Flush a particular public key's unwrapped keyfile from the agent, if it has one.
+    FlushKey(String),
+
+    /// Flush all keys from the agent. Succeeds with the number of keys flushed.
     FlushKeys,
 
     /// Ask the agent to exit.
@@ -78,6 +139,264 @@ enum FailureKind {
 
     /// The request failed because the requested query failed.
     Query,
+
+    /// The request failed because the password was incorrect, with the number of attempts left.
+    BadPassword(u32),
+
+    /// The request was refused because it followed an incorrect password too soon; it can be
+    /// retried after the given number of milliseconds.
+    Backoff(u64),
+
+    /// The request was refused because of too many incorrect passwords; it can be retried
+    /// after the given number of seconds, or with `UnwrapPolicy.force`.
+    LockedOut(u64),
+}
+
+/// How the agent throttles incorrect passwords for a keyfile, as configured by the client.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct UnwrapPolicy {
+    /// How many incorrect passwords the agent accepts before locking the keyfile out.
+    pub max_attempts: u32,
+
+    /// How long the agent waits after the first incorrect password, in milliseconds;
+    /// see `backend::unlock_backoff`.
+    pub backoff_ms: u64,
+
+    /// Whether to forget any previous incorrect passwords, including a lockout.
+    pub force: bool,
+}
+
+impl From<&config::Config> for UnwrapPolicy {
+    fn from(config: &config::Config) -> Self {
+        Self {
+            max_attempts: config.unlock_max_attempts.max(1),
+            backoff_ms: config.unlock_backoff_ms,
+            force: config.force_retry,
+        }
+    }
+}
+
+impl Default for UnwrapPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: config::DEFAULT_UNLOCK_MAX_ATTEMPTS,
+            backoff_ms: config::DEFAULT_UNLOCK_BACKOFF_MS,
+            force: false,
+        }
+    }
+}
+
+impl RequestBody {
+    /// Returns the name of this kind of request, as used by `kbs2 agent protocol`.
+    fn name(&self) -> &'static str {
+        // NOTE(ww): When adding a variant here, add an example of it to `examples` too.
+        match self {
+            RequestBody::UnwrapKey(..) => "UnwrapKey",
+            RequestBody::QueryUnwrappedKey(_) => "QueryUnwrappedKey",
+            RequestBody::GetUnwrappedKey(_) => "GetUnwrappedKey",
+            RequestBody::FlushKey(_) => "FlushKey",
+            RequestBody::FlushKeys => "FlushKeys",
+            RequestBody::Quit => "Quit",
+        }
+    }
+
+    /// Returns an example of every kind of request.
+    fn examples() -> Vec<RequestBody> {
+        let pubkey = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+
+        vec![
+            RequestBody::UnwrapKey(
+                pubkey.into(),
+                "/home/user/.config/kbs2/key".into(),
+                "hunter2".into(),
+                UnwrapPolicy::default(),
+            ),
+            RequestBody::QueryUnwrappedKey(pubkey.into()),
+            RequestBody::GetUnwrappedKey(pubkey.into()),
+            RequestBody::FlushKey(pubkey.into()),
+            RequestBody::FlushKeys,
+            RequestBody::Quit,
+        ]
+    }
+}
+
+impl Response {
+    /// Returns the name of this kind of response, as used by `kbs2 agent protocol`.
+    ///
+    /// Failures are named by their `FailureKind`, e.g. `Failure.Io`.
+    fn name(&self) -> &'static str {
+        // NOTE(ww): When adding a variant here, add an example of it to `examples` too.
+        match self {
+            Response::Success(_) => "Success",
+            Response::Failure(FailureKind::Auth) => "Failure.Auth",
+            Response::Failure(FailureKind::Io(_)) => "Failure.Io",
+            Response::Failure(FailureKind::Malformed(_)) => "Failure.Malformed",
+            Response::Failure(FailureKind::Unwrap(_)) => "Failure.Unwrap",
+            Response::Failure(FailureKind::VersionMismatch(_)) => "Failure.VersionMismatch",
+            Response::Failure(FailureKind::Query) => "Failure.Query",
+            Response::Failure(FailureKind::BadPassword(_)) => "Failure.BadPassword",
+            Response::Failure(FailureKind::Backoff(_)) => "Failure.Backoff",
+            Response::Failure(FailureKind::LockedOut(_)) => "Failure.LockedOut",
+        }
+    }
+
+    /// Returns an example of every kind of response.
+    fn examples() -> Vec<Response> {
+        vec![
+            Response::Success("OK".into()),
+            Response::Failure(FailureKind::Auth),
+            Response::Failure(FailureKind::Io("broken pipe".into())),
+            Response::Failure(FailureKind::Malformed("expected value".into())),
+            Response::Failure(FailureKind::Unwrap("incorrect password".into())),
+            Response::Failure(FailureKind::VersionMismatch(PROTOCOL_VERSION)),
+            Response::Failure(FailureKind::Query),
+            Response::Failure(FailureKind::BadPassword(2)),
+            Response::Failure(FailureKind::Backoff(500)),
+            Response::Failure(FailureKind::LockedOut(UNWRAP_LOCKOUT.as_secs())),
+        ]
+    }
+}
+
+/// Describes the agent protocol, with a complete example message for every kind of
+/// request and response.
+///
+/// Messages are exchanged as single lines of JSON over the agent's socket; each request
+/// receives exactly one response.
+#[derive(Debug)]
+pub struct ProtocolDescription {
+    /// The protocol version, which every request must carry.
+    pub protocol: u32,
+
+    /// An example of each request, keyed by name.
+    pub requests: Vec<(&'static str, serde_json::Value)>,
+
+    /// An example of each response, keyed by name.
+    pub responses: Vec<(&'static str, serde_json::Value)>,
+}
+
+impl ProtocolDescription {
+    /// Describes the current agent protocol.
+    pub fn current() -> Result<Self> {
+        let requests = RequestBody::examples()
+            .into_iter()
+            .map(|body| {
+                let name = body.name();
+                let request = Request {
+                    protocol: PROTOCOL_VERSION,
+                    body,
+                };
+                Ok((name, serde_json::to_value(request)?))
+            })
+            .collect::<Result<_>>()?;
+
+        let responses = Response::examples()
+            .into_iter()
+            .map(|response| Ok((response.name(), serde_json::to_value(&response)?)))
+            .collect::<Result<_>>()?;
+
+        Ok(ProtocolDescription {
+            protocol: PROTOCOL_VERSION,
+            requests,
+            responses,
+        })
+    }
+
+    /// Returns the example message for the named request or response, if there is one.
+    pub fn example(&self, name: &str) -> Option<&serde_json::Value> {
+        self.requests
+            .iter()
+            .chain(self.responses.iter())
+            .find(|(n, _)| *n == name)
+            .map(|(_, example)| example)
+    }
+
+    /// Returns this description as a JSON object.
+    pub fn to_json(&self) -> serde_json::Value {
+        let examples = |examples: &[(&str, serde_json::Value)]| {
+            examples
+                .iter()
+                .map(|(name, example)| (name.to_string(), example.clone()))
+                .collect::<serde_json::Map<_, _>>()
+        };
+
+        serde_json::json!({
+            "protocol": self.protocol,
+            "requests": examples(&self.requests),
+            "responses": examples(&self.responses),
+        })
+    }
+}
+
+/// The agent's state with respect to a single key, as reported by `kbs2 agent query`.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Status {
+    /// Whether the key is wrapped, i.e. managed by the agent at all.
+    pub wrapped: bool,
+
+    /// Whether an agent is listening on `socket`.
+    pub running: bool,
+
+    /// Whether the running agent has the key unwrapped.
+    pub key_loaded: bool,
+
+    /// The agent's socket path.
+    pub socket: PathBuf,
+}
+
+impl Status {
+    /// Queries the running agent (if any) for the given public key.
+    pub fn query(pubkey: &str, wrapped: bool) -> Result<Self> {
+        let client = Client::new().ok();
+
+        // NOTE(ww): Bare keys are never loaded into the agent, so there's nothing to ask.
+        let key_loaded = match &client {
+            Some(client) if wrapped => client.query_key(pubkey)?,
+            _ => false,
+        };
+
+        Ok(Self {
+            wrapped,
+            running: client.is_some(),
+            key_loaded,
+            socket: Agent::path(),
+        })
+    }
+
+    /// Returns the exit code that `kbs2 agent query` signals this state with.
+    ///
+    /// This is 2 for bare keys, 3 if the agent isn't running, 1 if the key isn't
+    /// loaded, and 0 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        if !self.wrapped {
+            2
+        } else if !self.running {
+            3
+        } else if !self.key_loaded {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let running = if self.running {
+            "agent running"
+        } else {
+            "agent not running"
+        };
+
+        let key = if !self.wrapped {
+            "bare key (not managed by the agent)"
+        } else if self.key_loaded {
+            "key loaded"
+        } else {
+            "key not loaded"
+        };
+
+        write!(f, "{running}, {key}")
+    }
 }
 
 /// A convenience trait for marshaling and unmarshaling `RequestBody`s and `Response`s
@@ -93,7 +412,7 @@ trait Message {
         // one line before expecting a response), but it's one less thing to think about.
         // NOTE(ww): Safe unwrap: we only perform after checking `is_ok`, and we capture
         // the error by using `Result<Vec<_>, _>` with `collect`.
-        #[allow(clippy::unwrap_used)]
+        #[allow(clippy::unwrap_used, clippy::unbuffered_bytes)]
         let data: Result<Vec<_>, _> = reader
             .bytes()
             .take_while(|b| b.is_ok() && *b.as_ref().unwrap() != b'\n')
@@ -119,30 +438,166 @@ trait Message {
 impl Message for Request {}
 impl Message for Response {}
 
+/// Returns the executable of the process with the given PID.
+#[cfg(target_os = "linux")]
+fn process_exe(pid: i32) -> Result<PathBuf> {
+    fs::read_link(format!("/proc/{pid}/exe"))
+        .with_context(|| format!("couldn't find the executable of process {pid}"))
+}
+
+/// Returns whether the process with the given PID is running the same binary as we are.
+#[cfg(target_os = "linux")]
+fn is_own_binary(pid: i32) -> bool {
+    match (process_exe(pid), process_exe(std::process::id() as i32)) {
+        (Ok(theirs), Ok(ours)) => theirs == ours,
+        (Err(e), _) | (_, Err(e)) => {
+            log::error!("strict client check failed: {e:?}");
+            false
+        }
+    }
+}
+
+/// Identifies a keyfile as it was when the agent unwrapped it, so that the agent can
+/// notice when the keyfile is moved, rewrapped, or replaced.
+#[derive(Debug, PartialEq)]
+struct KeyfileState {
+    /// The canonical path to the keyfile.
+    path: PathBuf,
+    /// The keyfile's modification time.
+    mtime: SystemTime,
+    /// A hash of the keyfile's (wrapped) contents.
+    digest: u64,
+}
+
+impl KeyfileState {
+    /// Returns the current state of the keyfile at the given path.
+    fn current(keyfile: &Path) -> Result<Self> {
+        let path = fs::canonicalize(keyfile)
+            .with_context(|| format!("couldn't resolve keyfile: {}", keyfile.display()))?;
+        let mtime = fs::metadata(&path)?.modified()?;
+
+        let mut hasher = DefaultHasher::new();
+        util::read_guarded(&path, MAX_WRAPPED_KEY_FILESIZE)?.hash(&mut hasher);
+
+        Ok(Self {
+            path,
+            mtime,
+            digest: hasher.finish(),
+        })
+    }
+
+    /// Returns whether the keyfile is still the one that this state was taken from.
+    fn is_current(&self) -> bool {
+        Self::current(&self.path).is_ok_and(|current| current == *self)
+    }
+}
+
+/// The incorrect passwords given for a keyfile since it was last unwrapped.
+struct FailedUnwraps {
+    /// How many incorrect passwords have been given.
+    count: u32,
+    /// When the most recent incorrect password was given.
+    last: Instant,
+}
+
 /// Represents the state in a running `kbs2` authentication agent.
 pub struct Agent {
     /// The local path to the Unix domain socket.
     agent_path: PathBuf,
-    /// A map of public key => (keyfile path, unwrapped key material).
-    unwrapped_keys: HashMap<String, (String, SecretString)>,
+    /// A map of public key => (keyfile state, unwrapped key material).
+    unwrapped_keys: HashMap<String, (KeyfileState, SecretString)>,
+    /// A map of canonical keyfile path => incorrect passwords given for it.
+    failed_unwraps: HashMap<PathBuf, FailedUnwraps>,
     /// Whether or not the agent intends to quit momentarily.
     quitting: bool,
+    /// Whether the agent only serves clients running the agent's own binary.
+    strict: bool,
+    /// Whether the agent tells systemd when it's ready and when it's stopping.
+    systemd_notify: bool,
 }
 
 impl Agent {
-    /// Returns a unique, user-specific socket path that the authentication agent listens on.
+    /// Returns a unique, user-specific socket path that the authentication agent listens on,
+    /// unless overridden with `$KBS2_AGENT_SOCK`.
     fn path() -> PathBuf {
+        // NOTE(ww): The override lets a user run more than one agent at once, e.g. one per
+        // test in our own test suite, without disturbing their real one.
+        if let Some(path) = std::env::var_os("KBS2_AGENT_SOCK").filter(|path| !path.is_empty()) {
+            return path.into();
+        }
+
         let mut agent_path = PathBuf::from("/tmp");
         agent_path.push(format!("kbs2-agent-{}", whoami::username()));
 
         agent_path
     }
 
+    /// Takes the user-wide lock that serializes unwrapping keys into the agent, waiting up
+    /// to `timeout` for any other `kbs2` process holding it.
+    ///
+    /// This keeps concurrent `kbs2` invocations from each prompting for the master password.
+    /// The lock is released when the returned handle is dropped, or when its holder dies.
+    pub fn unwrap_lock(timeout: Duration) -> Result<Flock<File>> {
+        Self::unwrap_lock_at(&Self::path(), timeout)
+    }
+
+    /// Like `unwrap_lock`, but for the agent that listens on `agent_path`.
+    #[doc(hidden)]
+    fn unwrap_lock_at(agent_path: &Path, timeout: Duration) -> Result<Flock<File>> {
+        let mut lock_path = agent_path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        // NOTE(ww): The lock lives in /tmp alongside the socket, so refuse to follow
+        // any symlink that someone else might have planted there.
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&lock_path)
+            .with_context(|| format!("failed to open unwrap lock: {}", lock_path.display()))?;
+
+        let start = Instant::now();
+        loop {
+            match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+                Ok(lock) => return Ok(lock),
+                Err((f, Errno::EWOULDBLOCK)) => file = f,
+                Err((_, e)) => {
+                    return Err(anyhow!(
+                        "failed to take unwrap lock: {} ({e})",
+                        lock_path.display()
+                    ))
+                }
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(anyhow!(
+                    "timed out after {}s waiting for another kbs2 process to unwrap the key \
+                     (lock: {})",
+                    timeout.as_secs(),
+                    lock_path.display()
+                ));
+            }
+
+            log::debug!("waiting for unwrap lock...");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
     /// Spawns a new agent as a daemon process, returning once the daemon
     /// is ready to begin serving clients.
-    pub fn spawn() -> Result<()> {
-        let agent_path = Self::path();
+    ///
+    /// The agent is spawned from `binary` if supplied, or from the running `kbs2`
+    /// binary otherwise. Spawning fails if the agent isn't ready within `timeout`.
+    pub fn spawn(binary: Option<&Path>, timeout: Duration) -> Result<()> {
+        Self::spawn_at(&Self::path(), binary, timeout)
+    }
 
+    /// Like `spawn`, but for an agent that listens on `agent_path`.
+    #[doc(hidden)]
+    fn spawn_at(agent_path: &Path, binary: Option<&Path>, timeout: Duration) -> Result<()> {
         // If an agent appears to be running already, do nothing.
         if agent_path.exists() {
             log::debug!("agent seems to be running; not trying to spawn another");
@@ -162,32 +617,70 @@ impl Agent {
 
         // NOTE(ww): Given the above, it *should* be safe to spawn based on the path returned by
         // `current_exe`: we know we aren't being tricked with any hardlink + SUID shenanigans.
-        let kbs2 = std::env::current_exe().with_context(|| "failed to locate the kbs2 binary")?;
+        // Some packaging schemes (e.g. AppImages) run us from a path that can't be re-executed,
+        // which is what `agent-binary` is for.
+        let kbs2 = match binary {
+            Some(binary) => binary.to_path_buf(),
+            None => std::env::current_exe().with_context(|| "failed to locate the kbs2 binary")?,
+        };
 
         // NOTE(ww): We could spawn the agent by forking and daemonizing, but that would require
         // at least one direct use of unsafe{} (for the fork itself), and potentially others.
         // This is a little simpler and requires less unsafety.
-        let _ = Command::new(kbs2)
+        let mut child = Command::new(&kbs2)
             .arg("agent")
+            .env("KBS2_AGENT_SOCK", agent_path)
             .stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::null())
-            .spawn()?;
+            .spawn()
+            .with_context(|| format!("failed to execute the agent binary: {}", kbs2.display()))?;
 
-        for attempt in 0..10 {
-            log::debug!("waiting for agent, loop {}...", attempt);
+        let start = Instant::now();
+        loop {
             thread::sleep(Duration::from_millis(10));
             if agent_path.exists() {
                 return Ok(());
             }
-        }
 
-        Err(anyhow!("agent spawn timeout exhausted"))
+            // NOTE(ww): A healthy agent daemonizes, so its initial process exits successfully.
+            // Anything else means that the agent never got off the ground.
+            if let Some(status) = child.try_wait()?.filter(|s| !s.success()) {
+                return Err(anyhow!(
+                    "agent binary exited before the agent started ({}): {}",
+                    status,
+                    kbs2.display()
+                ));
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(anyhow!(
+                    "agent never created its socket within {}ms (see agent-spawn-timeout-ms): {}",
+                    timeout.as_millis(),
+                    agent_path.display()
+                ));
+            }
+
+            log::debug!("waiting for agent...");
+        }
     }
 
     /// Initializes a new agent without accepting connections.
-    pub fn new() -> Result<Self> {
-        let agent_path = Self::path();
+    ///
+    /// In `strict` mode, the agent refuses to start unless its parent is a `kbs2` process
+    /// (specifically, the same binary as the agent) owned by the same user, and only serves
+    /// clients running the same binary.
+    pub fn new(strict: bool) -> Result<Self> {
+        Self::new_at(Self::path(), strict)
+    }
+
+    /// Like `new`, but for an agent that listens on `agent_path`.
+    #[doc(hidden)]
+    fn new_at(agent_path: PathBuf, strict: bool) -> Result<Self> {
+        if strict {
+            Self::check_parent().with_context(|| "agent-strict: refusing to start")?;
+        }
+
         if agent_path.exists() {
             return Err(anyhow!(
                 "an agent is already running or didn't exit cleanly"
@@ -198,10 +691,62 @@ impl Agent {
         Ok(Self {
             agent_path: agent_path,
             unwrapped_keys: HashMap::new(),
+            failed_unwraps: HashMap::new(),
             quitting: false,
+            strict: strict,
+            systemd_notify: false,
         })
     }
 
+    /// Has the agent notify systemd (via `NOTIFY_SOCKET`) once it's listening, and again
+    /// when it's stopping, for `Type=notify` services. Without `NOTIFY_SOCKET`, this does
+    /// nothing.
+    pub fn with_systemd_notify(mut self, systemd_notify: bool) -> Self {
+        self.systemd_notify = systemd_notify;
+        self
+    }
+
+    /// Sends the given state to systemd, if the agent was asked to.
+    ///
+    /// Failures are logged rather than returned: the agent works the same either way.
+    #[doc(hidden)]
+    fn notify(&self, state: &str) {
+        if self.systemd_notify {
+            if let Err(e) = sd_notify(state) {
+                log::error!("couldn't notify systemd ({state}): {e}");
+            }
+        }
+    }
+
+    /// Checks that our parent process is running our own binary, as our own user.
+    #[cfg(target_os = "linux")]
+    fn check_parent() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let ppid = nix::unistd::getppid().as_raw();
+        let owner = fs::metadata(format!("/proc/{ppid}"))
+            .with_context(|| format!("couldn't inspect parent process {ppid}"))?
+            .uid();
+        if owner != Uid::current().as_raw() {
+            return Err(anyhow!("parent process {ppid} belongs to another user"));
+        }
+
+        let exe = process_exe(ppid)?;
+        if exe != process_exe(std::process::id() as i32)? {
+            return Err(anyhow!(
+                "parent process {ppid} isn't kbs2: {}",
+                exe.display()
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn check_parent() -> Result<()> {
+        Err(anyhow!("agent-strict is only supported on Linux"))
+    }
+
     // TODO(ww): These can be replaced with the UnixStream.peer_cred API once it stabilizes:
     // https://doc.rust-lang.org/std/os/unix/net/struct.UnixStream.html#method.peer_cred
     #[cfg(any(target_os = "linux", target_os = "android",))]
@@ -210,7 +755,7 @@ impl Agent {
         use nix::sys::socket::sockopt::PeerCredentials;
 
         if let Ok(cred) = getsockopt(stream, PeerCredentials) {
-            cred.uid() == Uid::effective().as_raw()
+            cred.uid() == Uid::effective().as_raw() && (!self.strict || is_own_binary(cred.pid()))
         } else {
             log::error!("getsockopt failed; treating as auth failure");
             false
@@ -242,39 +787,79 @@ impl Agent {
     /// requests made during a client's connection.
     fn handle_request_body(&mut self, body: RequestBody) -> Response {
         match body {
-            RequestBody::UnwrapKey(pubkey, keyfile, password) => {
+            RequestBody::UnwrapKey(pubkey, keyfile, password, policy) => {
                 let password = Secret::new(password);
+                let state = match KeyfileState::current(Path::new(&keyfile)) {
+                    Ok(state) => state,
+                    Err(e) => {
+                        log::error!("keyfile unwrap failed: {:?}", e);
+                        return Response::Failure(FailureKind::Io(format!("{e:#}")));
+                    }
+                };
+
                 // If the running agent is already tracking an unwrapped key for this
-                // pubkey, return early with a success.
-                #[allow(clippy::map_entry)]
-                if self.unwrapped_keys.contains_key(&pubkey) {
-                    log::debug!(
-                        "client requested unwrap for already unwrapped keyfile: {}",
-                        keyfile
-                    );
-                    Response::Success("OK; agent already has unwrapped key".into())
-                } else {
-                    match RageLib::unwrap_keyfile(&keyfile, password) {
-                        Ok(unwrapped_key) => {
-                            self.unwrapped_keys.insert(pubkey, (keyfile, unwrapped_key));
-                            Response::Success("OK; unwrapped key ready".into())
-                        }
-                        Err(e) => {
-                            log::error!("keyfile unwrap failed: {:?}", e);
-                            Response::Failure(FailureKind::Unwrap(e.to_string()))
+                // pubkey from this exact keyfile, return early with a success. Otherwise,
+                // the keyfile has moved or changed since we unwrapped it, so unwrap it again.
+                match self.tracked_key(&pubkey) {
+                    Some((cached, _)) if *cached == state => {
+                        log::debug!(
+                            "client requested unwrap for already unwrapped keyfile: {}",
+                            keyfile
+                        );
+                        return Response::Success("OK; agent already has unwrapped key".into());
+                    }
+                    Some(_) => {
+                        log::debug!("keyfile changed since unwrap, re-unwrapping: {}", keyfile)
+                    }
+                    None => {}
+                }
+
+                if let Some(refusal) = self.throttle_unwrap(&state.path, &policy) {
+                    log::warn!("refusing to unwrap {}: {:?}", keyfile, refusal);
+                    return Response::Failure(refusal);
+                }
+
+                match RageLib::unwrap_keyfile(&state.path, password) {
+                    Ok(unwrapped_key) => {
+                        self.failed_unwraps.remove(&state.path);
+                        self.unwrapped_keys.insert(pubkey, (state, unwrapped_key));
+                        Response::Success("OK; unwrapped key ready".into())
+                    }
+                    Err(e) if e.is::<BadPassword>() => {
+                        log::error!("keyfile unwrap failed: incorrect password for {}", keyfile);
+
+                        let failures =
+                            self.failed_unwraps
+                                .entry(state.path)
+                                .or_insert(FailedUnwraps {
+                                    count: 0,
+                                    last: Instant::now(),
+                                });
+                        failures.count += 1;
+                        failures.last = Instant::now();
+
+                        match policy.max_attempts.saturating_sub(failures.count) {
+                            0 => {
+                                Response::Failure(FailureKind::LockedOut(UNWRAP_LOCKOUT.as_secs()))
+                            }
+                            left => Response::Failure(FailureKind::BadPassword(left)),
                         }
                     }
+                    Err(e) => {
+                        log::error!("keyfile unwrap failed: {:?}", e);
+                        Response::Failure(FailureKind::Unwrap(e.to_string()))
+                    }
                 }
             }
             RequestBody::QueryUnwrappedKey(pubkey) => {
-                if self.unwrapped_keys.contains_key(&pubkey) {
+                if self.current_key(&pubkey).is_some() {
                     Response::Success("OK".into())
                 } else {
                     Response::Failure(FailureKind::Query)
                 }
             }
             RequestBody::GetUnwrappedKey(pubkey) => {
-                if let Some((_, unwrapped_key)) = self.unwrapped_keys.get(&pubkey) {
+                if let Some(unwrapped_key) = self.current_key(&pubkey) {
                     log::debug!("successful key request for pubkey: {}", pubkey);
                     Response::Success(unwrapped_key.expose_secret().into())
                 } else {
@@ -282,11 +867,14 @@ impl Agent {
                     Response::Failure(FailureKind::Query)
                 }
             }
-            RequestBody::FlushKeys => {
-                self.unwrapped_keys.clear();
-                log::debug!("successfully flushed all unwrapped keys");
+            RequestBody::FlushKey(pubkey) => {
+                if let Some(pubkey) = self.tracked_pubkey(&pubkey) {
+                    self.unwrapped_keys.remove(&pubkey);
+                    log::debug!("flushed unwrapped key for pubkey: {}", pubkey);
+                }
                 Response::Success("OK".into())
             }
+            RequestBody::FlushKeys => Response::Success(self.flush_keys().to_string()),
             RequestBody::Quit => {
                 self.quitting = true;
                 log::debug!("agent exit requested");
@@ -295,6 +883,83 @@ impl Agent {
         }
     }
 
+    /// Returns why an attempt to unwrap the given keyfile should be refused, if it should be:
+    /// either it comes too soon after an incorrect password, or too many incorrect passwords
+    /// were given for the keyfile too recently.
+    fn throttle_unwrap(&mut self, keyfile: &Path, policy: &UnwrapPolicy) -> Option<FailureKind> {
+        if policy.force {
+            self.failed_unwraps.remove(keyfile);
+            return None;
+        }
+
+        let failures = self.failed_unwraps.get(keyfile)?;
+        let elapsed = failures.last.elapsed();
+
+        if failures.count >= policy.max_attempts {
+            if elapsed < UNWRAP_LOCKOUT {
+                let left = UNWRAP_LOCKOUT - elapsed;
+                return Some(FailureKind::LockedOut(left.as_secs().max(1)));
+            }
+
+            log::debug!("lockout expired for {}", keyfile.display());
+            self.failed_unwraps.remove(keyfile);
+            return None;
+        }
+
+        let backoff = unlock_backoff(policy.backoff_ms, failures.count);
+        (elapsed < backoff).then(|| {
+            let left = backoff - elapsed;
+            FailureKind::Backoff(left.as_millis().try_into().unwrap_or(u64::MAX).max(1))
+        })
+    }
+
+    /// Returns the unwrapped key for the given pubkey, if the agent has one.
+    ///
+    /// Keys whose keyfiles have moved or changed since they were unwrapped are forgotten,
+    /// so that clients unwrap them again.
+    fn current_key(&mut self, pubkey: &str) -> Option<&SecretString> {
+        let pubkey = self.tracked_pubkey(pubkey)?;
+
+        if self
+            .unwrapped_keys
+            .get(&pubkey)
+            .is_some_and(|(state, _)| !state.is_current())
+        {
+            log::debug!("keyfile for {} changed since unwrap, flushing", pubkey);
+            self.unwrapped_keys.remove(&pubkey);
+        }
+
+        self.unwrapped_keys.get(&pubkey).map(|(_, key)| key)
+    }
+
+    /// Returns the agent's entry for the given pubkey, if it has one; see `tracked_pubkey`.
+    fn tracked_key(&self, pubkey: &str) -> Option<&(KeyfileState, SecretString)> {
+        self.tracked_pubkey(pubkey)
+            .and_then(|pubkey| self.unwrapped_keys.get(&pubkey))
+    }
+
+    /// Returns the given pubkey if the agent has an unwrapped key for it.
+    ///
+    /// NOTE(ww): Clients' pubkeys are compared against every tracked pubkey in constant
+    /// time, rather than looked up directly, so that the time taken doesn't reveal how
+    /// much of a pubkey matched. Pubkeys aren't secret, but this is cheap defense in depth.
+    fn tracked_pubkey(&self, pubkey: &str) -> Option<String> {
+        self.unwrapped_keys.keys().fold(None, |found, tracked| {
+            match bool::from(tracked.as_bytes().ct_eq(pubkey.as_bytes())) {
+                true => Some(tracked.clone()),
+                false => found,
+            }
+        })
+    }
+
+    /// Forgets all unwrapped keys, returning how many there were.
+    fn flush_keys(&mut self) -> usize {
+        let flushed = self.unwrapped_keys.len();
+        self.unwrapped_keys.clear();
+        log::debug!("successfully flushed all unwrapped keys");
+        flushed
+    }
+
     /// Handles a single client connection.
     /// Individual clients may issue multiple requests in a single session.
     fn handle_client(&mut self, stream: UnixStream) {
@@ -352,6 +1017,15 @@ impl Agent {
 
         let listener = UnixListener::bind(&self.agent_path)?;
 
+        // NOTE(ww): Only now can clients connect, so only now are we ready.
+        self.notify("READY=1");
+
+        // NOTE(ww): SIGHUP flushes all unwrapped keys, but keeps the agent running.
+        // The handler wakes us through a pipe that we wait on alongside the listener, so
+        // that we flush immediately rather than on the next connection.
+        listener.set_nonblocking(true)?;
+        let (hup, _hup_write) = install_sighup_handler()?;
+
         // NOTE(ww): This could spawn a separate thread for each incoming connection, but I see
         // no reason to do so:
         //
@@ -363,18 +1037,61 @@ impl Agent {
         //    each `Agent` becomes an `Arc<Mutex<Agent>>` to protect the underlying `HashMap`,
         //    and makes actually quitting the agent with a `Quit` request more difficult than it
         //    needs to be.
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    self.handle_client(stream);
-                    if self.quitting {
-                        break;
-                    }
-                }
-                Err(e) => {
-                    log::error!("connect error: {:?}", e);
-                    continue;
-                }
+        let served = loop {
+            if self.quitting {
+                break Ok(());
+            }
+            if let Err(e) = self.serve_once(&listener, &hup) {
+                break Err(e);
+            }
+        };
+        SIGHUP_PIPE.store(-1, Ordering::SeqCst);
+
+        self.notify("STOPPING=1");
+
+        served
+    }
+
+    /// Waits for either a client or a `SIGHUP` (as signaled on `hup`), and handles it.
+    #[doc(hidden)]
+    fn serve_once(&mut self, listener: &UnixListener, hup: &OwnedFd) -> Result<()> {
+        let (flush, connect) = {
+            let mut fds = [
+                PollFd::new(hup.as_fd(), PollFlags::POLLIN),
+                PollFd::new(listener.as_fd(), PollFlags::POLLIN),
+            ];
+            match poll(&mut fds, PollTimeout::NONE) {
+                Ok(_) => {}
+                // NOTE(ww): This is usually the SIGHUP itself, which leaves the pipe readable
+                // for the next go-around.
+                Err(Errno::EINTR) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+
+            let ready = |fd: PollFd| fd.revents().is_some_and(|r| r.contains(PollFlags::POLLIN));
+            (ready(fds[0]), ready(fds[1]))
+        };
+
+        if flush {
+            // NOTE(ww): Several SIGHUPs in quick succession only need one flush.
+            let mut buf = [0; 64];
+            while matches!(nix::unistd::read(hup.as_raw_fd(), &mut buf), Ok(n) if n > 0) {}
+
+            log::debug!("SIGHUP received; flushing keys");
+            self.flush_keys();
+        }
+
+        if connect {
+            match listener.accept() {
+                // NOTE(ww): Some platforms have accepted sockets inherit the listener's
+                // O_NONBLOCK, but clients are served with blocking I/O.
+                Ok((stream, _)) => match stream.set_nonblocking(false) {
+                    Ok(()) => self.handle_client(stream),
+                    Err(e) => log::error!("connect error: {:?}", e),
+                },
+                // The client went away before we got to it.
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => log::error!("connect error: {:?}", e),
             }
         }
 
@@ -382,6 +1099,40 @@ impl Agent {
     }
 }
 
+/// Sends the given state (e.g. `READY=1`) to the service manager's notification socket,
+/// as named by `NOTIFY_SOCKET`. This is a tiny subset of `sd_notify(3)`.
+///
+/// Does nothing if `NOTIFY_SOCKET` isn't set, i.e. when not running under systemd.
+pub fn sd_notify(state: &str) -> Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify_socket(&socket, state),
+        None => Ok(()),
+    }
+}
+
+#[doc(hidden)]
+fn notify_socket(socket: &OsStr, state: &str) -> Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+
+    // NOTE(ww): A leading `@` means a socket in the abstract namespace, which only Linux has.
+    match socket.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(anyhow!("abstract NOTIFY_SOCKET is only supported on Linux")),
+        None => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+
+    Ok(())
+}
+
 impl Drop for Agent {
     fn drop(&mut self) {
         log::debug!("agent teardown");
@@ -389,7 +1140,7 @@ impl Drop for Agent {
         // NOTE(ww): We don't expect this to fail, but it's okay if it does: the agent gets dropped
         // at the very end of its lifecycle, meaning that an expect here is acceptable.
         #[allow(clippy::expect_used)]
-        fs::remove_file(Agent::path()).expect("attempted to remove missing agent socket");
+        fs::remove_file(&self.agent_path).expect("attempted to remove missing agent socket");
     }
 }
 
@@ -403,10 +1154,33 @@ pub struct Client {
 impl Client {
     /// Create and return a new client, failing if connection to the agent fails.
     pub fn new() -> Result<Self> {
+        Self::connect(&Agent::path())
+    }
+
+    /// Like `new`, but for an agent that listens on `agent_path`.
+    #[doc(hidden)]
+    fn connect(agent_path: &Path) -> Result<Self> {
         log::debug!("creating a new agent client");
 
-        let stream = UnixStream::connect(Agent::path())
-            .with_context(|| "failed to connect to agent; is it running?")?;
+        let stream = UnixStream::connect(agent_path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => anyhow!(
+                "no kbs2 agent is running (no socket at {}); start one with `kbs2 agent`",
+                agent_path.display()
+            ),
+            // NOTE(ww): A socket that refuses connections was left behind by an agent that
+            // didn't exit cleanly, e.g. because it crashed or was killed.
+            io::ErrorKind::ConnectionRefused => anyhow!(
+                "the kbs2 agent isn't running, but left its socket behind; \
+                 remove it with `rm {}` and try again",
+                agent_path.display()
+            ),
+            _ => anyhow!(
+                "failed to connect to the kbs2 agent at {}: {}",
+                agent_path.display(),
+                e
+            ),
+        })?;
+
         Ok(Self { stream })
     }
 
@@ -424,22 +1198,47 @@ impl Client {
 
     /// Instruct the agent to unwrap the given keyfile, using the given password.
     /// The keyfile path and its unwrapped contents are associated with the given pubkey.
-    pub fn add_key(&self, pubkey: &str, keyfile: &str, password: SecretString) -> Result<()> {
+    ///
+    /// An incorrect password fails with `BadPassword`, and a keyfile that the agent has
+    /// locked out fails with `TooManyAttempts`. If the agent asks us to back off first,
+    /// we wait and try again.
+    pub fn add_key(
+        &self,
+        pubkey: &str,
+        keyfile: &str,
+        password: SecretString,
+        policy: &UnwrapPolicy,
+    ) -> Result<()> {
         log::debug!("add_key: requesting that agent unwrap {}", keyfile);
 
-        let body = RequestBody::UnwrapKey(
-            pubkey.into(),
-            keyfile.into(),
-            password.expose_secret().into(),
-        );
-        let resp = self.request(body)?;
-
-        match resp {
-            Response::Success(msg) => {
-                log::debug!("agent reports success: {}", msg);
-                Ok(())
+        loop {
+            let body = RequestBody::UnwrapKey(
+                pubkey.into(),
+                keyfile.into(),
+                password.expose_secret().into(),
+                policy.clone(),
+            );
+
+            match self.request(body)? {
+                Response::Success(msg) => {
+                    log::debug!("agent reports success: {}", msg);
+                    return Ok(());
+                }
+                Response::Failure(FailureKind::Backoff(ms)) => {
+                    log::debug!("agent asked us to back off for {}ms", ms);
+                    thread::sleep(Duration::from_millis(ms));
+                }
+                Response::Failure(FailureKind::BadPassword(_)) => return Err(BadPassword.into()),
+                Response::Failure(FailureKind::LockedOut(secs)) => {
+                    return Err(TooManyAttempts {
+                        retry_after: Some(Duration::from_secs(secs)),
+                    }
+                    .into())
+                }
+                Response::Failure(kind) => {
+                    return Err(anyhow!("adding key to agent failed: {:?}", kind))
+                }
             }
-            Response::Failure(kind) => Err(anyhow!("adding key to agent failed: {:?}", kind)),
         }
     }
 
@@ -473,11 +1272,26 @@ impl Client {
         }
     }
 
-    /// Ask the agent to flush all of its unwrapped keys.
-    pub fn flush_keys(&self) -> Result<()> {
+    /// Ask the agent to flush its unwrapped key for the given pubkey, if it has one.
+    pub fn flush_key(&self, pubkey: &str) -> Result<()> {
+        log::debug!("flush_key: asking agent to forget key for {}", pubkey);
+
+        match self.request(RequestBody::FlushKey(pubkey.into()))? {
+            Response::Success(_) => Ok(()),
+            Response::Failure(kind) => Err(anyhow!("flushing key from agent failed: {:?}", kind)),
+        }
+    }
+
+    /// Ask the agent to flush all of its unwrapped keys, returning how many it flushed.
+    pub fn flush_keys(&self) -> Result<usize> {
         log::debug!("flush_keys: asking agent to forget all keys");
-        self.request(RequestBody::FlushKeys)?;
-        Ok(())
+
+        match self.request(RequestBody::FlushKeys)? {
+            Response::Success(flushed) => flushed
+                .parse()
+                .with_context(|| format!("agent sent a malformed key count: {flushed}")),
+            Response::Failure(kind) => Err(anyhow!("flushing keys from agent failed: {:?}", kind)),
+        }
     }
 
     /// Ask the agent to quit gracefully.
@@ -487,3 +1301,374 @@ impl Client {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use secrecy::SecretString;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_protocol_examples_roundtrip() {
+        let description = ProtocolDescription::current().unwrap();
+
+        assert_eq!(description.requests.len(), RequestBody::examples().len());
+        for (name, example) in &description.requests {
+            let request: Request = serde_json::from_value(example.clone()).unwrap();
+            assert_eq!(request.protocol, PROTOCOL_VERSION);
+            assert_eq!(request.body.name(), *name);
+            assert_eq!(&serde_json::to_value(&request).unwrap(), example);
+        }
+
+        assert_eq!(description.responses.len(), Response::examples().len());
+        for (name, example) in &description.responses {
+            let response: Response = serde_json::from_value(example.clone()).unwrap();
+            assert_eq!(response.name(), *name);
+            assert_eq!(&serde_json::to_value(&response).unwrap(), example);
+        }
+
+        assert!(description.example("FlushKeys").is_some());
+        assert!(description.example("Failure.Io").is_some());
+        assert!(description.example("Nonexistent").is_none());
+    }
+
+    #[test]
+    fn test_status() {
+        let status = |wrapped, running, key_loaded| Status {
+            wrapped,
+            running,
+            key_loaded,
+            socket: "/tmp/kbs2-agent-test".into(),
+        };
+
+        for (status, code, line) in [
+            (status(true, true, true), 0, "agent running, key loaded"),
+            (
+                status(true, true, false),
+                1,
+                "agent running, key not loaded",
+            ),
+            (
+                status(true, false, false),
+                3,
+                "agent not running, key not loaded",
+            ),
+            (
+                status(false, true, false),
+                2,
+                "agent running, bare key (not managed by the agent)",
+            ),
+        ] {
+            assert_eq!(status.exit_code(), code);
+            assert_eq!(status.to_string(), line);
+        }
+
+        assert_eq!(
+            serde_json::to_value(status(true, true, false)).unwrap(),
+            serde_json::json!({
+                "wrapped": true,
+                "running": true,
+                "key_loaded": false,
+                "socket": "/tmp/kbs2-agent-test",
+            })
+        );
+    }
+
+    #[test]
+    fn test_unwrap_lock() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("agent.sock");
+        let lock = Agent::unwrap_lock_at(&socket, Duration::from_secs(1)).unwrap();
+
+        // Anybody else waits for the holder, and eventually gives up.
+        let err = Agent::unwrap_lock_at(&socket, Duration::from_millis(100)).unwrap_err();
+        assert!(err.to_string().contains("waiting for another kbs2 process"));
+
+        drop(lock);
+        assert!(Agent::unwrap_lock_at(&socket, Duration::from_millis(100)).is_ok());
+    }
+
+    #[test]
+    fn test_agent_spawn_failures() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("agent.sock");
+        let timeout = Duration::from_millis(50);
+        let spawn = |binary: &str| Agent::spawn_at(&socket, Some(Path::new(binary)), timeout);
+
+        // Spawning is a no-op when an agent seems to be running already.
+        fs::write(&socket, "").unwrap();
+        assert!(spawn("/nonexistent/kbs2").is_ok());
+        fs::remove_file(&socket).unwrap();
+
+        // Spawning always refuses to run as root.
+        if Uid::current().is_root() {
+            let err = spawn("/bin/true").unwrap_err();
+            assert!(err.to_string().contains("unusual UID or UID/EUID pair"));
+            return;
+        }
+
+        let err = spawn("/nonexistent/kbs2").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("failed to execute the agent binary"));
+
+        let err = spawn("/bin/false").unwrap_err();
+        assert!(err.to_string().contains("exited before the agent started"));
+
+        let err = spawn("/bin/true").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("never created its socket within 50ms"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_strict_checks() {
+        let ours = std::env::current_exe().unwrap();
+        assert_eq!(process_exe(std::process::id() as i32).unwrap(), ours);
+        assert!(is_own_binary(std::process::id() as i32));
+        assert!(!is_own_binary(1));
+
+        // Our parent is the test harness, not this test binary.
+        let err = Agent::new(true).err().unwrap();
+        assert!(format!("{err:#}").starts_with("agent-strict: refusing to start: parent process"));
+    }
+
+    /// Serializes the tests that run an agent, since agents share the process-wide
+    /// SIGHUP handler.
+    static AGENT_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_agent_sighup_flushes_keys() {
+        let _lock = AGENT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let dir = tempdir().unwrap();
+        let keyfile = dir.path().join("key");
+        let pubkey =
+            RageLib::create_wrapped_keypair(&keyfile, SecretString::new("password".into()))
+                .unwrap();
+
+        let socket = dir.path().join("agent.sock");
+        let mut agent = Agent::new_at(socket.clone(), false).unwrap();
+        let resp = agent.handle_request_body(RequestBody::UnwrapKey(
+            pubkey.clone(),
+            keyfile.to_str().unwrap().into(),
+            "password".into(),
+            UnwrapPolicy::default(),
+        ));
+        assert!(matches!(resp, Response::Success(_)));
+        assert!(agent.tracked_key(&pubkey).is_some());
+
+        let listener = UnixListener::bind(&socket).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let (hup, _hup_write) = install_sighup_handler().unwrap();
+
+        // With no client in sight, SIGHUP alone is enough to wake the agent and flush.
+        signal::raise(Signal::SIGHUP).unwrap();
+        agent.serve_once(&listener, &hup).unwrap();
+        assert!(agent.tracked_key(&pubkey).is_none());
+        SIGHUP_PIPE.store(-1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_client_unreachable_agent() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("agent.sock");
+
+        let err = Client::connect(&socket).err().unwrap();
+        assert!(err.to_string().starts_with("no kbs2 agent is running"));
+
+        // Leave a socket behind, like an agent that was killed.
+        drop(UnixListener::bind(&socket).unwrap());
+        let err = Client::connect(&socket).err().unwrap();
+        assert!(err.to_string().contains("left its socket behind"));
+    }
+
+    /// Runs an agent on its own socket in `dir`, returning a client connected to it
+    /// along with the agent's thread.
+    fn run_agent(dir: &Path) -> (Client, thread::JoinHandle<Result<()>>) {
+        let socket = dir.join("agent.sock");
+        let mut agent = Agent::new_at(socket.clone(), false).unwrap();
+        let handle = thread::spawn(move || agent.run());
+
+        let client = (0..100)
+            .find_map(|_| {
+                thread::sleep(Duration::from_millis(10));
+                Client::connect(&socket).ok()
+            })
+            .unwrap();
+
+        (client, handle)
+    }
+
+    #[test]
+    fn test_agent_keyfile_changes() {
+        let _lock = AGENT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let dir = tempdir().unwrap();
+        let keyfile = dir.path().join("key");
+        let pubkey =
+            RageLib::create_wrapped_keypair(&keyfile, SecretString::new("old".into())).unwrap();
+        let keyfile = keyfile.to_str().unwrap();
+
+        let (client, handle) = run_agent(dir.path());
+
+        client
+            .add_key(
+                &pubkey,
+                keyfile,
+                SecretString::new("old".into()),
+                &UnwrapPolicy::default(),
+            )
+            .unwrap();
+        let key = client.get_key(&pubkey).unwrap();
+
+        // The same keyfile, by another path, is the same keyfile.
+        let indirect = dir.path().join(".").join("key");
+        client
+            .add_key(
+                &pubkey,
+                indirect.to_str().unwrap(),
+                SecretString::new("wrong".into()),
+                &UnwrapPolicy::default(),
+            )
+            .unwrap();
+
+        // Once the keyfile is rewrapped, the agent forgets the old unwrapped key...
+        RageLib::rewrap_keyfile(
+            keyfile,
+            SecretString::new("old".into()),
+            SecretString::new("new".into()),
+        )
+        .unwrap();
+        assert!(!client.query_key(&pubkey).unwrap());
+        assert!(client.get_key(&pubkey).is_err());
+
+        // ...and only accepts the new password for it.
+        assert!(client
+            .add_key(
+                &pubkey,
+                keyfile,
+                SecretString::new("old".into()),
+                &UnwrapPolicy::default()
+            )
+            .is_err());
+        client
+            .add_key(
+                &pubkey,
+                keyfile,
+                SecretString::new("new".into()),
+                &UnwrapPolicy::default(),
+            )
+            .unwrap();
+        assert_eq!(client.get_key(&pubkey).unwrap(), key);
+
+        // Moving the keyfile also requires unwrapping it again.
+        let moved = dir.path().join("moved-key");
+        fs::rename(keyfile, &moved).unwrap();
+        assert!(!client.query_key(&pubkey).unwrap());
+        client
+            .add_key(
+                &pubkey,
+                moved.to_str().unwrap(),
+                SecretString::new("new".into()),
+                &UnwrapPolicy::default(),
+            )
+            .unwrap();
+        assert!(client.query_key(&pubkey).unwrap());
+
+        // Flushing one key leaves the others alone.
+        client.flush_key("age1notakey").unwrap();
+        assert!(client.query_key(&pubkey).unwrap());
+        client.flush_key(&pubkey).unwrap();
+        assert!(!client.query_key(&pubkey).unwrap());
+
+        client.quit_agent().unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_agent_unwrap_lockout() {
+        let _lock = AGENT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let dir = tempdir().unwrap();
+        let keyfile = dir.path().join("key");
+        let pubkey =
+            RageLib::create_wrapped_keypair(&keyfile, SecretString::new("right".into())).unwrap();
+        let keyfile = keyfile.to_str().unwrap();
+
+        let (client, handle) = run_agent(dir.path());
+
+        let policy = UnwrapPolicy {
+            max_attempts: 2,
+            backoff_ms: 50,
+            force: false,
+        };
+
+        // The first incorrect password is just incorrect...
+        let err = client
+            .add_key(&pubkey, keyfile, SecretString::new("wrong".into()), &policy)
+            .unwrap_err();
+        assert!(err.is::<BadPassword>());
+
+        // ...and the next attempt is made to wait out the backoff, before it locks us out.
+        let start = Instant::now();
+        let err = client
+            .add_key(&pubkey, keyfile, SecretString::new("wrong".into()), &policy)
+            .unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(err.is::<TooManyAttempts>());
+
+        // Once locked out, even the right password is refused...
+        let err = client
+            .add_key(&pubkey, keyfile, SecretString::new("right".into()), &policy)
+            .unwrap_err();
+        assert!(err.is::<TooManyAttempts>());
+        assert!(!client.query_key(&pubkey).unwrap());
+
+        // ...unless the client forces a retry.
+        let forced = UnwrapPolicy {
+            force: true,
+            ..policy
+        };
+        client
+            .add_key(&pubkey, keyfile, SecretString::new("right".into()), &forced)
+            .unwrap();
+        assert!(client.query_key(&pubkey).unwrap());
+
+        client.quit_agent().unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_notify_socket() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        assert!(notify_socket(dir.path().join("nonexistent").as_os_str(), "READY=1").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_notify_socket_abstract() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("kbs2-test-notify-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+
+        notify_socket(OsStr::new(&format!("@{name}")), "STOPPING=1").unwrap();
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STOPPING=1");
+    }
+}
@@ -0,0 +1,396 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::kbs2::input::Aborted;
+use crate::kbs2::session::NoSuchRecord;
+use crate::kbs2::util;
+
+/// The default size, in bytes, at which the audit log is rotated.
+pub const DEFAULT_AUDIT_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// The outcome of an audited operation.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+    Aborted,
+}
+
+impl Outcome {
+    /// Returns the outcome corresponding to the given (possibly failed) result.
+    pub fn of<T>(result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => Outcome::Success,
+            Err(e) if e.is::<Aborted>() => Outcome::Aborted,
+            Err(_) => Outcome::Failure,
+        }
+    }
+}
+
+/// What was done to a record.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Read,
+    Write,
+    Delete,
+}
+
+/// Something that `kbs2` did, as recorded in the audit log.
+///
+/// NOTE(ww): Every field here is either an enumeration, a number, a record label,
+/// or a name taken from the config or command line (never a record field or an
+/// argument value), so no secret can end up in the log. In particular, errors are
+/// logged by kind rather than by message, since messages can quote their inputs.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event {
+    /// A `kbs2` subcommand ran to completion (successfully or otherwise).
+    Command {
+        /// The subcommand's name, e.g. `pass`.
+        subcommand: String,
+
+        /// The labels of the records given on the command line, if any.
+        labels: Vec<String>,
+
+        outcome: Outcome,
+
+        /// The kind of error that the subcommand failed with, if it failed.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error_kind: Option<String>,
+    },
+
+    /// A hook was run.
+    Hook {
+        /// The hook's command, as configured.
+        command: String,
+
+        /// The hook's exit code, if it exited normally.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exit_code: Option<i32>,
+
+        outcome: Outcome,
+    },
+
+    /// A wrapped key was unwrapped, either into the agent or directly.
+    Unwrap {
+        /// The public key of the unwrapped identity.
+        public_key: String,
+
+        /// Whether the key was unwrapped into the agent.
+        agent: bool,
+
+        outcome: Outcome,
+    },
+
+    /// A record was read, written, or deleted.
+    Record {
+        label: String,
+
+        operation: Operation,
+
+        outcome: Outcome,
+    },
+}
+
+/// A single line of the audit log.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Entry {
+    /// When the event happened, as seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// The ID of the `kbs2` process that logged the event.
+    pub pid: u32,
+
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Entry {
+    /// Creates a new entry for the given event, happening now.
+    pub fn new(event: Event) -> Self {
+        Self {
+            timestamp: util::current_timestamp(),
+            pid: std::process::id(),
+            event,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Success => write!(f, "success"),
+            Outcome::Failure => write!(f, "failure"),
+            Outcome::Aborted => write!(f, "aborted"),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Read => write!(f, "read"),
+            Operation::Write => write!(f, "write"),
+            Operation::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time =
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(self.timestamp));
+        write!(f, "{time} [{}] ", self.pid)?;
+
+        match &self.event {
+            Event::Command {
+                subcommand,
+                labels,
+                outcome,
+                error_kind,
+            } => {
+                write!(f, "command: {subcommand}")?;
+                if !labels.is_empty() {
+                    write!(f, " ({})", labels.join(", "))?;
+                }
+                write!(f, ": {outcome}")?;
+                if let Some(error_kind) = error_kind {
+                    write!(f, " ({error_kind})")?;
+                }
+                Ok(())
+            }
+            Event::Hook {
+                command,
+                exit_code,
+                outcome,
+            } => {
+                write!(f, "hook: {command}: {outcome}")?;
+                if let Some(exit_code) = exit_code {
+                    write!(f, " (exit code {exit_code})")?;
+                }
+                Ok(())
+            }
+            Event::Unwrap {
+                public_key,
+                agent,
+                outcome,
+            } => {
+                let via = if *agent { "agent" } else { "direct" };
+                write!(f, "unwrap ({via}): {public_key}: {outcome}")
+            }
+            Event::Record {
+                label,
+                operation,
+                outcome,
+            } => write!(f, "record {operation}: {label}: {outcome}"),
+        }
+    }
+}
+
+/// Returns a short, non-sensitive description of the kind of the given error.
+///
+/// This is `NoSuchRecord` for missing records, the kind of the underlying I/O error
+/// (e.g. `NotFound`) if there is one, and `aborted` or `error` otherwise.
+pub fn error_kind(e: &anyhow::Error) -> String {
+    if e.is::<Aborted>() {
+        return "aborted".into();
+    }
+
+    if e.chain().any(|cause| cause.is::<NoSuchRecord>()) {
+        return "NoSuchRecord".into();
+    }
+
+    e.chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+        .map(|e| format!("{:?}", e.kind()))
+        .unwrap_or_else(|| "error".into())
+}
+
+/// Returns the path that the audit log at `path` is rotated to.
+pub fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    rotated.into()
+}
+
+/// Appends an entry to the audit log at `path`, first rotating the log if the entry
+/// would take it past `max_bytes`.
+///
+/// Only one rotated log is kept.
+pub fn append(path: &Path, max_bytes: u64, entry: &Entry) -> Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.len() > 0 && metadata.len() + line.len() as u64 > max_bytes {
+            fs::rename(path, rotated_path(path))
+                .with_context(|| format!("failed to rotate audit log: {}", path.display()))?;
+        }
+    }
+
+    // NOTE(ww): Each entry is written with a single `write`, which `O_APPEND`
+    // keeps from interleaving with concurrent `kbs2` processes.
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut log| log.write_all(line.as_bytes()))
+        .with_context(|| format!("failed to write audit log: {}", path.display()))
+}
+
+/// Returns every entry in the audit log at `path` (including its rotated log),
+/// oldest first.
+///
+/// Malformed lines are skipped.
+pub fn entries(path: &Path) -> Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for path in [rotated_path(path), path.into()] {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to read audit log: {}", path.display()))
+            }
+        };
+
+        for line in contents.lines() {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(_) => log::debug!("skipping malformed audit log line: {:?}", line),
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use anyhow::anyhow;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn dummy_entry(subcommand: &str) -> Entry {
+        Entry {
+            timestamp: 1234567890,
+            pid: 1,
+            event: Event::Command {
+                subcommand: subcommand.into(),
+                labels: vec!["github".into()],
+                outcome: Outcome::Success,
+                error_kind: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_append_and_entries() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+
+        assert!(entries(&path).unwrap().is_empty());
+
+        append(&path, DEFAULT_AUDIT_LOG_MAX_BYTES, &dummy_entry("pass")).unwrap();
+        append(&path, DEFAULT_AUDIT_LOG_MAX_BYTES, &dummy_entry("env")).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let logged = entries(&path).unwrap();
+        assert_eq!(logged, vec![dummy_entry("pass"), dummy_entry("env")]);
+
+        let line = fs::read_to_string(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(line.lines().next().unwrap()).unwrap();
+        assert_eq!(json["event"], "command");
+        assert_eq!(json["subcommand"], "pass");
+        assert_eq!(json["outcome"], "success");
+        assert!(json.get("error_kind").is_none());
+    }
+
+    #[test]
+    fn test_append_rotates() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let size = serde_json::to_string(&dummy_entry("pass")).unwrap().len() as u64 + 1;
+
+        // Room for exactly two entries before rotating.
+        for subcommand in ["a", "b", "c", "d", "e"] {
+            append(&path, size * 2, &dummy_entry(subcommand)).unwrap();
+        }
+
+        assert!(fs::metadata(&path).unwrap().len() <= size * 2);
+        let subcommands: Vec<_> = entries(&path)
+            .unwrap()
+            .into_iter()
+            .map(|e| match e.event {
+                Event::Command { subcommand, .. } => subcommand,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(subcommands, ["c", "d", "e"]);
+    }
+
+    #[test]
+    fn test_entry_display() {
+        assert_eq!(
+            dummy_entry("pass").to_string(),
+            "2009-02-13T23:31:30Z [1] command: pass (github): success"
+        );
+
+        let entry = Entry {
+            timestamp: 0,
+            pid: 2,
+            event: Event::Hook {
+                command: "false".into(),
+                exit_code: Some(1),
+                outcome: Outcome::Failure,
+            },
+        };
+        assert_eq!(
+            entry.to_string(),
+            "1970-01-01T00:00:00Z [2] hook: false: failure (exit code 1)"
+        );
+
+        let entry = Entry {
+            timestamp: 0,
+            pid: 3,
+            event: Event::Record {
+                label: "github".into(),
+                operation: Operation::Delete,
+                outcome: Outcome::Success,
+            },
+        };
+        assert_eq!(
+            entry.to_string(),
+            "1970-01-01T00:00:00Z [3] record delete: github: success"
+        );
+    }
+
+    #[test]
+    fn test_error_kind() {
+        assert_eq!(error_kind(&anyhow!("bad record: hunter2")), "error");
+        assert_eq!(
+            error_kind(&anyhow::Error::from(NoSuchRecord("foo".into())).context("hunter2")),
+            "NoSuchRecord"
+        );
+        assert_eq!(error_kind(&Aborted.into()), "aborted");
+
+        let e: anyhow::Error = io::Error::from(io::ErrorKind::NotFound).into();
+        assert_eq!(error_kind(&e.context("hunter2")), "NotFound");
+    }
+}
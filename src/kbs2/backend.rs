@@ -1,14 +1,21 @@
-use std::io::{Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::Path;
+use std::time::Duration;
 
 use age::armor::{ArmoredReader, ArmoredWriter, Format};
 use age::{Decryptor, IdentityFileEntry};
 use anyhow::{anyhow, Context, Result};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
 use secrecy::{ExposeSecret, SecretString};
 
 use crate::kbs2::agent;
+use crate::kbs2::audit;
 use crate::kbs2::config;
+use crate::kbs2::i18n::{self, Message};
+use crate::kbs2::input::{self, WithHelp};
 use crate::kbs2::record::Record;
+use crate::kbs2::timings;
 use crate::kbs2::util;
 
 /// The maximum size of a wrapped key file, on disk.
@@ -18,6 +25,254 @@ use crate::kbs2::util;
 /// number of harden the I/O that the agent does, and a single page/4K seems reasonable.
 pub const MAX_WRAPPED_KEY_FILESIZE: u64 = 4096;
 
+/// How long to wait for another `kbs2` process that's prompting for the master password.
+const UNWRAP_LOCK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The first line of a wrapped (i.e. passphrase-encrypted and ASCII-armored) key file.
+const WRAPPED_KEY_HEADER: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// The byte that a DEFLATE-compressed record's plaintext starts with.
+///
+/// NOTE(ww): Uncompressed records are plain JSON objects, so they always start with `{`
+/// and can never be mistaken for compressed ones.
+const DEFLATE_HEADER: u8 = 0x01;
+
+/// The error returned when a record isn't encrypted to any of the backend's identities,
+/// e.g. because it was encrypted to some other keypair.
+#[derive(Debug)]
+pub struct ForeignRecord {
+    /// The recipients that the record *is* encrypted to, as described by `header_recipients`.
+    pub recipients: Vec<String>,
+}
+
+impl std::fmt::Display for ForeignRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the record isn't encrypted to your key (backend reports: NoMatchingKeys)"
+        )?;
+
+        match self.recipients.is_empty() {
+            true => Ok(()),
+            false => write!(f, "; it's encrypted to {}", self.recipients.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for ForeignRecord {}
+
+/// The error returned when a wrapped keyfile can't be unwrapped because the password is wrong.
+#[derive(Debug)]
+pub struct BadPassword;
+
+impl std::fmt::Display for BadPassword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", i18n::text(Message::ErrorBadPassword))
+    }
+}
+
+impl std::error::Error for BadPassword {}
+
+/// The error returned once a password has been entered incorrectly too many times.
+#[derive(Debug)]
+pub struct TooManyAttempts {
+    /// How long until the agent accepts another attempt, if it's the one refusing them.
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for TooManyAttempts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many incorrect passwords")?;
+
+        match self.retry_after {
+            Some(retry_after) => write!(
+                f,
+                "; try again in {}s, or pass --force-retry",
+                retry_after.as_secs().max(1)
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for TooManyAttempts {}
+
+/// Returns how long to wait after the given number of consecutive incorrect master
+/// passwords: `backoff_ms` after the first, doubling after each one after that.
+pub fn unlock_backoff(backoff_ms: u64, failures: u32) -> Duration {
+    let factor = 1u64 << failures.saturating_sub(1).min(16);
+    Duration::from_millis(backoff_ms.saturating_mul(factor))
+}
+
+/// Prompts for a master password and passes it to `attempt`, prompting again whenever
+/// `attempt` fails with `BadPassword`.
+///
+/// Each retry waits for `unlock_backoff` first, and the master password is prompted for
+/// at most `unlock-max-attempts` times before this fails with `TooManyAttempts`.
+pub fn with_master_password<T>(
+    config: &config::Config,
+    prompt: Option<&'static str>,
+    mut attempt: impl FnMut(SecretString) -> Result<T>,
+) -> Result<T> {
+    let max_attempts = config.unlock_max_attempts.max(1);
+    let description = util::password_description(&config.store, &config.config_dir);
+
+    for failures in 1..=max_attempts {
+        let password = timings::span("pinentry", || {
+            util::get_password(prompt, &description, &config.pinentry)
+        })?;
+
+        match attempt(password) {
+            Err(e) if e.is::<BadPassword>() && failures < max_attempts => {
+                util::warn(&format!("{e}; {} attempt(s) left", max_attempts - failures));
+                std::thread::sleep(unlock_backoff(config.unlock_backoff_ms, failures));
+            }
+            Err(e) if e.is::<BadPassword>() => break,
+            result => return result,
+        }
+    }
+
+    Err(TooManyAttempts { retry_after: None }.into())
+}
+
+/// Describes each of the recipients that the given ASCII-armored age file is encrypted to,
+/// as far as its header identifies them.
+///
+/// NOTE: age deliberately doesn't record which key an X25519 stanza was made for, so those
+/// can only be described by type. SSH stanzas carry a short tag of the recipient's key.
+pub fn header_recipients(encrypted: &str) -> Result<Vec<String>> {
+    let mut contents = vec![];
+    ArmoredReader::new(encrypted.as_bytes())
+        .read_to_end(&mut contents)
+        .map_err(|e| anyhow!("unable to read age header: {}", e))?;
+
+    let mut lines = contents.split(|b| *b == b'\n');
+    if lines.next() != Some(b"age-encryption.org/v1") {
+        return Err(anyhow!("unable to read age header: not an age file"));
+    }
+
+    let mut recipients = vec![];
+    for line in lines.take_while(|l| !l.starts_with(b"---")) {
+        let Some(stanza) = line.strip_prefix(b"-> ") else {
+            continue;
+        };
+
+        let stanza = String::from_utf8_lossy(stanza);
+        let mut args = stanza.split(' ');
+        let (kind, tag) = (args.next().unwrap_or_default(), args.next());
+        let recipient = match (kind, tag) {
+            ("X25519", _) => "an X25519 key".into(),
+            ("scrypt", _) => "a passphrase".into(),
+            ("ssh-ed25519" | "ssh-rsa", Some(tag)) => format!("an {kind} key (tag {tag})"),
+            // NOTE(ww): Grease stanzas are random noise that every age client ignores.
+            (kind, _) if kind.ends_with("-grease") => continue,
+            (kind, _) => format!("a {kind} key"),
+        };
+
+        recipients.push(recipient);
+    }
+
+    Ok(recipients)
+}
+
+/// Describes an age decryption error in terms of what it means for the record, e.g. that
+/// it's encrypted to another key or that it's corrupt.
+///
+/// `encrypted` is the record's ASCII-armored ciphertext, if it's at hand; it's used to
+/// describe who a record that's encrypted to another key is for.
+#[doc(hidden)]
+fn decrypt_error(e: age::DecryptError, encrypted: Option<&str>) -> anyhow::Error {
+    let reason = match &e {
+        age::DecryptError::NoMatchingKeys => {
+            return ForeignRecord {
+                recipients: encrypted
+                    .and_then(|encrypted| header_recipients(encrypted).ok())
+                    .unwrap_or_default(),
+            }
+            .into()
+        }
+        age::DecryptError::InvalidHeader => "the record is corrupt: its header is invalid".into(),
+        age::DecryptError::InvalidMac => {
+            "the record is corrupt (or was tampered with): its header doesn't match its MAC".into()
+        }
+        age::DecryptError::DecryptionFailed => {
+            "the record is corrupt: its contents failed to decrypt".into()
+        }
+        age::DecryptError::KeyDecryptionFailed => "your key itself couldn't be decrypted".into(),
+        age::DecryptError::UnknownFormat => {
+            "the record isn't in a format that kbs2 understands (was it made by a newer age?)"
+                .into()
+        }
+        age::DecryptError::MissingPlugin { binary_name } => {
+            format!("the record needs the {binary_name} plugin, which isn't installed")
+        }
+        age::DecryptError::Io(e) if is_armor_error(e) => {
+            "the record is corrupt: its ASCII armor is invalid".into()
+        }
+        age::DecryptError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            "the record is empty, truncated, or not an age file".into()
+        }
+        _ => "unable to decrypt".into(),
+    };
+
+    anyhow!("{reason} (backend reports: {e:?})")
+}
+
+/// Like `decrypt_error`, but for errors while reading a record's decrypted contents, i.e.
+/// after its header has been read and decrypted.
+#[doc(hidden)]
+fn payload_error(e: io::Error) -> anyhow::Error {
+    let reason = match e.kind() {
+        _ if is_armor_error(&e) => "the record is corrupt: its ASCII armor is invalid",
+        io::ErrorKind::InvalidData => "the record is corrupt: its contents failed to decrypt",
+        io::ErrorKind::UnexpectedEof => "the record is truncated",
+        _ => "i/o error while decrypting",
+    };
+
+    anyhow!("{reason} (backend reports: {e})")
+}
+
+/// Reads from a decompressor, failing once it produces more than `max-record-size` bytes,
+/// so that a small compressed record can't expand to fill memory.
+#[doc(hidden)]
+struct DecompressionLimit<R> {
+    inner: io::Take<R>,
+    limit: u64,
+}
+
+impl<R: Read> DecompressionLimit<R> {
+    fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner: inner.take(limit.saturating_add(1)),
+            limit,
+        }
+    }
+}
+
+impl<R: Read> Read for DecompressionLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        // NOTE(ww): The inner reader is limited to one byte past the limit, so running out
+        // means that the limit was exceeded.
+        if self.inner.limit() == 0 {
+            return Err(io::Error::other(format!(
+                "record is larger than max-record-size ({} bytes) once decompressed",
+                self.limit
+            )));
+        }
+
+        Ok(read)
+    }
+}
+
+/// Returns whether the given I/O error came from parsing ASCII armor.
+#[doc(hidden)]
+fn is_armor_error(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|e| e.is::<age::armor::ArmoredReadError>())
+}
+
 /// Represents the operations that all age backends are capable of.
 pub trait Backend {
     /// Creates an age keypair, saving the private component to the given path.
@@ -31,6 +286,17 @@ pub trait Backend {
     /// NOTE: Like `create_keypair`, this writes an ASCII-armored private component.
     fn create_wrapped_keypair<P: AsRef<Path>>(path: P, password: SecretString) -> Result<String>;
 
+    /// Validates an existing identity file, returning its public key.
+    ///
+    /// If `password` is given, the identity file is expected to be wrapped with it.
+    /// If `public_key` is given, it must match the identity; it's required for identities
+    /// whose public key can't be derived, such as plugin identities.
+    fn existing_keypair<P: AsRef<Path>>(
+        path: P,
+        password: Option<SecretString>,
+        public_key: Option<&str>,
+    ) -> Result<String>;
+
     /// Unwraps the given `keyfile` using `password`, returning the unwrapped contents.
     fn unwrap_keyfile<P: AsRef<Path>>(keyfile: P, password: SecretString) -> Result<SecretString>;
 
@@ -48,37 +314,180 @@ pub trait Backend {
 
     /// Decrypts the given ASCII-armored string, returning it as a Record.
     fn decrypt(&self, encrypted: &str) -> Result<Record>;
+
+    /// Like `encrypt`, but streams the ASCII-armored result into `writer` instead of
+    /// returning it.
+    fn encrypt_stream<W: Write>(&self, record: &Record, writer: W) -> Result<()>;
+
+    /// Like `decrypt`, but streams the ASCII-armored input from `reader`, so that
+    /// neither it nor the serialized record is ever held in memory in full.
+    fn decrypt_stream<R: Read>(&self, reader: R) -> Result<Record>;
+}
+
+/// The recipient that records are encrypted to.
+#[derive(Clone)]
+pub enum RageRecipient {
+    /// A native X25519 recipient, i.e. a standard `age1...` public key.
+    Native(age::x25519::Recipient),
+
+    /// A recipient handled by an age plugin, e.g. `age1yubikey1...`.
+    Plugin(age::plugin::Recipient),
+}
+
+impl RageRecipient {
+    /// Returns this recipient in a form suitable for an `age::Encryptor`.
+    fn to_recipient(&self) -> Result<Box<dyn age::Recipient + Send>> {
+        match self {
+            RageRecipient::Native(r) => Ok(Box::new(r.clone())),
+            RageRecipient::Plugin(r) => Ok(Box::new(
+                age::plugin::RecipientPluginV1::new(
+                    r.plugin(),
+                    std::slice::from_ref(r),
+                    &[],
+                    PluginCallbacks,
+                )
+                .map_err(|e| anyhow!("unable to load age plugin: {}", e))?,
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for RageRecipient {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(recipient) = s.parse::<age::x25519::Recipient>() {
+            return Ok(RageRecipient::Native(recipient));
+        }
+
+        // NOTE(ww): age itself can encrypt to SSH keys, but decrypting needs the private key
+        // in a file: ssh-agent only signs, and age's SSH identities need a key exchange.
+        // We don't support them at all, rather than half-support them.
+        if SSH_KEY_PREFIXES.iter().any(|prefix| s.starts_with(prefix)) {
+            return Err(anyhow!(
+                "SSH keys can't be used as kbs2 keys (ssh-agent can't decrypt age files); \
+                 use an age key or an age plugin instead"
+            ));
+        }
+
+        s.parse::<age::plugin::Recipient>()
+            .map(RageRecipient::Plugin)
+            .map_err(|e| anyhow!("unable to parse public key (backend reports: {:?})", e))
+    }
+}
+
+/// The prefixes of OpenSSH public keys, which `RageRecipient` explicitly rejects.
+const SSH_KEY_PREFIXES: &[&str] = &["ssh-", "ecdsa-sha2-", "sk-ssh-", "sk-ecdsa-sha2-"];
+
+impl From<age::x25519::Recipient> for RageRecipient {
+    fn from(recipient: age::x25519::Recipient) -> Self {
+        RageRecipient::Native(recipient)
+    }
+}
+
+/// The callbacks used to interact with the user on behalf of age plugins,
+/// e.g. to request a hardware token's PIN.
+#[derive(Clone)]
+struct PluginCallbacks;
+
+impl age::Callbacks for PluginCallbacks {
+    fn display_message(&self, message: &str) {
+        eprintln!("{message}");
+    }
+
+    fn confirm(&self, message: &str, yes_string: &str, no_string: Option<&str>) -> Option<bool> {
+        let help = format!("[y] {yes_string}, [n] {}", no_string.unwrap_or("no"));
+        input::prompted_on_tty(|| inquire::Confirm::new(message).with_help(&help).prompt()).ok()
+    }
+
+    fn request_public_string(&self, description: &str) -> Option<String> {
+        input::prompted_on_tty(|| inquire::Text::new(description).prompt()).ok()
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<SecretString> {
+        input::prompted_on_tty(|| {
+            inquire::Password::new(description)
+                .without_confirmation()
+                .prompt()
+        })
+        .ok()
+        .map(SecretString::new)
+    }
 }
 
 /// Encapsulates the age crate (i.e., the `rage` CLI's backing library).
 pub struct RageLib {
-    pub pubkey: age::x25519::Recipient,
-    pub identities: Vec<age::x25519::Identity>,
+    pub pubkey: RageRecipient,
+    pub identities: Vec<Box<dyn age::Identity + Send + Sync>>,
+
+    /// The escrow recipients that every record is additionally encrypted to.
+    pub escrow: Vec<RageRecipient>,
+
+    /// How records are compressed before they're encrypted.
+    pub compression: config::Compression,
+
+    /// The largest that a compressed record may be once it's decompressed, in bytes.
+    pub max_record_size: u64,
 }
 
 impl RageLib {
     pub fn new(config: &config::Config) -> Result<RageLib> {
-        let pubkey = config
-            .public_key
-            .parse::<age::x25519::Recipient>()
-            .map_err(|e| anyhow!("unable to parse public key (backend reports: {:?})", e))?;
+        let pubkey = config.public_key.parse::<RageRecipient>()?;
+
+        let identities = if config.wrapped && config.no_agent {
+            log::debug!("config specifies a wrapped key, unwrapping without the agent");
+
+            let unwrapped_key = with_master_password(config, None, |password| {
+                timings::span("key unwrap", || {
+                    RageLib::unwrap_keyfile(&config.keyfile, password)
+                })
+            });
+            config.audit(audit::Event::Unwrap {
+                public_key: config.public_key.clone(),
+                agent: false,
+                outcome: audit::Outcome::of(&unwrapped_key),
+            });
+            let unwrapped_key = unwrapped_key?;
 
-        let identities = if config.wrapped {
+            log::debug!("parsing unwrapped key");
+            age::IdentityFile::from_buffer(unwrapped_key.expose_secret().as_bytes())?
+        } else if config.wrapped {
             log::debug!("config specifies a wrapped key");
 
-            let client = agent::Client::new().with_context(|| "failed to connect to kbs2 agent")?;
+            // NOTE(ww): The agent's own error explains what's wrong and how to fix it; all we add
+            // is the way around it, for when the agent can't be fixed right away.
+            let client = timings::span("agent connect", agent::Client::new).map_err(|e| {
+                anyhow!(
+                    "{}; or, pass --no-agent to unwrap the keyfile without the agent",
+                    e
+                )
+            })?;
 
             if !client.query_key(&config.public_key)? {
-                client.add_key(
-                    &config.public_key,
-                    &config.keyfile,
-                    util::get_password(None, &config.pinentry)?,
-                )?;
+                // Only one process prompts for the password at a time; everybody else
+                // waits, and then (usually) finds the key already unwrapped.
+                let _lock = agent::Agent::unwrap_lock(UNWRAP_LOCK_TIMEOUT)?;
+
+                if !client.query_key(&config.public_key)? {
+                    let policy = agent::UnwrapPolicy::from(config);
+                    let added = with_master_password(config, None, |password| {
+                        timings::span("key unwrap", || {
+                            client.add_key(&config.public_key, &config.keyfile, password, &policy)
+                        })
+                    });
+                    config.audit(audit::Event::Unwrap {
+                        public_key: config.public_key.clone(),
+                        agent: true,
+                        outcome: audit::Outcome::of(&added),
+                    });
+                    added?;
+                }
             }
 
-            let unwrapped_key = client
-                .get_key(&config.public_key)
-                .with_context(|| format!("agent has no unwrapped key for {}", config.keyfile))?;
+            let unwrapped_key = timings::span("key fetch", || client.get_key(&config.public_key))
+                .with_context(|| {
+                format!("agent has no unwrapped key for {}", config.keyfile)
+            })?;
 
             log::debug!("parsing unwrapped key");
             age::IdentityFile::from_buffer(unwrapped_key.as_bytes())?
@@ -95,24 +504,177 @@ impl RageLib {
             ));
         }
 
-        let identities = identities
+        let identities = Self::load_identities(identities)?;
+        let escrow = Self::escrow_recipients(config)?;
+
+        Ok(RageLib {
+            pubkey,
+            identities,
+            escrow,
+            compression: config.compress,
+            max_record_size: config.max_record_size,
+        })
+    }
+
+    /// Creates a backend that decrypts with the identities in the given keyfile rather than
+    /// the configured one, prompting for the keyfile's password if it's wrapped. Records are
+    /// still encrypted to the configured public key.
+    pub fn with_keyfile<P: AsRef<Path>>(config: &config::Config, keyfile: P) -> Result<RageLib> {
+        let pubkey = config.public_key.parse::<RageRecipient>()?;
+
+        let identities = if Self::is_wrapped_keyfile(&keyfile)? {
+            let unwrapped_key = with_master_password(
+                config,
+                Some(i18n::text(Message::KeyfilePasswordPrompt)),
+                |password| RageLib::unwrap_keyfile(&keyfile, password),
+            )?;
+
+            age::IdentityFile::from_buffer(unwrapped_key.expose_secret().as_bytes())?
+        } else {
+            age::IdentityFile::from_file(keyfile.as_ref().display().to_string())?
+        }
+        .into_identities();
+
+        if identities.is_empty() {
+            return Err(anyhow!(
+                "no private keys in the keyfile: {}",
+                keyfile.as_ref().display()
+            ));
+        }
+
+        let identities = Self::load_identities(identities)?;
+        let escrow = Self::escrow_recipients(config)?;
+
+        Ok(RageLib {
+            pubkey,
+            identities,
+            escrow,
+            compression: config.compress,
+            max_record_size: config.max_record_size,
+        })
+    }
+
+    #[doc(hidden)]
+    fn load_identities(
+        entries: Vec<IdentityFileEntry>,
+    ) -> Result<Vec<Box<dyn age::Identity + Send + Sync>>> {
+        entries
             .into_iter()
             .map(|i| match i {
-                // NOTE(ww): We're not using the plugin feature of the `age` crate,
-                // so this is the only variant.
-                IdentityFileEntry::Native(i) => i,
+                IdentityFileEntry::Native(i) => {
+                    Ok(Box::new(i) as Box<dyn age::Identity + Send + Sync>)
+                }
+                IdentityFileEntry::Plugin(i) => {
+                    log::debug!("keyfile contains a plugin identity: {}", i.plugin());
+                    age::plugin::IdentityPluginV1::new(
+                        i.plugin(),
+                        std::slice::from_ref(&i),
+                        PluginCallbacks,
+                    )
+                    .map(|i| Box::new(i) as Box<dyn age::Identity + Send + Sync>)
+                    .map_err(|e| anyhow!("unable to load age plugin: {}", e))
+                }
             })
-            .collect();
+            .collect()
+    }
 
-        Ok(RageLib { pubkey, identities })
+    /// Parses the config's escrow recipients.
+    pub fn escrow_recipients(config: &config::Config) -> Result<Vec<RageRecipient>> {
+        config
+            .escrow_recipients
+            .iter()
+            .map(|r| {
+                r.parse::<RageRecipient>()
+                    .with_context(|| format!("invalid escrow recipient: {r}"))
+            })
+            .collect()
+    }
+
+    /// Returns whether the given key file is wrapped, judging only by its header.
+    pub fn is_wrapped_keyfile<P: AsRef<Path>>(path: P) -> Result<bool> {
+        let contents = util::read_guarded(path, MAX_WRAPPED_KEY_FILESIZE)?;
+
+        Ok(contents.starts_with(WRAPPED_KEY_HEADER))
+    }
+
+    /// Encrypts the given record to arbitrary recipients, rather than this backend's
+    /// own public key, returning it as an ASCII-armored string.
+    pub fn encrypt_to(&self, record: &Record, recipients: &[RageRecipient]) -> Result<String> {
+        let mut encrypted = vec![];
+        self.encrypt_stream_to(record, recipients, &mut encrypted)?;
+
+        Ok(String::from_utf8(encrypted)?)
+    }
+
+    /// Like `encrypt_to`, but streams the ASCII-armored result into `writer`.
+    pub fn encrypt_stream_to<W: Write>(
+        &self,
+        record: &Record,
+        recipients: &[RageRecipient],
+        writer: W,
+    ) -> Result<()> {
+        let mut writer = armored_encryptor(recipients, writer)?;
+        serde_json::to_writer(&mut writer, record)?;
+        writer.finish().and_then(|armor| armor.finish())?;
+
+        Ok(())
+    }
+
+    /// Encrypts arbitrary plaintext (rather than a record) to this backend's own public key,
+    /// returning it as an ASCII-armored string.
+    pub fn encrypt_plaintext(&self, plaintext: &[u8]) -> Result<String> {
+        let mut encrypted = vec![];
+        let mut writer = armored_encryptor(std::slice::from_ref(&self.pubkey), &mut encrypted)?;
+        writer.write_all(plaintext)?;
+        writer.finish().and_then(|armor| armor.finish())?;
+
+        Ok(String::from_utf8(encrypted)?)
+    }
+
+    /// Returns the recipients that the given record is encrypted to: its own, if it has
+    /// any, and otherwise just this backend's public key; plus any escrow recipients.
+    #[doc(hidden)]
+    fn record_recipients(&self, record: &Record) -> Result<Vec<RageRecipient>> {
+        let mut recipients = match &record.recipients {
+            Some(recipients) => recipients
+                .iter()
+                .map(|r| {
+                    r.parse::<RageRecipient>()
+                        .with_context(|| format!("invalid recipient: {r}"))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![self.pubkey.clone()],
+        };
+        recipients.extend(self.escrow.iter().cloned());
+
+        Ok(recipients)
     }
 }
 
+/// Returns a writer that encrypts everything written to it to the given recipients,
+/// writing the ASCII-armored result to `writer`.
+#[doc(hidden)]
+fn armored_encryptor<W: Write>(
+    recipients: &[RageRecipient],
+    writer: W,
+) -> Result<age::stream::StreamWriter<ArmoredWriter<W>>> {
+    let recipients = recipients
+        .iter()
+        .map(RageRecipient::to_recipient)
+        .collect::<Result<Vec<_>>>()?;
+    let encryptor = age::Encryptor::with_recipients(recipients)
+        .ok_or_else(|| anyhow!("can't encrypt to no recipients"))?;
+
+    encryptor
+        .wrap_output(ArmoredWriter::wrap_output(writer, Format::AsciiArmor)?)
+        .map_err(|e| anyhow!("wrap_output failed (backend report: {:?})", e))
+}
+
 impl Backend for RageLib {
     fn create_keypair<P: AsRef<Path>>(path: P) -> Result<String> {
         let keypair = age::x25519::Identity::generate();
 
-        std::fs::write(path, keypair.to_string().expose_secret())?;
+        util::write_secret_file(path, keypair.to_string().expose_secret(), true)?;
 
         Ok(keypair.to_public().to_string())
     }
@@ -120,11 +682,71 @@ impl Backend for RageLib {
     fn create_wrapped_keypair<P: AsRef<Path>>(path: P, password: SecretString) -> Result<String> {
         let keypair = age::x25519::Identity::generate();
         let wrapped_key = Self::wrap_key(keypair.to_string(), password)?;
-        std::fs::write(path, wrapped_key)?;
+        util::write_secret_file(path, wrapped_key, true)?;
 
         Ok(keypair.to_public().to_string())
     }
 
+    fn existing_keypair<P: AsRef<Path>>(
+        path: P,
+        password: Option<SecretString>,
+        public_key: Option<&str>,
+    ) -> Result<String> {
+        let identity = match password {
+            Some(password) => Self::unwrap_keyfile(&path, password)?,
+            None => {
+                let contents = util::read_guarded(&path, MAX_WRAPPED_KEY_FILESIZE)?;
+                if contents.starts_with(WRAPPED_KEY_HEADER) {
+                    return Err(anyhow!(
+                        "identity file is wrapped, but no password was given"
+                    ));
+                }
+
+                SecretString::new(
+                    String::from_utf8(contents)
+                        .map_err(|_| anyhow!("identity file is not valid UTF-8"))?,
+                )
+            }
+        };
+
+        let mut identities = age::IdentityFile::from_buffer(identity.expose_secret().as_bytes())
+            .map_err(|e| anyhow!("unable to parse identity file: {}", e))?
+            .into_identities();
+
+        if identities.len() != 1 {
+            return Err(anyhow!(
+                "expected exactly one private key in the identity file, but got {}",
+                identities.len()
+            ));
+        }
+
+        // NOTE(ww): This unwrap is safe, since we've just checked the length.
+        #[allow(clippy::unwrap_used)]
+        match (identities.pop().unwrap(), public_key) {
+            (IdentityFileEntry::Native(identity), public_key) => {
+                let derived = identity.to_public().to_string();
+                match public_key {
+                    Some(public_key) if public_key != derived => Err(anyhow!(
+                        "public key {} doesn't match the identity (expected {})",
+                        public_key,
+                        derived
+                    )),
+                    _ => Ok(derived),
+                }
+            }
+            (IdentityFileEntry::Plugin(_), Some(public_key)) => {
+                // We can't check a plugin identity against its public key without
+                // the plugin (and potentially its hardware), so just check the latter's form.
+                public_key.parse::<RageRecipient>()?;
+                Ok(public_key.into())
+            }
+            (IdentityFileEntry::Plugin(identity), None) => Err(anyhow!(
+                "can't derive a public key for a {} plugin identity; pass it explicitly",
+                identity.plugin()
+            )),
+        }
+    }
+
     fn unwrap_keyfile<P: AsRef<Path>>(keyfile: P, password: SecretString) -> Result<SecretString> {
         let wrapped_key = util::read_guarded(&keyfile, MAX_WRAPPED_KEY_FILESIZE)?;
 
@@ -153,7 +775,12 @@ impl Backend for RageLib {
         // breathing room.
         decryptor
             .decrypt(&password, Some(22))
-            .map_err(|e| anyhow!("unable to decrypt (backend reports: {:?})", e))
+            .map_err(|e| match e {
+                // NOTE(ww): For a passphrase-wrapped key, this is the scrypt stanza's
+                // authentication failing, i.e. the wrong password.
+                age::DecryptError::DecryptionFailed => BadPassword.into(),
+                e => anyhow!("unable to decrypt (backend reports: {:?})", e),
+            })
             .and_then(|mut r| {
                 r.read_to_string(&mut unwrapped_key)
                     .map_err(|_| anyhow!("i/o error while decrypting"))
@@ -185,30 +812,108 @@ impl Backend for RageLib {
         let unwrapped_key = Self::unwrap_keyfile(&keyfile, old)?;
         let rewrapped_key = Self::wrap_key(unwrapped_key, new)?;
 
-        std::fs::write(&keyfile, rewrapped_key)?;
+        // NOTE(ww): The keyfile is configured deliberately, so if it's a symlink,
+        // we replace what it points to.
+        let keyfile = std::fs::canonicalize(&keyfile)?;
+        util::write_secret_file(&keyfile, rewrapped_key, true)?;
         Ok(())
     }
 
     fn encrypt(&self, record: &Record) -> Result<String> {
-        #[allow(clippy::unwrap_used)]
-        let encryptor =
-            age::Encryptor::with_recipients(vec![Box::new(self.pubkey.clone())]).unwrap();
-        let mut encrypted = vec![];
-        let mut writer = encryptor
-            .wrap_output(ArmoredWriter::wrap_output(
-                &mut encrypted,
-                Format::AsciiArmor,
-            )?)
-            .map_err(|e| anyhow!("wrap_output failed (backend report: {:?})", e))?;
-        writer.write_all(serde_json::to_string(record)?.as_bytes())?;
-        writer.finish().and_then(|armor| armor.finish())?;
+        timings::span("encrypt", || {
+            let mut encrypted = vec![];
+            self.encrypt_record_stream(record, &mut encrypted)?;
 
-        Ok(String::from_utf8(encrypted)?)
+            Ok(String::from_utf8(encrypted)?)
+        })
     }
 
     fn decrypt(&self, encrypted: &str) -> Result<Record> {
+        timings::span("decrypt", || {
+            Ok(serde_json::from_str(&self.decrypt_plaintext(encrypted)?)?)
+        })
+    }
+
+    fn encrypt_stream<W: Write>(&self, record: &Record, writer: W) -> Result<()> {
+        timings::span("encrypt", || self.encrypt_record_stream(record, writer))
+    }
+
+    // NOTE(ww): The record is read as it's decrypted, so its "decrypt" span includes the read.
+    fn decrypt_stream<R: Read>(&self, reader: R) -> Result<Record> {
+        timings::span("decrypt", || self.decrypt_stream_inner(reader))
+    }
+}
+
+impl RageLib {
+    /// Encrypts the given record to its recipients, compressing it first if this backend
+    /// is configured to.
+    ///
+    /// NOTE(ww): `encrypt_to` never compresses, since its output (e.g. a shared record) may
+    /// be read by a `kbs2` that doesn't know how to decompress it.
+    #[doc(hidden)]
+    fn encrypt_record_stream<W: Write>(&self, record: &Record, writer: W) -> Result<()> {
+        let recipients = self.record_recipients(record)?;
+
+        match self.compression {
+            config::Compression::None => self.encrypt_stream_to(record, &recipients, writer),
+            config::Compression::Deflate => {
+                let mut writer = armored_encryptor(&recipients, writer)?;
+                writer.write_all(&[DEFLATE_HEADER])?;
+
+                let mut encoder = DeflateEncoder::new(&mut writer, flate2::Compression::default());
+                serde_json::to_writer(&mut encoder, record)?;
+                encoder.finish()?;
+
+                writer.finish().and_then(|armor| armor.finish())?;
+                Ok(())
+            }
+        }
+    }
+
+    #[doc(hidden)]
+    fn decrypt_stream_inner<R: Read>(&self, reader: R) -> Result<Record> {
+        let decryptor = match age::Decryptor::new_buffered(ArmoredReader::new(reader))
+            .map_err(|e| decrypt_error(e, None))?
+        {
+            age::Decryptor::Recipients(d) => d,
+            // NOTE(ww): See `decrypt_plaintext`.
+            _ => unreachable!(),
+        };
+
+        let decrypted = decryptor
+            .decrypt(
+                self.identities
+                    .iter()
+                    .map(|i| i.as_ref() as &dyn age::Identity),
+            )
+            // NOTE(ww): The header has already been consumed from the stream by now, so we
+            // can't describe the recipients of a foreign record the way `decrypt_plaintext` does.
+            .map_err(|e| decrypt_error(e, None))?;
+
+        let mut decrypted = BufReader::new(decrypted);
+        let compressed =
+            decrypted.fill_buf().map_err(payload_error)?.first() == Some(&DEFLATE_HEADER);
+        let decrypted: Box<dyn Read> = if compressed {
+            decrypted.consume(1);
+            Box::new(BufReader::new(DecompressionLimit::new(
+                DeflateDecoder::new(decrypted),
+                self.max_record_size,
+            )))
+        } else {
+            Box::new(decrypted)
+        };
+
+        serde_json::from_reader(decrypted).map_err(|e| match e.is_io() {
+            true => payload_error(e.into()),
+            false => e.into(),
+        })
+    }
+
+    /// Decrypts the given ASCII-armored string, returning the record's serialized
+    /// form (decompressed, if it was compressed) without interpreting it.
+    pub fn decrypt_plaintext(&self, encrypted: &str) -> Result<String> {
         let decryptor = match age::Decryptor::new(ArmoredReader::new(encrypted.as_bytes()))
-            .map_err(|e| anyhow!("unable to load private key (backend reports: {:?})", e))?
+            .map_err(|e| decrypt_error(e, Some(encrypted)))?
         {
             age::Decryptor::Recipients(d) => d,
             // NOTE(ww): we should be fully unwrapped (if we were wrapped to begin with)
@@ -216,24 +921,34 @@ impl Backend for RageLib {
             _ => unreachable!(),
         };
 
-        let mut decrypted = String::new();
+        let mut decrypted = vec![];
 
         decryptor
-            .decrypt(self.identities.iter().map(|i| i as &dyn age::Identity))
-            .map_err(|e| anyhow!("unable to decrypt (backend reports: {:?})", e))
-            .and_then(|mut r| {
-                r.read_to_string(&mut decrypted)
-                    .map_err(|e| anyhow!("i/o error while decrypting: {:?}", e))
-            })?;
-
-        Ok(serde_json::from_str(&decrypted)?)
+            .decrypt(
+                self.identities
+                    .iter()
+                    .map(|i| i.as_ref() as &dyn age::Identity),
+            )
+            .map_err(|e| decrypt_error(e, Some(encrypted)))
+            .and_then(|mut r| r.read_to_end(&mut decrypted).map_err(payload_error))?;
+
+        match decrypted.split_first() {
+            Some((&DEFLATE_HEADER, compressed)) => {
+                let mut decompressed = String::new();
+                DecompressionLimit::new(DeflateDecoder::new(compressed), self.max_record_size)
+                    .read_to_string(&mut decompressed)
+                    .map_err(|e| anyhow!("failed to decompress record: {e}"))?;
+                Ok(decompressed)
+            }
+            _ => Ok(String::from_utf8(decrypted)?),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::kbs2::record::{LoginFields, RecordBody};
+    use crate::kbs2::record::{LoginFields, RecordBody, UnstructuredFields};
 
     fn dummy_login() -> Record {
         Record::new(
@@ -241,6 +956,7 @@ mod tests {
             RecordBody::Login(LoginFields {
                 username: "foobar".into(),
                 password: "bazqux".into(),
+                extra: Default::default(),
             }),
         )
     }
@@ -249,8 +965,11 @@ mod tests {
         let key = age::x25519::Identity::generate();
 
         RageLib {
-            pubkey: key.to_public(),
-            identities: vec![key],
+            pubkey: key.to_public().into(),
+            identities: vec![Box::new(key)],
+            escrow: vec![],
+            compression: Default::default(),
+            max_record_size: config::DEFAULT_MAX_RECORD_SIZE,
         }
     }
 
@@ -259,8 +978,11 @@ mod tests {
         let key2 = age::x25519::Identity::generate();
 
         RageLib {
-            pubkey: key1.to_public(),
-            identities: vec![key2],
+            pubkey: key1.to_public().into(),
+            identities: vec![Box::new(key2)],
+            escrow: vec![],
+            compression: Default::default(),
+            max_record_size: config::DEFAULT_MAX_RECORD_SIZE,
         }
     }
 
@@ -288,6 +1010,83 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_ragelib_unwrap_keyfile_bad_password() {
+        let keyfile = tempfile::NamedTempFile::new().unwrap();
+
+        RageLib::create_wrapped_keypair(&keyfile, SecretString::new("weakpassword".into()))
+            .unwrap();
+
+        // The wrong password is reported as such, rather than as a generic failure.
+        let err = RageLib::unwrap_keyfile(&keyfile, SecretString::new("wrong".into())).unwrap_err();
+        assert!(err.is::<BadPassword>());
+
+        // A keyfile that isn't wrapped at all is a different failure.
+        std::fs::write(&keyfile, "not a wrapped key").unwrap();
+        let err = RageLib::unwrap_keyfile(&keyfile, SecretString::new("weakpassword".into()))
+            .unwrap_err();
+        assert!(!err.is::<BadPassword>());
+    }
+
+    #[test]
+    fn test_unlock_backoff() {
+        assert_eq!(unlock_backoff(500, 0), Duration::from_millis(500));
+        assert_eq!(unlock_backoff(500, 1), Duration::from_millis(500));
+        assert_eq!(unlock_backoff(500, 2), Duration::from_millis(1000));
+        assert_eq!(unlock_backoff(500, 3), Duration::from_millis(2000));
+        assert_eq!(unlock_backoff(0, 3), Duration::ZERO);
+        assert_eq!(
+            unlock_backoff(u64::MAX, 100),
+            Duration::from_millis(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_ragelib_existing_keypair() {
+        let keyfile = tempfile::NamedTempFile::new().unwrap();
+        let public_key = RageLib::create_keypair(&keyfile).unwrap();
+
+        // The public key is derived from an unwrapped identity, and checked if given.
+        assert_eq!(
+            RageLib::existing_keypair(&keyfile, None, None).unwrap(),
+            public_key
+        );
+        assert!(RageLib::existing_keypair(&keyfile, None, Some(&public_key)).is_ok());
+        assert!(RageLib::existing_keypair(
+            &keyfile,
+            None,
+            Some(&age::x25519::Identity::generate().to_public().to_string())
+        )
+        .is_err());
+        assert!(!RageLib::is_wrapped_keyfile(&keyfile).unwrap());
+
+        let wrapped_keyfile = tempfile::NamedTempFile::new().unwrap();
+        let public_key = RageLib::create_wrapped_keypair(
+            &wrapped_keyfile,
+            SecretString::new("weakpassword".into()),
+        )
+        .unwrap();
+
+        // Wrapped identities require the right password.
+        assert!(RageLib::is_wrapped_keyfile(&wrapped_keyfile).unwrap());
+        assert!(RageLib::existing_keypair(&wrapped_keyfile, None, None).is_err());
+        assert!(RageLib::existing_keypair(
+            &wrapped_keyfile,
+            Some(SecretString::new("wrongpassword".into())),
+            None
+        )
+        .is_err());
+        assert_eq!(
+            RageLib::existing_keypair(
+                &wrapped_keyfile,
+                Some(SecretString::new("weakpassword".into())),
+                None
+            )
+            .unwrap(),
+            public_key
+        );
+    }
+
     #[test]
     fn test_ragelib_rewrap_keyfile() {
         let keyfile = tempfile::NamedTempFile::new().unwrap();
@@ -353,8 +1152,274 @@ mod tests {
 
             assert_eq!(
                 err.to_string(),
-                "unable to decrypt (backend reports: NoMatchingKeys)"
+                "the record isn't encrypted to your key (backend reports: NoMatchingKeys); \
+                 it's encrypted to an X25519 key"
             );
+            assert!(err.downcast_ref::<ForeignRecord>().is_some());
+        }
+    }
+
+    #[test]
+    fn test_ragelib_decrypt_errors() {
+        let backend = ragelib_backend();
+        let encrypted = backend.encrypt(&dummy_login()).unwrap();
+        // NOTE(ww): Both decryption paths should explain failures the same way.
+        let decrypt_err = |encrypted: &str| {
+            let err = backend.decrypt(encrypted).unwrap_err().to_string();
+            let stream_err = backend.decrypt_stream(encrypted.as_bytes()).unwrap_err();
+            assert_eq!(err, stream_err.to_string());
+            err
+        };
+
+        let err = ragelib_backend_bad_keypair()
+            .decrypt(&encrypted)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("the record isn't encrypted to your key"));
+
+        assert!(decrypt_err("not a record")
+            .starts_with("the record is empty, truncated, or not an age file"));
+
+        let lines = encrypted.lines().collect::<Vec<_>>();
+        let mut mangled = lines.clone();
+        mangled[1] = "!!!!";
+        assert!(decrypt_err(&mangled.join("\n"))
+            .starts_with("the record is corrupt: its ASCII armor is invalid"));
+
+        assert!(decrypt_err(&lines[..lines.len() - 2].join("\n"))
+            .starts_with("the record is corrupt: its ASCII armor is invalid"));
+
+        let mut raw = vec![];
+        ArmoredReader::new(encrypted.as_bytes())
+            .read_to_end(&mut raw)
+            .unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        let mut flipped = vec![];
+        let mut writer = ArmoredWriter::wrap_output(&mut flipped, Format::AsciiArmor).unwrap();
+        writer.write_all(&raw).unwrap();
+        writer.finish().unwrap();
+        assert!(decrypt_err(std::str::from_utf8(&flipped).unwrap())
+            .starts_with("the record is corrupt: its contents failed to decrypt"));
+    }
+
+    #[test]
+    fn test_ragelib_stream() {
+        let backend = ragelib_backend();
+        let record = Record::new(
+            "big",
+            RecordBody::Unstructured(UnstructuredFields {
+                contents: "x".repeat(4 * 1024 * 1024),
+                extra: Default::default(),
+            }),
+        );
+
+        // Streamed and buffered ciphertexts are interchangeable.
+        let mut streamed = vec![];
+        backend.encrypt_stream(&record, &mut streamed).unwrap();
+        let streamed = String::from_utf8(streamed).unwrap();
+        assert_eq!(backend.decrypt(&streamed).unwrap(), record);
+        assert_eq!(backend.decrypt_stream(streamed.as_bytes()).unwrap(), record);
+
+        let buffered = backend.encrypt(&record).unwrap();
+        assert_eq!(backend.decrypt_stream(buffered.as_bytes()).unwrap(), record);
+
+        // A truncated stream is an error, not a partial record.
+        let truncated = &streamed[..streamed.len() / 2];
+        assert!(backend.decrypt_stream(truncated.as_bytes()).is_err());
+
+        let err = ragelib_backend_bad_keypair()
+            .decrypt_stream(streamed.as_bytes())
+            .unwrap_err();
+        assert!(err.downcast_ref::<ForeignRecord>().is_some());
+    }
+
+    #[test]
+    fn test_ragelib_encrypt_recipients() {
+        let (key1, key2) = (
+            age::x25519::Identity::generate(),
+            age::x25519::Identity::generate(),
+        );
+        let mut record = dummy_login();
+        record.recipients = Some(vec![
+            key1.to_public().to_string(),
+            key2.to_public().to_string(),
+        ]);
+
+        let backend1 = RageLib {
+            pubkey: key1.to_public().into(),
+            identities: vec![Box::new(key1)],
+            escrow: vec![],
+            compression: Default::default(),
+            max_record_size: config::DEFAULT_MAX_RECORD_SIZE,
+        };
+        let backend2 = RageLib {
+            pubkey: key2.to_public().into(),
+            identities: vec![Box::new(key2)],
+            escrow: vec![],
+            compression: Default::default(),
+            max_record_size: config::DEFAULT_MAX_RECORD_SIZE,
+        };
+
+        // Every one of the record's recipients can decrypt it, but nobody else can.
+        let encrypted = backend1.encrypt(&record).unwrap();
+        assert_eq!(backend1.decrypt(&encrypted).unwrap(), record);
+        assert_eq!(backend2.decrypt(&encrypted).unwrap(), record);
+        assert!(ragelib_backend().decrypt(&encrypted).is_err());
+
+        record.recipients = Some(vec!["not-a-key".into()]);
+        let err = backend1.encrypt(&record).unwrap_err();
+        assert_eq!(err.to_string(), "invalid recipient: not-a-key");
+
+        record.recipients = Some(vec![]);
+        assert!(backend1.encrypt(&record).is_err());
+    }
+
+    #[test]
+    fn test_ragelib_compression() {
+        let mut backend = ragelib_backend();
+        let compressible = Record::new(
+            "compressible",
+            RecordBody::Unstructured(UnstructuredFields {
+                contents: "{\"key\": \"value\"}, ".repeat(256),
+                extra: Default::default(),
+            }),
+        );
+        let empty = Record::new(
+            "",
+            RecordBody::Unstructured(UnstructuredFields {
+                contents: "".into(),
+                extra: Default::default(),
+            }),
+        );
+
+        let login = dummy_login();
+        for record in [&login, &compressible, &empty] {
+            backend.compression = config::Compression::None;
+            let uncompressed = backend.encrypt(record).unwrap();
+
+            backend.compression = config::Compression::Deflate;
+            let compressed = backend.encrypt(record).unwrap();
+            let mut streamed = vec![];
+            backend.encrypt_stream(record, &mut streamed).unwrap();
+            let streamed = String::from_utf8(streamed).unwrap();
+
+            // Compressed and uncompressed records are readable either way, so that
+            // stores can contain both.
+            for compression in [config::Compression::None, config::Compression::Deflate] {
+                backend.compression = compression;
+                for encrypted in [&uncompressed, &compressed, &streamed] {
+                    assert_eq!(backend.decrypt(encrypted).unwrap(), *record);
+                    assert_eq!(
+                        backend.decrypt_stream(encrypted.as_bytes()).unwrap(),
+                        *record
+                    );
+                    assert_eq!(
+                        backend.decrypt_plaintext(encrypted).unwrap(),
+                        serde_json::to_string(record).unwrap()
+                    );
+                }
+            }
         }
+
+        backend.compression = config::Compression::None;
+        let uncompressed = backend.encrypt(&compressible).unwrap();
+        backend.compression = config::Compression::Deflate;
+        let compressed = backend.encrypt(&compressible).unwrap();
+        assert!(compressed.len() < uncompressed.len() / 4);
+
+        // Sharing never compresses.
+        let shared = backend
+            .encrypt_to(&compressible, &[backend.pubkey.clone()])
+            .unwrap();
+        assert!(backend.decrypt_plaintext(&shared).unwrap().starts_with('{'));
+    }
+
+    #[test]
+    fn test_ragelib_decompression_limit() {
+        let mut backend = ragelib_backend();
+        backend.compression = config::Compression::Deflate;
+
+        let bomb = Record::new(
+            "bomb",
+            RecordBody::Unstructured(UnstructuredFields {
+                contents: "0".repeat(64 * 1024),
+                extra: Default::default(),
+            }),
+        );
+        let compressed = backend.encrypt(&bomb).unwrap();
+
+        // The compressed record is well within the limit; decompressed, it isn't.
+        backend.max_record_size = 4096;
+        assert!((compressed.len() as u64) < backend.max_record_size);
+        for result in [
+            backend.decrypt(&compressed).map(|_| ()),
+            backend.decrypt_stream(compressed.as_bytes()).map(|_| ()),
+            backend.decrypt_plaintext(&compressed).map(|_| ()),
+        ] {
+            assert!(format!("{:#}", result.unwrap_err()).contains("max-record-size"));
+        }
+
+        backend.max_record_size = 128 * 1024;
+        assert_eq!(backend.decrypt(&compressed).unwrap(), bomb);
+    }
+
+    #[test]
+    fn test_ragelib_encrypt_escrow() {
+        let escrow = age::x25519::Identity::generate();
+        let mut backend = ragelib_backend();
+        backend.escrow = vec![escrow.to_public().into()];
+
+        let escrow_backend = RageLib {
+            pubkey: escrow.to_public().into(),
+            identities: vec![Box::new(escrow)],
+            escrow: vec![],
+            compression: Default::default(),
+            max_record_size: config::DEFAULT_MAX_RECORD_SIZE,
+        };
+
+        // The escrow recipient can decrypt every record, including ones with their own recipients.
+        let mut record = dummy_login();
+        let encrypted = backend.encrypt(&record).unwrap();
+        assert_eq!(backend.decrypt(&encrypted).unwrap(), record);
+        assert_eq!(escrow_backend.decrypt(&encrypted).unwrap(), record);
+
+        record.recipients = Some(vec![age::x25519::Identity::generate()
+            .to_public()
+            .to_string()]);
+        let encrypted = backend.encrypt(&record).unwrap();
+        assert_eq!(escrow_backend.decrypt(&encrypted).unwrap(), record);
+    }
+
+    #[test]
+    fn test_ragerecipient_rejects_ssh_keys() {
+        for key in [
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHsKLqeplhpW+uObz5dvMgjz1OxfM/XXUB+VHtZ6isGN",
+            "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTY=",
+            "sk-ssh-ed25519@openssh.com AAAAGnNrLXNzaC1lZDI1NTE5QG9wZW5zc2guY29t",
+        ] {
+            let err = key.parse::<RageRecipient>().err().unwrap();
+            assert!(err
+                .to_string()
+                .contains("ssh-agent can't decrypt age files"));
+        }
+    }
+
+    #[test]
+    fn test_header_recipients() {
+        let mut record = dummy_login();
+        record.recipients = Some(vec![
+            age::x25519::Identity::generate().to_public().to_string(),
+            age::x25519::Identity::generate().to_public().to_string(),
+        ]);
+
+        let encrypted = ragelib_backend().encrypt(&record).unwrap();
+        assert_eq!(
+            header_recipients(&encrypted).unwrap(),
+            vec!["an X25519 key", "an X25519 key"]
+        );
+
+        assert!(header_recipients("not an age file").is_err());
     }
 }
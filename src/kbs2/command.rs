@@ -1,58 +1,219 @@
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::env;
-use std::fmt::Write as _;
-use std::io::{self, stdin, IsTerminal, Read, Seek, Write};
+use std::fmt;
+use std::io::{self, stdin, IsTerminal, Read, Write};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{anyhow, Result};
 use arboard::Clipboard;
 use clap::ArgMatches;
 use daemonize::Daemonize;
-use inquire::Confirm;
+use inquire::{Confirm, Select};
 use nix::unistd::{fork, ForkResult};
-use secrecy::{ExposeSecret, Secret};
+use secrecy::zeroize::Zeroizing;
 
 use crate::kbs2::agent;
+use crate::kbs2::audit;
 use crate::kbs2::backend::{self, Backend};
 use crate::kbs2::config::{self, Pinentry};
+use crate::kbs2::dedupe;
+use crate::kbs2::doctor;
 use crate::kbs2::generator::Generator;
-use crate::kbs2::input::Input;
+use crate::kbs2::i18n::{self, Message};
+use crate::kbs2::importer::{ConflictStrategy, ImportSummary, Resolution};
+use crate::kbs2::input::{self, Input, WithHelp};
+use crate::kbs2::merge::{self, Side};
 use crate::kbs2::record::{
-    self, EnvironmentFields, LoginFields, Record, RecordBody, UnstructuredFields,
+    self, EnvironmentFields, LoginFields, Record, RecordBody, RecordKind, TotpFields,
+    UnstructuredFields,
 };
-use crate::kbs2::session::Session;
+use crate::kbs2::report::{Outcome, Report};
+use crate::kbs2::session::{LockedRecord, NoSuchRecord, OnRecordError, Session};
+use crate::kbs2::store;
+use crate::kbs2::tty;
+use crate::kbs2::update;
 use crate::kbs2::util;
+use crate::kbs2::version::VersionInfo;
+
+/// The number of records that `kbs2 rm` removes without confirmation, by default.
+const RM_CONFIRM_THRESHOLD: usize = 10;
+
+/// Asks the user to confirm a bulk operation on `count` records, if that's more than
+/// `confirm-threshold` (or the operation's `default_threshold`) allows and `--yes`
+/// wasn't passed. Returns whether the operation should go ahead.
+#[doc(hidden)]
+fn confirm_bulk(
+    config: &config::Config,
+    matches: &ArgMatches,
+    count: usize,
+    default_threshold: usize,
+    prompt: Confirm,
+) -> Result<bool> {
+    let threshold = config.confirm_threshold.unwrap_or(default_threshold);
+
+    if count <= threshold {
+        return Ok(true);
+    }
+
+    confirm(matches, prompt, || {
+        anyhow!(i18n::format(
+            Message::ErrorNeedsConfirmation,
+            &[&count, &threshold]
+        ))
+    })
+}
+
+/// Returns the labels passed on the command line or (with `--stdin-labels`) read from stdin,
+/// one per line; `None` if there are neither.
+///
+/// Labels read from stdin skip empty lines and duplicates, and must all satisfy `has_record`
+/// before any of them are returned, so that a typo can't leave a batch half-done.
+#[doc(hidden)]
+fn label_args(
+    matches: &ArgMatches,
+    has_record: impl Fn(&str) -> bool,
+) -> Result<Option<Vec<String>>> {
+    #[allow(clippy::unwrap_used)]
+    if !*matches.get_one::<bool>("stdin-labels").unwrap() {
+        return Ok(matches
+            .get_many::<String>("label")
+            .map(|labels| labels.cloned().collect()));
+    }
+
+    let mut seen = HashSet::new();
+    let mut labels = vec![];
+    for line in stdin().lines() {
+        let label = line?;
+
+        if label.is_empty() {
+            log::debug!("--stdin-labels: skipping an empty line");
+            continue;
+        }
+
+        if !seen.insert(label.clone()) {
+            log::debug!("--stdin-labels: skipping a duplicate of {label}");
+            continue;
+        }
+
+        if !has_record(&label) {
+            return Err(NoSuchRecord(label).into());
+        }
+
+        labels.push(label);
+    }
+
+    Ok(Some(labels))
+}
+
+/// Asks the user to confirm an operation, unless `--yes` was passed. Returns whether
+/// the operation should go ahead, or fails with `refusal` if there's no terminal to ask on.
+#[doc(hidden)]
+fn confirm(
+    matches: &ArgMatches,
+    prompt: Confirm,
+    refusal: impl FnOnce() -> anyhow::Error,
+) -> Result<bool> {
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("yes").unwrap() {
+        return Ok(true);
+    }
+
+    // NOTE(ww): With --stdin-labels, stdin has already been read to its end, leaving
+    // nothing to answer with.
+    let stdin_labels = matches.try_get_one::<bool>("stdin-labels").ok().flatten();
+    if !stdin().is_terminal() || stdin_labels == Some(&true) {
+        return Err(refusal());
+    }
+
+    input::prompted(|| prompt.prompt())
+}
 
 /// Implements the `kbs2 init` command.
 pub fn init(matches: &ArgMatches, config_dir: &Path) -> Result<()> {
     log::debug!("initializing a new config");
 
     #[allow(clippy::unwrap_used)]
-    if config_dir.join(config::CONFIG_BASENAME).exists()
-        && !*matches.get_one::<bool>("force").unwrap()
-    {
-        return Err(anyhow!(
-            "refusing to overwrite your current config without --force"
-        ));
+    if config_dir.join(config::CONFIG_BASENAME).exists() {
+        if *matches.get_one::<bool>("if-not-exists").unwrap() {
+            log::debug!("config already exists; leaving it alone");
+            return Ok(());
+        } else if !*matches.get_one::<bool>("force").unwrap() {
+            return Err(anyhow!(
+                "refusing to overwrite your current config without --force"
+            ));
+        }
     }
 
     #[allow(clippy::unwrap_used)]
-    let store_dir = matches.get_one::<PathBuf>("store-dir").unwrap().as_path();
+    let store_dir = util::expand_path(matches.get_one::<PathBuf>("store-dir").unwrap())?;
+
+    // NOTE(ww): A store that shares a directory with the config puts the keyfile wherever
+    // the store gets synced to, so we refuse unless explicitly told otherwise.
+    #[allow(clippy::unwrap_used)]
+    if let Some(overlap) = config::directory_overlap(&store_dir, config_dir) {
+        if !*matches.get_one::<bool>("allow-overlapping-store").unwrap() {
+            return Err(anyhow!(
+                "refusing to initialize: {overlap}; pick a separate store directory, or pass --allow-overlapping-store"
+            ));
+        }
+
+        util::warn(&format!(
+            "{overlap}; kbs2's own files won't be listed as records"
+        ));
+    }
 
     // Warn, but don't fail, if the store directory is already present.
     if store_dir.exists() {
         util::warn("Requested store directory already exists");
     }
 
+    let identity = match matches.get_one::<PathBuf>("identity") {
+        Some(path) => {
+            let path = util::expand_path(path)?;
+            if !path.is_file() {
+                return Err(anyhow!("no such identity file: {}", path.display()));
+            }
+
+            Some(config::ExistingIdentity {
+                path,
+                public_key: matches.get_one::<String>("public-key").cloned(),
+            })
+        }
+        None => None,
+    };
+
+    #[allow(clippy::unwrap_used)]
+    let wrapped = !*matches.get_one::<bool>("insecure-not-wrapped").unwrap();
+
+    // Check this up front, rather than after prompting for a password that can't be used.
+    if let Some(identity) = &identity {
+        if wrapped && !backend::RageLib::is_wrapped_keyfile(&identity.path)? {
+            return Err(anyhow!(
+                "identity file isn't wrapped; wrap it with `age -p -a` or pass --insecure-not-wrapped"
+            ));
+        }
+    }
+
     #[allow(clippy::unwrap_used)]
-    let password = if !*matches.get_one::<bool>("insecure-not-wrapped").unwrap() {
-        Some(util::get_password(None, Pinentry::default())?)
-    } else {
+    let password = if !wrapped {
         None
+    } else if *matches.get_one::<bool>("password-stdin").unwrap() {
+        Some(util::read_password_stdin()?)
+    } else {
+        Some(util::get_password(
+            None,
+            &util::password_description(&store_dir, config_dir),
+            Pinentry::default(),
+        )?)
     };
 
-    config::initialize(&config_dir, &store_dir, password)
+    config::initialize(config_dir, &store_dir, password, identity)
 }
 
 /// Implements the `kbs2 agent` command (and subcommands).
@@ -61,7 +222,9 @@ pub fn agent(matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     // No subcommand: run the agent itself
     if matches.subcommand().is_none() {
-        let mut agent = agent::Agent::new()?;
+        #[allow(clippy::unwrap_used)]
+        let mut agent = agent::Agent::new(config.agent_strict)?
+            .with_systemd_notify(*matches.get_one::<bool>("systemd-notify").unwrap());
         #[allow(clippy::unwrap_used)]
         if !matches.get_one::<bool>("foreground").unwrap() {
             Daemonize::new().start()?;
@@ -72,6 +235,8 @@ pub fn agent(matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     match matches.subcommand() {
         Some(("flush", matches)) => agent_flush(matches),
+        Some(("lock", matches)) => agent_lock(matches),
+        Some(("protocol", matches)) => agent_protocol(matches),
         Some(("query", matches)) => agent_query(matches, config),
         Some(("unwrap", matches)) => agent_unwrap(matches, config),
         _ => unreachable!(),
@@ -93,29 +258,146 @@ fn agent_flush(matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+/// Implements the `kbs2 agent lock` subcommand.
+fn agent_lock(matches: &ArgMatches) -> Result<()> {
+    lock_agent(matches).map(|_| ())
+}
+
+/// Flushes every key from the agent (and quits it, if `--quit` was passed), returning
+/// how many keys were flushed, or `None` if no agent is running.
+#[doc(hidden)]
+fn lock_agent(matches: &ArgMatches) -> Result<Option<usize>> {
+    log::debug!("locking the agent");
+
+    // NOTE(ww): If there's no agent to connect to, there are no unwrapped keys to
+    // flush: the agent is as locked as it can be, so we succeed silently.
+    let Ok(client) = agent::Client::new() else {
+        log::debug!("no agent running; nothing to lock");
+        return Ok(None);
+    };
+
+    let flushed = client.flush_keys()?;
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("quit").unwrap() {
+        client.quit_agent()?;
+    }
+
+    Ok(Some(flushed))
+}
+
+/// Implements the `kbs2 agent protocol` subcommand.
+fn agent_protocol(matches: &ArgMatches) -> Result<()> {
+    let description = agent::ProtocolDescription::current()?;
+    let mut stdout = io::stdout().lock();
+
+    match matches.get_one::<String>("example") {
+        Some(name) => {
+            let example = description
+                .example(name)
+                .ok_or_else(|| anyhow!("no such request or response: {}", name))?;
+            writeln!(stdout, "{example}")?;
+        }
+        None => writeln!(
+            stdout,
+            "{}",
+            serde_json::to_string_pretty(&description.to_json())?
+        )?,
+    }
+
+    Ok(stdout.flush()?)
+}
+
 /// Implements the `kbs2 agent query` subcommand.
-fn agent_query(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
+fn agent_query(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     log::debug!("querying the agent for a key's existence");
 
-    // It doesn't make sense to query the agent for keypairs that the agent
-    // doesn't manage. Use a specific code to signal this case.
-    if !config.wrapped {
-        std::process::exit(2);
+    let status = agent::Status::query(&config.public_key, config.wrapped)?;
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("json").unwrap() {
+        println!("{}", serde_json::to_string(&status)?);
+    } else {
+        println!("{status}");
     }
 
-    // Don't allow client creation to fail the normal way: if we can't create
-    // a client for whatever reason (e.g., the agent isn't running), exit
-    // with a specific code to signal our state to the user.
-    let client = agent::Client::new().unwrap_or_else(|_| std::process::exit(3));
-    if !client.query_key(&config.public_key)? {
-        std::process::exit(1);
+    // NOTE(ww): The exit code is the original (and still primary) interface here:
+    // 2 for bare keys, 3 for no agent, 1 for a missing key, and 0 otherwise.
+    match status.exit_code() {
+        0 => Ok(()),
+        code => process::exit(code),
+    }
+}
+
+/// Implements the `kbs2 agent unwrap` subcommand.
+fn agent_unwrap(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    if let Some(dir) = matches.get_one::<PathBuf>("all") {
+        return agent_unwrap_all(&util::expand_path(dir)?);
+    }
+
+    if !unwrap_into_agent(config)? {
+        println!("kbs2 agent already has this key; ignoring.");
     }
 
     Ok(())
 }
 
-/// Implements the `kbs2 agent unwrap` subcommand.
-fn agent_unwrap(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
+/// Implements `kbs2 agent unwrap --all`, unwrapping the key of every config in `dir` (and
+/// in its immediate subdirectories) into the running agent.
+///
+/// Configs that share a key only prompt for its master password once, since the agent
+/// already has the key by the time the others come up.
+#[doc(hidden)]
+fn agent_unwrap_all(dir: &Path) -> Result<()> {
+    let has_config = |dir: &Path| dir.join(config::CONFIG_BASENAME).is_file();
+
+    let mut config_dirs = std::fs::read_dir(dir)
+        .map_err(|e| anyhow!("couldn't read {}: {e}", dir.display()))?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| has_config(path))
+        .collect::<Vec<_>>();
+    config_dirs.sort();
+    if has_config(dir) {
+        config_dirs.insert(0, dir.into());
+    }
+
+    if config_dirs.is_empty() {
+        return Err(anyhow!("no kbs2 configs in {}", dir.display()));
+    }
+
+    let mut failed = 0;
+    for config_dir in &config_dirs {
+        let status = config::load(config_dir).and_then(|config| match config.wrapped {
+            true => unwrap_into_agent(&config).map(|unwrapped| match unwrapped {
+                true => "key unwrapped",
+                false => "key already loaded",
+            }),
+            false => Ok("bare key; skipped"),
+        });
+
+        let name = util::tilde_path(config_dir);
+        match status {
+            Ok(status) => println!("{name}: {status}"),
+            Err(e) => {
+                println!("{name}: {e:#}");
+                failed += 1;
+            }
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(anyhow!(
+            "{failed} of {} configs couldn't be unwrapped",
+            config_dirs.len()
+        )),
+    }
+}
+
+/// Unwraps the config's key into the running agent, prompting for the master password.
+/// Returns whether the key was unwrapped, i.e. `false` if the agent already had it.
+#[doc(hidden)]
+fn unwrap_into_agent(config: &config::Config) -> Result<bool> {
     log::debug!("asking the agent to unwrap a key");
 
     // Bare keys are loaded directly from their `keyfile`.
@@ -125,12 +407,58 @@ fn agent_unwrap(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     let client = agent::Client::new()?;
     if client.query_key(&config.public_key)? {
-        println!("kbs2 agent already has this key; ignoring.");
-        return Ok(());
+        return Ok(false);
+    }
+
+    let policy = agent::UnwrapPolicy::from(config);
+    let added = backend::with_master_password(config, None, |password| {
+        client.add_key(&config.public_key, &config.keyfile, password, &policy)
+    });
+    config.audit(audit::Event::Unwrap {
+        public_key: config.public_key.clone(),
+        agent: true,
+        outcome: audit::Outcome::of(&added),
+    });
+
+    added.map(|_| true)
+}
+
+/// Implements the `kbs2 unlock` command.
+pub fn unlock(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("unlocking the key");
+
+    // NOTE(ww): Unlike `kbs2 agent unwrap`, this works from a cold start: the agent
+    // is started first, just as any other command would start it.
+    if config.wrapped && config.agent_autostart {
+        agent::Agent::spawn(
+            config.agent_binary.as_deref().map(Path::new),
+            Duration::from_millis(config.agent_spawn_timeout_ms),
+        )?;
+    }
+
+    let status = match unwrap_into_agent(config)? {
+        true => "key loaded",
+        false => "key already loaded",
+    };
+
+    if !util::quiet() {
+        println!("{status}");
     }
 
-    let password = util::get_password(None, &config.pinentry)?;
-    client.add_key(&config.public_key, &config.keyfile, password)?;
+    Ok(())
+}
+
+/// Implements the `kbs2 lock` command.
+pub fn lock(matches: &ArgMatches, _config: &config::Config) -> Result<()> {
+    let status = match lock_agent(matches)? {
+        Some(1) => "1 key flushed".into(),
+        Some(flushed) => format!("{flushed} keys flushed"),
+        None => "no agent running".into(),
+    };
+
+    if !util::quiet() {
+        println!("{status}");
+    }
 
     Ok(())
 }
@@ -141,210 +469,1959 @@ pub fn new(matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     let session: Session = config.try_into()?;
 
+    #[allow(clippy::unwrap_used)]
+    let template = matches.get_one::<String>("label").unwrap();
+
+    // NOTE(ww): `{seq}` is the only token that needs the existing labels, so we don't list
+    // the store for anything else.
+    let existing = match template.contains("{seq}") {
+        true => session.record_labels()?,
+        false => vec![],
+    };
+    let label = &util::expand_label_template(template, util::current_timestamp(), &existing)?;
+    if label != template {
+        println!("{label}");
+    }
+
     if let Some(pre_hook) = &session.config.commands.new.pre_hook {
         log::debug!("pre-hook: {}", pre_hook);
-        session.config.call_hook(pre_hook, &[])?;
+        if !session
+            .config
+            .call_vetoable_hook(pre_hook, &[], &[("KBS2_LABEL", label)])?
+        {
+            util::warn(&format!("pre-hook vetoed creating record: {label}"));
+            return Ok(());
+        }
     }
 
-    #[allow(clippy::unwrap_used)]
-    let label = matches.get_one::<String>("label").unwrap();
-
     #[allow(clippy::unwrap_used)]
     if session.has_record(label) && !matches.get_one::<bool>("force").unwrap() {
         return Err(anyhow!("refusing to overwrite a record without --force"));
     }
 
+    if session.has_record(label) {
+        ensure_unlocked(matches, &session.peek_record(label)?)?;
+    }
+
     let config = session.config.with_matches(matches);
 
     #[allow(clippy::unwrap_used)]
-    let record = match matches
-        .get_one::<String>("kind")
-        .map(AsRef::as_ref)
-        .unwrap()
-    {
-        "login" => Record::new(label, LoginFields::input(&config)?),
-        "environment" => Record::new(label, EnvironmentFields::input(&config)?),
-        "unstructured" => Record::new(label, UnstructuredFields::input(&config)?),
-        _ => unreachable!(),
+    let interactive_fields = *matches.get_one::<bool>("interactive-fields").unwrap();
+    if interactive_fields && config.terse() {
+        return Err(anyhow!(
+            "--interactive-fields needs an interactive terminal (and no --terse)"
+        ));
+    }
+
+    #[allow(clippy::unwrap_used)]
+    let record = match matches.get_one::<RecordKind>("kind").unwrap() {
+        RecordKind::Login => Record::new(label, LoginFields::input(&config)?),
+        RecordKind::Environment => Record::new(label, EnvironmentFields::input(&config)?),
+        RecordKind::Unstructured => Record::new(label, UnstructuredFields::input(&config)?),
+        RecordKind::Totp => Record::new(label, TotpFields::input(&config)?),
+    };
+
+    #[allow(clippy::unwrap_used)]
+    let burn = *matches.get_one::<bool>("burn-after-reading").unwrap();
+
+    let record = Record {
+        generated_by: config.generated_by(),
+        totp: matches.get_one::<String>("totp").cloned(),
+        notes: config.notes()?,
+        icon: if session.config.commands.new.prompt_icon && !config.terse() {
+            input::icon()?
+        } else {
+            None
+        },
+        custom_fields: if interactive_fields {
+            Some(input::custom_fields()?).filter(|fields| !fields.is_empty())
+        } else {
+            None
+        },
+        recipients: matches.get_many::<String>("recipient").map(|recipients| {
+            let mut all = vec![session.config.public_key.clone()];
+            for recipient in recipients {
+                if !all.contains(recipient) {
+                    all.push(recipient.clone());
+                }
+            }
+            all
+        }),
+        burn,
+        ..record
     };
 
+    if let Some(totp) = &record.totp {
+        if !session.has_record(totp) {
+            util::warn(&format!("linked TOTP record doesn't exist (yet): {totp}"));
+        }
+    }
+
     session.add_record(&record)?;
 
     if let Some(post_hook) = &session.config.commands.new.post_hook {
         log::debug!("post-hook: {}", post_hook);
-        session.config.call_hook(post_hook, &[label])?;
+        match &record.generated_by {
+            Some(generator) => session.config.call_hook_with_env(
+                post_hook,
+                &[label],
+                &[("KBS2_GENERATOR", generator)],
+            )?,
+            None => session.config.call_hook(post_hook, &[label])?,
+        }
     }
 
     Ok(())
 }
 
-/// Implements the `kbs2 list` command.
-pub fn list(matches: &ArgMatches, config: &config::Config) -> Result<()> {
-    log::debug!("listing records");
-
-    let session: Session = config.try_into()?;
+/// Warns if the given record has fields that this version of `kbs2` doesn't understand.
+#[doc(hidden)]
+fn warn_unknown_fields(record: &Record) {
+    let unknown = record.unknown_fields();
+    if !unknown.is_empty() {
+        util::warn(&format!(
+            "{} has fields not understood by this version of kbs2 (they will be preserved): {}",
+            record.label,
+            unknown.join(", ")
+        ));
+    }
+}
 
-    #[allow(clippy::unwrap_used)]
-    let (details, filter_kind) = (
-        *matches.get_one::<bool>("details").unwrap(),
-        matches.contains_id("kind"),
-    );
+/// Criteria for selecting records, shared by `kbs2 list` and `kbs2 dump`.
+struct RecordFilter {
+    kind: Option<RecordKind>,
+    modified_since: Option<u64>,
+    created_since: Option<u64>,
+}
 
-    for label in session.record_labels()? {
-        let mut display = String::new();
+impl RecordFilter {
+    fn new(kind: Option<RecordKind>, matches: &ArgMatches) -> Self {
+        Self {
+            kind,
+            modified_since: matches.get_one::<u64>("modified-since").copied(),
+            created_since: matches.get_one::<u64>("created-since").copied(),
+        }
+    }
 
-        if details || filter_kind {
-            let record = session.get_record(&label)?;
+    /// Returns whether this filter needs to inspect records at all.
+    fn is_active(&self) -> bool {
+        self.kind.is_some() || self.modified_since.is_some() || self.created_since.is_some()
+    }
 
-            if filter_kind {
-                #[allow(clippy::unwrap_used)]
-                let kind = matches.get_one::<String>("kind").unwrap();
-                if &record.body.to_string() != kind {
-                    continue;
-                }
-            }
+    fn matches(&self, record: &Record) -> bool {
+        self.kind.is_none_or(|k| record.body.kind() == k)
+            && self.modified_since.is_none_or(|t| record.timestamp >= t)
+            && self.created_since.is_none_or(|t| record.created() >= t)
+    }
+}
 
-            display.push_str(&label);
+/// The error produced by `kbs2 list --fail-if-empty` when it lists no records.
+#[derive(Debug)]
+pub enum NothingListed {
+    /// The store has no records at all.
+    EmptyStore,
+    /// The store has records, but none of them matched the filters.
+    NoMatches,
+}
 
-            if details {
-                write!(display, " {} {}", record.body, record.timestamp)?;
+impl fmt::Display for NothingListed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NothingListed::EmptyStore => write!(f, "no records listed: the store is empty"),
+            NothingListed::NoMatches => {
+                write!(f, "no records listed: no records matched the filters")
             }
-        } else {
-            display.push_str(&label);
         }
-
-        println!("{display}");
     }
-
-    Ok(())
 }
 
-/// Implements the `kbs2 rm` command.
-pub fn rm(matches: &ArgMatches, config: &config::Config) -> Result<()> {
-    log::debug!("removing a record");
-
-    let session: Session = config.try_into()?;
+impl std::error::Error for NothingListed {}
 
-    #[allow(clippy::unwrap_used)]
-    let labels: Vec<_> = matches
-        .get_many::<String>("label")
-        .unwrap()
-        .map(AsRef::as_ref)
-        .collect();
+/// Returns the number of threads requested with `--jobs`, or one per CPU.
+#[doc(hidden)]
+fn jobs(matches: &ArgMatches) -> usize {
+    matches
+        .get_one::<usize>("jobs")
+        .copied()
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZeroUsize::get))
+}
 
-    for label in &labels {
-        session.delete_record(label)?;
-    }
+/// Returns the most recently modified record (of the given kind, if any) for `--last`.
+///
+/// NOTE(ww): Timestamps live inside the encrypted records, so this decrypts every one.
+#[doc(hidden)]
+fn last_record(session: &Session, kind: Option<RecordKind>) -> Result<Record> {
+    let labels = session.record_labels()?;
+    let jobs = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+    session
+        .get_records(&labels, jobs)?
+        .into_iter()
+        .filter(|record| kind.is_none_or(|kind| record.body.kind() == kind))
+        .max_by(|a, b| (a.timestamp, &a.label).cmp(&(b.timestamp, &b.label)))
+        .ok_or_else(|| match kind {
+            Some(kind) => anyhow!("--last: there are no {kind} records in the store"),
+            None => anyhow!("--last: there are no records in the store"),
+        })
+}
 
-    if let Some(post_hook) = &session.config.commands.rm.post_hook {
-        log::debug!("post-hook: {}", post_hook);
-        session.config.call_hook(post_hook, &labels)?;
+/// Returns the given record's username, if it's a login with a (non-empty) username.
+#[doc(hidden)]
+fn record_username(record: &Record) -> Option<&str> {
+    match &record.body {
+        RecordBody::Login(login) if !login.username.is_empty() => Some(&login.username),
+        _ => None,
     }
-
-    Ok(())
 }
 
-/// Implements the `kbs2 rename` command.
-pub fn rename(matches: &ArgMatches, config: &config::Config) -> Result<()> {
-    log::debug!("renaming a record");
+/// Implements the `kbs2 list` command.
+pub fn list(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("listing records");
 
     let session: Session = config.try_into()?;
 
-    #[allow(clippy::unwrap_used)]
-    let old_label: &str = matches.get_one::<String>("old-label").unwrap();
-
-    #[allow(clippy::unwrap_used)]
-    let new_label: &str = matches.get_one::<String>("new-label").unwrap();
+    let filter = RecordFilter::new(matches.get_one::<RecordKind>("kind").copied(), matches);
 
     #[allow(clippy::unwrap_used)]
-    if session.has_record(new_label) && !matches.get_one::<bool>("force").unwrap() {
-        return Err(anyhow!("refusing to overwrite a record without --force"));
-    }
-
-    session.rename_record(old_label, new_label)?;
+    let (show_username, json, porcelain) = (
+        *matches.get_one::<bool>("show-username").unwrap(),
+        *matches.get_one::<bool>("json").unwrap(),
+        *matches.get_one::<bool>("porcelain").unwrap(),
+    );
 
-    if let Some(post_hook) = &session.config.commands.rename.post_hook {
-        log::debug!("post-hook: {}", post_hook);
-        session
-            .config
-            .call_hook(post_hook, &[old_label, new_label])?;
+    if show_username && !config.list_usernames {
+        return Err(anyhow!(
+            "--show-username was passed, but list-usernames is disabled"
+        ));
     }
 
-    Ok(())
-}
-
-/// Implements the `kbs2 dump` command.
-pub fn dump(matches: &ArgMatches, config: &config::Config) -> Result<()> {
-    log::debug!("dumping a record");
+    // NOTE(ww): --show-username implies --details, except with --json.
+    #[allow(clippy::unwrap_used)]
+    let details = *matches.get_one::<bool>("details").unwrap() || show_username;
 
-    let session: Session = config.try_into()?;
+    // NOTE(ww): We write through a locked handle rather than `print!`, since the latter
+    // panics when our reader goes away (e.g. `kbs2 list | head`). `main` treats the
+    // resulting broken pipe error as a clean exit.
+    let mut stdout = io::stdout().lock();
 
-    #[allow(clippy::unwrap_used)]
-    let labels: Vec<_> = matches.get_many::<String>("label").unwrap().collect();
+    let jobs = jobs(matches);
 
-    for label in labels {
-        let record = session.get_record(label)?;
+    let labels = session.cached_record_labels()?;
+    let mut listed = 0;
 
+    // The plain listing only needs to decrypt when filtering.
+    if !details && !json && !porcelain {
         #[allow(clippy::unwrap_used)]
-        if *matches.get_one::<bool>("json").unwrap() {
-            println!("{}", serde_json::to_string(&record)?);
+        let terminator = if *matches.get_one::<bool>("null").unwrap() {
+            '\0'
         } else {
-            println!("Label {}\nKind {}", label, record.body);
+            '\n'
+        };
 
-            match record.body {
-                RecordBody::Login(l) => {
-                    println!("Username {}\nPassword {}", l.username, l.password)
-                }
-                RecordBody::Environment(e) => {
-                    println!("Variable {}\nValue {}", e.variable, e.value)
+        if !filter.is_active() {
+            for label in &labels {
+                write!(stdout, "{label}{terminator}")?;
+                listed += 1;
+            }
+        } else {
+            for (label, record) in labels.iter().zip(session.get_records(&labels, jobs)?) {
+                warn_unknown_fields(&record);
+
+                if filter.matches(&record) {
+                    write!(stdout, "{label}{terminator}")?;
+                    listed += 1;
                 }
-                RecordBody::Unstructured(u) => println!("Contents {}", u.contents),
             }
         }
-    }
-
-    Ok(())
-}
+    } else if json {
+        for record in session.get_records(&labels, jobs)? {
+            warn_unknown_fields(&record);
 
-/// Implements the `kbs2 pass` command.
-pub fn pass(matches: &ArgMatches, config: &config::Config) -> Result<()> {
-    log::debug!("getting a login's password");
+            if !filter.matches(&record) {
+                continue;
+            }
 
-    let session: Session = config.try_into()?;
+            let mut details = serde_json::json!({
+                "label": record.label,
+                "kind": record.body.to_string(),
+                "timestamp": record.timestamp,
+                "created": record.created(),
+                "locked": record.locked,
+                "burn": record.burn,
+                "icon": record.icon,
+            });
+            if show_username {
+                details["username"] = record_username(&record).into();
+            }
+            writeln!(stdout, "{details}")?;
+            listed += 1;
+        }
+    } else if porcelain {
+        // NOTE(ww): This format is a stable interface: fields can only ever be added
+        // to the end of each line, and never removed, reordered, or localized.
+        for record in session.get_records(&labels, jobs)? {
+            warn_unknown_fields(&record);
+
+            if !filter.matches(&record) {
+                continue;
+            }
 
-    if let Some(pre_hook) = &session.config.commands.pass.pre_hook {
-        log::debug!("pre-hook: {}", pre_hook);
-        session.config.call_hook(pre_hook, &[])?;
-    }
+            let flags = [(record.locked, "locked"), (record.burn, "burn")]
+                .into_iter()
+                .filter_map(|(flagged, flag)| flagged.then_some(flag))
+                .collect::<Vec<_>>();
+            writeln!(
+                stdout,
+                "{}\t{}\t{}\t{}\t{}",
+                util::porcelain_escape(&record.label),
+                record.body.kind(),
+                record.timestamp,
+                record.created(),
+                if flags.is_empty() {
+                    "-".into()
+                } else {
+                    flags.join(",")
+                }
+            )?;
+            listed += 1;
+        }
+    } else {
+        let label_width = session.config.commands.list.label_width;
+        let mut rows = vec![];
 
+        #[allow(clippy::unwrap_used)]
+        if !*matches.get_one::<bool>("no-header").unwrap() {
+            let mut header = vec!["LABEL".into(), "KIND".into(), "TIMESTAMP".into()];
+            if show_username {
+                header.push("USERNAME".into());
+            }
+            rows.push(header);
+        }
+
+        for (label, record) in labels.iter().zip(session.get_records(&labels, jobs)?) {
+            warn_unknown_fields(&record);
+
+            if !filter.matches(&record) {
+                continue;
+            }
+
+            let marks = [(record.locked, "locked"), (record.burn, "burn")]
+                .into_iter()
+                .filter_map(|(marked, mark)| marked.then_some(mark))
+                .collect::<Vec<_>>();
+            let kind = match marks.is_empty() {
+                true => record.body.to_string(),
+                false => format!("{} ({})", record.body, marks.join(", ")),
+            };
+            let mut row = vec![
+                util::truncate_with_ellipsis(label, label_width),
+                kind,
+                record.timestamp.to_string(),
+            ];
+            if show_username {
+                row.push(record_username(&record).unwrap_or("-").into());
+            }
+            rows.push(row);
+            listed += 1;
+        }
+
+        for line in util::render_table(&rows) {
+            writeln!(stdout, "{line}")?;
+        }
+    }
+
+    stdout.flush()?;
+
+    #[allow(clippy::unwrap_used)]
+    let fail_if_empty = *matches.get_one::<bool>("fail-if-empty").unwrap();
+
+    // NOTE(ww): Only people see this hint; scripts (and pipes) get the same empty output
+    // as ever.
+    if labels.is_empty() && !fail_if_empty && io::stdout().is_terminal() && !util::quiet() {
+        eprintln!("{}", i18n::text(Message::HintEmptyStore));
+    }
+
+    if listed == 0 && fail_if_empty {
+        return Err(if labels.is_empty() {
+            NothingListed::EmptyStore
+        } else {
+            NothingListed::NoMatches
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 status` command.
+pub fn status(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("reporting status");
+
+    // NOTE(ww): This is meant to be run from shell prompts and status bars, so it
+    // deliberately avoids creating a `Session`: that can spawn the agent, or even
+    // prompt for the master password.
+    let records = store::open(config)?.labels()?.len();
+    let agent = agent::Status::query(&config.public_key, config.wrapped)?;
+    let unlocked = !config.wrapped || agent.key_loaded;
+
+    let mut stdout = io::stdout().lock();
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("porcelain").unwrap() {
+        // NOTE(ww): As with `kbs2 list --porcelain`, this format is a stable interface:
+        // keys can only ever be added to the end, and never removed, reordered, or localized.
+        let flag = |b: bool| if b { "1" } else { "0" };
+        for (key, value) in [
+            ("store", util::porcelain_escape(&config.store)),
+            ("records", records.to_string().into()),
+            ("wrapped", flag(config.wrapped).into()),
+            ("agent", flag(agent.running).into()),
+            ("unlocked", flag(unlocked).into()),
+        ] {
+            writeln!(stdout, "{key}\t{value}")?;
+        }
+    } else {
+        writeln!(stdout, "store: {} ({records} records)", config.store)?;
+        writeln!(
+            stdout,
+            "agent: {}",
+            if agent.running {
+                "running"
+            } else {
+                "not running"
+            }
+        )?;
+        writeln!(
+            stdout,
+            "key: {}",
+            match (config.wrapped, unlocked) {
+                (false, _) => "bare",
+                (true, true) => "unlocked",
+                (true, false) => "locked",
+            }
+        )?;
+    }
+
+    Ok(stdout.flush()?)
+}
+
+/// Implements the `kbs2 rm` command.
+pub fn rm(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("removing a record");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let labels = label_args(matches, |label| session.has_record(label))?.unwrap();
+    let mut labels: Vec<_> = labels.iter().map(AsRef::as_ref).collect();
+    labels.sort();
+    labels.dedup();
+
+    // Plan the removal in full before removing anything, so that a bad label
+    // (or a guardrail) can't leave the store partially wiped.
+    if let Some(limit) = matches.get_one::<usize>("limit") {
+        if labels.len() > *limit {
+            return Err(anyhow!(
+                "refusing to remove {} records (more than --limit {})",
+                labels.len(),
+                limit
+            ));
+        }
+    }
+
+    #[allow(clippy::unwrap_used)]
+    let force_locked = *matches.get_one::<bool>("force-locked").unwrap();
+    let kind = matches.get_one::<RecordKind>("kind");
+    for label in &labels {
+        if !session.has_record(label) {
+            return Err(NoSuchRecord((*label).into()).into());
+        }
+
+        // NOTE(ww): Records are only decrypted if there's something to check them for.
+        if kind.is_none() && force_locked {
+            continue;
+        }
+
+        // NOTE(ww): A record that can't be decrypted can't be locked as far as we can
+        // tell, and removing it is often the whole point.
+        let record = match session.peek_record(label) {
+            Ok(record) => record,
+            Err(e) if kind.is_none() => {
+                log::debug!("{label}: not checking lock: {e}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        ensure_unlocked(matches, &record)?;
+
+        if let Some(kind) = kind {
+            if record.body.kind() != *kind {
+                return Err(anyhow!(
+                    "refusing to remove {} record {} (not {})",
+                    record.body,
+                    label,
+                    kind
+                ));
+            }
+        }
+    }
+
+    if config.dry_run {
+        for label in &labels {
+            println!("would delete '{label}'");
+        }
+
+        return Ok(());
+    }
+
+    if labels.len() > 1 {
+        println!("Removing {} records:", labels.len());
+        for label in &labels {
+            println!("  {label}");
+        }
+    }
+
+    // In a shared store, someone else may have just updated a record that we're about to
+    // remove: ask before removing anything that changed within the configured window.
+    let recent_window = session.config.commands.rm.recent_window_secs;
+    let modified: Vec<_> = labels
+        .iter()
+        .map(|label| session.record_modified(label))
+        .collect();
+    if let Some(window) = recent_window {
+        let now = SystemTime::now();
+        let recent: Vec<_> = labels
+            .iter()
+            .zip(&modified)
+            .filter_map(|(label, mtime)| {
+                // NOTE(ww): An mtime in the future (e.g. from another machine's clock) is as
+                // recent as it gets.
+                let age = now.duration_since((*mtime)?).unwrap_or_default();
+                (age.as_secs() < window).then_some((label, age))
+            })
+            .collect();
+
+        if !recent.is_empty() {
+            for (label, age) in &recent {
+                util::warn(&format!("{label} was modified {}s ago", age.as_secs()));
+            }
+
+            let confirmed = confirm(
+                matches,
+                Confirm::new(&i18n::format(
+                    Message::ConfirmRemoveRecent,
+                    &[&recent.len()],
+                ))
+                .with_default(false),
+                || {
+                    anyhow!(i18n::format(
+                        Message::ErrorRecentlyModified,
+                        &[&recent.len(), &window]
+                    ))
+                },
+            )?;
+            if !confirmed {
+                return Ok(());
+            }
+        }
+    }
+
+    if !confirm_bulk(
+        config,
+        matches,
+        labels.len(),
+        RM_CONFIRM_THRESHOLD,
+        Confirm::new(&i18n::format(Message::ConfirmRemove, &[&labels.len()])).with_default(false),
+    )? {
+        return Ok(());
+    }
+
+    // Anything that changed while we were asking is left alone, along with everything else.
+    if recent_window.is_some() {
+        for (label, mtime) in labels.iter().zip(&modified) {
+            if session.record_modified(label) != *mtime {
+                return Err(anyhow!(
+                    "refusing to remove {label}: it was modified while confirming; nothing removed"
+                ));
+            }
+        }
+    }
+
+    session.batch(|| {
+        for label in &labels {
+            session.delete_record(label)?;
+        }
+
+        Ok(())
+    })?;
+
+    if let Some(post_hook) = &session.config.commands.rm.post_hook {
+        log::debug!("post-hook: {}", post_hook);
+        session.config.call_hook(post_hook, &labels)?;
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 rename` command.
+pub fn rename(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("renaming a record");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let old_label: &str = matches.get_one::<String>("old-label").unwrap();
+
+    #[allow(clippy::unwrap_used)]
+    let new_label: &str = matches.get_one::<String>("new-label").unwrap();
+
+    #[allow(clippy::unwrap_used)]
+    let merging = *matches.get_one::<bool>("merge").unwrap();
+
+    // Renaming removes the old record, and overwriting (or merging into) the new one
+    // modifies it: neither can be locked.
+    ensure_unlocked(matches, &session.peek_record(old_label)?)?;
+    #[allow(clippy::unwrap_used)]
+    if session.has_record(new_label) && (merging || *matches.get_one::<bool>("force").unwrap()) {
+        ensure_unlocked(matches, &session.peek_record(new_label)?)?;
+    }
+
+    #[allow(clippy::unwrap_used)]
+    if merging && session.has_record(new_label) {
+        let preference = matches
+            .get_one::<String>("prefer")
+            .map(|p| p.parse())
+            .transpose()?;
+
+        if config.dry_run {
+            println!("would merge '{old_label}' into '{new_label}'");
+            return Ok(());
+        }
+
+        if !merge_records(&session, old_label, new_label, preference)? {
+            return Ok(());
+        }
+    } else if session.has_record(new_label) && !matches.get_one::<bool>("force").unwrap() {
+        return Err(anyhow!(
+            "refusing to overwrite a record without --force (or --merge)"
+        ));
+    } else if config.dry_run {
+        if session.has_record(new_label) {
+            println!("would overwrite '{new_label}'");
+        }
+        println!("would rename '{old_label}' to '{new_label}'");
+        return Ok(());
+    } else {
+        session.rename_record(old_label, new_label)?;
+    }
+
+    if let Some(post_hook) = &session.config.commands.rename.post_hook {
+        log::debug!("post-hook: {}", post_hook);
+        session
+            .config
+            .call_hook(post_hook, &[old_label, new_label])?;
+    }
+
+    Ok(())
+}
+
+/// Fails if the given record is locked, unless `--force-locked` was passed.
+#[doc(hidden)]
+fn ensure_unlocked(matches: &ArgMatches, record: &Record) -> Result<()> {
+    #[allow(clippy::unwrap_used)]
+    if record.locked && !*matches.get_one::<bool>("force-locked").unwrap() {
+        return Err(LockedRecord(record.label.clone()).into());
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 lock-record` and `kbs2 unlock-record` commands.
+pub fn lock_record(matches: &ArgMatches, config: &config::Config, locked: bool) -> Result<()> {
+    log::debug!("setting locked={locked} on records");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let labels: Vec<_> = matches.get_many::<String>("label").unwrap().collect();
+
+    // NOTE(ww): Locking doesn't touch the record's contents, so its timestamp stays put.
+    session.batch(|| {
+        for label in labels {
+            let mut record = session.get_record(label)?;
+            if record.locked == locked {
+                log::debug!("{label}: already locked={locked}");
+                continue;
+            }
+
+            record.locked = locked;
+            session.add_record(&record)?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Merges the record labeled `src` into the record labeled `dst`, and then removes `src`.
+///
+/// Conflicting fields are resolved with `preference`, or interactively without one.
+/// Returns whether the merge was carried out.
+#[doc(hidden)]
+fn merge_records(
+    session: &Session,
+    src: &str,
+    dst: &str,
+    preference: Option<merge::Preference>,
+) -> Result<bool> {
+    if src == dst {
+        return Err(anyhow!("can't merge a record into itself"));
+    }
+
+    if preference.is_none() && !stdin().is_terminal() {
+        return Err(anyhow!("merging non-interactively requires --prefer"));
+    }
+
+    let src_record = session.get_record(src)?;
+    let dst_record = session.get_record(dst)?;
+    let conflicts = merge::field_conflicts(&src_record, &dst_record)?;
+    let label = |side| match side {
+        Side::Src => src,
+        Side::Dst => dst,
+    };
+
+    let mut choices = Vec::with_capacity(conflicts.len());
+    for conflict in &conflicts {
+        let side = match (conflict.fill(), preference) {
+            (Some(side), _) => side,
+            (None, Some(preference)) => preference.side(&src_record, &dst_record),
+            (None, None) => {
+                let options = vec![
+                    format!("{}: {}", src, conflict.display(Side::Src)),
+                    format!("{}: {}", dst, conflict.display(Side::Dst)),
+                ];
+                let choice = input::prompted(|| {
+                    Select::new(
+                        &i18n::format(Message::SelectMergeSide, &[&conflict.field]),
+                        options,
+                    )
+                    .raw_prompt()
+                })?;
+
+                if choice.index == 0 {
+                    Side::Src
+                } else {
+                    Side::Dst
+                }
+            }
+        };
+        choices.push(side);
+    }
+
+    println!("Merging {src} into {dst}:");
+    for (conflict, side) in conflicts.iter().zip(&choices) {
+        println!(
+            "  {}: keeping {} from {}",
+            conflict.field,
+            conflict.display(*side),
+            label(*side)
+        );
+    }
+    if conflicts.is_empty() {
+        println!("  (no conflicting fields)");
+    }
+    println!("{src} will be removed.");
+
+    if preference.is_none()
+        && !input::prompted(|| {
+            Confirm::new(i18n::text(Message::ConfirmMerge))
+                .with_default(false)
+                .prompt()
+        })?
+    {
+        return Ok(false);
+    }
+
+    let merged = merge::merge(src_record, dst_record, &conflicts, &choices)?;
+    session.batch(|| {
+        session.add_record(&merged)?;
+        session.delete_record(src)
+    })?;
+
+    Ok(true)
+}
+
+/// Implements the `kbs2 dedupe` command.
+pub fn dedupe(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("looking for duplicate records");
+
+    let session: Session = config.try_into()?;
+
+    let labels = session.record_labels()?;
+    let groups = dedupe::find_duplicates(&session.get_records(&labels, jobs(matches))?);
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("json").unwrap() {
+        println!("{}", serde_json::to_string(&groups)?);
+        return Ok(());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    if !*matches.get_one::<bool>("interactive").unwrap() {
+        let mut stdout = io::stdout().lock();
+        for group in &groups {
+            writeln!(stdout, "{}: {}", group.reason, group.labels.join(", "))?;
+        }
+        return Ok(stdout.flush()?);
+    }
+
+    let actions = [
+        Message::DedupeKeepOne,
+        Message::DedupeMerge,
+        Message::DedupeSkip,
+    ];
+    for group in groups {
+        // A record can be in more than one group, so earlier actions may have removed some.
+        let labels = group
+            .labels
+            .into_iter()
+            .filter(|label| session.has_record(label))
+            .collect::<Vec<_>>();
+        if labels.len() < 2 {
+            continue;
+        }
+
+        println!("{}: {}", group.reason, labels.join(", "));
+        let action = input::prompted(|| {
+            Select::new(
+                i18n::text(Message::SelectDedupeAction),
+                actions.iter().map(|action| i18n::text(*action)).collect(),
+            )
+            .raw_prompt()
+        })?;
+        if actions[action.index] == Message::DedupeSkip {
+            continue;
+        }
+
+        let keep = input::prompted(|| {
+            Select::new(i18n::text(Message::SelectDedupeKeep), labels.clone()).prompt()
+        })?;
+        let others = labels
+            .iter()
+            .filter(|label| **label != keep)
+            .collect::<Vec<_>>();
+
+        // NOTE(ww): Merging rewrites the kept record, while keeping one leaves it alone.
+        let merge = actions[action.index] == Message::DedupeMerge;
+        for label in &labels {
+            if merge || *label != keep {
+                ensure_unlocked(matches, &session.peek_record(label)?)?;
+            }
+        }
+
+        if merge {
+            for other in others {
+                merge_records(&session, other, &keep, None)?;
+            }
+        } else if input::prompted(|| {
+            Confirm::new(&i18n::format(Message::ConfirmRemove, &[&others.len()]))
+                .with_default(false)
+                .prompt()
+        })? {
+            session.batch(|| {
+                for other in others {
+                    session.delete_record(other)?;
+                }
+
+                Ok(())
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 dump` command.
+pub fn dump(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("dumping a record");
+
+    // NOTE(ww): Raw dumps never touch the backend, so we skip creating a `Session`
+    // (and the agent round-trip that it can involve) entirely.
+    // NOTE(ww): See the note in `list` about writing to `stdout`.
+    let mut stdout = io::stdout().lock();
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("encrypted").unwrap() {
+        let filenames = OnceCell::new();
+        #[allow(clippy::unwrap_used)]
+        let labels = label_args(matches, |label| {
+            config.record_path_in(label, &filenames).is_file()
+        })?
+        .unwrap();
+        for label in &labels {
+            let record_path = config.record_path_in(label, &filenames);
+            let contents = std::fs::read(&record_path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => anyhow::Error::from(NoSuchRecord(label.clone())),
+                _ => e.into(),
+            })?;
+
+            stdout.write_all(&contents)?;
+        }
+
+        return Ok(stdout.flush()?);
+    }
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let all = *matches.get_one::<bool>("all").unwrap();
+    #[allow(clippy::unwrap_used)]
+    let labels = if all {
+        let mut labels = session.record_labels()?;
+        labels.sort();
+        labels
+    } else if *matches.get_one::<bool>("last").unwrap() {
+        vec![last_record(&session, None)?.label]
+    } else {
+        label_args(matches, |label| session.has_record(label))?.unwrap()
+    };
+    let filter = RecordFilter::new(None, matches);
+
+    // NOTE(ww): JSON output is meant for other programs, so only the human-readable formats
+    // ask before putting secrets on the screen. Pipes and redirects are never asked about.
+    #[allow(clippy::unwrap_used)]
+    if session.config.confirm_dump
+        && !*matches.get_one::<bool>("json").unwrap()
+        && io::stdout().is_terminal()
+    {
+        let prompt = match labels.as_slice() {
+            [label] => i18n::format(Message::ConfirmDump, &[label]),
+            labels => i18n::format(Message::ConfirmDumpMany, &[&labels.len()]),
+        };
+
+        if !confirm(matches, Confirm::new(&prompt).with_default(false), || {
+            anyhow!(i18n::text(Message::ErrorDumpNeedsConfirmation))
+        })? {
+            return Ok(());
+        }
+    }
+
+    for record in session.iter_records(&labels, OnRecordError::Fail) {
+        let record = record?;
+
+        if !filter.matches(&record) {
+            continue;
+        }
+
+        // NOTE(ww): Dumping the whole store (e.g. for a backup) isn't an access of anything.
+        if !all {
+            session.track_access(&record.label);
+        }
+
+        // NOTE(ww): Burn-after-reading records are only burned once they've been written
+        // out in full, so a failure partway through leaves them in the store.
+        let burned = session.burn_after(&record, || {
+            #[allow(clippy::unwrap_used)]
+            if *matches.get_one::<bool>("iso-timestamps").unwrap() {
+                let record = record::IsoTimestamps::from(&record);
+                writeln!(stdout, "{}", serde_json::to_string(&record)?)?;
+            } else if *matches.get_one::<bool>("json").unwrap() {
+                writeln!(stdout, "{}", serde_json::to_string(&record)?)?;
+            } else if *matches.get_one::<bool>("fields-only").unwrap() {
+                match &record.body {
+                    RecordBody::Login(l) => writeln!(stdout, "{}\n{}", l.username, l.password)?,
+                    RecordBody::Environment(e) => writeln!(stdout, "{}\n{}", e.variable, e.value)?,
+                    RecordBody::Unstructured(u) => writeln!(stdout, "{}", u.contents)?,
+                    RecordBody::Totp(t) => writeln!(stdout, "{}", t.secret)?,
+                }
+            } else {
+                writeln!(stdout, "Label {}\nKind {}", record.label, record.body)?;
+
+                if let Some(icon) = &record.icon {
+                    writeln!(stdout, "Icon {icon}")?;
+                }
+
+                match &record.body {
+                    RecordBody::Login(l) => {
+                        writeln!(stdout, "Username {}\nPassword {}", l.username, l.password)?
+                    }
+                    RecordBody::Environment(e) => {
+                        writeln!(stdout, "Variable {}\nValue {}", e.variable, e.value)?
+                    }
+                    RecordBody::Unstructured(u) => writeln!(stdout, "Contents {}", u.contents)?,
+                    RecordBody::Totp(t) => writeln!(stdout, "Secret {}", t.secret)?,
+                }
+
+                if let Some(totp) = &record.totp {
+                    writeln!(stdout, "TOTP {totp}")?;
+                }
+
+                if let Some(notes) = &record.notes {
+                    writeln!(stdout, "Notes {notes}")?;
+                }
+
+                for (name, value) in record.custom_fields.iter().flatten() {
+                    writeln!(stdout, "Field {name}: {value}")?;
+                }
+
+                if let Some(recipients) = &record.recipients {
+                    writeln!(stdout, "Recipients {}", recipients.join(", "))?;
+                }
+            }
+
+            Ok(stdout.flush()?)
+        })?;
+
+        if burned {
+            eprintln!("{}", i18n::format(Message::RecordBurned, &[&record.label]));
+        }
+    }
+
+    Ok(stdout.flush()?)
+}
+
+/// Implements the `kbs2 get` command.
+pub fn get(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("getting a record's fields");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let record = session.get_record(matches.get_one::<String>("label").unwrap())?;
+    session.track_access(&record.label);
+
+    // NOTE(ww): Fields are checked against the record's kind before anything is printed,
+    // so that a typo doesn't produce partial output (or burn the record).
+    let known = record.body.fields();
+    let fields = match matches.get_many::<String>("field") {
+        Some(fields) => fields
+            .map(|field| {
+                known.iter().copied().find(|f| f == field).ok_or_else(|| {
+                    anyhow!(
+                        "{} records have no field named {field} (expected one of: {})",
+                        record.body.kind(),
+                        known.join(", ")
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => known.to_vec(),
+    };
+
+    // NOTE(ww): See the note in `list` about writing to `stdout`.
+    let mut stdout = io::stdout().lock();
+    let burned = session.burn_after(&record, || {
+        #[allow(clippy::unwrap_used)]
+        if *matches.get_one::<bool>("json").unwrap() {
+            let mut json = serde_json::Map::new();
+            json.insert("label".into(), record.label.clone().into());
+            json.insert("kind".into(), record.body.kind().to_string().into());
+            for field in &fields {
+                json.insert((*field).into(), record.body.field(field).into());
+            }
+
+            writeln!(stdout, "{}", serde_json::Value::Object(json))?;
+        } else {
+            for field in &fields {
+                writeln!(stdout, "{}", record.body.field(field).unwrap_or_default())?;
+            }
+        }
+
+        Ok(stdout.flush()?)
+    })?;
+
+    if burned {
+        eprintln!("{}", i18n::format(Message::RecordBurned, &[&record.label]));
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 import` command.
+pub fn import(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("import subcommand dispatch");
+
+    match matches.subcommand() {
+        Some(("raw", matches)) => {
+            let session: Session = config.try_into()?;
+
+            #[allow(clippy::unwrap_used)]
+            let label = matches.get_one::<String>("label").unwrap();
+
+            #[allow(clippy::unwrap_used)]
+            let strategy = if *matches.get_one::<bool>("force").unwrap() {
+                Some(ConflictStrategy::Overwrite)
+            } else {
+                matches
+                    .get_one::<String>("on-conflict")
+                    .map(|s| s.parse())
+                    .transpose()?
+            };
+
+            if strategy == Some(ConflictStrategy::Overwrite) && session.has_record(label) {
+                ensure_unlocked(matches, &session.peek_record(label)?)?;
+            }
+
+            let mut armored = String::new();
+            stdin().read_to_string(&mut armored)?;
+
+            let result = session.import_raw_record(label, &armored, strategy);
+
+            if config.dry_run {
+                match result? {
+                    Resolution::Add(label) => println!("would add '{label}'"),
+                    Resolution::Overwrite(label) => println!("would overwrite '{label}'"),
+                    Resolution::Rename(new_label) => {
+                        println!("would add '{label}' as '{new_label}'")
+                    }
+                    Resolution::Skip => println!("would skip '{label}' (already present)"),
+                }
+
+                return Ok(());
+            }
+
+            let mut report = Report::new("import");
+            match &result {
+                Ok(Resolution::Skip) => {
+                    report.record(label, Outcome::Skipped, Some("already present".into()))
+                }
+                Ok(Resolution::Rename(new_label)) => {
+                    report.record(label, Outcome::Ok, Some(format!("renamed to {new_label}")))
+                }
+                result => report.result(label, result),
+            }
+            if let Some(path) = report_path(matches)? {
+                report.write(path)?;
+            }
+
+            let mut summary = ImportSummary::default();
+            summary.tally(&result?);
+
+            println!("{summary}");
+        }
+        Some((_, _)) => unreachable!(),
+        None => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 share` command.
+pub fn share(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("sharing a record");
+
+    #[allow(clippy::unwrap_used)]
+    let recipient = matches
+        .get_one::<String>("to")
+        .unwrap()
+        .parse::<backend::RageRecipient>()?;
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let label = matches.get_one::<String>("label").unwrap();
+    let mut record = session.get_record(label)?;
+
+    // NOTE(ww): TOTP links refer to labels in *our* store, which mean nothing to
+    // the recipient, so we don't share them. Likewise for the record's own recipients.
+    record.totp = None;
+    record.recipients = None;
+
+    let shared = session.backend.encrypt_to(&record, &[recipient])?;
+    match matches.get_one::<PathBuf>("output") {
+        Some(output) => std::fs::write(output, shared)?,
+        None => io::stdout().write_all(shared.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 receive` command.
+pub fn receive(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("receiving a shared record");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let label = matches.get_one::<String>("as").unwrap();
+
+    #[allow(clippy::unwrap_used)]
+    if session.has_record(label) && !*matches.get_one::<bool>("force").unwrap() {
+        return Err(anyhow!("refusing to overwrite a record without --force"));
+    }
+
+    let shared = match matches.get_one::<PathBuf>("input") {
+        Some(input) => std::fs::read_to_string(input)?,
+        None => {
+            let mut shared = String::new();
+            stdin().read_to_string(&mut shared)?;
+            shared
+        }
+    };
+
+    let mut record = session
+        .backend
+        .decrypt(&shared)
+        .map_err(|e| anyhow!("shared record doesn't decrypt: {}", e))?;
+
+    record.label = label.into();
+    record.timestamp = util::current_timestamp();
+
+    session.add_record(&record)
+}
+
+/// Implements the `kbs2 fsck` command.
+pub fn fsck(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("checking records");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let repair = *matches.get_one::<bool>("repair").unwrap();
+    if repair && !stdin().is_terminal() {
+        return Err(anyhow!("fsck --repair requires an interactive terminal"));
+    }
+
+    #[allow(clippy::unwrap_used)]
+    let fix = *matches.get_one::<bool>("fix").unwrap();
+
+    // Stores set up before `kbs2 init` refused overlapping directories can still have
+    // them, so we point that out (and how to fix it) before checking any records.
+    let overlap = config::directory_overlap(&config.store_path(), Path::new(&config.config_dir));
+    if let Some(overlap) = overlap {
+        println!(
+            "store: {overlap}; move the records to a directory of their own and point `store` in {} at it",
+            config.config_path().display()
+        );
+    }
+
+    // NOTE(ww): A store that can't be written to still reads just fine, so this is
+    // easy to miss until the next `kbs2 new` fails.
+    let writable = util::probe_writable(&config.store_path());
+    if let Err(e) = &writable {
+        println!("store: {e}");
+    }
+
+    let mut labels = match matches.get_many::<String>("label") {
+        Some(labels) => labels.map(|l| session.resolve_label(l)).collect(),
+        None => session.record_labels()?,
+    };
+    labels.sort();
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("identify-foreign").unwrap() {
+        return fsck_foreign(&session, &labels);
+    }
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("accept-manifest").unwrap() {
+        if !config.manifest {
+            return Err(anyhow!(
+                "the manifest isn't enabled; set manifest = true to use it"
+            ));
+        }
+
+        session.rebuild_manifest()?;
+        println!("store: manifest updated ({} records)", labels.len());
+        return Ok(());
+    }
+
+    // Files with non-UTF-8 names can't be records, so they're left out of listings; they're
+    // only ever reported here, with escaped names so they can be cleaned up.
+    let mut invalid_names = 0;
+    if matches.get_many::<String>("label").is_none() {
+        for name in session.invalid_names()? {
+            println!("store: {name:?}: file name isn't valid UTF-8; rename or remove it");
+            invalid_names += 1;
+        }
+    }
+
+    // The manifest covers the whole store, so it's only checked when the whole store is.
+    let mut untracked = 0;
+    if config.manifest && matches.get_many::<String>("label").is_none() {
+        // NOTE(ww): The manifest is only encrypted to the public key, not authenticated, so
+        // a clean result here doesn't rule out somebody who knows the key rewriting it.
+        util::warn(
+            "the manifest isn't authenticated: anybody with the public key can rewrite it \
+             to hide changes to the store",
+        );
+        match session.manifest_discrepancies()? {
+            Some(discrepancies) => {
+                for discrepancy in &discrepancies {
+                    println!("{discrepancy}");
+                }
+                untracked = discrepancies.len();
+            }
+            None => {
+                println!("store: the manifest is missing (deleted, or never created)");
+                untracked = 1;
+            }
+        }
+    }
+
+    let policy = config.label_normalization;
+    let mut unhealthy = 0;
+    let mut locked = 0;
+    for label in &labels {
+        // Records stored under labels that don't follow label-normalization (e.g. from
+        // before it was enabled) are still found, but can be renamed into line with --fix.
+        let normalized = policy.normalize(label);
+        let label = if normalized == *label {
+            label
+        } else if !fix {
+            println!("{label}: label isn't normalized (expected {normalized})");
+            unhealthy += 1;
+            label
+        } else if session.record_labels()?.contains(&normalized) {
+            println!("{label}: label isn't normalized, but {normalized} already exists");
+            unhealthy += 1;
+            label
+        } else {
+            session.rename_record(label, &normalized)?;
+            println!("{label}: renamed to {normalized}");
+            &normalized
+        };
+
+        let (mut json, anomalies) = match session.get_record_json(label).and_then(|json| {
+            let anomalies = record::field_anomalies(&json)?;
+            Ok((json, anomalies))
+        }) {
+            Ok(checked) => checked,
+            Err(e) => {
+                println!("{label}: {e:#}");
+                unhealthy += 1;
+                continue;
+            }
+        };
+
+        if json["locked"] == true {
+            locked += 1;
+        }
+
+        if anomalies.is_empty() {
+            continue;
+        }
+
+        for anomaly in &anomalies {
+            println!("{label}: {anomaly}");
+        }
+
+        if !repair {
+            unhealthy += 1;
+            continue;
+        }
+
+        for anomaly in &anomalies {
+            let field = anomaly.field();
+            let message = format!("{label}: {field}?");
+            let value = match field {
+                "password" | "value" | "secret" => input::prompted(|| {
+                    inquire::Password::new(&message)
+                        .without_confirmation()
+                        .prompt()
+                })?,
+                _ => input::prompted(|| inquire::Text::new(&message).prompt())?,
+            };
+
+            json["body"]["fields"][field] = value.into();
+        }
+
+        let record: Record = serde_json::from_value(json)?;
+        session.add_record(&record)?;
+        println!("{label}: repaired");
+    }
+
+    // NOTE(ww): Locked records aren't a problem, but they're worth knowing about before
+    // e.g. a bulk `kbs2 rm` refuses to touch them.
+    if locked > 0 {
+        println!("store: {locked} of {} records are locked", labels.len());
+    }
+
+    if unhealthy == 0 && invalid_names > 0 {
+        return Err(anyhow!(
+            "the store contains {invalid_names} file(s) with non-UTF-8 names"
+        ));
+    }
+
+    match (unhealthy, untracked, overlap, writable) {
+        (0, 0, None, Ok(())) => Ok(()),
+        (0, 0, None, Err(e)) => Err(e.context("the store isn't writable")),
+        (0, 0, Some(overlap), _) => Err(anyhow!("the store's layout has problems: {overlap}")),
+        (0, _, _, _) => Err(anyhow!(
+            "the store doesn't match its manifest; if that's expected, accept its current \
+             contents with `kbs2 fsck --accept-manifest`"
+        )),
+        _ => Err(anyhow!(
+            "{unhealthy} of {} records have problems",
+            labels.len()
+        )),
+    }
+}
+
+/// Implements `kbs2 fsck --identify-foreign`, reporting the records that are encrypted
+/// to some other keypair and which recipients they're encrypted to instead.
+#[doc(hidden)]
+fn fsck_foreign(session: &Session, labels: &[String]) -> Result<()> {
+    let mut foreign = 0;
+    for label in labels {
+        if let Err(e) = session.get_record_json(label) {
+            if let Some(e) = e.downcast_ref::<backend::ForeignRecord>() {
+                match e.recipients.is_empty() {
+                    true => println!("{label}: encrypted to an unknown recipient"),
+                    false => println!("{label}: encrypted to {}", e.recipients.join(", ")),
+                }
+                foreign += 1;
+            }
+        }
+    }
+
+    match foreign {
+        0 => Ok(()),
+        _ => Err(anyhow!(
+            "{foreign} of {} records are encrypted to another key; \
+             rescue them with `kbs2 reencrypt --with-identity <keyfile>`",
+            labels.len()
+        )),
+    }
+}
+
+/// Implements the `kbs2 reencrypt` command.
+pub fn reencrypt(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("re-encrypting records");
+
+    let session: Session = config.try_into()?;
+
+    // Without another identity, records are decrypted with the current one: that's how
+    // e.g. a change of escrow recipients is applied to just a few records.
+    let other = matches
+        .get_one::<PathBuf>("with-identity")
+        .map(|keyfile| backend::RageLib::with_keyfile(config, &util::expand_path(keyfile)?))
+        .transpose()?;
+
+    // By default, every record that the current identity can't decrypt is a candidate.
+    let labels = match label_args(matches, |label| session.has_record(label))? {
+        Some(labels) => labels,
+        None => {
+            let mut labels = session.record_labels()?;
+            labels.retain(|label| {
+                session
+                    .get_record_json(label)
+                    .is_err_and(|e| e.is::<backend::ForeignRecord>())
+            });
+            labels.sort();
+            labels
+        }
+    };
+
+    let mut report = Report::new("reencrypt");
+    for label in &labels {
+        let result = match &other {
+            Some(other) => session.reencrypt_record(label, other),
+            None => session
+                .get_record(label)
+                .and_then(|record| match config.dry_run {
+                    true => Ok(()),
+                    false => session.add_record(&record),
+                }),
+        };
+        match &result {
+            Ok(()) if config.dry_run => println!("would re-encrypt '{label}'"),
+            Ok(()) => println!("{label}: re-encrypted"),
+            Err(e) => println!("{label}: {e:#}"),
+        }
+        report.result(label, &result);
+    }
+
+    finish_report(matches, &report, "re-encrypted")
+}
+
+/// Returns the path given with `--report`, if any.
+#[doc(hidden)]
+fn report_path(matches: &ArgMatches) -> Result<Option<PathBuf>> {
+    matches
+        .get_one::<PathBuf>("report")
+        .map(util::expand_path)
+        .transpose()
+}
+
+/// Prints a summary of the given report, writes it to the path given with `--report` (if any),
+/// and then fails if any record failed.
+#[doc(hidden)]
+fn finish_report(matches: &ArgMatches, report: &Report, verb: &str) -> Result<()> {
+    println!("Summary:");
+    for line in report.summary() {
+        println!("  {line}");
+    }
+
+    report.finish(report_path(matches)?.as_deref(), verb)
+}
+
+/// Implements the `kbs2 bench` command.
+pub fn bench(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("bench subcommand dispatch");
+
+    match matches.subcommand() {
+        Some(("populate", matches)) => bench_populate(matches, config),
+        Some(("read", matches)) => bench_read(matches, config),
+        Some(("labels", matches)) => bench_labels(matches, config),
+        _ => unreachable!(),
+    }
+}
+
+/// Implements the `kbs2 bench populate` subcommand.
+fn bench_populate(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("populating the store with synthetic records");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let count = *matches.get_one::<usize>("count").unwrap();
+    #[allow(clippy::unwrap_used)]
+    let prefix = matches.get_one::<String>("prefix").unwrap();
+
+    // NOTE(ww): The default generator always exists. We deliberately don't go through
+    // `generate_secret`, since a seed would make every synthetic password the same.
+    let generator = config
+        .generator("default")
+        .ok_or_else(|| anyhow!("missing default generator"))?;
+
+    let records = (0..count)
+        .map(|i| {
+            let label = format!("{prefix}{i}");
+            if session.has_record(&label) {
+                return Err(anyhow!("refusing to overwrite a record: {label}"));
+            }
+
+            Ok(Record::new(
+                &label,
+                RecordBody::Login(LoginFields {
+                    username: generator.secret()?,
+                    password: generator.secret()?,
+                    extra: Default::default(),
+                }),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let start = Instant::now();
+    session.add_records(&records, jobs(matches))?;
+    report_bench("added", count, start.elapsed());
+
+    Ok(())
+}
+
+/// Implements the `kbs2 bench read` subcommand.
+fn bench_read(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("timing a full decrypt pass over the store");
+
+    let session: Session = config.try_into()?;
+    let labels = session.record_labels()?;
+
+    let start = Instant::now();
+    let records = session.get_records(&labels, jobs(matches))?;
+    report_bench("decrypted", records.len(), start.elapsed());
+
+    Ok(())
+}
+
+/// Implements the `kbs2 bench labels` subcommand.
+fn bench_labels(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("timing a listing of the store's labels");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let iterations = *matches.get_one::<usize>("iterations").unwrap();
+
+    let start = Instant::now();
+    let mut count = 0;
+    for _ in 0..iterations {
+        count += session.record_labels()?.len();
+    }
+    report_bench("listed", count, start.elapsed());
+
+    Ok(())
+}
+
+#[doc(hidden)]
+fn report_bench(verb: &str, count: usize, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { count as f64 / secs } else { 0.0 };
+
+    println!("{verb} {count} records in {secs:.3}s ({rate:.1} records/s)");
+}
+
+/// Implements the `kbs2 doctor` command.
+pub fn doctor(matches: &ArgMatches, config_dir: &Path, no_agent: bool) -> Result<()> {
+    log::debug!("running diagnostic checks");
+
+    let checks = doctor::run(config_dir, no_agent);
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("json").unwrap() {
+        let report = serde_json::json!({
+            "version": VersionInfo::current(),
+            "checks": checks,
+        });
+        println!("{report}");
+    } else {
+        for check in &checks {
+            println!("[{}] {}: {}", check.status, check.name, check.message);
+            if let Some(hint) = &check.hint {
+                println!("       hint: {hint}");
+            }
+        }
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|c| c.status == doctor::Status::Fail)
+        .count();
+    if failed > 0 {
+        return Err(anyhow!("{} check(s) failed", failed));
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 version` command.
+pub fn version(matches: &ArgMatches) -> Result<()> {
+    let info = VersionInfo::current();
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("json").unwrap() {
+        println!("{}", serde_json::to_string(&info)?);
+    } else {
+        println!("{info}");
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 upgrade` command.
+///
+/// `kbs2` never upgrades itself; this only checks for (and links to) a newer release.
+pub fn upgrade(matches: &ArgMatches, config_dir: &Path) -> Result<()> {
+    log::debug!("checking for updates");
+
+    let check = update::check(config_dir)?;
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("json").unwrap() {
+        println!("{}", serde_json::to_string(&check)?);
+    } else if check.update_available {
+        println!(
+            "{}",
+            i18n::format(
+                Message::NoticeUpdateAvailable,
+                &[&check.latest, &check.current, &update::RELEASES_URL]
+            )
+        );
+    } else {
+        println!("kbs2 {} is up to date", check.current);
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 unused` command.
+pub fn unused(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("listing unused records");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("reset").unwrap() {
+        return session.reset_access_log();
+    }
+
+    if !session.config.track_access {
+        util::warn("track-access is disabled; accesses are not being recorded");
+    }
+
+    // Without --since, only records that have never been accessed are listed.
+    let cutoff = matches
+        .get_one::<u64>("since")
+        .map(|since| util::current_timestamp().saturating_sub(*since));
+
+    let accesses = session.last_accesses()?;
+    let mut unused: Vec<_> = session
+        .record_labels()?
+        .into_iter()
+        .filter(|label| match (accesses.get(label), cutoff) {
+            (None, _) => true,
+            (Some(last), Some(cutoff)) => *last < cutoff,
+            (Some(_), None) => false,
+        })
+        .collect();
+    unused.sort();
+
+    let mut stdout = io::stdout().lock();
+    for label in unused {
+        writeln!(stdout, "{label}")?;
+    }
+
+    Ok(stdout.flush()?)
+}
+
+/// Implements the `kbs2 pass` command.
+pub fn pass(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("getting a login's password");
+
+    let session: Session = config.try_into()?;
+
+    if let Some(pre_hook) = &session.config.commands.pass.pre_hook {
+        log::debug!("pre-hook: {}", pre_hook);
+        session.config.call_hook(pre_hook, &[])?;
+    }
+
+    let mut record = match matches.get_one::<String>("label") {
+        Some(label) => session.get_record(label)?,
+        None => last_record(&session, Some(RecordKind::Login))?,
+    };
+    session.track_access(&record.label);
+
+    let password = match &mut record.body {
+        RecordBody::Login(l) => std::mem::take(&mut l.password),
+        _ => {
+            return Err(anyhow!(i18n::format(
+                Message::ErrorNotALogin,
+                &[&record.label]
+            )))
+        }
+    };
+    let label = &record.label;
+
+    // NOTE(ww): The clipboard is handled by a forked child long after we'd have burned
+    // the record, so there's no telling whether it was ever really read.
     #[allow(clippy::unwrap_used)]
-    let label = matches.get_one::<String>("label").unwrap();
-    let record = session.get_record(label)?;
+    if record.burn && *matches.get_one::<bool>("clipboard").unwrap() {
+        return Err(anyhow!(
+            "{label} is burn-after-reading, and can't be copied to the clipboard; print it instead"
+        ));
+    }
+
+    #[allow(clippy::unwrap_used)]
+    let (with_totp, sequential_totp) = (
+        *matches.get_one::<bool>("with-totp").unwrap(),
+        *matches.get_one::<bool>("totp").unwrap(),
+    );
+
+    let totp = if with_totp || sequential_totp {
+        Some(linked_totp(&session, label, record.totp.as_deref())?)
+    } else {
+        None
+    };
 
-    let login = match record.body {
-        RecordBody::Login(l) => l,
-        _ => return Err(anyhow!("not a login record: {}", label)),
+    // `--with-totp` grabs the code up front, while `--totp` waits until it's needed.
+    let code = match &totp {
+        Some(totp) if with_totp => Some(totp.code(util::current_timestamp())?),
+        _ => None,
     };
 
-    let password = login.password;
+    let interactive = stdin().is_terminal() && io::stdout().is_terminal();
+    let show_for = matches.get_one::<u64>("show-for").copied();
+    if show_for.is_some() && !interactive {
+        return Err(anyhow!("--show-for requires a terminal"));
+    }
 
+    // NOTE(ww): `reveal-timeout` is only a default for `--show-for`, so it never applies
+    // when `--show-for` couldn't have been passed, or when we're not on a terminal.
     #[allow(clippy::unwrap_used)]
-    if *matches.get_one::<bool>("clipboard").unwrap() {
-        // NOTE(ww): fork() is unsafe in multithreaded programs where the child calls
-        // non async-signal-safe functions. kbs2 is single threaded, so this usage is fine.
-        unsafe {
-            match fork() {
-                Ok(ForkResult::Child) => {
-                    clip(password, &session)?;
+    let show_for = show_for.or_else(|| {
+        let reveal_timeout = session
+            .config
+            .commands
+            .pass
+            .reveal_timeout
+            .filter(|&s| s > 0)?;
+        let conflicting = *matches.get_one::<bool>("clipboard").unwrap() || sequential_totp;
+        (interactive && !conflicting).then_some(reveal_timeout)
+    });
+
+    let burned = session.burn_after(&record, || {
+        #[allow(clippy::unwrap_used)]
+        if let Some(seconds) = show_for {
+            let lines = [Some(password), code]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>();
+            tty::show_transiently(&lines, Duration::from_secs(seconds))?;
+        } else if *matches.get_one::<bool>("clipboard").unwrap() {
+            check_clip(
+                &password,
+                &session,
+                Some(ClipOverrides {
+                    force: *matches.get_one::<bool>("force").unwrap(),
+                    allow_multiline: *matches.get_one::<bool>("allow-multiline").unwrap(),
+                }),
+            )?;
+
+            // NOTE(ww): The TOTP code is printed before forking, since the forked child
+            // continues through the rest of this function once the clipboard is cleared.
+            if let Some(code) = &code {
+                println!("{code}");
+            }
+
+            // NOTE(ww): fork() is unsafe in multithreaded programs where the child calls
+            // non async-signal-safe functions. kbs2 is single threaded, so this usage is fine.
+            unsafe {
+                match fork() {
+                    Ok(ForkResult::Child) => {
+                        clip(password, &session)?;
+
+                        // With `--totp`, the code replaces the password once the latter is cleared.
+                        if let Some(totp) = totp.filter(|_| sequential_totp) {
+                            clip(totp.code(util::current_timestamp())?, &session)?;
+                        }
+                    }
+                    Err(_) => return Err(anyhow!("clipboard fork failed")),
+                    _ => {}
                 }
-                Err(_) => return Err(anyhow!("clipboard fork failed")),
-                _ => {}
             }
+        } else if let Some(totp) = totp.filter(|_| sequential_totp) {
+            // Interactive users get labeled values, and a chance to use the password
+            // before the code is computed; scripts just get one value per line.
+            if stdin().is_terminal() && io::stdout().is_terminal() {
+                println!("{}", i18n::format(Message::LabeledPassword, &[&password]));
+                input::prompted(|| {
+                    inquire::Text::new(i18n::text(Message::PressEnterForTotp)).prompt()
+                })?;
+                let code = totp.code(util::current_timestamp())?;
+                println!("{}", i18n::format(Message::LabeledTotp, &[&code]));
+            } else {
+                println!("{password}");
+                println!("{}", totp.code(util::current_timestamp())?);
+            }
+        } else if let Some(code) = code {
+            println!("{password}");
+
+            if !stdin().is_terminal() {
+                print!("{code}");
+            } else {
+                println!("{code}");
+            }
+        } else if !stdin().is_terminal() {
+            print!("{password}");
+        } else {
+            println!("{password}");
+        }
+
+        Ok(io::stdout().flush()?)
+    })?;
+
+    if burned {
+        eprintln!("{}", i18n::format(Message::RecordBurned, &[label]));
+    }
+
+    if let Some(post_hook) = &session.config.commands.pass.post_hook {
+        log::debug!("post-hook: {}", post_hook);
+        session.config.call_hook(post_hook, &[])?;
+    }
+
+    Ok(())
+}
+
+/// Implements the (hidden) `kbs2 __launcher` command.
+///
+/// This lists every login as `label<TAB>username<TAB>kind`, reads a selection back (from
+/// stdin, or from the `--menu-cmd` program), and copies the selected login's password to
+/// the clipboard like `kbs2 pass -c`. Doing this in one process means that launcher
+/// integrations (rofi, wofi, dmenu, ...) only pay for one startup and one decryption pass.
+pub fn launcher(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("running the launcher");
+
+    let session: Session = config.try_into()?;
+
+    if let Some(pre_hook) = &session.config.commands.pass.pre_hook {
+        log::debug!("pre-hook: {}", pre_hook);
+        session.config.call_hook(pre_hook, &[])?;
+    }
+
+    let labels = session.record_labels()?;
+    let logins = labels
+        .iter()
+        .zip(session.get_records(&labels, jobs(matches))?)
+        .filter_map(|(label, record)| match record.body {
+            RecordBody::Login(login) => Some((label, login, record.icon)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // NOTE(ww): Tabs and newlines would break the format, so they're replaced in the
+    // (purely informational) username and icon columns. Labels are matched exactly, so
+    // they're left alone.
+    let menu = logins
+        .iter()
+        .map(|(label, login, icon)| {
+            let icon = icon
+                .as_ref()
+                .map(|icon| format!("\t{}", icon.replace(['\t', '\n'], " ")))
+                .unwrap_or_default();
+            format!(
+                "{label}\t{}\t{}{icon}\n",
+                login.username.replace(['\t', '\n'], " "),
+                RecordKind::Login
+            )
+        })
+        .collect::<String>();
+
+    let selection = match matches.get_one::<String>("menu-cmd") {
+        Some(menu_cmd) => {
+            let (command, args) = util::parse_and_split_args(menu_cmd)?;
+            let mut child = process::Command::new(&command)
+                .args(&args)
+                .stdin(process::Stdio::piped())
+                .stdout(process::Stdio::piped())
+                .spawn()
+                .map_err(|e| anyhow!("failed to run the menu command {}: {}", command, e))?;
+
+            // NOTE(ww): This unwrap is safe, since stdin is always piped above.
+            #[allow(clippy::unwrap_used)]
+            child.stdin.take().unwrap().write_all(menu.as_bytes())?;
+
+            let output = child.wait_with_output()?;
+
+            // NOTE(ww): Menus conventionally exit with an error when dismissed.
+            if !output.status.success() {
+                log::debug!("menu command exited with {}", output.status);
+                return Ok(());
+            }
+
+            String::from_utf8(output.stdout)?
+        }
+        None => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(menu.as_bytes())?;
+            stdout.flush()?;
+
+            let mut selection = String::new();
+            stdin().read_line(&mut selection)?;
+            selection
+        }
+    };
+
+    // NOTE(ww): Menus echo back the whole line that was picked, so the label is its first
+    // column. An empty selection means that nothing was picked.
+    let selection = selection.lines().next().unwrap_or_default();
+    let label = selection.split('\t').next().unwrap_or_default();
+    if label.is_empty() {
+        return Ok(());
+    }
+
+    let (_, login, _) = logins
+        .into_iter()
+        .find(|(l, _, _)| *l == label)
+        .ok_or_else(|| anyhow!(i18n::format(Message::ErrorNotALogin, &[&label])))?;
+
+    check_clip(&login.password, &session, None)?;
+
+    // NOTE(ww): See `pass` for why forking here is fine.
+    unsafe {
+        match fork() {
+            Ok(ForkResult::Child) => clip(login.password, &session)?,
+            Err(_) => return Err(anyhow!("clipboard fork failed")),
+            _ => {}
         }
-    } else if !stdin().is_terminal() {
-        print!("{password}");
-    } else {
-        println!("{password}");
     }
 
     if let Some(post_hook) = &session.config.commands.pass.post_hook {
@@ -355,6 +2432,70 @@ pub fn pass(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     Ok(())
 }
 
+/// Retrieves the TOTP record linked to the record with the given label, if any.
+#[doc(hidden)]
+fn linked_totp(session: &Session, label: &str, totp: Option<&str>) -> Result<TotpFields> {
+    let totp = totp.ok_or_else(|| anyhow!("no TOTP record linked to {}", label))?;
+
+    match session.get_record(totp)?.body {
+        RecordBody::Totp(t) => Ok(t),
+        _ => Err(anyhow!("not a TOTP record: {}", totp)),
+    }
+}
+
+/// The clipboard checks overridden on the command line, for the commands that take
+/// `--force` and `--allow-multiline`.
+#[doc(hidden)]
+struct ClipOverrides {
+    force: bool,
+    allow_multiline: bool,
+}
+
+/// Refuses to copy oversized or multi-line values to the clipboard, unless overridden.
+/// Commands without `overrides` can't override the checks, so their refusals don't
+/// suggest it.
+///
+/// This runs before forking for `clip`, so that refusals fail the command itself.
+#[doc(hidden)]
+fn check_clip(value: &str, session: &Session, overrides: Option<ClipOverrides>) -> Result<()> {
+    let refuse = |message: String, flag: &str| match overrides {
+        Some(_) => anyhow!(
+            "{message}; {}",
+            i18n::format(Message::HintClipboardOverride, &[&flag])
+        ),
+        None => anyhow!(message),
+    };
+
+    let max_bytes = session.config.commands.pass.clipboard_max_bytes;
+    let force = overrides.as_ref().is_some_and(|o| o.force);
+    if !force && max_bytes > 0 && value.len() as u64 > max_bytes {
+        return Err(refuse(
+            i18n::format(
+                Message::ErrorClipboardTooLarge,
+                &[&value.len().to_string(), &max_bytes.to_string()],
+            ),
+            "--force",
+        ));
+    }
+
+    let allow_multiline = overrides.as_ref().is_some_and(|o| o.allow_multiline);
+    if !allow_multiline && value.lines().nth(1).is_some() {
+        return Err(refuse(
+            i18n::text(Message::ErrorClipboardMultiline).into(),
+            "--allow-multiline",
+        ));
+    }
+
+    // NOTE(ww): Clipboard managers keep their own history, which `clear-after` can't touch.
+    if session.config.commands.pass.clear_after {
+        if let Some(manager) = util::running_clipboard_manager() {
+            util::warn(&i18n::format(Message::WarnClipboardManager, &[&manager]));
+        }
+    }
+
+    Ok(())
+}
+
 #[doc(hidden)]
 fn clip(password: String, session: &Session) -> Result<()> {
     let clipboard_duration = session.config.commands.pass.clipboard_duration;
@@ -383,22 +2524,111 @@ pub fn env(matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     let session: Session = config.try_into()?;
 
+    // With --all, non-environment records are skipped; otherwise, they're an error.
     #[allow(clippy::unwrap_used)]
-    let label = matches.get_one::<String>("label").unwrap();
-    let record = session.get_record(label)?;
+    let all = *matches.get_one::<bool>("all").unwrap();
+    let labels = if all {
+        let mut labels = session.record_labels()?;
+        labels.sort();
+        labels
+    } else {
+        #[allow(clippy::unwrap_used)]
+        matches
+            .get_many::<String>("label")
+            .unwrap()
+            .cloned()
+            .collect()
+    };
+
+    let prefix = matches
+        .get_one::<String>("prefix")
+        .map_or("", String::as_str);
 
-    let environment = match record.body {
-        RecordBody::Environment(e) => e,
-        _ => return Err(anyhow!("not an environment record: {}", label)),
+    // NOTE(ww): With --all, a single unreadable record shouldn't keep the rest of the
+    // environment from being loaded.
+    let on_error = if all {
+        OnRecordError::Warn
+    } else {
+        OnRecordError::Fail
     };
 
+    let mut environments = vec![];
+    for record in session.iter_records(&labels, on_error) {
+        let record = record?;
+        // NOTE(ww): Loading every environment record (e.g. from a shell's startup files)
+        // doesn't say anything about which of them are still in use.
+        if !all {
+            session.track_access(&record.label);
+        }
+
+        match record.body {
+            RecordBody::Environment(e) => environments.push((record.label, e)),
+            _ if all => continue,
+            _ => return Err(anyhow!("not an environment record: {}", record.label)),
+        }
+    }
+
     #[allow(clippy::unwrap_used)]
-    if *matches.get_one::<bool>("value-only").unwrap() {
-        println!("{}", environment.value);
-    } else if *matches.get_one::<bool>("no-export").unwrap() {
-        println!("{}={}", environment.variable, environment.value);
-    } else {
-        println!("export {}={}", environment.variable, environment.value);
+    if *matches.get_one::<bool>("json").unwrap() {
+        let with_meta = *matches.get_one::<bool>("with-meta").unwrap();
+
+        let mut variables = serde_json::Map::new();
+        let mut sources: HashMap<String, String> = HashMap::new();
+        for (label, environment) in environments {
+            let variable = format!("{prefix}{}", environment.variable);
+
+            if let Some(previous) = sources.insert(variable.clone(), label.clone()) {
+                util::warn(&format!(
+                    "{variable} is set by both {previous} and {label}; using {label}"
+                ));
+            }
+
+            let value = if with_meta {
+                serde_json::json!({ "value": environment.value, "label": label })
+            } else {
+                environment.value.into()
+            };
+            variables.insert(variable, value);
+        }
+
+        println!("{}", serde_json::Value::Object(variables));
+        return Ok(());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    let (value_only, no_export) = (
+        *matches.get_one::<bool>("value-only").unwrap(),
+        *matches.get_one::<bool>("no-export").unwrap(),
+    );
+
+    // NOTE(ww): `--value-only` prints bare values, which aren't shell syntax (and are
+    // typically consumed by `$(...)`), so only assignments are checked and quoted.
+    // Unlike values, variable names can't be quoted into safety, so we refuse to
+    // print any assignments at all if one of them is invalid.
+    if !value_only {
+        for (label, environment) in &environments {
+            let variable = format!("{prefix}{}", environment.variable);
+            if !util::is_shell_variable(&variable) {
+                return Err(anyhow!(
+                    "not a valid shell variable name: {:?} (in {})",
+                    variable,
+                    label
+                ));
+            }
+        }
+    }
+
+    for (_, environment) in environments {
+        let variable = format!("{prefix}{}", environment.variable);
+        let value = util::shell_quote(&environment.value);
+
+        if value_only {
+            println!("{}", environment.value);
+        } else if no_export {
+            println!("{variable}={value}");
+        } else {
+            println!("export {variable}={value}");
+        }
     }
 
     Ok(())
@@ -410,6 +2640,251 @@ pub fn edit(matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     let session: Session = config.try_into()?;
 
+    // With --stdin or --set, the edited record comes from stdin or the command line,
+    // and there's no editor at all.
+    #[allow(clippy::unwrap_used)]
+    let editor = match *matches.get_one::<bool>("stdin").unwrap() || matches.contains_id("set") {
+        true => None,
+        false => Some(editor_command(&session)?),
+    };
+
+    #[allow(clippy::unwrap_used)]
+    let label = matches.get_one::<String>("label").unwrap();
+    let mut record = session.get_record(label)?;
+    ensure_unlocked(matches, &record)?;
+    let old_secret = Zeroizing::new(secret_of(&record).to_string());
+
+    if let Some(settings) = matches.get_many::<String>("set") {
+        for setting in settings {
+            set_record_setting(&mut record, setting)?;
+        }
+
+        return save_edited_record(matches, &session, label, &old_secret, record);
+    }
+
+    // Kind changes are only allowed with --to-kind, in which case the user starts from
+    // the record's fields mapped onto the new kind.
+    let old_kind = record.body.kind();
+    let old_fields = record.body.fields();
+    let to_kind = matches.get_one::<RecordKind>("to-kind").copied();
+    if let Some(kind) = to_kind {
+        if kind == old_kind {
+            return Err(anyhow!("{} is already a {} record", label, kind));
+        }
+
+        record.body = record.body.convert(kind);
+    }
+    let kind = record.body.kind();
+
+    let validate = |record: &Record| {
+        if record.body.kind() != kind {
+            return Err(anyhow!(
+                "can't change the record's kind from {} to {} (use --to-kind to convert it)",
+                kind,
+                record.body
+            ));
+        }
+
+        if let Some(icon) = &record.icon {
+            record::check_icon(icon)?;
+        }
+
+        if to_kind.is_some() {
+            if let Some(field) = old_fields
+                .iter()
+                .find(|f| record.body.extra().contains_key(**f))
+            {
+                return Err(anyhow!(
+                    "field left over from the {} record: {} (move its value or remove it)",
+                    old_kind,
+                    field
+                ));
+            }
+        }
+
+        Ok(())
+    };
+
+    let Some((editor, editor_args)) = editor else {
+        let mut contents = Zeroizing::new(vec![]);
+        stdin().read_to_end(&mut contents)?;
+
+        let edited = parse_edited_record(&contents)?;
+        validate(&edited)?;
+
+        return save_edited_record(matches, &session, label, &old_secret, edited);
+    };
+
+    let mut file = tempfile::NamedTempFile::new()?;
+    file.write_all(&serde_json::to_vec_pretty(&record)?)?;
+
+    // If editing fails, the user's changes stay in the temporary file. Interactively, they
+    // can go back to the editor or fix the file up themselves and retry; otherwise, we
+    // keep the file around so that nothing is lost.
+    let mut reopen = true;
+
+    // NOTE(ww): Until the editor has actually run (or the user has had a chance to fix the
+    // file up by hand), the file holds nothing but the original record's plaintext, which
+    // isn't worth leaving behind.
+    let mut touched = false;
+    let record = loop {
+        let result = if reopen {
+            run_editor(&editor, &editor_args, file.path()).and_then(|status| {
+                touched = true;
+                if !status.success() {
+                    return Err(anyhow!("editor exited unsuccessfully ({})", status));
+                }
+                Ok(())
+            })
+        } else {
+            Ok(())
+        }
+        .and_then(|()| read_edited_record(file.path()))
+        .and_then(|record| validate(&record).map(|()| record));
+
+        let err = match result {
+            Ok(record) => break record,
+            Err(err) => err,
+        };
+
+        if !stdin().is_terminal() {
+            if !touched {
+                return Err(err);
+            }
+
+            let (_, path) = file.keep()?;
+            return Err(err.context(format!(
+                "your changes were left in {}; shred it when you're done",
+                path.display()
+            )));
+        }
+
+        eprintln!("Error: {err:#}");
+        eprintln!("Your changes are in {}", file.path().display());
+
+        let options = vec![
+            i18n::text(Message::EditReopen),
+            i18n::text(Message::EditRetry),
+            i18n::text(Message::EditAbort),
+        ];
+        let choice = input::prompted(|| {
+            Select::new(i18n::text(Message::SelectEditRecovery), options).raw_prompt()
+        });
+        match choice {
+            Ok(choice) if choice.index < 2 => {
+                reopen = choice.index == 0;
+                touched |= !reopen;
+            }
+            _ if !touched => return Err(input::Aborted.into()),
+            _ => {
+                let (_, path) = file.keep()?;
+                util::warn(&format!(
+                    "{label} is unchanged; your changes were left in {}, so shred it when you're done",
+                    path.display()
+                ));
+                return Err(input::Aborted.into());
+            }
+        }
+    };
+
+    save_edited_record(matches, &session, label, &old_secret, record)
+}
+
+/// Applies a `KEY=VALUE` setting from `kbs2 edit --set` to the given record. An empty
+/// value clears the setting.
+#[doc(hidden)]
+fn set_record_setting(record: &mut Record, setting: &str) -> Result<()> {
+    let Some((key, value)) = setting.split_once('=') else {
+        return Err(anyhow!("expected KEY=VALUE, got: {setting}"));
+    };
+
+    match key.trim() {
+        "icon" => {
+            let icon = value.trim();
+            if !icon.is_empty() {
+                record::check_icon(icon)?;
+            }
+            record.icon = Some(icon.to_string()).filter(|icon| !icon.is_empty());
+        }
+        key => return Err(anyhow!("unknown setting: {key} (expected one of: icon)")),
+    }
+
+    Ok(())
+}
+
+/// Returns the value of the record's sensitive field, e.g. a login's password.
+#[doc(hidden)]
+fn secret_of(record: &Record) -> &str {
+    record
+        .body
+        .field(record.body.sensitive_field())
+        .unwrap_or_default()
+}
+
+/// Saves a record edited with `kbs2 edit` under `label`, or under its new label if it was
+/// renamed and renaming is allowed. `old_secret` is the record's secret before editing.
+#[doc(hidden)]
+fn save_edited_record(
+    matches: &ArgMatches,
+    session: &Session,
+    label: &str,
+    old_secret: &str,
+    mut record: Record,
+) -> Result<()> {
+    // Users can only change the label with `kbs2 edit` when renaming is explicitly allowed.
+    #[allow(clippy::unwrap_used)]
+    let allow_rename = *matches.get_one::<bool>("allow-rename").unwrap()
+        || session.config.commands.edit.allow_rename;
+    let renamed = allow_rename && record.label != label;
+
+    if renamed {
+        if record.label.is_empty() || record.label.contains('/') {
+            return Err(anyhow!("invalid record label: {:?}", record.label));
+        }
+
+        if session.has_record(&record.label) {
+            return Err(anyhow!(
+                "refusing to rename to an existing record: {}",
+                record.label
+            ));
+        }
+
+        log::debug!("renaming {} to {} during edit", label, record.label);
+    } else {
+        record.label = label.into();
+    }
+
+    // Users can't modify the timestamp, at least not with `kbs2 edit`.
+    record.timestamp = util::current_timestamp();
+
+    // NOTE(ww): A secret that's been changed by hand wasn't produced by a generator.
+    if secret_of(&record) != old_secret {
+        record.generated_by = None;
+    }
+
+    // NOTE(ww): Renaming writes the new record and removes the old one, which should
+    // be a single change to the store.
+    session.batch(|| {
+        session.add_record(&record)?;
+
+        if renamed {
+            session.delete_record(label)?;
+        }
+
+        Ok(())
+    })?;
+
+    if let Some(post_hook) = &session.config.commands.edit.post_hook {
+        log::debug!("post-hook: {}", post_hook);
+        session.config.call_hook(post_hook, &[])?;
+    }
+
+    Ok(())
+}
+
+/// Returns the editor (and its arguments) that `kbs2 edit` runs.
+#[doc(hidden)]
+fn editor_command(session: &Session) -> Result<(String, Vec<String>)> {
     let editor = match session
         .config
         .commands
@@ -423,64 +2898,122 @@ pub fn edit(matches: &ArgMatches, config: &config::Config) -> Result<()> {
         None => return Err(anyhow!("no editor configured to edit with")),
     };
 
-    let (editor, editor_args) = util::parse_and_split_args(&editor)?;
+    let (editor, mut editor_args) = util::parse_and_split_args(&editor)?;
+
+    // NOTE(ww): GUI editors usually hand the file off to a running instance and exit
+    // immediately, in which case we'd read back the record before it's been edited.
+    // For the ones we know about, make sure they're told to wait.
+    if let Some(flag) = util::editor_wait_flag(&editor, &editor_args) {
+        log::debug!("adding {} to GUI editor's arguments", flag);
+        editor_args.insert(0, flag.into());
+    }
 
     log::debug!("editor: {}, args: {:?}", editor, editor_args);
 
+    Ok((editor, editor_args))
+}
+
+/// Runs the editor on the file at `path`, waiting for it to exit.
+///
+/// Only failing to start the editor is an error; the caller checks the returned status.
+#[doc(hidden)]
+fn run_editor(editor: &str, editor_args: &[String], path: &Path) -> Result<process::ExitStatus> {
+    process::Command::new(editor)
+        .args(editor_args)
+        .arg(path)
+        .status()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => anyhow!("editor not found: {}", editor),
+            _ => anyhow!("failed to run the editor ({}): {}", editor, e),
+        })
+}
+
+/// Reads an edited record back from the file at `path`.
+#[doc(hidden)]
+fn read_edited_record(path: &Path) -> Result<Record> {
+    // NOTE(ww): We re-read by path rather than through an open handle, since some
+    // editors save by replacing the file rather than writing to it in place.
+    let record_contents = Zeroizing::new(std::fs::read(path)?);
+
+    parse_edited_record(&record_contents)
+}
+
+/// Parses an edited record, as JSON.
+#[doc(hidden)]
+fn parse_edited_record(contents: &[u8]) -> Result<Record> {
+    serde_json::from_slice(contents)
+        .map_err(|e| anyhow!("edited record is not a valid record: {}", e))
+}
+
+/// Implements the `kbs2 generate` command.
+pub fn generate(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     #[allow(clippy::unwrap_used)]
-    let label = matches.get_one::<String>("label").unwrap();
-    let record = session.get_record(label)?;
+    let generator = config.resolve_generator(matches.get_one::<String>("generator").unwrap())?;
 
-    let mut file = tempfile::NamedTempFile::new()?;
-    file.write_all(&serde_json::to_vec_pretty(&record)?)?;
+    config.check_generator_entropy(&generator)?;
 
-    if !process::Command::new(&editor)
-        .args(&editor_args)
-        .arg(file.path())
-        .status()
-        .map_or(false, |o| o.success())
-    {
-        return Err(anyhow!("failed to run the editor"));
+    let Some(label) = matches.get_one::<String>("update") else {
+        println!("{}", config.generate_secret(&generator)?);
+        return Ok(());
+    };
+
+    let session: Session = config.try_into()?;
+    let mut record = session.get_record(label)?;
+    ensure_unlocked(matches, &record)?;
+
+    let field = matches
+        .get_one::<String>("field")
+        .map(String::as_str)
+        .unwrap_or_else(|| record.body.sensitive_field());
+
+    if config.dry_run {
+        if record.body.field_mut(field).is_none() {
+            return Err(anyhow!("{} records have no {} field", record.body, field));
+        }
+
+        println!("would update the {field} field of '{label}'");
+        return Ok(());
+    }
+
+    let secret = config.generate_secret(&generator)?;
+    if field == record.body.sensitive_field() {
+        record.generated_by = Some(generator.name.clone());
+    }
+
+    // NOTE(ww): Refuse before the record is updated, so that nothing is lost.
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("clipboard").unwrap() {
+        check_clip(&secret, &session, None)?;
     }
 
-    // Rewind, pull the changed contents, deserialize back into a record.
-    file.rewind()?;
-    let mut record_contents = vec![];
-    file.read_to_end(&mut record_contents)?;
+    let kind = record.body.kind();
+    let value = record
+        .body
+        .field_mut(field)
+        .ok_or_else(|| anyhow!("{} records have no {} field", kind, field))?;
+    value.clone_from(&secret);
 
-    let mut record = serde_json::from_slice::<record::Record>(&record_contents)?;
-
-    // Users can't modify these fields, at least not with `kbs2 edit`.
-    record.label = label.into();
     record.timestamp = util::current_timestamp();
-
     session.add_record(&record)?;
 
-    if let Some(post_hook) = &session.config.commands.edit.post_hook {
-        log::debug!("post-hook: {}", post_hook);
-        session.config.call_hook(post_hook, &[])?;
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("print").unwrap() {
+        println!("{secret}");
     }
 
-    Ok(())
-}
-
-/// Implements the `kbs2 generate` command.
-pub fn generate(matches: &ArgMatches, config: &config::Config) -> Result<()> {
-    let generator = {
-        #[allow(clippy::unwrap_used)]
-        let generator_name = matches.get_one::<String>("generator").unwrap();
-        match config.generator(generator_name) {
-            Some(generator) => generator,
-            None => {
-                return Err(anyhow!(
-                    "couldn't find a generator named {}",
-                    generator_name
-                ))
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("clipboard").unwrap() {
+        // NOTE(ww): See the note in `kbs2 pass` about fork() safety.
+        unsafe {
+            match fork() {
+                Ok(ForkResult::Child) => {
+                    clip(secret, &session)?;
+                }
+                Err(_) => return Err(anyhow!("clipboard fork failed")),
+                _ => {}
             }
         }
-    };
-
-    println!("{}", generator.secret()?);
+    }
 
     Ok(())
 }
@@ -495,7 +3028,7 @@ pub fn rewrap(matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     #[allow(clippy::unwrap_used)]
     if !*matches.get_one::<bool>("no-backup").unwrap() {
-        let keyfile_backup: PathBuf = format!("{}.old", &config.keyfile).into();
+        let keyfile_backup = config::backup_path(&config.keyfile_path(), None);
 
         #[allow(clippy::unwrap_used)]
         if keyfile_backup.exists() && !*matches.get_one::<bool>("force").unwrap() {
@@ -504,14 +3037,44 @@ pub fn rewrap(matches: &ArgMatches, config: &config::Config) -> Result<()> {
             ));
         }
 
-        std::fs::copy(&config.keyfile, &keyfile_backup)?;
+        #[allow(clippy::unwrap_used)]
+        util::write_secret_file(
+            &keyfile_backup,
+            std::fs::read(&config.keyfile)?,
+            *matches.get_one::<bool>("force").unwrap(),
+        )?;
         println!("Backup of the OLD wrapped keyfile saved to: {keyfile_backup:?}");
     }
 
-    let old = util::get_password(Some("OLD master password: "), &config.pinentry)?;
-    let new = util::get_password(Some("NEW master password: "), &config.pinentry)?;
+    // NOTE(ww): The OLD password is checked before the NEW one is prompted for, so that
+    // a typo in it can be retried without re-entering the NEW one.
+    let old = backend::with_master_password(
+        config,
+        Some(i18n::text(Message::OldMasterPasswordPrompt)),
+        |password| {
+            backend::RageLib::unwrap_keyfile(&config.keyfile, password.clone())?;
+            Ok(password)
+        },
+    )?;
+    let new = util::get_password(
+        Some(i18n::text(Message::NewMasterPasswordPrompt)),
+        &util::password_description(&config.store, &config.config_dir),
+        &config.pinentry,
+    )?;
+
+    backend::RageLib::rewrap_keyfile(&config.keyfile, old, new)?;
 
-    backend::RageLib::rewrap_keyfile(&config.keyfile, old, new)
+    // Have the agent (if there is one) forget the key that was unwrapped from the old
+    // keyfile, so that the next client unwraps the rewrapped one.
+    if let Ok(client) = agent::Client::new() {
+        if let Err(e) = client.flush_key(&config.public_key) {
+            util::warn(&format!(
+                "failed to flush the rewrapped key from the agent: {e}"
+            ));
+        }
+    }
+
+    Ok(())
 }
 
 /// Implements the `kbs2 rekey` command.
@@ -524,94 +3087,130 @@ pub fn rekey(matches: &ArgMatches, config: &config::Config) -> Result<()> {
         return Err(anyhow!("rekeying is only supported on wrapped keys"));
     }
 
+    // When relocating the key, the old keyfile is left untouched and serves as its own backup.
+    let new_keyfile = match matches.get_one::<PathBuf>("new-keyfile") {
+        Some(path) => {
+            let path = util::expand_path(path)?;
+            if path.exists() {
+                return Err(anyhow!(
+                    "refusing to overwrite an existing file with the new key: {}",
+                    path.display()
+                ));
+            }
+
+            Some(
+                path.to_str()
+                    .ok_or_else(|| anyhow!("unrepresentable keyfile path: {:?}", path))?
+                    .to_string(),
+            )
+        }
+        None => None,
+    };
+
     let session: Session = config.try_into()?;
 
+    // NOTE(ww): Rekeying re-creates the manifest under the new key, which would accept
+    // any changes that were made outside of kbs2. So we make sure there aren't any first.
+    if config.manifest {
+        if let Some(discrepancies) = session.manifest_discrepancies()? {
+            if !discrepancies.is_empty() {
+                return Err(anyhow!(
+                    "refusing to rekey a store that doesn't match its manifest; run `kbs2 fsck` for details"
+                ));
+            }
+        }
+    }
+
+    let labels = session.record_labels()?;
+
     println!(
         "This subcommand REKEYS your entire store ({}) and REWRITES your config",
         session.config.store
     );
 
-    if !Confirm::new("Are you SURE you want to continue?")
-        .with_default(false)
-        .with_help_message("Be certain! If you are not certain, press [enter] to do nothing.")
-        .prompt()?
-    {
+    // NOTE(ww): Rekeying rewrites the config and keyfile even in an empty store, so it
+    // always asks, whatever `confirm-threshold` says.
+    if !confirm(
+        matches,
+        Confirm::new(i18n::text(Message::ConfirmRekey))
+            .with_default(false)
+            .with_help(i18n::text(Message::HelpConfirmRekey)),
+        || anyhow!(i18n::text(Message::ErrorNeedsYes)),
+    )? {
         return Ok(());
     }
 
     #[allow(clippy::unwrap_used)]
     if !*matches.get_one::<bool>("no-backup").unwrap() {
-        // First, back up the keyfile.
-        let keyfile_backup: PathBuf = format!("{}.old", &config.keyfile).into();
-        if keyfile_backup.exists() {
-            return Err(anyhow!(
-                "refusing to overwrite a previous key backup during rekeying; resolve manually"
-            ));
-        }
-
-        std::fs::copy(&config.keyfile, &keyfile_backup)?;
-        println!("Backup of the OLD wrapped keyfile saved to: {keyfile_backup:?}");
-
-        // Next, the config itself.
-        let config_backup: PathBuf =
-            Path::new(&config.config_dir).join(format!("{}.old", config::CONFIG_BASENAME));
-        if config_backup.exists() {
-            return Err(anyhow!(
-                "refusing to overwrite a previous config backup during rekeying; resolve manually"
-            ));
+        let backup_dir = matches
+            .get_one::<PathBuf>("backup-dir")
+            .map(util::expand_path)
+            .transpose()?;
+        if let Some(backup_dir) = &backup_dir {
+            std::fs::create_dir_all(backup_dir)?;
         }
+        let backup_dir = backup_dir.as_deref();
 
-        std::fs::copy(
-            Path::new(&config.config_dir).join(config::CONFIG_BASENAME),
-            &config_backup,
-        )?;
-        println!("Backup of the OLD config saved to: {config_backup:?}");
+        // First, back up the keyfile (unless we're moving away from it).
+        if new_keyfile.is_none() {
+            let keyfile_backup = config::backup_path(&config.keyfile_path(), backup_dir);
+            if keyfile_backup.exists() {
+                return Err(anyhow!(
+                    "refusing to overwrite a previous key backup during rekeying; resolve manually"
+                ));
+            }
 
-        // Finally, every record in the store.
-        let store_backup: PathBuf = format!("{}.old", &config.store).into();
-        if store_backup.exists() {
-            return Err(anyhow!(
-                "refusing to overwrite a previous store backup during rekeying; resolve manually"
-            ));
+            util::write_secret_file(&keyfile_backup, std::fs::read(&config.keyfile)?, false)?;
+            println!("Backup of the OLD wrapped keyfile saved to: {keyfile_backup:?}");
         }
 
-        std::fs::create_dir_all(&store_backup)?;
-        for label in session.record_labels()? {
-            std::fs::copy(
-                Path::new(&config.store).join(&label),
-                store_backup.join(&label),
-            )?;
-        }
-        println!("Backup of the OLD store saved to: {:?}", &store_backup);
+        // Next, the config itself, and finally every record in the store.
+        back_up_config(config, backup_dir, "rekeying")?;
+        back_up_store(config, &labels, backup_dir, "rekeying")?;
     }
 
     // Decrypt and collect all records.
-    let records: Vec<Secret<record::Record>> = {
-        let records: Result<Vec<record::Record>> = session
-            .record_labels()?
-            .iter()
-            .map(|l| session.get_record(l))
-            .collect();
-
-        records?.into_iter().map(Secret::new).collect()
-    };
+    // NOTE(ww): These are `Zeroizing` rather than `Secret`, since records with their own
+    // recipients need to be updated with the new public key before they're re-encrypted.
+    let mut records: Zeroizing<Vec<record::Record>> = Zeroizing::new(
+        session
+            .iter_records(&labels, OnRecordError::Fail)
+            .collect::<Result<_>>()?,
+    );
+    let old_public_key = config.public_key.clone();
 
     // Get a new master password.
-    let new_password = util::get_password(Some("NEW master password: "), &config.pinentry)?;
+    let new_password = util::get_password(
+        Some(i18n::text(Message::NewMasterPasswordPrompt)),
+        &util::password_description(&config.store, &config.config_dir),
+        &config.pinentry,
+    )?;
 
-    // Use it to generate a new wrapped keypair, overwriting the previous keypair.
-    let public_key =
-        backend::RageLib::create_wrapped_keypair(&config.keyfile, new_password.clone())?;
+    // Use it to generate a new wrapped keypair, overwriting the previous keypair
+    // unless we've been asked to put it somewhere else.
+    let keyfile = match new_keyfile {
+        Some(new_keyfile) => {
+            if let Some(parent) = Path::new(&new_keyfile).parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            println!("The OLD wrapped keyfile remains at: {:?}", config.keyfile);
+            new_keyfile
+        }
+        None => config.keyfile.clone(),
+    };
+    // NOTE(ww): As in `rewrap`, a symlinked keyfile is replaced through its symlink.
+    let public_key = backend::RageLib::create_wrapped_keypair(
+        std::fs::canonicalize(&keyfile).unwrap_or_else(|_| keyfile.clone().into()),
+        new_password.clone(),
+    )?;
 
-    // Dupe the current config, update only the public key field, and write it back.
+    // Dupe the current config, update only the key fields, and write it back.
     let config = config::Config {
         public_key,
+        keyfile,
         ..config.clone()
     };
-    std::fs::write(
-        Path::new(&config.config_dir).join(config::CONFIG_BASENAME),
-        toml::to_string(&config)?,
-    )?;
+    config::save(&config)?;
 
     // Flush the stale key from the active agent, and add the new key to the agent.
     // NOTE(ww): This scope is essential: we need to drop this client before we
@@ -622,22 +3221,387 @@ pub fn rekey(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     {
         let client = agent::Client::new()?;
         client.flush_keys()?;
-        client.add_key(&config.public_key, &config.keyfile, new_password)?;
+        client.add_key(
+            &config.public_key,
+            &config.keyfile,
+            new_password,
+            &agent::UnwrapPolicy::from(&config),
+        )?;
     }
 
     // Create a new session from the new config and use it to re-encrypt each record.
+    // NOTE(ww): The manifest is still encrypted to the old key at this point, so it can't
+    // be verified; it's re-created under the new key instead.
     println!("Re-encrypting all records, be patient...");
+    let config = config::Config {
+        verify_manifest: false,
+        ..config
+    };
     let session: Session = (&config).try_into()?;
-    for record in records {
-        log::debug!("re-encrypting {}", record.expose_secret().label);
-        session.add_record(record.expose_secret())?;
+    if config.manifest {
+        session.rebuild_manifest()?;
+    }
+
+    // NOTE(ww): The new key is already in place by now, so we keep going past failures:
+    // stopping early would leave every remaining record encrypted to the old key.
+    let mut report = Report::new("rekey");
+    session.batch(|| {
+        for record in records.iter_mut() {
+            log::debug!("re-encrypting {}", record.label);
+
+            if let Some(recipients) = &mut record.recipients {
+                for recipient in recipients.iter_mut() {
+                    if *recipient == old_public_key {
+                        recipient.clone_from(&session.config.public_key);
+                    }
+                }
+            }
+
+            report.result(&record.label, &session.add_record(record));
+        }
+
+        Ok(())
+    })?;
+
+    if report.count(Outcome::Failed) > 0 {
+        util::warn("records that couldn't be re-encrypted are still encrypted to the OLD key");
+    } else {
+        println!("All done.");
+    }
+
+    finish_report(matches, &report, "re-encrypted")
+}
+
+/// Copies the config to `config.toml.old` (in `backup_dir`, if given), refusing to overwrite
+/// an earlier backup.
+#[doc(hidden)]
+fn back_up_config(
+    config: &config::Config,
+    backup_dir: Option<&Path>,
+    operation: &str,
+) -> Result<()> {
+    let config_backup = config::backup_path(&config.config_path(), backup_dir);
+    if config_backup.exists() {
+        return Err(anyhow!(
+            "refusing to overwrite a previous config backup during {operation}; resolve manually"
+        ));
+    }
+
+    std::fs::copy(config.config_path(), &config_backup)?;
+    println!("Backup of the OLD config saved to: {config_backup:?}");
+
+    Ok(())
+}
+
+/// Copies the given records to a `.old` directory alongside the store (or in `backup_dir`),
+/// refusing to overwrite an earlier backup.
+#[doc(hidden)]
+fn back_up_store(
+    config: &config::Config,
+    labels: &[String],
+    backup_dir: Option<&Path>,
+    operation: &str,
+) -> Result<()> {
+    let store_backup = config::backup_path(&config.store_path(), backup_dir);
+    if store_backup.exists() {
+        return Err(anyhow!(
+            "refusing to overwrite a previous store backup during {operation}; resolve manually"
+        ));
+    }
+
+    std::fs::create_dir_all(&store_backup)?;
+    for record_path in config.record_paths(labels) {
+        // NOTE(ww): Every record path has a filename, since it's a file in the store.
+        #[allow(clippy::unwrap_used)]
+        std::fs::copy(
+            &record_path,
+            store_backup.join(record_path.file_name().unwrap()),
+        )?;
+    }
+    println!("Backup of the OLD store saved to: {:?}", &store_backup);
+
+    Ok(())
+}
+
+/// Implements the `kbs2 migrate-store` command.
+pub fn migrate_store(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("attempting to migrate the store");
+
+    // Whatever isn't being migrated stays as it is.
+    let to = matches
+        .get_one::<config::RecordFilenames>("to")
+        .copied()
+        .unwrap_or(config.record_filenames);
+    let compress = matches
+        .get_one::<config::Compression>("compress")
+        .copied()
+        .unwrap_or(config.compress);
+
+    let session: Session = config.try_into()?;
+
+    // NOTE(ww): Like rekeying, migrating re-creates the manifest, so we make sure it
+    // isn't hiding any changes that were made outside of kbs2 first.
+    if config.manifest {
+        if let Some(discrepancies) = session.manifest_discrepancies()? {
+            if !discrepancies.is_empty() {
+                return Err(anyhow!(
+                    "refusing to migrate a store that doesn't match its manifest; run `kbs2 fsck` for details"
+                ));
+            }
+        }
+    }
+
+    let labels = session.record_labels()?;
+
+    println!(
+        "This subcommand REWRITES every record in your store ({}) and your config",
+        config.store
+    );
+
+    // NOTE(ww): Like rekeying, migrating rewrites the config even in an empty store.
+    if !confirm(
+        matches,
+        Confirm::new(i18n::text(Message::ConfirmRekey))
+            .with_default(false)
+            .with_help(i18n::text(Message::HelpConfirmRekey)),
+        || anyhow!(i18n::text(Message::ErrorNeedsYes)),
+    )? {
+        return Ok(());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    if !*matches.get_one::<bool>("no-backup").unwrap() {
+        back_up_config(config, None, "migration")?;
+        back_up_store(config, &labels, None, "migration")?;
+    }
+
+    let records: Zeroizing<Vec<record::Record>> = Zeroizing::new(
+        session
+            .iter_records(&labels, OnRecordError::Fail)
+            .collect::<Result<_>>()?,
+    );
+
+    // Write every record in the new format to a staging directory within the store, and
+    // make sure that each one reads back as itself before the store is touched.
+    // NOTE(ww): This is what catches labels that collide in the new format, e.g.
+    // case-distinct labels on a case-insensitive filesystem.
+    let staging = tempfile::Builder::new()
+        .prefix(".kbs2-migrate-")
+        .tempdir_in(&config.store)?;
+    let target = config::Config {
+        store: staging
+            .path()
+            .to_str()
+            .ok_or_else(|| anyhow!("unrepresentable staging path: {:?}", staging.path()))?
+            .into(),
+        record_filenames: to,
+        compress,
+        manifest: false,
+        ..config.clone()
+    };
+
+    println!("Migrating all records, be patient...");
+    let staged: Session = (&target).try_into()?;
+    for record in records.iter() {
+        log::debug!("migrating {}", record.label);
+        staged.add_record(record)?;
+    }
+
+    for record in records.iter() {
+        if staged.get_record(&record.label)? != *record {
+            return Err(anyhow!(
+                "{} didn't survive migration (does it collide with another record?); the store is unchanged",
+                record.label
+            ));
+        }
+    }
+
+    // Move the migrated records into the store, and then remove any of the old files
+    // that they didn't replace.
+    // NOTE(ww): The old paths have to be found first, since the migrated records would
+    // otherwise shadow them.
+    let old_paths = config.record_paths(&labels);
+    let old_size = disk_usage(&old_paths)?;
+    let new_size = disk_usage(records.iter().map(|r| target.record_path(&r.label)))?;
+    let store = config.store_path();
+    let mut migrated = HashSet::new();
+    for record in records.iter() {
+        let staged_path = target.record_path(&record.label);
+
+        // NOTE(ww): Every record path has a filename, since it's a file in the store.
+        #[allow(clippy::unwrap_used)]
+        let path = store.join(staged_path.file_name().unwrap());
+        std::fs::rename(&staged_path, &path)?;
+        migrated.insert(path);
+    }
+
+    for old_path in old_paths {
+        if !migrated.contains(&old_path) && old_path.is_file() {
+            std::fs::remove_file(&old_path)?;
+        }
+    }
+
+    // Finally, record the new format in the config, and bring the manifest up to date.
+    let config = config::Config {
+        record_filenames: to,
+        compress,
+        ..config.clone()
+    };
+    config::save(&config)?;
+
+    if config.manifest {
+        let config = config::Config {
+            verify_manifest: false,
+            ..config
+        };
+        let session: Session = (&config).try_into()?;
+        session.rebuild_manifest()?;
     }
 
+    println!("Records on disk: {old_size} bytes before, {new_size} bytes after.");
     println!("All done.");
 
     Ok(())
 }
 
+/// Returns the total size of the given files, in bytes.
+#[doc(hidden)]
+fn disk_usage<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Result<u64> {
+    paths.into_iter().try_fold(0, |total, path| {
+        Ok(total + std::fs::metadata(path.as_ref())?.len())
+    })
+}
+
+/// Implements the `kbs2 sync` command.
+pub fn sync(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("syncing the store");
+
+    if config.sync.pull.is_none() && config.sync.push.is_none() {
+        return Err(anyhow!(
+            "no sync commands configured; set sync.pull and/or sync.push"
+        ));
+    }
+
+    #[allow(clippy::unwrap_used)]
+    let (pull_only, push_only) = (
+        *matches.get_one::<bool>("pull").unwrap(),
+        *matches.get_one::<bool>("push").unwrap(),
+    );
+
+    if !push_only {
+        config.sync_pull()?;
+    }
+
+    if !pull_only {
+        config.sync_push()?;
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 hooks` command.
+pub fn hooks(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("hooks subcommand dispatch");
+
+    match matches.subcommand() {
+        Some(("list", _)) => {
+            let mut rows = vec![vec!["NAME".into(), "COMMAND".into(), "PATH".into()]];
+            for hook in config.hooks() {
+                let Some(command) = hook.command else {
+                    continue;
+                };
+
+                let path = match hook.resolve(config) {
+                    Some(path) => path.display().to_string(),
+                    None => "(not found)".into(),
+                };
+
+                rows.push(vec![hook.name.into(), command.into(), path]);
+            }
+
+            for line in util::render_table(&rows) {
+                println!("{line}");
+            }
+        }
+        Some(("run", matches)) => {
+            #[allow(clippy::unwrap_used)]
+            let name = matches.get_one::<String>("name").unwrap();
+
+            let hooks = config.hooks();
+            let hook = hooks
+                .iter()
+                .find(|h| h.name == name)
+                .ok_or_else(|| anyhow!("no such hook: {}", name))?;
+            let command = hook
+                .command
+                .ok_or_else(|| anyhow!("hook is not configured: {}", name))?;
+
+            let envs = hook
+                .dummy_envs
+                .iter()
+                .map(|(key, value)| format!("{key}={value} "))
+                .collect::<String>();
+            println!("Running: {}{} {}", envs, command, hook.dummy_args.join(" "));
+            let output = config.test_hook(command, hook.dummy_args, hook.dummy_envs)?;
+
+            let vetoed = hook.vetoable && output.status.code() == Some(config::HOOK_VETO_CODE);
+            match output.status.code() {
+                Some(code) if vetoed => println!("Exit status: {code} (vetoed)"),
+                Some(code) => println!("Exit status: {code}"),
+                None => println!("Exit status: terminated by signal"),
+            }
+            println!("Stdout:\n{}", String::from_utf8_lossy(&output.stdout));
+            println!("Stderr:\n{}", String::from_utf8_lossy(&output.stderr));
+
+            if !output.status.success() && !vetoed {
+                return Err(anyhow!("hook exited with an error code: {}", command));
+            }
+        }
+        Some((_, _)) => unreachable!(),
+        None => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 audit-log` command.
+pub fn audit_log(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("audit-log subcommand dispatch");
+
+    let Some(audit_log) = &config.audit_log else {
+        return Err(anyhow!("no audit-log is configured"));
+    };
+
+    match matches.subcommand() {
+        Some(("tail", matches)) => {
+            let since = matches.get_one::<u64>("since").copied().unwrap_or(0);
+            #[allow(clippy::unwrap_used)]
+            let lines = *matches.get_one::<usize>("lines").unwrap();
+
+            let entries: Vec<_> = audit::entries(Path::new(audit_log))?
+                .into_iter()
+                .filter(|e| e.timestamp >= since)
+                .collect();
+
+            // NOTE(ww): See the note in `list` about writing to `stdout`.
+            let mut stdout = io::stdout().lock();
+            for entry in &entries[entries.len().saturating_sub(lines)..] {
+                #[allow(clippy::unwrap_used)]
+                if *matches.get_one::<bool>("json").unwrap() {
+                    writeln!(stdout, "{}", serde_json::to_string(entry)?)?;
+                } else {
+                    writeln!(stdout, "{entry}")?;
+                }
+            }
+            stdout.flush()?;
+        }
+        Some((_, _)) => unreachable!(),
+        None => unreachable!(),
+    }
+
+    Ok(())
+}
+
 /// Implements the `kbs2 config` command.
 pub fn config(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     log::debug!("config subcommand dispatch");
@@ -646,15 +3610,138 @@ pub fn config(matches: &ArgMatches, config: &config::Config) -> Result<()> {
         Some(("dump", matches)) =>
         {
             #[allow(clippy::unwrap_used)]
-            if *matches.get_one::<bool>("pretty").unwrap() {
+            if let Some(ext) = matches.get_one::<String>("ext") {
+                println!("{}", config.ext_config(ext)?);
+            } else if *matches.get_one::<bool>("explicit-only").unwrap() {
+                let config_path = config.config_path();
+                let explicit: toml::Value = toml::from_str(&std::fs::read_to_string(config_path)?)
+                    .map_err(|e| anyhow!("config loading error: {}", e))?;
+                let dumped = explicit_config(serde_json::to_value(config)?, &explicit);
+
+                if *matches.get_one::<bool>("pretty").unwrap() {
+                    serde_json::to_writer_pretty(io::stdout(), &dumped)?;
+                } else {
+                    serde_json::to_writer(io::stdout(), &dumped)?;
+                }
+            } else if *matches.get_one::<bool>("pretty").unwrap() {
                 serde_json::to_writer_pretty(io::stdout(), &config)?;
             } else {
                 serde_json::to_writer(io::stdout(), &config)?;
             }
         }
+        Some(("escrow", matches)) => config_escrow(matches, config)?,
         Some((_, _)) => unreachable!(),
         None => unreachable!(),
     }
 
     Ok(())
 }
+
+/// Implements `kbs2 config escrow`, listing the escrow recipients or adding one and
+/// re-encrypting every record to include it.
+#[doc(hidden)]
+fn config_escrow(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    let Some(recipient) = matches.get_one::<String>("add") else {
+        for recipient in &config.escrow_recipients {
+            println!("{recipient}");
+        }
+        return Ok(());
+    };
+
+    recipient
+        .parse::<backend::RageRecipient>()
+        .map_err(|e| anyhow!("invalid escrow recipient: {recipient}: {e}"))?;
+    if *recipient == config.public_key {
+        return Err(anyhow!(
+            "refusing to add the store's own public key as an escrow recipient"
+        ));
+    }
+
+    let session: Session = config.try_into()?;
+    let labels = session.record_labels()?;
+
+    println!(
+        "This subcommand RE-ENCRYPTS every record in your store ({}) to {recipient}, \
+         who will be able to decrypt all of them",
+        config.store
+    );
+
+    // NOTE(ww): Granting someone else access to the whole store always needs confirming,
+    // even when there are no records yet: every future record is encrypted to them too.
+    if !confirm(
+        matches,
+        Confirm::new(i18n::text(Message::ConfirmRekey))
+            .with_default(false)
+            .with_help(i18n::text(Message::HelpConfirmRekey)),
+        || anyhow!(i18n::text(Message::ErrorNeedsYes)),
+    )? {
+        return Ok(());
+    }
+
+    // NOTE(ww): An existing escrow recipient isn't an error: re-running `--add` is how
+    // records that failed to re-encrypt the first time get another chance.
+    let added = !config.escrow_recipients.contains(recipient);
+
+    #[allow(clippy::unwrap_used)]
+    if !*matches.get_one::<bool>("no-backup").unwrap() {
+        if added {
+            back_up_config(config, None, "escrow")?;
+        }
+        back_up_store(config, &labels, None, "escrow")?;
+    }
+
+    let records: Zeroizing<Vec<record::Record>> = Zeroizing::new(
+        session
+            .iter_records(&labels, OnRecordError::Fail)
+            .collect::<Result<_>>()?,
+    );
+
+    let config = if added {
+        let mut escrow_recipients = config.escrow_recipients.clone();
+        escrow_recipients.push(recipient.clone());
+        let config = config::Config {
+            escrow_recipients,
+            ..config.clone()
+        };
+        config::save(&config)?;
+        println!("Added escrow recipient: {recipient}");
+        config
+    } else {
+        println!("Already an escrow recipient: {recipient}");
+        config.clone()
+    };
+
+    let session: Session = (&config).try_into()?;
+    let mut report = Report::new("escrow");
+    session.batch(|| {
+        for record in records.iter() {
+            log::debug!("re-encrypting {}", record.label);
+            report.result(&record.label, &session.add_record(record));
+        }
+
+        Ok(())
+    })?;
+
+    finish_report(matches, &report, "re-encrypted")
+}
+
+/// Returns only the parts of the given serialized config whose keys are also present in
+/// `explicit` (the config as written on disk), recursing into tables. Anything else (e.g.
+/// the list of generators) is kept or dropped as a whole.
+#[doc(hidden)]
+fn explicit_config(dumped: serde_json::Value, explicit: &toml::Value) -> serde_json::Value {
+    match (dumped, explicit) {
+        (serde_json::Value::Object(dumped), toml::Value::Table(explicit)) => {
+            serde_json::Value::Object(
+                dumped
+                    .into_iter()
+                    .filter_map(|(key, value)| {
+                        let explicit = explicit.get(&key)?;
+                        Some((key, explicit_config(value, explicit)))
+                    })
+                    .collect(),
+            )
+        }
+        (dumped, _) => dumped,
+    }
+}
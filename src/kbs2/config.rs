@@ -1,20 +1,30 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::{OnceCell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::{stdin, IsTerminal};
+use std::io::{self, stdin, IsTerminal, Read};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Command, ExitStatus, Output, Stdio};
 
-use anyhow::{anyhow, Result};
-use clap::ArgMatches;
+use anyhow::{anyhow, Context, Result};
+use clap::builder::PossibleValue;
+use clap::{ArgMatches, ValueEnum};
 use lazy_static::lazy_static;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use secrecy::SecretString;
 use serde::{de, Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
 use xdg::BaseDirectories;
 
+use crate::kbs2::audit;
 use crate::kbs2::backend::{Backend, RageLib};
 use crate::kbs2::generator::Generator;
+use crate::kbs2::i18n;
+use crate::kbs2::input::{FIELD_ARGS, MAX_FIELD_FILE_SIZE, TERSE_IFS};
+use crate::kbs2::timings;
 use crate::kbs2::util;
 
 /// The default basename for the main config file, relative to the configuration
@@ -25,6 +35,41 @@ pub static CONFIG_BASENAME: &str = "config.toml";
 /// the configuration directory.
 pub static DEFAULT_KEY_BASENAME: &str = "key";
 
+/// The exit code that a vetoable hook uses to cancel the operation it precedes,
+/// without failing the command.
+pub const HOOK_VETO_CODE: i32 = 100;
+
+/// The maximum size of a generator's wordlist, in bytes.
+pub const MAX_WORDLIST_SIZE: u64 = 1024 * 1024;
+
+/// The minimum number of words in a generator's wordlist.
+pub const MIN_WORDLIST_WORDS: usize = 1024;
+
+/// The default time to wait for an auto-started agent, in milliseconds.
+pub const DEFAULT_AGENT_SPAWN_TIMEOUT_MS: u64 = 100;
+
+/// The default number of master password attempts before unlocking gives up.
+pub const DEFAULT_UNLOCK_MAX_ATTEMPTS: u32 = 3;
+
+/// The default delay after the first incorrect master password, in milliseconds.
+pub const DEFAULT_UNLOCK_BACKOFF_MS: u64 = 500;
+
+/// The default maximum size of a record file, in bytes.
+///
+/// Ordinary records are a few hundred bytes, so this is generous.
+pub const DEFAULT_MAX_RECORD_SIZE: u64 = 1024 * 1024;
+
+/// The default size, in bytes, above which records are streamed rather than buffered.
+pub const DEFAULT_STREAM_THRESHOLD: u64 = 256 * 1024;
+
+/// The default entropy, in bits, below which generated secrets are considered weak.
+pub const DEFAULT_MIN_GENERATOR_ENTROPY: u32 = 64;
+
+/// The environment variable that seeds generators, making their secrets reproducible.
+///
+/// This is for testing only: seeded secrets are entirely predictable.
+pub const GENERATOR_SEED_ENV: &str = "KBS2_GENERATOR_SEED";
+
 lazy_static! {
     // We're completely hosed if we can't find a reasonable set of base directories,
     // so there isn't much point in trying to avoid this `expect`.
@@ -49,6 +94,28 @@ pub struct Config {
     #[serde(skip)]
     pub config_dir: String,
 
+    /// Whether or not to bypass the kbs2 authentication agent entirely, unwrapping
+    /// the keyfile directly instead.
+    ///
+    /// **NOTE**: This field is never loaded from the configuration file itself;
+    /// it's set by the global `--no-agent` flag.
+    #[serde(skip)]
+    pub no_agent: bool,
+
+    /// Whether or not to ignore the agent's lockout after too many incorrect master passwords.
+    ///
+    /// **NOTE**: This field is never loaded from the configuration file itself;
+    /// it's set by the global `--force-retry` flag.
+    #[serde(skip)]
+    pub force_retry: bool,
+
+    /// Whether or not this is a dry run, in which nothing may be written to the store.
+    ///
+    /// **NOTE**: This field is never loaded from the configuration file itself;
+    /// it's set by the global `--dry-run` flag.
+    #[serde(skip)]
+    pub dry_run: bool,
+
     /// The public component of the keypair.
     #[serde(rename = "public-key")]
     pub public_key: String,
@@ -58,12 +125,36 @@ pub struct Config {
     #[serde(deserialize_with = "deserialize_with_tilde")]
     pub keyfile: String,
 
+    /// Escrow recipients that every record is additionally encrypted to, e.g. a recovery
+    /// key that's kept offline.
+    #[serde(rename = "escrow-recipients")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub escrow_recipients: Vec<String>,
+
     /// Whether or not to auto-start the kbs2 authentication agent when
     /// creating a session.
     #[serde(rename = "agent-autostart")]
     #[serde(default = "default_as_true")]
     pub agent_autostart: bool,
 
+    /// How long to wait for an auto-started agent to become ready, in milliseconds.
+    #[serde(rename = "agent-spawn-timeout-ms")]
+    #[serde(default = "default_agent_spawn_timeout_ms")]
+    pub agent_spawn_timeout_ms: u64,
+
+    /// An optional path to the `kbs2` binary used to auto-start the agent, instead
+    /// of the currently running binary.
+    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
+    #[serde(rename = "agent-binary")]
+    #[serde(default)]
+    pub agent_binary: Option<String>,
+
+    /// Whether the agent only starts when launched by `kbs2` itself, and only serves
+    /// clients that are `kbs2` processes.
+    #[serde(rename = "agent-strict")]
+    #[serde(default)]
+    pub agent_strict: bool,
+
     /// Whether or not the private component of the keypair is wrapped with
     /// a passphrase.
     #[serde(default = "default_as_true")]
@@ -73,10 +164,54 @@ pub struct Config {
     #[serde(deserialize_with = "deserialize_with_tilde")]
     pub store: String,
 
+    /// The kind of store that records are kept in.
+    #[serde(rename = "store-backend")]
+    #[serde(default)]
+    pub store_backend: StoreBackendKind,
+
+    /// How record labels are mapped to filenames within the store.
+    #[serde(rename = "record-filenames")]
+    #[serde(default)]
+    pub record_filenames: RecordFilenames,
+
+    /// How record labels are normalized before they're stored or looked up.
+    #[serde(rename = "label-normalization")]
+    #[serde(default)]
+    pub label_normalization: LabelNormalization,
+
+    /// An optional extension (e.g. `age`) for record files in the store.
+    #[serde(rename = "record-extension")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record_extension: Option<String>,
+
+    /// How records are compressed before they're encrypted.
+    #[serde(default)]
+    pub compress: Compression,
+
     /// The pinentry binary to use for password prompts.
     #[serde(default)]
     pub pinentry: Pinentry,
 
+    /// How many times to prompt for the master password before giving up.
+    #[serde(rename = "unlock-max-attempts")]
+    #[serde(default = "default_unlock_max_attempts")]
+    pub unlock_max_attempts: u32,
+
+    /// How long to wait after the first incorrect master password, in milliseconds.
+    /// The wait doubles after each subsequent incorrect password.
+    #[serde(rename = "unlock-backoff-ms")]
+    #[serde(default = "default_unlock_backoff_ms")]
+    pub unlock_backoff_ms: u64,
+
+    /// The locale for interactive prompts and messages, unless `$KBS2_LOCALE` is set.
+    #[serde(default)]
+    pub locale: i18n::Locale,
+
+    /// Whether or not to check for a newer release of `kbs2` (at most once a day).
+    #[serde(default)]
+    #[serde(rename = "check-for-updates")]
+    pub check_for_updates: bool,
+
     /// An optional command to run before each `kbs2` subcommand.
     #[serde(deserialize_with = "deserialize_optional_with_tilde")]
     #[serde(rename = "pre-hook")]
@@ -100,19 +235,133 @@ pub struct Config {
     #[serde(rename = "reentrant-hooks")]
     pub reentrant_hooks: bool,
 
+    /// Whether or not pre-hooks are run during a `--dry-run`. Post-hooks never are.
+    #[serde(default)]
+    #[serde(rename = "dry-run-pre-hooks")]
+    pub dry_run_pre_hooks: bool,
+
+    /// The working directory that hooks are run in.
+    #[serde(default)]
+    #[serde(rename = "hook-cwd")]
+    pub hook_cwd: HookCwd,
+
+    /// Whether or not record accesses are logged, for `kbs2 unused`.
+    #[serde(default)]
+    #[serde(rename = "track-access")]
+    pub track_access: bool,
+
+    /// The maximum size of a record file in the store, in bytes.
+    #[serde(default = "default_max_record_size")]
+    #[serde(rename = "max-record-size")]
+    pub max_record_size: u64,
+
+    /// The size of a record, in bytes, above which it's streamed to and from the store
+    /// rather than buffered in memory.
+    #[serde(default = "default_stream_threshold")]
+    #[serde(rename = "stream-threshold")]
+    pub stream_threshold: u64,
+
+    /// Whether or not `kbs2` keeps an encrypted manifest of the records in the store,
+    /// so that records changed or deleted outside of `kbs2` are noticed. The manifest
+    /// isn't authenticated, so it doesn't detect deliberate tampering.
+    #[serde(default)]
+    pub manifest: bool,
+
+    /// Whether or not the store is checked against its manifest whenever it's opened,
+    /// rather than only by `kbs2 fsck`.
+    #[serde(default)]
+    #[serde(rename = "verify-manifest")]
+    pub verify_manifest: bool,
+
+    /// The estimated entropy, in bits, below which using a generator produces a warning.
+    /// `0` disables the warning.
+    #[serde(default = "default_min_generator_entropy")]
+    #[serde(rename = "min-generator-entropy")]
+    pub min_generator_entropy: u32,
+
+    /// Whether or not `KBS2_GENERATOR_SEED` is refused, rather than used to seed generators.
+    #[serde(default)]
+    #[serde(rename = "forbid-seeded-generation")]
+    pub forbid_seeded_generation: bool,
+
+    /// The number of records that a bulk operation (like `kbs2 rm` or `kbs2 rekey`) can
+    /// affect without asking for confirmation, overriding each operation's own default.
+    #[serde(rename = "confirm-threshold")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm_threshold: Option<usize>,
+
+    /// An optional file to log `kbs2`'s operations to, one JSON object per line.
+    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
+    #[serde(rename = "audit-log")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<String>,
+
+    /// The size, in bytes, at which the audit log is rotated.
+    #[serde(default = "default_audit_log_max_bytes")]
+    #[serde(rename = "audit-log-max-bytes")]
+    pub audit_log_max_bytes: u64,
+
+    /// Whether the audit log includes every record that's read, written, or deleted.
+    #[serde(default = "default_as_true")]
+    #[serde(rename = "audit-records")]
+    pub audit_records: bool,
+
+    /// Whether `kbs2 list --show-username` is allowed to display usernames.
+    #[serde(default = "default_as_true")]
+    #[serde(rename = "list-usernames")]
+    pub list_usernames: bool,
+
+    /// Whether `kbs2 dump` asks for confirmation before printing secrets to a terminal.
+    #[serde(default = "default_as_true")]
+    #[serde(rename = "confirm-dump")]
+    pub confirm_dump: bool,
+
     /// Any secret generators configured by the user.
     #[serde(default)]
     pub generators: Vec<GeneratorConfig>,
 
+    /// Settings for synchronizing the store with a remote.
+    #[serde(default)]
+    pub sync: SyncConfig,
+
+    /// Settings for the appearance of interactive prompts.
+    #[serde(default)]
+    pub ui: UiConfig,
+
     /// Per-command configuration.
     #[serde(default)]
     pub commands: CommandConfigs,
 }
 
 impl Config {
+    /// Returns the `[commands.ext.<name>]` table for the given external command, as JSON.
+    ///
+    /// An external command without any configuration gets an empty object.
+    pub fn ext_config(&self, name: &str) -> Result<String> {
+        match self.commands.ext.get(name) {
+            Some(table) => Ok(serde_json::to_string(table)?),
+            None => Ok("{}".into()),
+        }
+    }
+
+    /// Records the given event in the audit log, if `audit-log` is set.
+    pub fn audit(&self, event: audit::Event) {
+        let Some(audit_log) = &self.audit_log else {
+            return;
+        };
+
+        // NOTE(ww): Failing to audit an operation shouldn't prevent it.
+        let entry = audit::Entry::new(event);
+        if let Err(e) = audit::append(Path::new(audit_log), self.audit_log_max_bytes, &entry) {
+            util::warn(&format!("failed to write audit log: {e}"));
+        }
+    }
+
     /// Calls a command as a hook, meaning:
-    /// * The command is run with the `kbs2` store as its working directory
-    /// * The command is run with `KBS2_HOOK=1` in its environment
+    /// * The command is run with the `kbs2` store as its working directory, unless
+    ///   `hook-cwd` says otherwise
+    /// * The command is run with `KBS2_HOOK=1` in its environment, along with
+    ///   `KBS2_CONFIG_DIR`, `KBS2_STORE`, and `KBS2_PUBLIC_KEY`
     ///
     /// Hooks have the following behavior:
     /// 1. If `reentrant-hooks` is `true` *or* `KBS2_HOOK` is *not* present in the environment,
@@ -120,29 +369,149 @@ impl Config {
     /// 2. If `reentrant-hooks` is `false` (the default) *and* `KBS2_HOOK` is already present
     ///    (indicating that we're already in a hook), nothing is run.
     pub fn call_hook(&self, cmd: &str, args: &[&str]) -> Result<()> {
-        if self.reentrant_hooks || env::var("KBS2_HOOK").is_err() {
-            let success = Command::new(cmd)
-                .args(args)
-                .current_dir(Path::new(&self.store))
-                .env("KBS2_HOOK", "1")
-                .env("KBS2_CONFIG_DIR", &self.config_dir)
-                .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .status()
-                .map(|s| s.success())
-                .map_err(|_| anyhow!("failed to run hook: {}", cmd))?;
-
-            if success {
-                Ok(())
-            } else {
+        self.call_hook_with_env(cmd, args, &[])
+    }
+
+    /// Calls a command as a hook, exactly as `call_hook` does, with additional
+    /// variables in its environment.
+    pub fn call_hook_with_env(
+        &self,
+        cmd: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+    ) -> Result<()> {
+        match self.run_hook(cmd, args, envs)? {
+            Some(status) if !status.success() => {
+                Err(anyhow!("hook exited with an error code: {}", cmd))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Calls a command as a hook, exactly as `call_hook_with_env` does, except that the
+    /// hook can veto the operation it precedes by exiting with `HOOK_VETO_CODE`.
+    ///
+    /// Returns whether the operation should proceed.
+    pub fn call_vetoable_hook(
+        &self,
+        cmd: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+    ) -> Result<bool> {
+        match self.run_hook(cmd, args, envs)? {
+            Some(status) if status.code() == Some(HOOK_VETO_CODE) => Ok(false),
+            Some(status) if !status.success() => {
                 Err(anyhow!("hook exited with an error code: {}", cmd))
             }
+            _ => Ok(true),
+        }
+    }
+
+    /// Runs a hook, returning its exit status (or `None` if it was skipped).
+    #[doc(hidden)]
+    fn run_hook(
+        &self,
+        cmd: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+    ) -> Result<Option<ExitStatus>> {
+        if self.reentrant_hooks || env::var("KBS2_HOOK").is_err() {
+            let status = timings::span("hook", || {
+                self.hook_command(cmd, args)
+                    .envs(envs.iter().copied())
+                    .stdout(Stdio::null())
+                    .status()
+            })
+            .map_err(|_| anyhow!("failed to run hook: {}", cmd));
+
+            self.audit(audit::Event::Hook {
+                command: cmd.into(),
+                exit_code: status.as_ref().ok().and_then(ExitStatus::code),
+                outcome: match &status {
+                    Ok(status) if status.success() => audit::Outcome::Success,
+                    _ => audit::Outcome::Failure,
+                },
+            });
+
+            status.map(Some)
         } else {
             util::warn("nested hook requested without reentrant-hooks; skipping");
-            Ok(())
+            Ok(None)
         }
     }
 
+    /// Runs a command as a hook, exactly as `call_hook` does, but captures its output
+    /// and returns it (including its exit status) instead of checking it.
+    ///
+    /// Unlike `call_hook`, this ignores `reentrant-hooks`: it's intended for testing hooks.
+    pub fn test_hook(&self, cmd: &str, args: &[&str], envs: &[(&str, &str)]) -> Result<Output> {
+        self.hook_command(cmd, args)
+            .envs(envs.iter().copied())
+            .output()
+            .map_err(|_| anyhow!("failed to run hook: {}", cmd))
+    }
+
+    #[doc(hidden)]
+    fn hook_command(&self, cmd: &str, args: &[&str]) -> Command {
+        let mut command = Command::new(cmd);
+        command
+            .args(args)
+            .env("KBS2_HOOK", "1")
+            .env("KBS2_DRY_RUN", if self.dry_run { "1" } else { "0" })
+            .env("KBS2_CONFIG_DIR", &self.config_dir)
+            .env("KBS2_STORE", &self.store)
+            .env("KBS2_PUBLIC_KEY", &self.public_key)
+            .stdin(Stdio::null());
+
+        if let Some(dir) = self.hook_dir() {
+            command.current_dir(dir);
+        }
+
+        command
+    }
+
+    /// Returns the directory that hooks are run in, per `hook-cwd`, or `None` if they
+    /// inherit `kbs2`'s own working directory.
+    pub fn hook_dir(&self) -> Option<PathBuf> {
+        match self.hook_cwd {
+            HookCwd::Store => Some(self.store_path()),
+            HookCwd::Config => Some(PathBuf::from(&self.config_dir)),
+            HookCwd::Inherit => None,
+        }
+    }
+
+    /// Returns every hook known to `kbs2`, whether or not it's configured.
+    pub fn hooks(&self) -> Vec<Hook<'_>> {
+        let commands = &self.commands;
+
+        vec![
+            Hook::new("pre-hook", &self.pre_hook, &[]),
+            Hook::new("post-hook", &self.post_hook, &[]),
+            Hook::new("error-hook", &self.error_hook, &["an error message"]),
+            Hook::new("new.pre-hook", &commands.new.pre_hook, &[])
+                .with_dummy_envs(&[("KBS2_LABEL", "label")])
+                .vetoable(),
+            Hook::new("new.post-hook", &commands.new.post_hook, &["label"])
+                .with_dummy_envs(&[("KBS2_GENERATOR", "default")]),
+            Hook::new("pass.pre-hook", &commands.pass.pre_hook, &[]),
+            Hook::new("pass.post-hook", &commands.pass.post_hook, &[]),
+            Hook::new("pass.clear-hook", &commands.pass.clear_hook, &[]),
+            Hook::new("edit.post-hook", &commands.edit.post_hook, &[]),
+            Hook::new(
+                "rm.post-hook",
+                &commands.rm.post_hook,
+                &["label1", "label2"],
+            ),
+            Hook::new(
+                "rename.post-hook",
+                &commands.rename.post_hook,
+                &["old-label", "new-label"],
+            ),
+            Hook::new("sync.pull", &self.sync.pull, &[]),
+            Hook::new("sync.push", &self.sync.push, &[]),
+        ]
+    }
+
     /// Given the `name` of a configured generator, return that generator
     /// if it exists.
     pub fn generator(&self, name: &str) -> Option<&GeneratorConfig> {
@@ -151,58 +520,763 @@ impl Config {
             .find(|&generator_config| generator_config.name() == name)
     }
 
+    /// Resolves the given name to a generator: first to the generator with exactly that
+    /// name, and only then to an inline generator spec (see `parse_generator_spec`).
+    ///
+    /// NOTE(ww): Named generators always take precedence, so that e.g. a generator that's
+    /// literally named `len=6` keeps working as it always has.
+    pub fn resolve_generator(&self, name: &str) -> Result<Cow<'_, GeneratorConfig>> {
+        if let Some(generator) = self.generator(name) {
+            return Ok(Cow::Borrowed(generator));
+        }
+
+        if !name.contains('=') {
+            return Err(anyhow!("no generator named {name}"));
+        }
+
+        self.parse_generator_spec(name)
+            .map(Cow::Owned)
+            .map_err(|e| anyhow!("no generator named {name}, and it isn't a valid spec: {e}"))
+    }
+
+    /// Parses an inline generator spec: comma-separated settings that adjust a configured
+    /// generator, e.g. `len=6` or `len=32,from=hexonly`.
+    ///
+    /// The settings are `len` (the secret's length) and `from` (the generator to adjust,
+    /// `default` unless given).
+    #[doc(hidden)]
+    fn parse_generator_spec(&self, spec: &str) -> Result<GeneratorConfig> {
+        let mut from = "default";
+        let mut length = None;
+        for setting in spec.split(',') {
+            match setting.split_once('=') {
+                Some(("len", value)) => {
+                    length = Some(
+                        value
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|&length| length > 0)
+                            .ok_or_else(|| anyhow!("invalid length: {value:?}"))?,
+                    )
+                }
+                Some(("from", value)) => from = value,
+                _ => {
+                    return Err(anyhow!(
+                        "unknown setting: {setting:?} (expected len=N or from=NAME)"
+                    ))
+                }
+            }
+        }
+
+        let base = self
+            .generator(from)
+            .ok_or_else(|| anyhow!("no generator named {from}"))?;
+
+        Ok(GeneratorConfig {
+            name: spec.into(),
+            length: length.unwrap_or(base.length),
+            ..base.clone()
+        })
+    }
+
+    /// Warns if the given generator's secrets have less estimated entropy than
+    /// `min-generator-entropy` allows.
+    pub fn check_generator_entropy(&self, generator: &GeneratorConfig) -> Result<()> {
+        if self.min_generator_entropy == 0 {
+            return Ok(());
+        }
+
+        let entropy = generator.entropy()?;
+        if entropy < f64::from(self.min_generator_entropy) {
+            util::warn(&format!(
+                "generator '{}' produces secrets with only ~{:.0} bits of entropy \
+                 (min-generator-entropy is {}); consider a longer length or more alphabets",
+                generator.name, entropy, self.min_generator_entropy
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns a secret from the given generator.
+    ///
+    /// When `KBS2_GENERATOR_SEED` is set, the generator is seeded with it, making the secret
+    /// reproducible. That's only meant for tests, so it's loudly warned about, and refused
+    /// entirely with `forbid-seeded-generation`.
+    pub fn generate_secret(&self, generator: &GeneratorConfig) -> Result<String> {
+        let Some(seed) = env::var_os(GENERATOR_SEED_ENV) else {
+            return generator.secret();
+        };
+
+        if self.forbid_seeded_generation {
+            return Err(anyhow!(
+                "{} is set, but forbid-seeded-generation is enabled",
+                GENERATOR_SEED_ENV
+            ));
+        }
+
+        let seed = seed
+            .to_str()
+            .and_then(|seed| seed.parse::<u64>().ok())
+            .ok_or_else(|| anyhow!("{} must be an integer: {:?}", GENERATOR_SEED_ENV, seed))?;
+
+        util::warn(&format!(
+            "{GENERATOR_SEED_ENV} is set; generated secrets are NOT random (for testing only)"
+        ));
+        generator.secret_with_rng(&mut StdRng::seed_from_u64(seed))
+    }
+
+    /// Pulls the store from its remote, if a pull command is configured.
+    pub fn sync_pull(&self) -> Result<()> {
+        if let Some(pull) = &self.sync.pull {
+            log::debug!("sync pull: {}", pull);
+            self.call_hook(pull, &[])
+                .with_context(|| "failed to pull the store")?;
+        }
+
+        Ok(())
+    }
+
+    /// Pushes the store to its remote, if a push command is configured.
+    pub fn sync_push(&self) -> Result<()> {
+        if let Some(push) = &self.sync.push {
+            log::debug!("sync push: {}", push);
+            self.call_hook(push, &[])
+                .with_context(|| "failed to push the store")?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the path to the record store.
+    pub fn store_path(&self) -> PathBuf {
+        PathBuf::from(&self.store)
+    }
+
+    /// Returns the path to the (possibly wrapped) keyfile.
+    pub fn keyfile_path(&self) -> PathBuf {
+        PathBuf::from(&self.keyfile)
+    }
+
+    /// Returns the path to the config file itself.
+    pub fn config_path(&self) -> PathBuf {
+        Path::new(&self.config_dir).join(CONFIG_BASENAME)
+    }
+
+    /// Returns the suffix appended to record filenames, including its leading `.`,
+    /// or an empty string if `record-extension` isn't set.
+    pub fn record_suffix(&self) -> String {
+        match self.record_extension.as_deref() {
+            Some(extension) => format!(".{}", extension.trim_start_matches('.')),
+            None => String::new(),
+        }
+    }
+
+    /// Returns the record label for the given filename in the store.
+    pub fn record_label(&self, filename: &str) -> String {
+        let suffix = self.record_suffix();
+        let filename = match filename.strip_suffix(&suffix) {
+            Some(stem) if !suffix.is_empty() && !stem.is_empty() => stem,
+            _ => filename,
+        };
+
+        self.record_filenames.label(filename)
+    }
+
+    /// Returns the path to the file that the record with the given label is (or would be)
+    /// stored in.
+    pub fn record_path(&self, label: &str) -> PathBuf {
+        self.record_path_in(label, &OnceCell::new())
+    }
+
+    /// Like `record_path`, but for many labels at once, listing the store at most once.
+    pub fn record_paths(&self, labels: &[String]) -> Vec<PathBuf> {
+        let filenames = OnceCell::new();
+        labels
+            .iter()
+            .map(|label| self.record_path_in(label, &filenames))
+            .collect()
+    }
+
+    /// Returns the names of the files in the store.
+    pub fn store_filenames(&self) -> HashSet<OsString> {
+        fs::read_dir(self.store_path())
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry.file_name())
+            .collect()
+    }
+
+    /// Like `record_path`, but with the store's filenames (as from `store_filenames`) listed
+    /// into `filenames`, if they're needed and it's empty.
+    pub fn record_path_in(&self, label: &str, filenames: &OnceCell<HashSet<OsString>>) -> PathBuf {
+        let store = self.store_path();
+        let filename = self.record_filenames.filename(label);
+        let preferred = format!("{filename}{}", self.record_suffix());
+
+        if store.join(&preferred).exists() {
+            return store.join(preferred);
+        }
+
+        // NOTE(ww): Records written before `record-extension` was set don't have it,
+        // so we keep finding those too.
+        if preferred != filename && store.join(&filename).is_file() {
+            return store.join(filename);
+        }
+
+        // NOTE(ww): Stores created before switching to escaped filenames contain records
+        // named directly by their labels, so we keep finding those. We can't just check
+        // whether the direct path exists, since that's exactly the check that a
+        // case-insensitive filesystem gets wrong.
+        if filename != label
+            && filenames
+                .get_or_init(|| self.store_filenames())
+                .contains(OsStr::new(label))
+        {
+            return store.join(label);
+        }
+
+        store.join(preferred)
+    }
+
+    /// Returns whether the given label is named like one of the files that `kbs2` itself
+    /// keeps alongside the config: `config.toml`, the keyfile's basename, or a `.old` backup.
+    ///
+    /// Such labels are never listed as records, wherever the store is.
+    pub fn is_reserved_label(&self, label: &str) -> bool {
+        label == CONFIG_BASENAME
+            || label.ends_with(".old")
+            || self
+                .keyfile_path()
+                .file_name()
+                .is_some_and(|name| name == label)
+    }
+
+    /// Returns whether the given path is (or would be) one of the files that `kbs2`
+    /// itself keeps alongside the config, whether or not that file exists yet.
+    pub fn is_own_path(&self, path: &Path) -> bool {
+        // NOTE(ww): Comparing names first keeps this cheap for the common case.
+        let mut own_paths = self
+            .own_paths()
+            .filter(|own| own.file_name() == path.file_name())
+            .peekable();
+        if own_paths.peek().is_none() {
+            return false;
+        }
+
+        let path = resolve_path(path);
+        own_paths.any(|own| resolve_path(&own) == path)
+    }
+
+    #[doc(hidden)]
+    fn own_paths(&self) -> impl Iterator<Item = PathBuf> {
+        [self.config_path(), self.keyfile_path()]
+            .into_iter()
+            .flat_map(|path| {
+                let old = backup_path(&path, None);
+                [path, old]
+            })
+    }
+
     /// Create a `RuntimeConfig` from this config and the given `matches`.
     pub fn with_matches<'a>(&'a self, matches: &'a ArgMatches) -> RuntimeConfig<'a> {
         RuntimeConfig {
             config: self,
             matches,
+            generated_by: RefCell::new(None),
+        }
+    }
+}
+
+/// A hook, i.e. a command that `kbs2` runs in response to some event.
+pub struct Hook<'a> {
+    /// The hook's name, as its configuration key (e.g. `pass.clear-hook`).
+    pub name: &'static str,
+
+    /// The hook's command, if configured.
+    pub command: Option<&'a str>,
+
+    /// Representative arguments that `kbs2` passes to this hook.
+    pub dummy_args: &'static [&'static str],
+
+    /// Representative variables that `kbs2` adds to this hook's environment, on top of
+    /// those that every hook gets.
+    pub dummy_envs: &'static [(&'static str, &'static str)],
+
+    /// Whether this hook can veto the operation it precedes, by exiting with
+    /// `HOOK_VETO_CODE`.
+    pub vetoable: bool,
+}
+
+impl<'a> Hook<'a> {
+    fn new(
+        name: &'static str,
+        command: &'a Option<String>,
+        dummy_args: &'static [&'static str],
+    ) -> Self {
+        Hook {
+            name,
+            command: command.as_deref(),
+            dummy_args,
+            dummy_envs: &[],
+            vetoable: false,
+        }
+    }
+
+    #[doc(hidden)]
+    fn with_dummy_envs(mut self, dummy_envs: &'static [(&'static str, &'static str)]) -> Self {
+        self.dummy_envs = dummy_envs;
+        self
+    }
+
+    #[doc(hidden)]
+    fn vetoable(mut self) -> Self {
+        self.vetoable = true;
+        self
+    }
+
+    /// Returns the path to the executable that this hook's command resolves to, if it exists.
+    ///
+    /// Like any other hook, commands containing a path separator are resolved relative to
+    /// the directory that hooks run in (see `hook-cwd`), and all other commands are looked
+    /// up on the `$PATH`.
+    pub fn resolve(&self, config: &Config) -> Option<PathBuf> {
+        let command = self.command?;
+
+        if command.contains(std::path::MAIN_SEPARATOR) {
+            let dir = config.hook_dir().or_else(|| env::current_dir().ok())?;
+            let path = dir.join(command);
+            return path.is_file().then_some(path);
+        }
+
+        env::split_paths(&env::var_os("PATH")?)
+            .map(|dir| dir.join(command))
+            .find(|path| path.is_file())
+    }
+}
+
+/// The working directories that hooks can be run in.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookCwd {
+    /// Hooks are run in the `kbs2` store.
+    #[default]
+    Store,
+
+    /// Hooks are run in the `kbs2` config directory.
+    Config,
+
+    /// Hooks are run in whatever directory `kbs2` itself was run in.
+    Inherit,
+}
+
+/// The ways that records can be compressed before they're encrypted.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Records are encrypted as-is.
+    #[default]
+    None,
+
+    /// Records are compressed with DEFLATE.
+    Deflate,
+}
+
+impl ValueEnum for Compression {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Compression::None, Compression::Deflate]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Compression::None => Some(PossibleValue::new("none")),
+            Compression::Deflate => Some(PossibleValue::new("deflate")),
+        }
+    }
+}
+
+/// The kinds of backends that the store can be kept in; see `store::StoreBackend`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackendKind {
+    /// Each record is a file within the `store` directory.
+    #[default]
+    Fs,
+}
+
+/// The schemes for mapping record labels to filenames within the store.
+///
+/// Configs without a scheme use `Direct`, since that's how every store was laid out
+/// before there was a choice.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFilenames {
+    /// Each record is stored in a file named exactly by its label.
+    #[default]
+    Direct,
+
+    /// Each record is stored in a file named by its label, with uppercase characters
+    /// (and the escape character itself) escaped with `^`. This keeps labels that differ
+    /// only by case from colliding on case-insensitive filesystems.
+    Escaped,
+}
+
+impl ValueEnum for RecordFilenames {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[RecordFilenames::Direct, RecordFilenames::Escaped]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            RecordFilenames::Direct => Some(PossibleValue::new("direct")),
+            RecordFilenames::Escaped => Some(PossibleValue::new("escaped")),
+        }
+    }
+}
+
+impl RecordFilenames {
+    /// Returns the scheme that `kbs2 init` configures for new stores.
+    pub fn for_new_store() -> Self {
+        // NOTE(ww): The common macOS and Windows filesystems are case-insensitive by default.
+        if cfg!(any(target_os = "macos", target_os = "windows")) {
+            RecordFilenames::Escaped
+        } else {
+            RecordFilenames::Direct
+        }
+    }
+
+    /// Returns the filename for the given record label.
+    pub fn filename(&self, label: &str) -> String {
+        match self {
+            RecordFilenames::Direct => label.into(),
+            RecordFilenames::Escaped => {
+                let mut filename = String::with_capacity(label.len());
+                for c in label.chars() {
+                    match c {
+                        '^' => filename.push_str("^^"),
+                        c if c.is_ascii_uppercase() => {
+                            filename.push('^');
+                            filename.push(c.to_ascii_lowercase());
+                        }
+                        c => filename.push(c),
+                    }
+                }
+                filename
+            }
+        }
+    }
+
+    /// Returns the record label for the given filename.
+    ///
+    /// Filenames that aren't validly escaped are assumed to predate escaping,
+    /// and are returned unchanged.
+    pub fn label(&self, filename: &str) -> String {
+        match self {
+            RecordFilenames::Direct => filename.into(),
+            RecordFilenames::Escaped => {
+                let mut label = String::with_capacity(filename.len());
+                let mut chars = filename.chars();
+                while let Some(c) = chars.next() {
+                    if c != '^' {
+                        label.push(c);
+                        continue;
+                    }
+
+                    match chars.next() {
+                        Some('^') => label.push('^'),
+                        Some(c) if c.is_ascii_lowercase() => label.push(c.to_ascii_uppercase()),
+                        _ => return filename.into(),
+                    }
+                }
+                label
+            }
+        }
+    }
+}
+
+/// The policies for normalizing record labels.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelNormalization {
+    /// Labels are used exactly as given.
+    #[default]
+    None,
+
+    /// Labels are lowercased, so that labels differing only by case refer to the same record.
+    Lower,
+
+    /// Labels are put into Unicode Normalization Form C, so that visually identical labels
+    /// (e.g. with a precomposed or a combining accent) refer to the same record.
+    Nfc,
+}
+
+impl LabelNormalization {
+    /// Returns the given label, normalized according to this policy.
+    pub fn normalize(&self, label: &str) -> String {
+        match self {
+            Self::None => label.into(),
+            Self::Lower => label.to_lowercase(),
+            Self::Nfc => label.nfc().collect(),
+        }
+    }
+}
+
+/// A newtype wrapper around a `String`, used to provide a sensible default for `Config.pinentry`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pinentry(String);
+
+impl Default for Pinentry {
+    fn default() -> Self {
+        Self("pinentry".into())
+    }
+}
+
+impl AsRef<OsStr> for Pinentry {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_ref()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GeneratorConfig {
+    /// The name of the generator.
+    pub name: String,
+
+    /// The alphabets used by the generator.
+    #[serde(default)]
+    pub alphabets: Vec<Alphabet>,
+
+    /// The length of the secrets generated, in characters (or in words, for
+    /// wordlist generators).
+    pub length: usize,
+
+    /// An optional wordlist file, one word per line. When present, the generator
+    /// produces passphrases from it instead of sampling from its alphabets.
+    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wordlist: Option<String>,
+
+    /// The separator between words, for wordlist generators.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub separator: Option<String>,
+
+    /// The words loaded from `wordlist`, once they've been loaded and validated.
+    #[serde(skip)]
+    pub(crate) words: OnceCell<Vec<String>>,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            name: "default".into(),
+            alphabets: vec![
+                "abcdefghijklmnopqrstuvwxyz".into(),
+                "ABCDEFGHIJKLMNOPQRSTUVWXYZ".into(),
+                "0123456789".into(),
+                "(){}[]-_+=".into(),
+            ],
+            length: 16,
+            wordlist: None,
+            separator: None,
+            words: OnceCell::new(),
+        }
+    }
+}
+
+impl GeneratorConfig {
+    /// Returns whether any of this generator's alphabets has an explicit weight.
+    pub fn is_weighted(&self) -> bool {
+        self.alphabets
+            .iter()
+            .any(|a| matches!(a, Alphabet::Weighted { .. }))
+    }
+
+    /// Checks that every alphabet's weight is positive and finite.
+    pub fn check_weights(&self) -> Result<()> {
+        for alphabet in &self.alphabets {
+            let weight = alphabet.weight();
+            if !weight.is_finite() || weight <= 0.0 {
+                return Err(anyhow!(
+                    "alphabet weights must be positive and finite: {:?} has weight {}",
+                    alphabet.chars(),
+                    weight
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns this generator's words, loading and validating its wordlist on first use.
+    ///
+    /// Returns an empty list for generators without a wordlist.
+    pub fn words(&self) -> Result<&[String]> {
+        let Some(wordlist) = &self.wordlist else {
+            return Ok(&[]);
+        };
+
+        if let Some(words) = self.words.get() {
+            return Ok(words);
+        }
+
+        let words = load_wordlist(Path::new(wordlist))?;
+        Ok(self.words.get_or_init(|| words))
+    }
+}
+
+/// One of a generator's alphabets.
+///
+/// Alphabets are either plain strings of characters, or tables with an explicit weight
+/// (e.g. `{ chars = "(){}[]-_+=", weight = 0.1 }`) that controls how often the alphabet
+/// is sampled, relative to the others, when padding out a secret.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Alphabet {
+    /// A plain alphabet, with a weight of 1.
+    Plain(String),
+
+    /// An alphabet with an explicit weight.
+    Weighted { chars: String, weight: f64 },
+}
+
+impl Alphabet {
+    /// Returns the alphabet's characters.
+    pub fn chars(&self) -> &str {
+        match self {
+            Self::Plain(chars) | Self::Weighted { chars, .. } => chars,
+        }
+    }
+
+    /// Returns the alphabet's weight, relative to the generator's other alphabets.
+    pub fn weight(&self) -> f64 {
+        match self {
+            Self::Plain(_) => 1.0,
+            Self::Weighted { weight, .. } => *weight,
         }
     }
 }
 
-/// A newtype wrapper around a `String`, used to provide a sensible default for `Config.pinentry`.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Pinentry(String);
-
-impl Default for Pinentry {
-    fn default() -> Self {
-        Self("pinentry".into())
+impl From<&str> for Alphabet {
+    fn from(chars: &str) -> Self {
+        Self::Plain(chars.into())
     }
 }
 
-impl AsRef<OsStr> for Pinentry {
-    fn as_ref(&self) -> &OsStr {
-        self.0.as_ref()
+/// Reads and validates a wordlist, returning its words.
+///
+/// Each line holds one word, optionally preceded by dice rolls or other
+/// whitespace-separated columns (as in the EFF wordlists), which are ignored.
+/// Blank lines, non-ASCII words, and duplicates are rejected, since each silently
+/// reduces the entropy of the generated passphrases.
+fn load_wordlist(path: &Path) -> Result<Vec<String>> {
+    let contents = util::read_guarded(path, MAX_WORDLIST_SIZE)
+        .with_context(|| format!("failed to read wordlist: {}", path.display()))?;
+    let contents = String::from_utf8(contents)
+        .map_err(|_| anyhow!("wordlist is not valid UTF-8: {}", path.display()))?;
+
+    let mut seen = HashMap::new();
+    let mut words = vec![];
+    for (lineno, line) in contents.lines().enumerate().map(|(n, l)| (n + 1, l)) {
+        let Some(word) = line.split_whitespace().last() else {
+            return Err(anyhow!(
+                "{}:{lineno}: blank line in wordlist",
+                path.display()
+            ));
+        };
+
+        if !word.is_ascii() {
+            return Err(anyhow!(
+                "{}:{lineno}: non-ASCII word in wordlist: {word}",
+                path.display()
+            ));
+        }
+
+        if let Some(first) = seen.insert(word, lineno) {
+            return Err(anyhow!(
+                "{}:{lineno}: duplicate word in wordlist: {word} (first on line {first})",
+                path.display()
+            ));
+        }
+
+        words.push(word.to_string());
     }
-}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct GeneratorConfig {
-    /// The name of the generator.
-    pub name: String,
+    if words.len() < MIN_WORDLIST_WORDS {
+        return Err(anyhow!(
+            "wordlist is too short: {} ({} words, need at least {MIN_WORDLIST_WORDS})",
+            path.display(),
+            words.len()
+        ));
+    }
 
-    /// The alphabets used by the generator.
-    pub alphabets: Vec<String>,
+    Ok(words)
+}
 
-    /// The length of the secrets generated.
-    pub length: usize,
+/// Configuration settings for `kbs2 sync`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SyncConfig {
+    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
+    pub pull: Option<String>,
+    #[serde(deserialize_with = "deserialize_optional_with_tilde")]
+    pub push: Option<String>,
+    pub auto: bool,
 }
 
-impl Default for GeneratorConfig {
+/// Configuration settings for the appearance of interactive prompts.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UiConfig {
+    /// The prefix displayed before each prompt.
+    #[serde(rename = "prompt-prefix")]
+    pub prompt_prefix: String,
+
+    /// The color of prompt prefixes, answers, help messages, and highlighted options.
+    /// If unset, prompts keep their default colors.
+    #[serde(rename = "highlight-color")]
+    pub highlight_color: Option<HighlightColor>,
+
+    /// Whether or not prompts display `kbs2`'s help messages.
+    #[serde(rename = "show-help")]
+    pub show_help: bool,
+}
+
+impl Default for UiConfig {
     fn default() -> Self {
-        GeneratorConfig {
-            name: "default".into(),
-            alphabets: vec![
-                "abcdefghijklmnopqrstuvwxyz".into(),
-                "ABCDEFGHIJKLMNOPQRSTUVWXYZ".into(),
-                "0123456789".into(),
-                "(){}[]-_+=".into(),
-            ],
-            length: 16,
+        UiConfig {
+            prompt_prefix: "?".into(),
+            highlight_color: None,
+            show_help: true,
         }
     }
 }
 
+/// The colors that interactive prompts can be highlighted in.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HighlightColor {
+    /// No colors at all.
+    None,
+    Black,
+    LightRed,
+    DarkRed,
+    LightGreen,
+    DarkGreen,
+    LightYellow,
+    DarkYellow,
+    LightBlue,
+    DarkBlue,
+    LightMagenta,
+    DarkMagenta,
+    LightCyan,
+    DarkCyan,
+    White,
+    Grey,
+    DarkGrey,
+}
+
 /// The per-command configuration settings known to `kbs2`.
 #[derive(Clone, Default, Debug, Deserialize, Serialize)]
 #[serde(default)]
@@ -210,6 +1284,9 @@ pub struct CommandConfigs {
     /// Settings for `kbs2 new`.
     pub new: NewConfig,
 
+    /// Settings for `kbs2 list`.
+    pub list: ListConfig,
+
     /// Settings for `kbs2 pass`.
     pub pass: PassConfig,
 
@@ -239,6 +1316,31 @@ pub struct NewConfig {
     #[serde(deserialize_with = "deserialize_optional_with_tilde")]
     #[serde(rename = "post-hook")]
     pub post_hook: Option<String>,
+    #[serde(rename = "force-generate")]
+    pub force_generate: bool,
+    #[serde(rename = "terse-separator")]
+    pub terse_separator: Option<String>,
+    #[serde(rename = "prompt-icon")]
+    pub prompt_icon: bool,
+}
+
+/// Configuration settings for `kbs2 list`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ListConfig {
+    #[serde(rename = "label-width")]
+    pub label_width: usize,
+    #[serde(rename = "cache-labels")]
+    pub cache_labels: bool,
+}
+
+impl Default for ListConfig {
+    fn default() -> Self {
+        ListConfig {
+            label_width: 32,
+            cache_labels: false,
+        }
+    }
 }
 
 /// Configuration settings for `kbs2 pass`.
@@ -249,6 +1351,10 @@ pub struct PassConfig {
     pub clipboard_duration: u64,
     #[serde(rename = "clear-after")]
     pub clear_after: bool,
+    #[serde(rename = "reveal-timeout")]
+    pub reveal_timeout: Option<u64>,
+    #[serde(rename = "clipboard-max-bytes")]
+    pub clipboard_max_bytes: u64,
     #[serde(deserialize_with = "deserialize_optional_with_tilde")]
     #[serde(rename = "pre-hook")]
     pub pre_hook: Option<String>,
@@ -260,17 +1366,13 @@ pub struct PassConfig {
     pub clear_hook: Option<String>,
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
-pub enum X11Clipboard {
-    Clipboard,
-    Primary,
-}
-
 impl Default for PassConfig {
     fn default() -> Self {
         PassConfig {
             clipboard_duration: 10,
             clear_after: true,
+            reveal_timeout: None,
+            clipboard_max_bytes: 8192,
             pre_hook: None,
             post_hook: None,
             clear_hook: None,
@@ -283,6 +1385,8 @@ impl Default for PassConfig {
 #[serde(default)]
 pub struct EditConfig {
     pub editor: Option<String>,
+    #[serde(rename = "allow-rename")]
+    pub allow_rename: bool,
     #[serde(deserialize_with = "deserialize_optional_with_tilde")]
     #[serde(rename = "post-hook")]
     pub post_hook: Option<String>,
@@ -295,6 +1399,11 @@ pub struct RmConfig {
     #[serde(deserialize_with = "deserialize_optional_with_tilde")]
     #[serde(rename = "post-hook")]
     pub post_hook: Option<String>,
+
+    /// If set, records modified within this many seconds are only removed after
+    /// confirmation, e.g. because someone else just updated them in a shared store.
+    #[serde(rename = "recent-window-secs")]
+    pub recent_window_secs: Option<u64>,
 }
 
 /// Configuration settings for `kbs2 rename`.
@@ -311,27 +1420,181 @@ pub struct RenameConfig {
 pub struct RuntimeConfig<'a> {
     pub config: &'a Config,
     pub matches: &'a ArgMatches,
+
+    /// The name of the generator used by `generate`, if it's been called.
+    generated_by: RefCell<Option<String>>,
 }
 
 impl<'a> RuntimeConfig<'a> {
-    pub fn generator(&self) -> Result<&GeneratorConfig> {
-        // If the user explicitly requests a specific generator, use it.
+    pub fn generator(&self) -> Result<Cow<'_, GeneratorConfig>> {
+        // If the user explicitly requests a specific generator (or spec), use it.
         // Otherwise, use the default generator, which is always present.
         if let Some(generator) = self.matches.get_one::<String>("generator") {
-            self.config
-                .generator(generator)
-                .ok_or_else(|| anyhow!("no generator named {generator}"))
+            self.config.resolve_generator(generator)
         } else {
             // Failure here indicates a bug, since we should always have a default.
             self.config
                 .generator("default")
+                .map(Cow::Borrowed)
                 .ok_or_else(|| anyhow!("missing default generator?"))
         }
     }
 
+    /// Generates a secret with the selected generator, remembering the generator's name.
+    pub fn generate(&self) -> Result<String> {
+        let generator = self.generator()?;
+        self.config.check_generator_entropy(&generator)?;
+        let secret = self.config.generate_secret(&generator)?;
+
+        *self.generated_by.borrow_mut() = Some(generator.name.clone());
+        Ok(secret)
+    }
+
+    /// Returns the name of the generator used by `generate`, if any secret was generated.
+    pub fn generated_by(&self) -> Option<String> {
+        self.generated_by.borrow().clone()
+    }
+
+    /// Returns the notes supplied with `--notes` or `--notes-file`, if any.
+    pub fn notes(&self) -> Result<Option<String>> {
+        if let Ok(Some(notes)) = self.matches.try_get_one::<String>("notes") {
+            return Ok(Some(notes.clone()));
+        }
+
+        match self.matches.try_get_one::<PathBuf>("notes-file") {
+            Ok(Some(path)) => {
+                let notes = util::read_guarded(path, MAX_FIELD_FILE_SIZE)
+                    .with_context(|| format!("failed to read notes from {}", path.display()))?;
+
+                String::from_utf8(notes)
+                    .map(Some)
+                    .map_err(|_| anyhow!("notes in {} are not valid UTF-8", path.display()))
+            }
+            _ => Ok(None),
+        }
+    }
+
     pub fn terse(&self) -> bool {
         !stdin().is_terminal() || *self.matches.get_one::<bool>("terse").unwrap_or(&false)
     }
+
+    /// Returns the separator between terse fields: `--terse-separator` if given, then
+    /// `commands.new.terse-separator`, and `TERSE_IFS` otherwise.
+    pub fn terse_separator(&self) -> Result<&str> {
+        let separator = self
+            .matches
+            .try_get_one::<String>("terse-separator")
+            .ok()
+            .flatten()
+            .or(self.config.commands.new.terse_separator.as_ref())
+            .map_or(TERSE_IFS, String::as_str);
+
+        if separator.is_empty() {
+            return Err(anyhow!("the terse separator can't be empty"));
+        }
+
+        Ok(separator)
+    }
+
+    /// Returns whether sensitive fields must always be generated, rather than supplied.
+    pub fn force_generate(&self) -> bool {
+        self.config.commands.new.force_generate
+    }
+
+    /// Returns whether any record fields were supplied as individual arguments.
+    pub fn has_field_args(&self) -> bool {
+        FIELD_ARGS
+            .iter()
+            .any(|(field, stdin_field)| self.field_arg_present(field, stdin_field))
+            || self.field_files().next().is_some()
+    }
+
+    /// Checks that every record field supplied as an argument is one of `fields`,
+    /// and that no field is supplied more than once.
+    pub fn check_field_args(&self, fields: &[&str]) -> Result<()> {
+        for (field, stdin_field) in FIELD_ARGS {
+            let present = self.field_arg_present(field, stdin_field);
+            let files = self.field_files().filter(|(f, _)| f == field).count();
+
+            if !fields.contains(field) && (present || files > 0) {
+                return Err(anyhow!(
+                    "{field} is not a field of this kind of record (expected one of: {})",
+                    fields.join(", ")
+                ));
+            }
+
+            if files > 1 || (files == 1 && present) {
+                return Err(anyhow!("{field} was supplied more than once"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value of the given record field, as supplied via its argument or `stdin`.
+    ///
+    /// Sensitive fields that aren't supplied are generated when `--generate` is passed.
+    pub fn field_arg(&self, field: &str, sensitive: bool) -> Result<String> {
+        if sensitive && self.force_generate() {
+            let stdin_field = format!("{field}-stdin");
+            if self.field_arg_present(field, &stdin_field)
+                || self.field_files().any(|(f, _)| f == field)
+            {
+                return Err(anyhow!(
+                    "{field} can't be supplied when commands.new.force-generate is enabled"
+                ));
+            }
+
+            return self.generate();
+        }
+
+        if let Some(value) = self.matches.get_one::<String>(field) {
+            return Ok(value.into());
+        }
+
+        if self.matches.get_flag(&format!("{field}-stdin")) {
+            let mut value = String::new();
+            io::stdin().read_to_string(&mut value)?;
+
+            if value.ends_with('\n') {
+                value.pop();
+            }
+
+            return Ok(value);
+        }
+
+        if let Some((_, path)) = self.field_files().find(|(f, _)| f == field) {
+            let value = util::read_guarded(path, MAX_FIELD_FILE_SIZE)
+                .with_context(|| format!("failed to read {field} from {}", path.display()))?;
+
+            return String::from_utf8(value)
+                .map_err(|_| anyhow!("{field} in {} is not valid UTF-8", path.display()));
+        }
+
+        if sensitive && self.matches.get_flag("generate") {
+            return self.generate();
+        }
+
+        Err(anyhow!(
+            "missing field: {field} (supply it with --{field}, --{field}-stdin, --field-file{})",
+            if sensitive { ", or --generate" } else { "" }
+        ))
+    }
+
+    #[doc(hidden)]
+    fn field_arg_present(&self, field: &str, stdin_field: &str) -> bool {
+        self.matches.contains_id(field) || self.matches.get_flag(stdin_field)
+    }
+
+    #[doc(hidden)]
+    fn field_files(&self) -> impl Iterator<Item = &(String, PathBuf)> {
+        self.matches
+            .try_get_many::<(String, PathBuf)>("field-file")
+            .ok()
+            .flatten()
+            .into_iter()
+            .flatten()
+    }
 }
 
 #[doc(hidden)]
@@ -360,6 +1623,48 @@ where
     }
 }
 
+#[doc(hidden)]
+#[inline]
+fn default_agent_spawn_timeout_ms() -> u64 {
+    DEFAULT_AGENT_SPAWN_TIMEOUT_MS
+}
+
+#[doc(hidden)]
+#[inline]
+fn default_unlock_max_attempts() -> u32 {
+    DEFAULT_UNLOCK_MAX_ATTEMPTS
+}
+
+#[doc(hidden)]
+#[inline]
+fn default_unlock_backoff_ms() -> u64 {
+    DEFAULT_UNLOCK_BACKOFF_MS
+}
+
+#[doc(hidden)]
+#[inline]
+fn default_max_record_size() -> u64 {
+    DEFAULT_MAX_RECORD_SIZE
+}
+
+#[doc(hidden)]
+#[inline]
+fn default_stream_threshold() -> u64 {
+    DEFAULT_STREAM_THRESHOLD
+}
+
+#[doc(hidden)]
+#[inline]
+fn default_audit_log_max_bytes() -> u64 {
+    audit::DEFAULT_AUDIT_LOG_MAX_BYTES
+}
+
+#[doc(hidden)]
+#[inline]
+fn default_min_generator_entropy() -> u32 {
+    DEFAULT_MIN_GENERATOR_ENTROPY
+}
+
 #[doc(hidden)]
 #[inline]
 fn default_as_true() -> bool {
@@ -367,6 +1672,91 @@ fn default_as_true() -> bool {
     true
 }
 
+/// How the store and config directories overlap, when they do.
+///
+/// Records and `kbs2`'s own files (the config, the keyfile, and their backups) shouldn't
+/// mix: syncing the store would publish them, and scripts that operate on every record
+/// can clobber them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirectoryOverlap {
+    /// The store is the config directory.
+    Same,
+
+    /// The store is inside the config directory.
+    StoreInConfig,
+
+    /// The config directory is inside the store.
+    ConfigInStore,
+}
+
+impl std::fmt::Display for DirectoryOverlap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DirectoryOverlap::Same => write!(f, "the store is the config directory"),
+            DirectoryOverlap::StoreInConfig => {
+                write!(f, "the store is inside the config directory")
+            }
+            DirectoryOverlap::ConfigInStore => {
+                write!(f, "the config directory is inside the store")
+            }
+        }
+    }
+}
+
+/// Returns how the given store and config directories overlap, if they do.
+///
+/// Either directory may not exist yet (e.g. during `kbs2 init`), in which case it's
+/// resolved as far as it exists.
+pub fn directory_overlap(store: &Path, config_dir: &Path) -> Option<DirectoryOverlap> {
+    let (store, config_dir) = (resolve_path(store), resolve_path(config_dir));
+
+    if store == config_dir {
+        Some(DirectoryOverlap::Same)
+    } else if store.starts_with(&config_dir) {
+        Some(DirectoryOverlap::StoreInConfig)
+    } else if config_dir.starts_with(&store) {
+        Some(DirectoryOverlap::ConfigInStore)
+    } else {
+        None
+    }
+}
+
+/// Returns the path to back `path` up to: `path` with `.old` appended, either alongside
+/// it or (if given) in `backup_dir`.
+pub fn backup_path(path: &Path, backup_dir: Option<&Path>) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".old");
+
+    match backup_dir {
+        Some(backup_dir) => backup_dir.join(name),
+        None => path.with_file_name(name),
+    }
+}
+
+/// Canonicalizes the given path as far as it exists, appending whatever doesn't.
+#[doc(hidden)]
+fn resolve_path(path: &Path) -> PathBuf {
+    if let Ok(path) = fs::canonicalize(path) {
+        return path;
+    }
+
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            resolve_path(parent).join(name)
+        }
+        _ => std::path::absolute(path).unwrap_or_else(|_| path.into()),
+    }
+}
+
+/// An existing age identity to initialize a config with, instead of a new keypair.
+pub struct ExistingIdentity {
+    /// The path to the identity file, which becomes the config's `keyfile`.
+    pub path: PathBuf,
+
+    /// The identity's public key, if known.
+    pub public_key: Option<String>,
+}
+
 /// Given a path to a `kbs2` configuration directory, initializes a configuration
 /// file and keypair within it.
 ///
@@ -375,21 +1765,34 @@ fn default_as_true() -> bool {
 /// * `config_dir` - The configuration directory to initialize within
 /// * `store_dir` - The record store directory to use
 /// * `password` - An optional master password for wrapping the secret
+/// * `identity` - An optional existing identity to use, instead of generating a keypair
 pub fn initialize<P: AsRef<Path>>(
     config_dir: P,
     store_dir: P,
     password: Option<SecretString>,
+    identity: Option<ExistingIdentity>,
 ) -> Result<()> {
     fs::create_dir_all(&config_dir)?;
 
-    let keyfile = config_dir.as_ref().join(DEFAULT_KEY_BASENAME);
-
-    let mut wrapped = false;
-    let public_key = if let Some(password) = password {
-        wrapped = true;
-        RageLib::create_wrapped_keypair(&keyfile, password)?
-    } else {
-        RageLib::create_keypair(&keyfile)?
+    let wrapped = password.is_some();
+    let (keyfile, public_key) = match identity {
+        Some(identity) => {
+            let public_key = RageLib::existing_keypair(
+                &identity.path,
+                password,
+                identity.public_key.as_deref(),
+            )?;
+            (identity.path, public_key)
+        }
+        None => {
+            let keyfile = config_dir.as_ref().join(DEFAULT_KEY_BASENAME);
+            let public_key = if let Some(password) = password {
+                RageLib::create_wrapped_keypair(&keyfile, password)?
+            } else {
+                RageLib::create_keypair(&keyfile)?
+            };
+            (keyfile, public_key)
+        }
     };
 
     log::debug!("public key: {}", public_key);
@@ -411,27 +1814,67 @@ pub fn initialize<P: AsRef<Path>>(
         toml::to_string(&Config {
             // NOTE(ww): Not actually serialized; just here to make the compiler happy.
             config_dir: config_dir,
+            no_agent: false,
+            force_retry: false,
+            dry_run: false,
             public_key: public_key,
             keyfile: keyfile
                 .to_str()
                 .ok_or_else(|| anyhow!("unrepresentable keyfile path: {:?}", keyfile))?
                 .into(),
+            escrow_recipients: vec![],
             agent_autostart: true,
+            agent_spawn_timeout_ms: DEFAULT_AGENT_SPAWN_TIMEOUT_MS,
+            agent_binary: None,
+            agent_strict: false,
             wrapped: wrapped,
             store: store,
+            store_backend: Default::default(),
+            record_filenames: RecordFilenames::for_new_store(),
+            label_normalization: Default::default(),
+            record_extension: None,
+            compress: Default::default(),
             pinentry: Default::default(),
+            unlock_max_attempts: DEFAULT_UNLOCK_MAX_ATTEMPTS,
+            unlock_backoff_ms: DEFAULT_UNLOCK_BACKOFF_MS,
+            locale: Default::default(),
+            check_for_updates: false,
             pre_hook: None,
             post_hook: None,
             error_hook: None,
             reentrant_hooks: false,
+            dry_run_pre_hooks: false,
+            hook_cwd: Default::default(),
+            track_access: false,
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            stream_threshold: DEFAULT_STREAM_THRESHOLD,
+            manifest: false,
+            verify_manifest: false,
+            min_generator_entropy: DEFAULT_MIN_GENERATOR_ENTROPY,
+            forbid_seeded_generation: false,
+            confirm_threshold: None,
+            audit_log: None,
+            audit_log_max_bytes: audit::DEFAULT_AUDIT_LOG_MAX_BYTES,
+            audit_records: true,
+            list_usernames: true,
+            confirm_dump: true,
             generators: vec![Default::default()],
+            sync: Default::default(),
+            ui: Default::default(),
             commands: Default::default(),
         })?
     };
 
-    fs::write(config_dir.as_ref().join(CONFIG_BASENAME), serialized)?;
+    let config_path = config_dir.as_ref().join(CONFIG_BASENAME);
+    fs::write(&config_path, serialized)
+        .map_err(|e| util::write_context(e, "writing the config", &config_path))
+}
 
-    Ok(())
+/// Writes the given config back to the configuration file in its `config_dir`.
+pub fn save(config: &Config) -> Result<()> {
+    let config_path = config.config_path();
+    fs::write(&config_path, toml::to_string(config)?)
+        .map_err(|e| util::write_context(e, "writing the config", &config_path))
 }
 
 /// Given a path to a `kbs2` configuration directory, loads the configuration
@@ -440,13 +1883,21 @@ pub fn load<P: AsRef<Path>>(config_dir: P) -> Result<Config> {
     let config_dir = config_dir.as_ref();
     let config_path = config_dir.join(CONFIG_BASENAME);
 
-    let contents = fs::read_to_string(config_path)?;
+    let contents = fs::read_to_string(&config_path).with_context(|| {
+        format!(
+            "failed to read {}; create it with `kbs2 init`",
+            config_path.display()
+        )
+    })?;
 
     let mut config = Config {
         config_dir: config_dir
             .to_str()
             .ok_or_else(|| anyhow!("unrepresentable config dir path: {:?}", config_dir))?
             .into(),
+        no_agent: false,
+        force_retry: false,
+        dry_run: false,
         ..toml::from_str(&contents).map_err(|e| anyhow!("config loading error: {}", e))?
     };
 
@@ -455,6 +1906,30 @@ pub fn load<P: AsRef<Path>>(config_dir: P) -> Result<Config> {
         config.generators.push(Default::default());
     }
 
+    if let Some(extension) = &config.record_extension {
+        let extension = extension.trim_start_matches('.');
+        if extension.is_empty() || extension.contains(std::path::is_separator) {
+            return Err(anyhow!(
+                "invalid record-extension: {:?}",
+                config.record_extension
+            ));
+        }
+    }
+
+    // NOTE(ww): A strict agent only starts under its own binary, so it would refuse
+    // every spawn from a different one.
+    if config.agent_strict && config.agent_binary.is_some() {
+        return Err(anyhow!("agent-strict can't be combined with agent-binary"));
+    }
+
+    // Validate alphabet weights (and cache any wordlists) up front, rather than on first use.
+    for generator in &config.generators {
+        generator
+            .check_weights()
+            .and_then(|_| generator.words().map(|_| ()))
+            .with_context(|| format!("invalid generator: {}", generator.name))?;
+    }
+
     Ok(config)
 }
 
@@ -467,20 +1942,58 @@ mod tests {
     fn dummy_config_unwrapped_key() -> Config {
         Config {
             config_dir: "/not/a/real/dir".into(),
+            no_agent: false,
+            force_retry: false,
+            dry_run: false,
             public_key: "not a real public key".into(),
             keyfile: "not a real private key file".into(),
+            escrow_recipients: vec![],
             agent_autostart: false,
+            agent_spawn_timeout_ms: DEFAULT_AGENT_SPAWN_TIMEOUT_MS,
+            agent_binary: None,
+            agent_strict: false,
             wrapped: false,
             store: "/tmp".into(),
+            store_backend: StoreBackendKind::Fs,
+            record_filenames: RecordFilenames::Direct,
+            label_normalization: LabelNormalization::None,
+            record_extension: None,
+            compress: Default::default(),
             pinentry: Default::default(),
+            unlock_max_attempts: DEFAULT_UNLOCK_MAX_ATTEMPTS,
+            unlock_backoff_ms: DEFAULT_UNLOCK_BACKOFF_MS,
+            locale: Default::default(),
+            check_for_updates: false,
             pre_hook: Some("true".into()),
             post_hook: Some("false".into()),
             error_hook: Some("true".into()),
             reentrant_hooks: false,
+            dry_run_pre_hooks: false,
+            hook_cwd: Default::default(),
+            track_access: false,
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            stream_threshold: DEFAULT_STREAM_THRESHOLD,
+            manifest: false,
+            verify_manifest: false,
+            min_generator_entropy: DEFAULT_MIN_GENERATOR_ENTROPY,
+            forbid_seeded_generation: false,
+            confirm_threshold: None,
+            audit_log: None,
+            audit_log_max_bytes: audit::DEFAULT_AUDIT_LOG_MAX_BYTES,
+            audit_records: true,
+            list_usernames: true,
+            confirm_dump: true,
             generators: vec![Default::default()],
+            sync: SyncConfig {
+                pull: Some("true".into()),
+                push: Some("false".into()),
+                auto: false,
+            },
+            ui: Default::default(),
             commands: CommandConfigs {
                 rm: RmConfig {
                     post_hook: Some("this-command-does-not-exist".into()),
+                    recent_window_secs: None,
                 },
                 ..Default::default()
             },
@@ -511,7 +2024,7 @@ mod tests {
         {
             let config_dir = tempdir().unwrap();
             let store_dir = tempdir().unwrap();
-            assert!(initialize(&config_dir, &store_dir, None).is_ok());
+            assert!(initialize(&config_dir, &store_dir, None, None).is_ok());
 
             let config_dir = config_dir.path();
             assert!(config_dir.exists());
@@ -536,7 +2049,8 @@ mod tests {
             assert!(initialize(
                 &config_dir,
                 &store_dir,
-                Some(SecretString::new("badpassword".into()))
+                Some(SecretString::new("badpassword".into())),
+                None
             )
             .is_ok());
 
@@ -560,7 +2074,7 @@ mod tests {
         {
             let config_dir = tempdir().unwrap();
             let store_dir = tempdir().unwrap();
-            initialize(&config_dir, &store_dir, None).unwrap();
+            initialize(&config_dir, &store_dir, None, None).unwrap();
 
             assert!(load(&config_dir).is_ok());
         }
@@ -568,14 +2082,70 @@ mod tests {
         {
             let config_dir = tempdir().unwrap();
             let store_dir = tempdir().unwrap();
-            initialize(&config_dir, &store_dir, None).unwrap();
+            initialize(&config_dir, &store_dir, None, None).unwrap();
 
             let config = load(&config_dir).unwrap();
             assert_eq!(config_dir.path().to_str().unwrap(), config.config_dir);
             assert_eq!(store_dir.path().to_str().unwrap(), config.store);
+            assert_eq!(config.record_filenames, RecordFilenames::for_new_store());
+        }
+
+        {
+            // Configs from before `record-filenames` existed keep their direct filenames,
+            // whatever the platform.
+            let config_dir = tempdir().unwrap();
+            let store_dir = tempdir().unwrap();
+            initialize(&config_dir, &store_dir, None, None).unwrap();
+
+            let config_path = config_dir.path().join(CONFIG_BASENAME);
+            let config = std::fs::read_to_string(&config_path).unwrap();
+            let config = config
+                .lines()
+                .filter(|line| !line.starts_with("record-filenames"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            std::fs::write(&config_path, config).unwrap();
+
+            let config = load(&config_dir).unwrap();
+            assert_eq!(config.record_filenames, RecordFilenames::Direct);
         }
     }
 
+    #[test]
+    fn test_load_agent_strict_binary() {
+        let config_dir = tempdir().unwrap();
+        let store_dir = tempdir().unwrap();
+        initialize(&config_dir, &store_dir, None, None).unwrap();
+
+        let config_path = config_dir.path().join(CONFIG_BASENAME);
+        let config = std::fs::read_to_string(&config_path).unwrap();
+        std::fs::write(
+            &config_path,
+            config.replace(
+                "agent-strict = false",
+                "agent-strict = true\nagent-binary = \"/usr/bin/kbs2\"",
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(
+            load(&config_dir).unwrap_err().to_string(),
+            "agent-strict can't be combined with agent-binary"
+        );
+    }
+
+    #[test]
+    fn test_ext_config() {
+        let mut config = dummy_config_unwrapped_key();
+        config.commands.ext.insert(
+            "frobulate".into(),
+            HashMap::from([("xyz".into(), toml::Value::Integer(1))]),
+        );
+
+        assert_eq!(config.ext_config("frobulate").unwrap(), r#"{"xyz":1}"#);
+        assert_eq!(config.ext_config("nonexistent").unwrap(), "{}");
+    }
+
     #[test]
     fn test_call_hook() {
         let config = dummy_config_unwrapped_key();
@@ -619,4 +2189,356 @@ mod tests {
         assert!(config.generator("default").is_some());
         assert!(config.generator("nonexistent-generator").is_none());
     }
+
+    #[test]
+    fn test_resolve_generator() {
+        let mut config = dummy_config_unwrapped_key();
+        config.generators.push(GeneratorConfig {
+            name: "hexonly".into(),
+            alphabets: vec!["0123456789abcdef".into()],
+            length: 16,
+            ..Default::default()
+        });
+
+        // Names resolve to their generators, and specs adjust a generator.
+        assert!(matches!(
+            config.resolve_generator("hexonly").unwrap(),
+            Cow::Borrowed(g) if g.name == "hexonly"
+        ));
+
+        let generator = config.resolve_generator("len=6").unwrap();
+        assert_eq!(generator.name, "len=6");
+        assert_eq!(generator.length, 6);
+        assert_eq!(generator.alphabets, GeneratorConfig::default().alphabets);
+
+        let generator = config.resolve_generator("len=32,from=hexonly").unwrap();
+        assert_eq!(generator.length, 32);
+        assert_eq!(generator.alphabets, ["0123456789abcdef".into()]);
+
+        // A generator that's literally named like a spec takes precedence over it.
+        config.generators.push(GeneratorConfig {
+            name: "len=6".into(),
+            length: 10,
+            ..Default::default()
+        });
+        assert_eq!(config.resolve_generator("len=6").unwrap().length, 10);
+
+        for (name, message) in [
+            ("nonexistent", "no generator named nonexistent"),
+            (
+                "len=0",
+                "no generator named len=0, and it isn't a valid spec: invalid length: \"0\"",
+            ),
+            (
+                "len=6,size=2",
+                "no generator named len=6,size=2, and it isn't a valid spec: unknown setting: \
+                 \"size=2\" (expected len=N or from=NAME)",
+            ),
+            (
+                "from=nonexistent",
+                "no generator named from=nonexistent, and it isn't a valid spec: \
+                 no generator named nonexistent",
+            ),
+        ] {
+            assert_eq!(
+                config.resolve_generator(name).unwrap_err().to_string(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_record_filenames() {
+        let direct = RecordFilenames::Direct;
+        let escaped = RecordFilenames::Escaped;
+
+        for label in ["github", "GitHub", "a^b", "^", "Über"] {
+            assert_eq!(direct.filename(label), label);
+            assert_eq!(direct.label(label), label);
+            assert_eq!(escaped.label(&escaped.filename(label)), label);
+        }
+
+        assert_eq!(escaped.filename("github"), "github");
+        assert_eq!(escaped.filename("GitHub"), "^git^hub");
+        assert_eq!(escaped.filename("a^b"), "a^^b");
+
+        // Filenames that predate escaping are returned as-is.
+        assert_eq!(escaped.label("GitHub"), "GitHub");
+        assert_eq!(escaped.label("trailing^"), "trailing^");
+    }
+
+    #[test]
+    fn test_paths() {
+        let mut config = dummy_config_unwrapped_key();
+        config.config_dir = "/home/jdoe/.config/kbs2".into();
+        config.store = "/home/jdoe/.local/share/kbs2".into();
+        config.keyfile = "/home/jdoe/.config/kbs2/key".into();
+
+        assert_eq!(
+            config.config_path(),
+            Path::new("/home/jdoe/.config/kbs2/config.toml")
+        );
+        assert_eq!(
+            config.store_path(),
+            Path::new("/home/jdoe/.local/share/kbs2")
+        );
+        assert_eq!(
+            config.keyfile_path(),
+            Path::new("/home/jdoe/.config/kbs2/key")
+        );
+
+        assert_eq!(
+            backup_path(&config.keyfile_path(), None),
+            Path::new("/home/jdoe/.config/kbs2/key.old")
+        );
+        assert_eq!(
+            backup_path(&config.store_path(), Some(Path::new("/backups"))),
+            Path::new("/backups/kbs2.old")
+        );
+
+        // Trailing separators don't change what gets backed up.
+        assert_eq!(
+            backup_path(Path::new("/home/jdoe/store/"), None),
+            Path::new("/home/jdoe/store.old")
+        );
+    }
+
+    #[test]
+    fn test_record_path() {
+        let store = tempdir().unwrap();
+        let mut config = dummy_config_unwrapped_key();
+        config.store = store.path().to_str().unwrap().into();
+        config.record_filenames = RecordFilenames::Escaped;
+
+        assert_eq!(config.record_path("github"), store.path().join("github"));
+        assert_eq!(config.record_path("GitHub"), store.path().join("^git^hub"));
+
+        // A record stored under its direct filename is still found.
+        fs::write(store.path().join("Legacy"), "").unwrap();
+        assert_eq!(config.record_path("Legacy"), store.path().join("Legacy"));
+
+        // With an extension, new records get it, but extensionless ones are still found.
+        config.record_extension = Some("age".into());
+        assert_eq!(
+            config.record_path("GitHub"),
+            store.path().join("^git^hub.age")
+        );
+        assert_eq!(config.record_path("Legacy"), store.path().join("Legacy"));
+        fs::write(store.path().join("github"), "").unwrap();
+        assert_eq!(config.record_path("github"), store.path().join("github"));
+        fs::write(store.path().join("github.age"), "").unwrap();
+        assert_eq!(
+            config.record_path("github"),
+            store.path().join("github.age")
+        );
+    }
+
+    #[test]
+    fn test_record_label() {
+        let mut config = dummy_config_unwrapped_key();
+        config.record_filenames = RecordFilenames::Escaped;
+        assert_eq!(config.record_label("^git^hub.age"), "GitHub.age");
+
+        // A leading dot in the extension is optional.
+        for extension in ["age", ".age"] {
+            config.record_extension = Some(extension.into());
+            assert_eq!(config.record_suffix(), ".age");
+            assert_eq!(config.record_label("^git^hub.age"), "GitHub");
+            assert_eq!(config.record_label("^git^hub"), "GitHub");
+            assert_eq!(config.record_label(".age"), ".age");
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        let config = dummy_config_unwrapped_key();
+        let hooks = config.hooks();
+
+        let configured: Vec<_> = hooks
+            .iter()
+            .filter(|h| h.command.is_some())
+            .map(|h| h.name)
+            .collect();
+        assert_eq!(
+            configured,
+            vec![
+                "pre-hook",
+                "post-hook",
+                "error-hook",
+                "rm.post-hook",
+                "sync.pull",
+                "sync.push"
+            ]
+        );
+
+        let pre_hook = hooks.iter().find(|h| h.name == "pre-hook").unwrap();
+        assert!(pre_hook.resolve(&config).unwrap().ends_with("true"));
+
+        let rm_hook = hooks.iter().find(|h| h.name == "rm.post-hook").unwrap();
+        assert!(rm_hook.resolve(&config).is_none());
+
+        // Hooks that get extra variables from `kbs2` get representative ones.
+        let new_hook = hooks.iter().find(|h| h.name == "new.post-hook").unwrap();
+        assert_eq!(new_hook.dummy_envs, [("KBS2_GENERATOR", "default")]);
+    }
+
+    #[test]
+    fn test_test_hook() {
+        let config = dummy_config_unwrapped_key();
+
+        {
+            let output = config.test_hook("echo", &["foo", "bar"], &[]).unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"foo bar\n");
+        }
+
+        {
+            let output = config.test_hook("false", &[], &[]).unwrap();
+            assert!(!output.status.success());
+        }
+
+        {
+            assert!(config
+                .test_hook("this-command-does-not-exist", &[], &[])
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut config = dummy_config_unwrapped_key();
+
+        assert!(config.sync_pull().is_ok());
+        assert_eq!(
+            config.sync_push().unwrap_err().to_string(),
+            "failed to push the store"
+        );
+
+        config.sync = Default::default();
+        assert!(config.sync_pull().is_ok());
+        assert!(config.sync_push().is_ok());
+    }
+
+    #[test]
+    fn test_ui_config() {
+        let ui: UiConfig = toml::from_str("").unwrap();
+        assert_eq!(ui.prompt_prefix, "?");
+        assert_eq!(ui.highlight_color, None);
+        assert!(ui.show_help);
+
+        let ui: UiConfig = toml::from_str(
+            r#"
+            prompt-prefix = ">>"
+            highlight-color = "dark-blue"
+            show-help = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(ui.prompt_prefix, ">>");
+        assert_eq!(ui.highlight_color, Some(HighlightColor::DarkBlue));
+        assert!(!ui.show_help);
+
+        let ui: UiConfig = toml::from_str(r#"highlight-color = "none""#).unwrap();
+        assert_eq!(ui.highlight_color, Some(HighlightColor::None));
+
+        assert!(toml::from_str::<UiConfig>(r#"highlight-color = "purple""#).is_err());
+    }
+
+    #[test]
+    fn test_load_wordlist() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("words");
+        let words: Vec<_> = (0..MIN_WORDLIST_WORDS)
+            .map(|i| format!("word{i}"))
+            .collect();
+
+        // Plain lists and dice-numbered lists are both fine.
+        std::fs::write(&path, words.join("\n")).unwrap();
+        assert_eq!(load_wordlist(&path).unwrap(), words);
+
+        let numbered: Vec<_> = words.iter().map(|w| format!("11111\t{w}")).collect();
+        std::fs::write(&path, numbered.join("\n")).unwrap();
+        assert_eq!(load_wordlist(&path).unwrap(), words);
+
+        let check = |contents: String, message: &str| {
+            std::fs::write(&path, contents).unwrap();
+            let err = load_wordlist(&path).unwrap_err().to_string();
+            assert!(err.contains(message), "{err}");
+        };
+
+        check(
+            format!("{}\nword0", words.join("\n")),
+            ":1025: duplicate word in wordlist: word0 (first on line 1)",
+        );
+        check(format!("word\n\n{}", words.join("\n")), ":2: blank line");
+        check(format!("wörd\n{}", words.join("\n")), ":1: non-ASCII word");
+        check(words[1..].join("\n"), "1023 words, need at least 1024");
+    }
+
+    #[test]
+    fn test_generator_words_cached() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("words");
+        let words: Vec<_> = (0..MIN_WORDLIST_WORDS)
+            .map(|i| format!("word{i}"))
+            .collect();
+        std::fs::write(&path, words.join("\n")).unwrap();
+
+        let generator = GeneratorConfig {
+            name: "words".into(),
+            length: 4,
+            wordlist: Some(path.to_str().unwrap().into()),
+            ..Default::default()
+        };
+        assert_eq!(generator.words().unwrap().len(), MIN_WORDLIST_WORDS);
+
+        // The wordlist is only read once.
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(generator.words().unwrap().len(), MIN_WORDLIST_WORDS);
+
+        assert!(GeneratorConfig::default().words().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_generator_alphabet_weights() {
+        let config_dir = tempdir().unwrap();
+        let store_dir = tempdir().unwrap();
+        initialize(&config_dir, &store_dir, None, None).unwrap();
+
+        let config_path = config_dir.path().join(CONFIG_BASENAME);
+        let config = std::fs::read_to_string(&config_path).unwrap();
+        let with_weight = |weight: &str| {
+            std::fs::write(
+                &config_path,
+                format!(
+                    "{config}\n[[generators]]\nname = \"weighted\"\nlength = 8\n\
+                     alphabets = [\"abc\", {{ chars = \"!@#\", weight = {weight} }}]\n"
+                ),
+            )
+            .unwrap();
+            load(&config_dir)
+        };
+
+        // Plain and weighted alphabets can be mixed.
+        let config = with_weight("0.1").unwrap();
+        let generator = config.generator("weighted").unwrap();
+        assert!(generator.is_weighted());
+        assert_eq!(
+            generator.alphabets,
+            [
+                Alphabet::Plain("abc".into()),
+                Alphabet::Weighted {
+                    chars: "!@#".into(),
+                    weight: 0.1
+                }
+            ]
+        );
+        assert!(!config.generator("default").unwrap().is_weighted());
+
+        for weight in ["0", "-1", "nan", "inf"] {
+            let err = with_weight(weight).unwrap_err();
+            assert_eq!(err.to_string(), "invalid generator: weighted");
+            assert!(format!("{err:#}").contains("must be positive and finite"));
+        }
+    }
 }
@@ -0,0 +1,371 @@
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::kbs2::agent;
+use crate::kbs2::backend::{Backend, RageLib, RageRecipient};
+use crate::kbs2::config;
+use crate::kbs2::record::{Record, RecordBody, UnstructuredFields};
+use crate::kbs2::util;
+
+/// The outcome of a single diagnostic check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Pass => write!(f, "pass"),
+            Status::Warn => write!(f, "warn"),
+            Status::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// A single diagnostic check, as reported by `kbs2 doctor`.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    /// The check's name, e.g. `keyfile`.
+    pub name: &'static str,
+
+    /// The check's outcome.
+    pub status: Status,
+
+    /// What the check found.
+    pub message: String,
+
+    /// How to fix whatever the check found, for warnings and failures.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(name: &'static str, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// Runs every diagnostic check against the config in `config_dir`, in order.
+///
+/// If the config can't be loaded, that's the only check: nothing else can be checked
+/// without it.
+pub fn run(config_dir: &Path, no_agent: bool) -> Vec<Check> {
+    let config_path = config_dir.join(config::CONFIG_BASENAME);
+
+    let mut config = match config::load(config_dir) {
+        Ok(config) => config,
+        Err(e) => {
+            return vec![Check::fail(
+                "config",
+                format!("failed to load {}: {e}", config_path.display()),
+                "fix the config file, or re-create it with `kbs2 init --force`",
+            )]
+        }
+    };
+    config.no_agent = no_agent;
+
+    vec![
+        Check::pass("config", format!("loaded {}", config_path.display())),
+        check_keyfile(&config),
+        check_agent(&config),
+        check_crypto(&config),
+        check_store(&config),
+        check_hooks(&config),
+        check_escrow(&config),
+    ]
+}
+
+#[doc(hidden)]
+fn check_keyfile(config: &config::Config) -> Check {
+    const NAME: &str = "keyfile";
+
+    if let Err(e) = config.public_key.parse::<RageRecipient>() {
+        return Check::fail(
+            NAME,
+            format!("invalid public-key: {e}"),
+            "set public-key to the public key that matches your keyfile",
+        );
+    }
+
+    let metadata = match fs::metadata(&config.keyfile) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            return Check::fail(
+                NAME,
+                format!("can't read {}: {e}", config.keyfile),
+                "check that keyfile points to your private key",
+            )
+        }
+    };
+
+    match RageLib::is_wrapped_keyfile(&config.keyfile) {
+        Ok(wrapped) if wrapped != config.wrapped => {
+            return Check::fail(
+                NAME,
+                format!(
+                    "{} is {}wrapped, but the config says wrapped = {}",
+                    config.keyfile,
+                    if wrapped { "" } else { "not " },
+                    config.wrapped
+                ),
+                format!("set wrapped = {wrapped}"),
+            )
+        }
+        Ok(_) => {}
+        Err(e) => {
+            return Check::fail(
+                NAME,
+                format!("can't read {}: {e}", config.keyfile),
+                "check that keyfile points to your private key",
+            )
+        }
+    }
+
+    // NOTE(ww): Wrapped keys can't be checked against the public key without their password.
+    if !config.wrapped {
+        if let Err(e) = RageLib::existing_keypair(&config.keyfile, None, Some(&config.public_key)) {
+            return Check::fail(
+                NAME,
+                format!("{} doesn't match public-key: {e}", config.keyfile),
+                "set public-key to the public key that matches your keyfile",
+            );
+        }
+    }
+
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Check::warn(
+            NAME,
+            format!("{} is accessible by other users", config.keyfile),
+            format!("chmod 600 {}", config.keyfile),
+        );
+    }
+
+    Check::pass(NAME, format!("{} is readable and valid", config.keyfile))
+}
+
+#[doc(hidden)]
+fn check_agent(config: &config::Config) -> Check {
+    const NAME: &str = "agent";
+
+    if !config.wrapped {
+        return Check::pass(NAME, "not needed: the key isn't wrapped");
+    } else if config.no_agent {
+        return Check::pass(NAME, "not used: --no-agent was passed");
+    }
+
+    if config.agent_autostart {
+        if let Err(e) = agent::Agent::spawn(
+            config.agent_binary.as_deref().map(Path::new),
+            Duration::from_millis(config.agent_spawn_timeout_ms),
+        ) {
+            return Check::fail(
+                NAME,
+                format!("failed to spawn the agent: {e}"),
+                "run `kbs2 agent --foreground` to see why the agent won't start",
+            );
+        }
+    }
+
+    let client = match agent::Client::new() {
+        Ok(client) => client,
+        Err(e) => {
+            return Check::fail(
+                NAME,
+                format!("failed to connect to the agent: {e}"),
+                "start the agent with `kbs2 agent`",
+            )
+        }
+    };
+
+    match client.query_key(&config.public_key) {
+        Ok(true) => Check::pass(NAME, "running, with the key unwrapped"),
+        Ok(false) => Check::pass(NAME, "running, without the key unwrapped"),
+        Err(e) => Check::fail(
+            NAME,
+            format!("the agent didn't respond properly: {e}"),
+            "restart the agent with `kbs2 agent flush --quit` and `kbs2 agent`",
+        ),
+    }
+}
+
+#[doc(hidden)]
+fn check_crypto(config: &config::Config) -> Check {
+    const NAME: &str = "crypto";
+
+    // NOTE(ww): We never prompt for a password here: a wrapped key is only used if the
+    // agent already has it.
+    if config.wrapped {
+        let loaded = !config.no_agent
+            && agent::Client::new()
+                .and_then(|client| client.query_key(&config.public_key))
+                .unwrap_or(false);
+
+        if !loaded {
+            return Check::warn(
+                NAME,
+                "skipped: the key is wrapped and not in the agent",
+                "run `kbs2 agent unwrap`, then run `kbs2 doctor` again",
+            );
+        }
+    }
+
+    let roundtrip = RageLib::new(config).and_then(|backend| {
+        let record = Record::new(
+            "kbs2-doctor",
+            RecordBody::Unstructured(UnstructuredFields {
+                contents: "kbs2 doctor".into(),
+                extra: Default::default(),
+            }),
+        );
+
+        let decrypted = backend.decrypt(&backend.encrypt(&record)?)?;
+        Ok(decrypted == record)
+    });
+
+    match roundtrip {
+        Ok(true) => Check::pass(NAME, "encrypted and decrypted a test record"),
+        Ok(false) => Check::fail(
+            NAME,
+            "a test record didn't survive encryption and decryption",
+            "this is a bug; please report it",
+        ),
+        Err(e) => Check::fail(
+            NAME,
+            format!("failed to encrypt and decrypt a test record: {e}"),
+            "check that public-key matches your keyfile",
+        ),
+    }
+}
+
+#[doc(hidden)]
+fn check_store(config: &config::Config) -> Check {
+    const NAME: &str = "store";
+
+    let store = config.store_path();
+    if !store.exists() {
+        return Check::warn(
+            NAME,
+            format!("{} doesn't exist yet", config.store),
+            "it's created when it's first used; if it should already exist, check that it's mounted",
+        );
+    } else if !store.is_dir() {
+        return Check::fail(
+            NAME,
+            format!("{} is not a directory", config.store),
+            format!("move {} out of the way, or change store", config.store),
+        );
+    }
+
+    if let Err(e) = util::probe_writable(&store) {
+        let hint = match e.downcast_ref::<util::WriteFailed>().map(|e| e.reason) {
+            Some(util::Unwritable::ReadOnly) => {
+                "remount its filesystem read-write, or move the store elsewhere".into()
+            }
+            Some(util::Unwritable::Full) => "free up space on its filesystem".into(),
+            _ => format!("check the permissions on {}", config.store),
+        };
+
+        return Check::fail(
+            NAME,
+            format!("{} is not writable: {}", config.store, e.root_cause()),
+            hint,
+        );
+    }
+
+    Check::pass(NAME, format!("{} is writable", config.store))
+}
+
+#[doc(hidden)]
+fn check_hooks(config: &config::Config) -> Check {
+    const NAME: &str = "hooks";
+
+    // NOTE(ww): Hooks are only resolved, never run: there's no argument that every hook
+    // is guaranteed to treat as a no-op, and hooks commonly have side effects (e.g. pushing).
+    let mut configured = 0;
+    let mut broken = vec![];
+    for hook in config.hooks() {
+        let Some(command) = hook.command else {
+            continue;
+        };
+        configured += 1;
+
+        match hook.resolve(config) {
+            Some(path)
+                if fs::metadata(&path).is_ok_and(|m| m.permissions().mode() & 0o111 != 0) => {}
+            Some(path) => broken.push(format!(
+                "{} ({} is not executable)",
+                hook.name,
+                path.display()
+            )),
+            None => broken.push(format!("{} ({command} not found)", hook.name)),
+        }
+    }
+
+    if configured == 0 {
+        Check::pass(NAME, "none configured")
+    } else if broken.is_empty() {
+        Check::pass(NAME, format!("{configured} configured, all executable"))
+    } else {
+        Check::fail(
+            NAME,
+            format!("broken hooks: {}", broken.join(", ")),
+            "fix the hooks' paths or permissions, and test them with `kbs2 hooks run`",
+        )
+    }
+}
+
+#[doc(hidden)]
+fn check_escrow(config: &config::Config) -> Check {
+    const NAME: &str = "escrow";
+
+    if config.escrow_recipients.is_empty() {
+        return Check::pass(NAME, "none configured");
+    }
+
+    match RageLib::escrow_recipients(config) {
+        Ok(_) => Check::pass(
+            NAME,
+            format!(
+                "records are also encrypted to {}",
+                config.escrow_recipients.join(", ")
+            ),
+        ),
+        Err(e) => Check::fail(
+            NAME,
+            format!("{e:#}"),
+            "fix or remove the recipient in escrow-recipients",
+        ),
+    }
+}
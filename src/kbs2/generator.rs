@@ -1,5 +1,9 @@
+use std::collections::{HashMap, HashSet};
+
 use anyhow::{anyhow, Result};
+use rand::distributions::{Distribution, WeightedIndex};
 use rand::seq::{IteratorRandom, SliceRandom};
+use rand::RngCore;
 
 use crate::kbs2::config;
 
@@ -9,7 +13,15 @@ pub trait Generator {
     fn name(&self) -> &str;
 
     /// Returns a secret produced by the generator.
-    fn secret(&self) -> Result<String>;
+    fn secret(&self) -> Result<String> {
+        self.secret_with_rng(&mut rand::thread_rng())
+    }
+
+    /// Returns a secret produced by the generator, using the given source of randomness.
+    fn secret_with_rng(&self, rng: &mut dyn RngCore) -> Result<String>;
+
+    /// Returns an estimate of the entropy, in bits, of the secrets produced by the generator.
+    fn entropy(&self) -> Result<f64>;
 }
 
 impl Generator for config::GeneratorConfig {
@@ -17,7 +29,21 @@ impl Generator for config::GeneratorConfig {
         &self.name
     }
 
-    fn secret(&self) -> Result<String> {
+    fn secret_with_rng(&self, rng: &mut dyn RngCore) -> Result<String> {
+        let words = self.words()?;
+        if !words.is_empty() {
+            if self.length == 0 {
+                return Err(anyhow!("generator length is invalid (must be nonzero)"));
+            }
+
+            let passphrase: Vec<_> = (0..self.length)
+                .filter_map(|_| words.choose(rng))
+                .map(String::as_str)
+                .collect();
+
+            return Ok(passphrase.join(self.separator.as_deref().unwrap_or("-")));
+        }
+
         // Invariants: we need at least one alphabet, and our length has to be nonzero.
         if self.alphabets.is_empty() {
             return Err(anyhow!("generator must have at least one alphabet"));
@@ -27,14 +53,16 @@ impl Generator for config::GeneratorConfig {
             return Err(anyhow!("generator length is invalid (must be nonzero)"));
         }
 
+        self.check_weights()?;
+
         // Our secret generation strategy:
         // 1. Sample each alphabet once
         // 2. Pad the secret out to the remaining length, sampling from all alphabets
+        //    (by weight, if any alphabet has one)
         // 3. Shuffle the result
 
-        let mut rng = rand::thread_rng();
         let mut secret = Vec::with_capacity(self.length);
-        for alphabet in self.alphabets.iter() {
+        for alphabet in self.alphabets.iter().map(|a| a.chars()) {
             if alphabet.is_empty() {
                 return Err(anyhow!("generator alphabet(s) must not be empty"));
             }
@@ -48,7 +76,7 @@ impl Generator for config::GeneratorConfig {
 
             // Safe unwrap: alphabet.chars() is always nonempty.
             #[allow(clippy::unwrap_used)]
-            secret.push(alphabet.chars().choose(&mut rng).unwrap());
+            secret.push(alphabet.chars().choose(rng).unwrap());
         }
 
         // If step 1 generated a longer password than "length" allows, fail.
@@ -58,15 +86,75 @@ impl Generator for config::GeneratorConfig {
             ));
         }
 
-        // Pad out with the combined alphabet.
-        let combined_alphabet = self.alphabets.iter().flat_map(|a| a.chars());
-        let remainder = combined_alphabet.choose_multiple(&mut rng, self.length - secret.len());
-        secret.extend(remainder);
+        if self.is_weighted() {
+            // Pad out by picking an alphabet by weight, and then a character from it.
+            let weights = WeightedIndex::new(self.alphabets.iter().map(|a| a.weight()))?;
+            for _ in secret.len()..self.length {
+                let alphabet = self.alphabets[weights.sample(rng)].chars();
+
+                // Safe unwrap: every alphabet was checked to be nonempty above.
+                #[allow(clippy::unwrap_used)]
+                secret.push(alphabet.chars().choose(rng).unwrap());
+            }
+        } else {
+            // Pad out with the combined alphabet.
+            let combined_alphabet = self.alphabets.iter().flat_map(|a| a.chars().chars());
+            let remainder = combined_alphabet.choose_multiple(rng, self.length - secret.len());
+            secret.extend(remainder);
+        }
 
         // Shuffle and return.
-        secret.shuffle(&mut rng);
+        secret.shuffle(rng);
         Ok(secret.into_iter().collect())
     }
+
+    fn entropy(&self) -> Result<f64> {
+        // NOTE(ww): Each word or character is (roughly) an independent uniform choice, so
+        // each contributes log2 of the number of choices. Characters that appear in more
+        // than one alphabet are only counted once, since repeating them adds nothing.
+        let choices = match self.words()? {
+            [] if self.is_weighted() => return Ok(self.length as f64 * self.weighted_entropy()),
+            [] => self
+                .alphabets
+                .iter()
+                .flat_map(|a| a.chars().chars())
+                .collect::<HashSet<_>>()
+                .len(),
+            words => words.len(),
+        };
+
+        if choices == 0 {
+            return Ok(0.0);
+        }
+
+        Ok(self.length as f64 * (choices as f64).log2())
+    }
+}
+
+impl config::GeneratorConfig {
+    /// Returns the (Shannon) entropy, in bits, of a single character sampled by weight.
+    ///
+    /// NOTE(ww): Weighting makes some characters likelier than others, so a weighted
+    /// character is worth less than log2 of the number of choices.
+    #[doc(hidden)]
+    fn weighted_entropy(&self) -> f64 {
+        let total: f64 = self.alphabets.iter().map(|a| a.weight()).sum();
+
+        let mut probabilities: HashMap<char, f64> = HashMap::new();
+        for alphabet in &self.alphabets {
+            let chars = alphabet.chars();
+            let each = alphabet.weight() / total / chars.chars().count() as f64;
+            for c in chars.chars() {
+                *probabilities.entry(c).or_default() += each;
+            }
+        }
+
+        probabilities
+            .values()
+            .filter(|p| p.is_finite() && **p > 0.0)
+            .map(|p| -p * p.log2())
+            .sum()
+    }
 }
 
 #[cfg(test)]
@@ -78,6 +166,7 @@ mod tests {
             name: "dummy-internal".into(),
             alphabets: alphabets.iter().map(|a| (*a).into()).collect(),
             length: 5,
+            ..Default::default()
         })
     }
 
@@ -89,6 +178,7 @@ mod tests {
                 name: "dummy-internal".into(),
                 alphabets: vec![],
                 length: 10,
+                ..Default::default()
             };
 
             assert_eq!(
@@ -103,6 +193,7 @@ mod tests {
                 name: "dummy-internal".into(),
                 alphabets: vec!["abcd".into()],
                 length: 0,
+                ..Default::default()
             };
 
             assert_eq!(
@@ -137,6 +228,7 @@ mod tests {
                     .map(Into::into)
                     .collect(),
                 length: 2,
+                ..Default::default()
             };
 
             assert_eq!(
@@ -153,6 +245,7 @@ mod tests {
                 name: "dummy-internal".into(),
                 alphabets: alphabets.into_iter().map(Into::into).collect(),
                 length: 10,
+                ..Default::default()
             };
 
             for secret in (0..100).map(|_| gen.secret()) {
@@ -166,4 +259,149 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_generator_entropy() {
+        // 16 characters from 72 distinct symbols: 16 * log2(72) bits.
+        let gen = config::GeneratorConfig::default();
+        assert!((gen.entropy().unwrap() - 98.72).abs() < 0.01);
+
+        // Four digits is only ~13 bits.
+        let gen = config::GeneratorConfig {
+            name: "pin".into(),
+            alphabets: vec!["0123456789".into()],
+            length: 4,
+            ..Default::default()
+        };
+        assert!((gen.entropy().unwrap() - 13.29).abs() < 0.01);
+
+        // Repeated characters don't count twice.
+        let gen = config::GeneratorConfig {
+            name: "repetitive".into(),
+            alphabets: vec!["abab".into(), "ab".into()],
+            length: 8,
+            ..Default::default()
+        };
+        assert_eq!(gen.entropy().unwrap(), 8.0);
+
+        // Nothing to choose from means no entropy.
+        let gen = config::GeneratorConfig {
+            name: "empty".into(),
+            alphabets: vec![],
+            length: 8,
+            ..Default::default()
+        };
+        assert_eq!(gen.entropy().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_weighted_generator() {
+        let lower = "abcdefghijklmnopqrstuvwxyz";
+        let symbols = "(){}[]-_+=";
+        let gen = config::GeneratorConfig {
+            name: "weighted".into(),
+            alphabets: vec![
+                lower.into(),
+                config::Alphabet::Weighted {
+                    chars: symbols.into(),
+                    weight: 0.1,
+                },
+            ],
+            length: 100,
+            ..Default::default()
+        };
+
+        // Each secret still has at least one character from each alphabet, but only ~1 in 11
+        // of the other 98 characters is a symbol.
+        let mut symbol_count = 0;
+        for _ in 0..100 {
+            let secret = gen.secret().unwrap();
+            assert_eq!(secret.len(), 100);
+            assert!(secret.chars().any(|c| lower.contains(c)));
+            assert!(secret.chars().any(|c| symbols.contains(c)));
+            symbol_count += secret.chars().filter(|c| symbols.contains(*c)).count();
+        }
+
+        let fraction = symbol_count as f64 / 10_000.0;
+        let expected = (1.0 + 98.0 / 11.0) / 100.0;
+        assert!((fraction - expected).abs() < 0.02, "{fraction}");
+
+        // Weighting towards fewer characters means less entropy than an unweighted generator.
+        let unweighted = config::GeneratorConfig {
+            alphabets: vec![lower.into(), symbols.into()],
+            ..gen.clone()
+        };
+        assert!(gen.entropy().unwrap() < unweighted.entropy().unwrap());
+
+        // Equal weights over disjoint, equally-sized alphabets are just a uniform choice.
+        let even = config::GeneratorConfig {
+            alphabets: vec![
+                config::Alphabet::Weighted {
+                    chars: "ab".into(),
+                    weight: 2.0,
+                },
+                config::Alphabet::Weighted {
+                    chars: "cd".into(),
+                    weight: 2.0,
+                },
+            ],
+            length: 8,
+            ..gen.clone()
+        };
+        assert!((even.entropy().unwrap() - 16.0).abs() < 1e-9);
+
+        // Invalid weights are rejected.
+        let invalid = config::GeneratorConfig {
+            alphabets: vec![config::Alphabet::Weighted {
+                chars: "ab".into(),
+                weight: -1.0,
+            }],
+            ..gen
+        };
+        assert!(invalid.secret().is_err());
+    }
+
+    #[test]
+    fn test_wordlist_generator() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("words");
+        let words: Vec<_> = (0..config::MIN_WORDLIST_WORDS)
+            .map(|i| format!("word{i}"))
+            .collect();
+        std::fs::write(&path, words.join("\n")).unwrap();
+
+        let gen = config::GeneratorConfig {
+            name: "dummy-wordlist".into(),
+            length: 5,
+            wordlist: Some(path.to_str().unwrap().into()),
+            separator: Some(" ".into()),
+            ..Default::default()
+        };
+
+        // Each word is one of 1024, i.e. 10 bits.
+        assert_eq!(gen.entropy().unwrap(), 50.0);
+
+        let secret = gen.secret().unwrap();
+        let chosen: Vec<_> = secret.split(' ').collect();
+        assert_eq!(chosen.len(), 5);
+        assert!(chosen.iter().all(|w| words.iter().any(|x| x == w)));
+    }
+
+    #[test]
+    fn test_secret_with_rng() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let gen = config::GeneratorConfig::default();
+
+        let secret = gen.secret_with_rng(&mut StdRng::seed_from_u64(42)).unwrap();
+        assert_eq!(
+            secret,
+            gen.secret_with_rng(&mut StdRng::seed_from_u64(42)).unwrap()
+        );
+        assert_ne!(
+            secret,
+            gen.secret_with_rng(&mut StdRng::seed_from_u64(7)).unwrap()
+        );
+    }
 }
@@ -0,0 +1,467 @@
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::kbs2::util;
+
+/// The environment variable that overrides the configured `locale`.
+pub static LOCALE_ENV: &str = "KBS2_LOCALE";
+
+/// The locales that `kbs2`'s messages are available in.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Locale {
+    /// English, the default.
+    #[default]
+    En,
+
+    /// German.
+    De,
+}
+
+impl Locale {
+    /// Every shipped locale.
+    #[cfg(test)]
+    pub const ALL: &'static [Locale] = &[Locale::En, Locale::De];
+
+    /// Returns the given message's text in this locale.
+    pub fn text(self, message: Message) -> &'static str {
+        match self {
+            Locale::En => en(message),
+            Locale::De => de(message),
+        }
+    }
+}
+
+impl std::str::FromStr for Locale {
+    type Err = anyhow::Error;
+
+    /// Parses a locale, ignoring any region or encoding (e.g. `de_DE.UTF-8` is `de`).
+    fn from_str(s: &str) -> Result<Self> {
+        let language = s
+            .split(['_', '-', '.'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        match language.as_str() {
+            "en" | "c" | "posix" => Ok(Locale::En),
+            "de" => Ok(Locale::De),
+            _ => Err(anyhow!("unsupported locale: {}", s)),
+        }
+    }
+}
+
+/// The locale that messages are currently rendered in; see `set_locale`.
+static LOCALE: AtomicU8 = AtomicU8::new(Locale::En as u8);
+
+/// The locale given by `$KBS2_LOCALE`, if it's set to a supported one.
+static ENV_LOCALE: OnceLock<Option<Locale>> = OnceLock::new();
+
+/// Renders subsequent messages in the locale given by `$KBS2_LOCALE`, or in `configured`
+/// if that's unset (or unsupported).
+pub fn set_locale(configured: Locale) {
+    // NOTE(ww): This is called more than once per run, but should only warn once.
+    let env_locale = ENV_LOCALE.get_or_init(|| match env::var(LOCALE_ENV) {
+        Ok(locale) if !locale.is_empty() => locale
+            .parse()
+            .map_err(|e| util::warn(&format!("{e}; using the configured locale instead")))
+            .ok(),
+        _ => None,
+    });
+
+    let locale = env_locale.unwrap_or(configured);
+    LOCALE.store(locale as u8, Ordering::Relaxed);
+}
+
+/// Returns the locale that messages are currently rendered in.
+pub fn locale() -> Locale {
+    match LOCALE.load(Ordering::Relaxed) {
+        l if l == Locale::De as u8 => Locale::De,
+        _ => Locale::En,
+    }
+}
+
+/// Returns the given message's text in the current locale.
+pub fn text(message: Message) -> &'static str {
+    locale().text(message)
+}
+
+/// Returns the given message in the current locale, with each `{N}` placeholder
+/// replaced by the `N`th argument.
+pub fn format(message: Message, args: &[&dyn fmt::Display]) -> String {
+    args.iter()
+        .enumerate()
+        .fold(text(message).to_string(), |text, (i, arg)| {
+            text.replace(&format!("{{{i}}}"), &arg.to_string())
+        })
+}
+
+/// The user-facing messages that `kbs2` can render in more than one locale.
+///
+/// NOTE: Only interactive and human-facing text belongs here. Anything that's meant
+/// to be parsed (JSON, `export` lines, audit log error kinds) must stay the same
+/// regardless of locale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Message {
+    PromptUsername,
+    PromptPassword,
+    PromptVariable,
+    PromptValue,
+    PromptContents,
+    PromptSecret,
+    PromptExtraFieldName,
+    PromptIcon,
+    HelpAutoGenerate,
+    HelpDoneWithFields,
+    HelpTotpSecret,
+    HelpIcon,
+    MasterPasswordPrompt,
+    MasterPasswordDescription,
+    MasterPasswordRequired,
+    KeyfilePasswordPrompt,
+    OldMasterPasswordPrompt,
+    NewMasterPasswordPrompt,
+    ConfirmRemove,
+    ConfirmRemoveRecent,
+    ConfirmMerge,
+    ConfirmRekey,
+    HelpConfirmRekey,
+    ConfirmDump,
+    ConfirmDumpMany,
+    SelectMergeSide,
+    SelectEditRecovery,
+    SelectDedupeAction,
+    SelectDedupeKeep,
+    DedupeKeepOne,
+    DedupeMerge,
+    DedupeSkip,
+    EditReopen,
+    EditRetry,
+    EditAbort,
+    PressEnterForTotp,
+    LabeledPassword,
+    LabeledTotp,
+    HintEmptyStore,
+    RecordBurned,
+    NoticeUpdateAvailable,
+    ErrorNoSuchRecord,
+    ErrorLockedRecord,
+    ErrorInvalidArmor,
+    ErrorAborted,
+    ErrorNotInteractive,
+    ErrorNotALogin,
+    ErrorClipboardTooLarge,
+    ErrorClipboardMultiline,
+    HintClipboardOverride,
+    WarnClipboardManager,
+    ErrorNeedsConfirmation,
+    ErrorNeedsYes,
+    ErrorDumpNeedsConfirmation,
+    ErrorBadPassword,
+    ErrorNoPasswordPrompt,
+    ErrorRecentlyModified,
+}
+
+impl Message {
+    /// Every message.
+    #[cfg(test)]
+    pub const ALL: &'static [Message] = &[
+        Message::PromptUsername,
+        Message::PromptPassword,
+        Message::PromptVariable,
+        Message::PromptValue,
+        Message::PromptContents,
+        Message::PromptSecret,
+        Message::PromptExtraFieldName,
+        Message::PromptIcon,
+        Message::HelpAutoGenerate,
+        Message::HelpDoneWithFields,
+        Message::HelpTotpSecret,
+        Message::HelpIcon,
+        Message::MasterPasswordPrompt,
+        Message::MasterPasswordDescription,
+        Message::MasterPasswordRequired,
+        Message::KeyfilePasswordPrompt,
+        Message::OldMasterPasswordPrompt,
+        Message::NewMasterPasswordPrompt,
+        Message::ConfirmRemove,
+        Message::ConfirmRemoveRecent,
+        Message::ConfirmMerge,
+        Message::ConfirmRekey,
+        Message::HelpConfirmRekey,
+        Message::ConfirmDump,
+        Message::ConfirmDumpMany,
+        Message::SelectMergeSide,
+        Message::SelectEditRecovery,
+        Message::SelectDedupeAction,
+        Message::SelectDedupeKeep,
+        Message::DedupeKeepOne,
+        Message::DedupeMerge,
+        Message::DedupeSkip,
+        Message::EditReopen,
+        Message::EditRetry,
+        Message::EditAbort,
+        Message::PressEnterForTotp,
+        Message::LabeledPassword,
+        Message::LabeledTotp,
+        Message::HintEmptyStore,
+        Message::RecordBurned,
+        Message::NoticeUpdateAvailable,
+        Message::ErrorNoSuchRecord,
+        Message::ErrorLockedRecord,
+        Message::ErrorInvalidArmor,
+        Message::ErrorAborted,
+        Message::ErrorNotInteractive,
+        Message::ErrorNotALogin,
+        Message::ErrorClipboardTooLarge,
+        Message::ErrorClipboardMultiline,
+        Message::HintClipboardOverride,
+        Message::WarnClipboardManager,
+        Message::ErrorNeedsConfirmation,
+        Message::ErrorNeedsYes,
+        Message::ErrorDumpNeedsConfirmation,
+        Message::ErrorBadPassword,
+        Message::ErrorNoPasswordPrompt,
+        Message::ErrorRecentlyModified,
+    ];
+}
+
+#[doc(hidden)]
+fn en(message: Message) -> &'static str {
+    match message {
+        Message::PromptUsername => "Username?",
+        Message::PromptPassword => "Password?",
+        Message::PromptVariable => "Variable?",
+        Message::PromptValue => "Value?",
+        Message::PromptContents => "Contents?",
+        Message::PromptSecret => "Secret?",
+        Message::PromptExtraFieldName => "Extra field name?",
+        Message::PromptIcon => "Icon?",
+        Message::HelpAutoGenerate => "Press [enter] to auto-generate",
+        Message::HelpDoneWithFields => "Press [enter] when done",
+        Message::HelpTotpSecret => "The base32-encoded TOTP secret",
+        Message::HelpIcon => "An emoji or icon name; press [enter] for none",
+        Message::MasterPasswordPrompt => "Password: ",
+        Message::MasterPasswordDescription => {
+            "Enter your master kbs2 password\n\nStore: {0}\nConfig: {1}"
+        }
+        Message::MasterPasswordRequired => "A non-empty password is required",
+        Message::KeyfilePasswordPrompt => "Keyfile password: ",
+        Message::OldMasterPasswordPrompt => "OLD master password: ",
+        Message::NewMasterPasswordPrompt => "NEW master password: ",
+        Message::ConfirmRemove => "Remove {0} records?",
+        Message::ConfirmRemoveRecent => "Remove {0} recently modified records anyway?",
+        Message::ConfirmMerge => "Apply this merge?",
+        Message::ConfirmRekey => "Are you SURE you want to continue?",
+        Message::ConfirmDump => "Print secrets for '{0}' to this terminal?",
+        Message::ConfirmDumpMany => "Print secrets for {0} records to this terminal?",
+        Message::HelpConfirmRekey => {
+            "Be certain! If you are not certain, press [enter] to do nothing."
+        }
+        Message::SelectMergeSide => "Keep which {0}?",
+        Message::SelectEditRecovery => "What now?",
+        Message::SelectDedupeAction => "What should be done with these records?",
+        Message::SelectDedupeKeep => "Keep which record?",
+        Message::DedupeKeepOne => "Keep one and remove the others",
+        Message::DedupeMerge => "Merge the others into one",
+        Message::DedupeSkip => "Skip",
+        Message::EditReopen => "Re-open the editor",
+        Message::EditRetry => "Retry parsing",
+        Message::EditAbort => "Abort",
+        Message::PressEnterForTotp => "Press [enter] for the TOTP code",
+        Message::LabeledPassword => "Password: {0}",
+        Message::LabeledTotp => "TOTP: {0}",
+        Message::HintEmptyStore => "No records yet; create one with `kbs2 new`.",
+        Message::RecordBurned => "{0} was burn-after-reading, and has been deleted from the store",
+        Message::NoticeUpdateAvailable => "kbs2 {0} is available (you have {1}): {2}",
+        Message::ErrorNoSuchRecord => "no such record: {0}",
+        Message::ErrorLockedRecord => {
+            "record is locked: {0} (unlock it with `kbs2 unlock-record {0}`, or pass --force-locked)"
+        }
+        Message::ErrorInvalidArmor => "record is not valid UTF-8 armor: {0} ({1})",
+        Message::ErrorAborted => "aborted",
+        Message::ErrorNotInteractive => "interactive prompt required but stdin is not a tty",
+        Message::ErrorNotALogin => "not a login record: {0}",
+        Message::ErrorClipboardTooLarge => {
+            "refusing to copy {0} bytes to the clipboard (more than clipboard-max-bytes {1})"
+        }
+        Message::ErrorClipboardMultiline => {
+            "refusing to copy a multi-line value to the clipboard (is it the right field?)"
+        }
+        Message::HintClipboardOverride => "pass {0} to copy it anyway",
+        Message::WarnClipboardManager => {
+            "{0} is running, and may keep the copied value in its history after it's cleared"
+        }
+        Message::ErrorNeedsConfirmation => {
+            "refusing to affect {0} records without confirmation \
+             (more than confirm-threshold {1}); pass --yes to confirm"
+        }
+        Message::ErrorNeedsYes => "refusing to go ahead without confirmation; pass --yes to confirm",
+        Message::ErrorDumpNeedsConfirmation => {
+            "refusing to print secrets to a terminal without confirmation; \
+             pass --yes, or set confirm-dump = false"
+        }
+        Message::ErrorBadPassword => "incorrect password",
+        Message::ErrorNoPasswordPrompt => {
+            "no way to prompt for a password (no working pinentry and no terminal); \
+             run `kbs2 agent unwrap` from a terminal first"
+        }
+        Message::ErrorRecentlyModified => {
+            "refusing to remove {0} recently modified records without confirmation \
+             (within commands.rm.recent-window-secs {1}); pass --yes to confirm"
+        }
+    }
+}
+
+#[doc(hidden)]
+fn de(message: Message) -> &'static str {
+    match message {
+        Message::PromptUsername => "Benutzername?",
+        Message::PromptPassword => "Passwort?",
+        Message::PromptVariable => "Variable?",
+        Message::PromptValue => "Wert?",
+        Message::PromptContents => "Inhalt?",
+        Message::PromptSecret => "Geheimnis?",
+        Message::PromptExtraFieldName => "Name des zusätzlichen Felds?",
+        Message::PromptIcon => "Symbol?",
+        Message::HelpAutoGenerate => "[Enter] drücken, um es automatisch zu generieren",
+        Message::HelpDoneWithFields => "[Enter] drücken, wenn fertig",
+        Message::HelpTotpSecret => "Das base32-kodierte TOTP-Geheimnis",
+        Message::HelpIcon => "Ein Emoji oder Symbolname; [Enter] drücken für keines",
+        Message::MasterPasswordPrompt => "Passwort: ",
+        Message::MasterPasswordDescription => {
+            "Geben Sie Ihr kbs2-Master-Passwort ein\n\nSpeicher: {0}\nKonfiguration: {1}"
+        }
+        Message::MasterPasswordRequired => "Ein nicht leeres Passwort ist erforderlich",
+        Message::KeyfilePasswordPrompt => "Passwort der Schlüsseldatei: ",
+        Message::OldMasterPasswordPrompt => "ALTES Master-Passwort: ",
+        Message::NewMasterPasswordPrompt => "NEUES Master-Passwort: ",
+        Message::ConfirmRemove => "{0} Einträge entfernen?",
+        Message::ConfirmRemoveRecent => "{0} kürzlich geänderte Einträge trotzdem entfernen?",
+        Message::ConfirmMerge => "Diese Zusammenführung anwenden?",
+        Message::ConfirmRekey => "Wollen Sie WIRKLICH fortfahren?",
+        Message::ConfirmDump => "Geheimnisse von '{0}' in diesem Terminal ausgeben?",
+        Message::ConfirmDumpMany => "Geheimnisse von {0} Einträgen in diesem Terminal ausgeben?",
+        Message::HelpConfirmRekey => {
+            "Seien Sie sich sicher! Falls nicht, drücken Sie [Enter], um nichts zu tun."
+        }
+        Message::SelectMergeSide => "Welchen Wert für {0} behalten?",
+        Message::SelectEditRecovery => "Was nun?",
+        Message::SelectDedupeAction => "Was soll mit diesen Einträgen geschehen?",
+        Message::SelectDedupeKeep => "Welchen Eintrag behalten?",
+        Message::DedupeKeepOne => "Einen behalten und die anderen entfernen",
+        Message::DedupeMerge => "Die anderen in einen zusammenführen",
+        Message::DedupeSkip => "Überspringen",
+        Message::EditReopen => "Editor erneut öffnen",
+        Message::EditRetry => "Erneut einlesen",
+        Message::EditAbort => "Abbrechen",
+        Message::PressEnterForTotp => "[Enter] drücken für den TOTP-Code",
+        Message::LabeledPassword => "Passwort: {0}",
+        Message::LabeledTotp => "TOTP: {0}",
+        Message::HintEmptyStore => "Noch keine Einträge; erstellen Sie einen mit `kbs2 new`.",
+        Message::RecordBurned => {
+            "{0} war nur einmal lesbar und wurde aus dem Speicher gelöscht"
+        }
+        Message::NoticeUpdateAvailable => "kbs2 {0} ist verfügbar (installiert ist {1}): {2}",
+        Message::ErrorNoSuchRecord => "kein solcher Eintrag: {0}",
+        Message::ErrorLockedRecord => {
+            "Eintrag ist gesperrt: {0} (entsperren mit `kbs2 unlock-record {0}`, oder --force-locked angeben)"
+        }
+        Message::ErrorInvalidArmor => "Eintrag ist kein gültiges UTF-8-Armor: {0} ({1})",
+        Message::ErrorAborted => "abgebrochen",
+        Message::ErrorNotInteractive => {
+            "interaktive Eingabe erforderlich, aber stdin ist kein Terminal"
+        }
+        Message::ErrorNotALogin => "kein Login-Eintrag: {0}",
+        Message::ErrorClipboardTooLarge => {
+            "{0} Bytes werden nicht in die Zwischenablage kopiert \
+             (mehr als clipboard-max-bytes {1})"
+        }
+        Message::ErrorClipboardMultiline => {
+            "mehrzeilige Werte werden nicht in die Zwischenablage kopiert \
+             (ist es das richtige Feld?)"
+        }
+        Message::HintClipboardOverride => "mit {0} trotzdem kopieren",
+        Message::WarnClipboardManager => {
+            "{0} läuft und behält den kopierten Wert eventuell auch nach dem Leeren in seinem Verlauf"
+        }
+        Message::ErrorNeedsConfirmation => {
+            "{0} Einträge werden ohne Bestätigung nicht verändert \
+             (mehr als confirm-threshold {1}); mit --yes bestätigen"
+        }
+        Message::ErrorNeedsYes => "ohne Bestätigung wird nicht fortgefahren; mit --yes bestätigen",
+        Message::ErrorDumpNeedsConfirmation => {
+            "Geheimnisse werden ohne Bestätigung nicht in einem Terminal ausgegeben; \
+             mit --yes bestätigen oder confirm-dump = false setzen"
+        }
+        Message::ErrorBadPassword => "falsches Passwort",
+        Message::ErrorNoPasswordPrompt => {
+            "keine Möglichkeit, nach einem Passwort zu fragen (kein funktionierendes pinentry \
+             und kein Terminal); zuerst `kbs2 agent unwrap` in einem Terminal ausführen"
+        }
+        Message::ErrorRecentlyModified => {
+            "{0} kürzlich geänderte Einträge werden ohne Bestätigung nicht entfernt \
+             (innerhalb von commands.rm.recent-window-secs {1}); mit --yes bestätigen"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the `{N}` placeholders in the given text, in order of appearance.
+    fn placeholders(text: &str) -> Vec<&str> {
+        let mut placeholders = text
+            .match_indices('{')
+            .filter_map(|(i, _)| text[i..].find('}').map(|j| &text[i..=i + j]))
+            .collect::<Vec<_>>();
+        placeholders.sort();
+        placeholders
+    }
+
+    #[test]
+    fn test_every_message_in_every_locale() {
+        for message in Message::ALL {
+            let english = Locale::En.text(*message);
+
+            for locale in Locale::ALL {
+                let text = locale.text(*message);
+                assert!(!text.is_empty(), "{message:?} is empty in {locale:?}");
+                assert_eq!(
+                    placeholders(text),
+                    placeholders(english),
+                    "{message:?} has the wrong placeholders in {locale:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_locale_from_str() {
+        assert_eq!("en".parse::<Locale>().unwrap(), Locale::En);
+        assert_eq!("C".parse::<Locale>().unwrap(), Locale::En);
+        assert_eq!("de".parse::<Locale>().unwrap(), Locale::De);
+        assert_eq!("de_DE.UTF-8".parse::<Locale>().unwrap(), Locale::De);
+        assert_eq!("de-AT".parse::<Locale>().unwrap(), Locale::De);
+        assert!("fr".parse::<Locale>().is_err());
+        assert!("".parse::<Locale>().is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        // NOTE: Tests never change the global locale, so this is always English.
+        assert_eq!(
+            format(Message::ErrorNeedsConfirmation, &[&12, &10]),
+            "refusing to affect 12 records without confirmation \
+             (more than confirm-threshold 10); pass --yes to confirm"
+        );
+        assert_eq!(format(Message::ConfirmMerge, &[]), "Apply this merge?");
+    }
+}
@@ -0,0 +1,282 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::kbs2::session::Session;
+
+/// The stringified names of the conflict strategies known to `kbs2`'s importers.
+pub static CONFLICT_STRATEGIES: &[&str] = &["skip", "overwrite", "rename", "newer"];
+
+/// How an importer handles an incoming record whose label is already in the store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Keep the stored record and discard the incoming one.
+    Skip,
+
+    /// Replace the stored record with the incoming one.
+    Overwrite,
+
+    /// Import the incoming record under a new label, with a numeric suffix.
+    Rename,
+
+    /// Keep whichever of the stored and incoming records was modified more recently.
+    Newer,
+}
+
+impl FromStr for ConflictStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(ConflictStrategy::Skip),
+            "overwrite" => Ok(ConflictStrategy::Overwrite),
+            "rename" => Ok(ConflictStrategy::Rename),
+            // NOTE(ww): "newest" is accepted for the benefit of users who guess it.
+            "newer" | "newest" => Ok(ConflictStrategy::Newer),
+            _ => Err(anyhow!("unknown conflict strategy: {}", s)),
+        }
+    }
+}
+
+/// The decision made for a single incoming record.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// The label isn't in the store yet; add the record under it.
+    Add(String),
+
+    /// The label is in the store; replace the stored record.
+    Overwrite(String),
+
+    /// The label is in the store; add the record under the given fresh label.
+    Rename(String),
+
+    /// The label is in the store; leave it alone.
+    Skip,
+}
+
+/// Decides what to do with an incoming record labeled `label`.
+///
+/// `incoming_timestamp` is the incoming record's modification time, if the source format
+/// has one; it's only consulted by `ConflictStrategy::Newer`. When `strategy` is `None`,
+/// conflicts are treated as errors.
+pub fn resolve_conflict(
+    session: &Session,
+    label: &str,
+    incoming_timestamp: Option<u64>,
+    strategy: Option<ConflictStrategy>,
+) -> Result<Resolution> {
+    if !session.has_record(label) {
+        return Ok(Resolution::Add(label.into()));
+    }
+
+    match strategy {
+        None => Err(anyhow!(
+            "refusing to overwrite {} without --force or --on-conflict",
+            label
+        )),
+        Some(ConflictStrategy::Skip) => Ok(Resolution::Skip),
+        Some(ConflictStrategy::Overwrite) => Ok(Resolution::Overwrite(label.into())),
+        Some(ConflictStrategy::Rename) => {
+            let mut suffix = 1;
+            loop {
+                let candidate = format!("{label}-{suffix}");
+                if !session.has_record(&candidate) {
+                    return Ok(Resolution::Rename(candidate));
+                }
+                suffix += 1;
+            }
+        }
+        Some(ConflictStrategy::Newer) => {
+            let stored_timestamp = session.get_record(label)?.timestamp;
+
+            match incoming_timestamp {
+                Some(timestamp) if timestamp > stored_timestamp => {
+                    Ok(Resolution::Overwrite(label.into()))
+                }
+                _ => Ok(Resolution::Skip),
+            }
+        }
+    }
+}
+
+/// Tallies the resolutions made over the course of an import.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub added: usize,
+    pub overwritten: usize,
+    pub renamed: usize,
+    pub skipped: usize,
+}
+
+impl ImportSummary {
+    /// Records a single resolution.
+    pub fn tally(&mut self, resolution: &Resolution) {
+        match resolution {
+            Resolution::Add(_) => self.added += 1,
+            Resolution::Overwrite(_) => self.overwritten += 1,
+            Resolution::Rename(_) => self.renamed += 1,
+            Resolution::Skip => self.skipped += 1,
+        }
+    }
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} overwritten, {} renamed, {} skipped",
+            self.added, self.overwritten, self.renamed, self.skipped
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::kbs2::record::{LoginFields, Record, RecordBody};
+    use crate::kbs2::session::tests::{dummy_config, dummy_session};
+
+    fn dummy_login(label: &str, timestamp: u64) -> Record {
+        Record {
+            timestamp,
+            created: None,
+            label: label.into(),
+            body: RecordBody::Login(LoginFields {
+                username: "user".into(),
+                password: "pass".into(),
+                extra: Default::default(),
+            }),
+            generated_by: None,
+            totp: None,
+            notes: None,
+            icon: None,
+            custom_fields: None,
+            recipients: None,
+            locked: false,
+            burn: false,
+            extra: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_conflict_strategy_from_str() {
+        for strategy in CONFLICT_STRATEGIES {
+            assert!(strategy.parse::<ConflictStrategy>().is_ok());
+        }
+
+        assert_eq!(
+            "newest".parse::<ConflictStrategy>().unwrap(),
+            ConflictStrategy::Newer
+        );
+        assert!("clobber".parse::<ConflictStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_resolve_conflict_no_conflict() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = dummy_session(&config);
+
+        for strategy in [None, Some(ConflictStrategy::Skip)] {
+            assert_eq!(
+                resolve_conflict(&session, "foo", None, strategy).unwrap(),
+                Resolution::Add("foo".into())
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve_conflict_no_strategy() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = dummy_session(&config);
+        session.add_record(&dummy_login("foo", 100)).unwrap();
+
+        assert!(resolve_conflict(&session, "foo", None, None).is_err());
+    }
+
+    #[test]
+    fn test_resolve_conflict_skip() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = dummy_session(&config);
+        session.add_record(&dummy_login("foo", 100)).unwrap();
+
+        assert_eq!(
+            resolve_conflict(&session, "foo", Some(200), Some(ConflictStrategy::Skip)).unwrap(),
+            Resolution::Skip
+        );
+    }
+
+    #[test]
+    fn test_resolve_conflict_overwrite() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = dummy_session(&config);
+        session.add_record(&dummy_login("foo", 100)).unwrap();
+
+        assert_eq!(
+            resolve_conflict(&session, "foo", Some(50), Some(ConflictStrategy::Overwrite)).unwrap(),
+            Resolution::Overwrite("foo".into())
+        );
+    }
+
+    #[test]
+    fn test_resolve_conflict_rename() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = dummy_session(&config);
+        session.add_record(&dummy_login("foo", 100)).unwrap();
+
+        assert_eq!(
+            resolve_conflict(&session, "foo", None, Some(ConflictStrategy::Rename)).unwrap(),
+            Resolution::Rename("foo-1".into())
+        );
+
+        session.add_record(&dummy_login("foo-1", 100)).unwrap();
+
+        assert_eq!(
+            resolve_conflict(&session, "foo", None, Some(ConflictStrategy::Rename)).unwrap(),
+            Resolution::Rename("foo-2".into())
+        );
+    }
+
+    #[test]
+    fn test_resolve_conflict_newer() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = dummy_session(&config);
+        session.add_record(&dummy_login("foo", 100)).unwrap();
+
+        assert_eq!(
+            resolve_conflict(&session, "foo", Some(200), Some(ConflictStrategy::Newer)).unwrap(),
+            Resolution::Overwrite("foo".into())
+        );
+
+        for timestamp in [Some(100), Some(50), None] {
+            assert_eq!(
+                resolve_conflict(&session, "foo", timestamp, Some(ConflictStrategy::Newer))
+                    .unwrap(),
+                Resolution::Skip
+            );
+        }
+    }
+
+    #[test]
+    fn test_import_summary() {
+        let mut summary = ImportSummary::default();
+
+        summary.tally(&Resolution::Add("a".into()));
+        summary.tally(&Resolution::Add("b".into()));
+        summary.tally(&Resolution::Rename("c-1".into()));
+        summary.tally(&Resolution::Skip);
+
+        assert_eq!(
+            summary.to_string(),
+            "2 added, 0 overwritten, 1 renamed, 1 skipped"
+        );
+    }
+}
@@ -1,22 +1,224 @@
-use std::io::{self, Read};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, stdin, IsTerminal, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use anyhow::{anyhow, Result};
-use inquire::{Password as Pass, Text};
+use inquire::error::{InquireError, InquireResult};
+use inquire::ui::{Color, RenderConfig, StyleSheet};
+use inquire::validator::Validation;
+use inquire::{Confirm, Password as Pass, Text};
+
+use super::record::{
+    self, EnvironmentFields, LoginFields, RecordBody, TotpFields, UnstructuredFields,
+};
+use crate::kbs2::config::{HighlightColor, RuntimeConfig, UiConfig};
+use crate::kbs2::i18n::{self, Message};
+use crate::kbs2::util;
+
+/// The default input separator used when input is gathered in "terse" mode.
+pub static TERSE_IFS: &str = "\x01";
 
-use super::record::{EnvironmentFields, LoginFields, RecordBody, UnstructuredFields};
-use crate::kbs2::config::RuntimeConfig;
-use crate::kbs2::generator::Generator;
+/// The record fields that can be supplied directly on the command line, each paired
+/// with the name of the argument that reads that field from `stdin` instead.
+pub static FIELD_ARGS: &[(&str, &str)] = &[
+    ("username", "username-stdin"),
+    ("password", "password-stdin"),
+    ("variable", "variable-stdin"),
+    ("value", "value-stdin"),
+    ("contents", "contents-stdin"),
+    ("secret", "secret-stdin"),
+];
+
+/// The maximum size of a file supplied via `--field-file`, in bytes.
+pub const MAX_FIELD_FILE_SIZE: u64 = 1024 * 1024;
+
+/// The error produced when the user interrupts (or cancels) an interactive prompt.
+#[derive(Debug)]
+pub struct Aborted;
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", i18n::text(Message::ErrorAborted))
+    }
+}
 
-/// The input separator used when input is gathered in "terse" mode.
-pub static TERSE_IFS: &str = "\x01";
+impl std::error::Error for Aborted {}
+
+/// Whether or not prompts display help messages; see `configure_prompts`.
+static SHOW_HELP: AtomicBool = AtomicBool::new(true);
+
+/// Applies the given `[ui]` settings to every subsequent interactive prompt.
+pub fn configure_prompts(ui: &UiConfig) {
+    // NOTE(ww): inquire only takes a `'static` prompt prefix. This is called at most a
+    // handful of times per run, so leaking the configured one is fine.
+    let prefix: &'static str = Box::leak(ui.prompt_prefix.clone().into_boxed_str());
+
+    let mut render = match ui.highlight_color {
+        Some(HighlightColor::None) => RenderConfig::empty(),
+        _ => RenderConfig::default(),
+    };
+    render.prompt_prefix.content = prefix;
+
+    // NOTE(ww): `RenderConfig::default` honors `$NO_COLOR`, so we do too.
+    if let Some(color) = ui.highlight_color.and_then(color) {
+        if env::var_os("NO_COLOR").is_none() {
+            render.prompt_prefix.style = StyleSheet::new().with_fg(color);
+            render.answered_prompt_prefix.style = StyleSheet::new().with_fg(color);
+            render.highlighted_option_prefix.style = StyleSheet::new().with_fg(color);
+            render.help_message = StyleSheet::new().with_fg(color);
+            render.answer = StyleSheet::new().with_fg(color);
+            render.selected_option = Some(StyleSheet::new().with_fg(color));
+        }
+    }
+
+    inquire::set_global_render_config(render);
+    SHOW_HELP.store(ui.show_help, Ordering::Relaxed);
+}
+
+/// Returns the inquire color for the given highlight color, if it has one.
+#[doc(hidden)]
+fn color(highlight: HighlightColor) -> Option<Color> {
+    Some(match highlight {
+        HighlightColor::None => return None,
+        HighlightColor::Black => Color::Black,
+        HighlightColor::LightRed => Color::LightRed,
+        HighlightColor::DarkRed => Color::DarkRed,
+        HighlightColor::LightGreen => Color::LightGreen,
+        HighlightColor::DarkGreen => Color::DarkGreen,
+        HighlightColor::LightYellow => Color::LightYellow,
+        HighlightColor::DarkYellow => Color::DarkYellow,
+        HighlightColor::LightBlue => Color::LightBlue,
+        HighlightColor::DarkBlue => Color::DarkBlue,
+        HighlightColor::LightMagenta => Color::LightMagenta,
+        HighlightColor::DarkMagenta => Color::DarkMagenta,
+        HighlightColor::LightCyan => Color::LightCyan,
+        HighlightColor::DarkCyan => Color::DarkCyan,
+        HighlightColor::White => Color::White,
+        HighlightColor::Grey => Color::Grey,
+        HighlightColor::DarkGrey => Color::DarkGrey,
+    })
+}
+
+/// Adds a help message to an interactive prompt, unless `ui.show-help` is disabled.
+pub trait WithHelp<'a>: Sized {
+    fn with_help(self, message: &'a str) -> Self;
+}
+
+macro_rules! impl_with_help {
+    ($($prompt:ident),*) => {
+        $(
+            impl<'a> WithHelp<'a> for $prompt<'a> {
+                fn with_help(self, message: &'a str) -> Self {
+                    match SHOW_HELP.load(Ordering::Relaxed) {
+                        true => self.with_help_message(message),
+                        false => self,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_with_help!(Confirm, Pass, Text);
+
+/// Runs an interactive prompt, failing immediately if there's no terminal to prompt on
+/// (rather than waiting forever for an answer), and turning an interruption (Ctrl-C)
+/// or cancellation (Esc) into `Aborted`.
+pub fn prompted<T>(prompt: impl FnOnce() -> InquireResult<T>) -> Result<T> {
+    if !stdin().is_terminal() {
+        return Err(anyhow!(i18n::text(Message::ErrorNotInteractive)));
+    }
+
+    finished(prompt())
+}
+
+/// Like `prompted`, but only requires a controlling terminal rather than a terminal
+/// on `stdin`. This is for prompts issued on behalf of age plugins, which can run while
+/// `stdin` is busy supplying something else (e.g. a record being imported).
+pub fn prompted_on_tty<T>(prompt: impl FnOnce() -> InquireResult<T>) -> Result<T> {
+    if OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .is_err()
+    {
+        return Err(anyhow!(i18n::text(Message::ErrorNotInteractive)));
+    }
+
+    finished(prompt())
+}
+
+/// Converts the result of an interactive prompt into a `Result`; see `prompted`.
+#[doc(hidden)]
+fn finished<T>(result: InquireResult<T>) -> Result<T> {
+    match result {
+        Err(InquireError::OperationInterrupted | InquireError::OperationCanceled) => {
+            Err(Aborted.into())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Prompts for ad-hoc fields, as name and value pairs, until the user enters a blank name.
+pub fn custom_fields() -> Result<BTreeMap<String, String>> {
+    let mut fields = BTreeMap::new();
+
+    loop {
+        let name = prompted(|| {
+            Text::new(i18n::text(Message::PromptExtraFieldName))
+                .with_help(i18n::text(Message::HelpDoneWithFields))
+                .prompt()
+        })?;
+        let name = name.trim();
+
+        if name.is_empty() {
+            return Ok(fields);
+        }
+
+        if fields.contains_key(name) {
+            util::warn(&format!("already added a field named {name}"));
+            continue;
+        }
+
+        let value = prompted(|| Text::new(&format!("{name}?")).prompt())?;
+        fields.insert(name.into(), value);
+    }
+}
+
+/// Prompts for a record's icon, returning `None` if the user enters a blank one.
+pub fn icon() -> Result<Option<String>> {
+    let icon = prompted(|| {
+        Text::new(i18n::text(Message::PromptIcon))
+            .with_help(i18n::text(Message::HelpIcon))
+            .with_validator(|icon: &str| {
+                Ok(match icon.trim() {
+                    "" => Validation::Valid,
+                    icon => match record::check_icon(icon) {
+                        Ok(()) => Validation::Valid,
+                        Err(e) => Validation::Invalid(e.to_string().into()),
+                    },
+                })
+            })
+            .prompt()
+    })?;
+
+    Ok(Some(icon.trim().to_string()).filter(|icon| !icon.is_empty()))
+}
 
 pub trait Input {
     const FIELD_COUNT: usize;
+    const FIELDS: &'static [&'static str];
 
     fn from_prompt(config: &RuntimeConfig) -> Result<RecordBody>;
     fn from_terse(config: &RuntimeConfig) -> Result<RecordBody>;
+    fn from_args(config: &RuntimeConfig) -> Result<RecordBody>;
+
+    fn take_terse_fields(config: &RuntimeConfig) -> Result<Vec<String>> {
+        let separator = config.terse_separator()?;
 
-    fn take_terse_fields() -> Result<Vec<String>> {
         let mut input = String::new();
         io::stdin().read_to_string(&mut input)?;
 
@@ -25,7 +227,7 @@ pub trait Input {
         }
 
         let fields = input
-            .splitn(Self::FIELD_COUNT, TERSE_IFS)
+            .splitn(Self::FIELD_COUNT, separator)
             .map(Into::into)
             .collect::<Vec<String>>();
 
@@ -37,11 +239,22 @@ pub trait Input {
             ));
         }
 
+        // NOTE(ww): The last field gets everything after the previous separator, so another
+        // separator in it usually means that the input had more fields than expected.
+        if Self::FIELD_COUNT > 1 && fields.last().is_some_and(|f| f.contains(separator)) {
+            util::warn(&format!(
+                "the last field contains the terse separator ({separator:?}); keeping it as-is"
+            ));
+        }
+
         Ok(fields)
     }
 
     fn input(config: &RuntimeConfig) -> Result<RecordBody> {
-        if config.terse() {
+        if config.has_field_args() {
+            config.check_field_args(Self::FIELDS)?;
+            Self::from_args(config)
+        } else if config.terse() {
             Self::from_terse(config)
         } else {
             Self::from_prompt(config)
@@ -51,102 +264,222 @@ pub trait Input {
 
 impl Input for LoginFields {
     const FIELD_COUNT: usize = 2;
+    const FIELDS: &'static [&'static str] = &["username", "password"];
 
     fn from_prompt(config: &RuntimeConfig) -> Result<RecordBody> {
         let username = if let Some(default_username) = &config.config.commands.new.default_username
         {
-            Text::new("Username?")
-                .with_default(default_username)
-                .prompt()?
+            prompted(|| {
+                Text::new(i18n::text(Message::PromptUsername))
+                    .with_default(default_username)
+                    .prompt()
+            })?
         } else {
-            Text::new("Username?").prompt()?
+            prompted(|| Text::new(i18n::text(Message::PromptUsername)).prompt())?
         };
 
-        let mut password = Pass::new("Password?")
-            .with_help_message("Press [enter] to auto-generate")
-            .without_confirmation()
-            .prompt()?;
-
-        if password.is_empty() {
-            password = config.generator()?.secret()?;
-        }
+        let password = if config.force_generate() {
+            config.generate()?
+        } else {
+            let password = prompted(|| {
+                Pass::new(i18n::text(Message::PromptPassword))
+                    .with_help(i18n::text(Message::HelpAutoGenerate))
+                    .without_confirmation()
+                    .prompt()
+            })?;
+
+            if password.is_empty() {
+                config.generate()?
+            } else {
+                password
+            }
+        };
 
-        Ok(RecordBody::Login(LoginFields { username, password }))
+        Ok(RecordBody::Login(LoginFields {
+            username,
+            password,
+            extra: Default::default(),
+        }))
     }
 
     fn from_terse(config: &RuntimeConfig) -> Result<RecordBody> {
         // NOTE: Backwards order here because we're popping from the vector.
         let (mut password, username) = {
-            let mut fields = Self::take_terse_fields()?;
+            let mut fields = Self::take_terse_fields(config)?;
 
             // Unwrap safety: take_terse_fields checks FIELD_COUNT to ensure sufficient elements.
             #[allow(clippy::unwrap_used)]
             (fields.pop().unwrap(), fields.pop().unwrap())
         };
 
+        if !password.is_empty() && config.force_generate() {
+            return Err(anyhow!(
+                "password can't be supplied when commands.new.force-generate is enabled"
+            ));
+        }
+
         if password.is_empty() {
-            password = config.generator()?.secret()?;
+            password = config.generate()?;
         }
 
-        Ok(RecordBody::Login(LoginFields { username, password }))
+        Ok(RecordBody::Login(LoginFields {
+            username,
+            password,
+            extra: Default::default(),
+        }))
+    }
+
+    fn from_args(config: &RuntimeConfig) -> Result<RecordBody> {
+        let username = config.field_arg("username", false)?;
+        let password = config.field_arg("password", true)?;
+
+        Ok(RecordBody::Login(LoginFields {
+            username,
+            password,
+            extra: Default::default(),
+        }))
     }
 }
 
 impl Input for EnvironmentFields {
     const FIELD_COUNT: usize = 2;
+    const FIELDS: &'static [&'static str] = &["variable", "value"];
 
     fn from_prompt(config: &RuntimeConfig) -> Result<RecordBody> {
-        let variable = Text::new("Variable?").prompt()?;
-        let mut value = Pass::new("Value?")
-            .with_help_message("Press [enter] to auto-generate")
-            .prompt()?;
-
-        if value.is_empty() {
-            value = config.generator()?.secret()?;
-        }
+        let variable = prompted(|| Text::new(i18n::text(Message::PromptVariable)).prompt())?;
+        let value = if config.force_generate() {
+            config.generate()?
+        } else {
+            let value = prompted(|| {
+                Pass::new(i18n::text(Message::PromptValue))
+                    .with_help(i18n::text(Message::HelpAutoGenerate))
+                    .prompt()
+            })?;
+
+            if value.is_empty() {
+                config.generate()?
+            } else {
+                value
+            }
+        };
 
         Ok(RecordBody::Environment(EnvironmentFields {
             variable,
             value,
+            extra: Default::default(),
         }))
     }
 
     fn from_terse(config: &RuntimeConfig) -> Result<RecordBody> {
         // NOTE: Backwards order here because we're popping from the vector.
         let (mut value, variable) = {
-            let mut fields = Self::take_terse_fields()?;
+            let mut fields = Self::take_terse_fields(config)?;
 
             // Unwrap safety: take_terse_fields checks FIELD_COUNT to ensure sufficient elements.
             #[allow(clippy::unwrap_used)]
             (fields.pop().unwrap(), fields.pop().unwrap())
         };
 
+        if !value.is_empty() && config.force_generate() {
+            return Err(anyhow!(
+                "value can't be supplied when commands.new.force-generate is enabled"
+            ));
+        }
+
         if value.is_empty() {
-            value = config.generator()?.secret()?;
+            value = config.generate()?;
         }
 
         Ok(RecordBody::Environment(EnvironmentFields {
             variable,
             value,
+            extra: Default::default(),
+        }))
+    }
+
+    fn from_args(config: &RuntimeConfig) -> Result<RecordBody> {
+        let variable = config.field_arg("variable", false)?;
+        let value = config.field_arg("value", true)?;
+
+        Ok(RecordBody::Environment(EnvironmentFields {
+            variable,
+            value,
+            extra: Default::default(),
         }))
     }
 }
 
 impl Input for UnstructuredFields {
     const FIELD_COUNT: usize = 1;
+    const FIELDS: &'static [&'static str] = &["contents"];
 
     fn from_prompt(_config: &RuntimeConfig) -> Result<RecordBody> {
-        let contents = Text::new("Contents?").prompt()?;
+        let contents = prompted(|| Text::new(i18n::text(Message::PromptContents)).prompt())?;
+
+        Ok(RecordBody::Unstructured(UnstructuredFields {
+            contents,
+            extra: Default::default(),
+        }))
+    }
+
+    fn from_terse(config: &RuntimeConfig) -> Result<RecordBody> {
+        // Unwrap safety: take_terse_fields checks FIELD_COUNT to ensure sufficient elements.
+        #[allow(clippy::unwrap_used)]
+        let contents = Self::take_terse_fields(config)?.pop().unwrap();
+
+        Ok(RecordBody::Unstructured(UnstructuredFields {
+            contents,
+            extra: Default::default(),
+        }))
+    }
 
-        Ok(RecordBody::Unstructured(UnstructuredFields { contents }))
+    fn from_args(config: &RuntimeConfig) -> Result<RecordBody> {
+        let contents = config.field_arg("contents", false)?;
+
+        Ok(RecordBody::Unstructured(UnstructuredFields {
+            contents,
+            extra: Default::default(),
+        }))
+    }
+}
+
+impl Input for TotpFields {
+    const FIELD_COUNT: usize = 1;
+    const FIELDS: &'static [&'static str] = &["secret"];
+
+    fn from_prompt(_config: &RuntimeConfig) -> Result<RecordBody> {
+        let secret = prompted(|| {
+            Pass::new(i18n::text(Message::PromptSecret))
+                .with_help(i18n::text(Message::HelpTotpSecret))
+                .without_confirmation()
+                .prompt()
+        })?;
+
+        Ok(RecordBody::Totp(TotpFields {
+            secret,
+            extra: Default::default(),
+        }))
     }
 
-    fn from_terse(_config: &RuntimeConfig) -> Result<RecordBody> {
+    fn from_terse(config: &RuntimeConfig) -> Result<RecordBody> {
         // Unwrap safety: take_terse_fields checks FIELD_COUNT to ensure sufficient elements.
         #[allow(clippy::unwrap_used)]
-        let contents = Self::take_terse_fields()?.pop().unwrap();
+        let secret = Self::take_terse_fields(config)?.pop().unwrap();
 
-        Ok(RecordBody::Unstructured(UnstructuredFields { contents }))
+        Ok(RecordBody::Totp(TotpFields {
+            secret,
+            extra: Default::default(),
+        }))
+    }
+
+    fn from_args(config: &RuntimeConfig) -> Result<RecordBody> {
+        // NOTE(ww): TOTP secrets are issued by the service, so generating one makes no sense.
+        let secret = config.field_arg("secret", false)?;
+
+        Ok(RecordBody::Totp(TotpFields {
+            secret,
+            extra: Default::default(),
+        }))
     }
 }
 
@@ -259,3 +592,23 @@ impl Input for UnstructuredFields {
 //         interactive_fields(names, config, generator)
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finished() {
+        assert_eq!(finished(Ok(1)).unwrap(), 1);
+
+        for err in [
+            InquireError::OperationInterrupted,
+            InquireError::OperationCanceled,
+        ] {
+            assert!(finished::<()>(Err(err)).unwrap_err().is::<Aborted>());
+        }
+
+        let err = finished::<()>(Err(InquireError::NotTTY)).unwrap_err();
+        assert!(!err.is::<Aborted>());
+    }
+}
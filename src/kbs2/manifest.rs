@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// The basename of the manifest, within the store.
+pub const MANIFEST_BASENAME: &str = ".manifest.age";
+
+/// A list of every record in the store, as of the last time that `kbs2` changed it.
+///
+/// The manifest is kept encrypted in the store (see `MANIFEST_BASENAME`), but only to the
+/// store's public key: it isn't authenticated, so anybody who knows the public key can write
+/// a new manifest to cover up a change to the records. It catches accidental changes (e.g.
+/// by a misbehaving sync), not deliberate ones.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Each record's label, and the SHA-256 of its encrypted contents.
+    pub records: BTreeMap<String, String>,
+}
+
+/// A difference between the manifest and the store's actual contents.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The record is in the manifest, but not in the store.
+    Missing(String),
+
+    /// The record is in the store, but not in the manifest.
+    Unexpected(String),
+
+    /// The record's encrypted contents don't match the manifest.
+    Modified(String),
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Discrepancy::Missing(label) => write!(f, "{label}: deleted outside of kbs2"),
+            Discrepancy::Unexpected(label) => write!(f, "{label}: added outside of kbs2"),
+            Discrepancy::Modified(label) => write!(f, "{label}: modified outside of kbs2"),
+        }
+    }
+}
+
+impl Manifest {
+    /// Hashes a record's encrypted contents, in the form that the manifest records.
+    pub fn hash(mut contents: impl Read) -> Result<String> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut contents, &mut hasher)?;
+
+        Ok(data_encoding::HEXLOWER.encode(&hasher.finalize()))
+    }
+
+    /// Returns every difference between this manifest and `actual`, the manifest of the
+    /// store as it is now, ordered by label.
+    pub fn discrepancies(&self, actual: &Manifest) -> Vec<Discrepancy> {
+        let mut discrepancies = vec![];
+
+        for (label, hash) in &self.records {
+            match actual.records.get(label) {
+                None => discrepancies.push(Discrepancy::Missing(label.clone())),
+                Some(actual) if actual != hash => {
+                    discrepancies.push(Discrepancy::Modified(label.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+
+        for label in actual.records.keys() {
+            if !self.records.contains_key(label) {
+                discrepancies.push(Discrepancy::Unexpected(label.clone()));
+            }
+        }
+
+        discrepancies.sort_by(|a, b| a.label().cmp(b.label()));
+        discrepancies
+    }
+}
+
+impl Discrepancy {
+    /// Returns the label of the record that this discrepancy is about.
+    pub fn label(&self) -> &str {
+        match self {
+            Discrepancy::Missing(label)
+            | Discrepancy::Unexpected(label)
+            | Discrepancy::Modified(label) => label,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(records: &[(&str, &str)]) -> Manifest {
+        Manifest {
+            records: records
+                .iter()
+                .map(|(label, hash)| (label.to_string(), hash.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(
+            Manifest::hash("".as_bytes()).unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_ne!(
+            Manifest::hash("foo".as_bytes()).unwrap(),
+            Manifest::hash("bar".as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_discrepancies() {
+        let expected = manifest(&[("a", "1"), ("b", "2"), ("c", "3")]);
+
+        assert!(expected.discrepancies(&expected).is_empty());
+        assert!(Manifest::default()
+            .discrepancies(&Manifest::default())
+            .is_empty());
+
+        let actual = manifest(&[("b", "2"), ("c", "4"), ("d", "5")]);
+        assert_eq!(
+            expected.discrepancies(&actual),
+            vec![
+                Discrepancy::Missing("a".into()),
+                Discrepancy::Modified("c".into()),
+                Discrepancy::Unexpected("d".into()),
+            ]
+        );
+        assert_eq!(
+            expected
+                .discrepancies(&actual)
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>(),
+            [
+                "a: deleted outside of kbs2",
+                "c: modified outside of kbs2",
+                "d: added outside of kbs2"
+            ]
+        );
+    }
+}
@@ -0,0 +1,419 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::kbs2::record::Record;
+
+/// The stringified names of the preferences that `kbs2 rename --merge` accepts.
+pub static MERGE_PREFERENCES: &[&str] = &["src", "dst", "newer"];
+
+/// One of the two records being merged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    /// The record being merged (and then removed).
+    Src,
+
+    /// The record being merged into.
+    Dst,
+}
+
+/// How conflicting fields are resolved automatically.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preference {
+    /// Always keep the source record's field.
+    Src,
+
+    /// Always keep the destination record's field.
+    Dst,
+
+    /// Keep the fields of whichever record was modified more recently.
+    Newer,
+}
+
+impl FromStr for Preference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "src" => Ok(Preference::Src),
+            "dst" => Ok(Preference::Dst),
+            "newer" => Ok(Preference::Newer),
+            _ => Err(anyhow!("unknown merge preference: {}", s)),
+        }
+    }
+}
+
+impl Preference {
+    /// Returns the side that this preference picks, for the given records.
+    pub fn side(&self, src: &Record, dst: &Record) -> Side {
+        match self {
+            Preference::Src => Side::Src,
+            Preference::Dst => Side::Dst,
+            // NOTE(ww): Ties go to the destination, since that's the record being kept.
+            Preference::Newer if src.timestamp > dst.timestamp => Side::Src,
+            Preference::Newer => Side::Dst,
+        }
+    }
+}
+
+/// A field whose value differs between the two records being merged.
+#[derive(Debug)]
+pub struct FieldConflict {
+    /// The field's name, e.g. `"password"`, or `"totp"`, `"notes"`, `"icon"`, and
+    /// `"recipients"` for the record's linked TOTP record, notes, icon, and extra recipients.
+    pub field: &'static str,
+
+    /// The field's value in the source record.
+    pub src: String,
+
+    /// The field's value in the destination record.
+    pub dst: String,
+
+    /// Whether the field holds a secret, and shouldn't be displayed.
+    pub sensitive: bool,
+}
+
+impl FieldConflict {
+    /// Returns the value on the given side.
+    pub fn value(&self, side: Side) -> &str {
+        match side {
+            Side::Src => &self.src,
+            Side::Dst => &self.dst,
+        }
+    }
+
+    /// Returns the value on the given side, in a form that's safe to display.
+    pub fn display(&self, side: Side) -> String {
+        let value = self.value(side);
+        match (value.is_empty(), self.sensitive) {
+            (true, _) => "(empty)".into(),
+            (false, true) => format!("(hidden, {} characters)", value.chars().count()),
+            (false, false) => format!("{value:?}"),
+        }
+    }
+
+    /// Returns the side that an empty field should be filled from, if exactly one side
+    /// of this conflict is empty.
+    ///
+    /// An empty field is never worth keeping over a non-empty one, so these don't need
+    /// to be resolved by the user.
+    pub fn fill(&self) -> Option<Side> {
+        // NOTE(ww): Except for recipients: sharing the merged record with someone new
+        // is for the user to decide.
+        if self.field == "recipients" {
+            return None;
+        }
+
+        match (self.src.is_empty(), self.dst.is_empty()) {
+            (false, true) => Some(Side::Src),
+            (true, false) => Some(Side::Dst),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FieldConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} / {}",
+            self.field,
+            self.display(Side::Src),
+            self.display(Side::Dst)
+        )
+    }
+}
+
+/// Returns the fields that differ between `src` and `dst`.
+///
+/// Records of different kinds can't be merged.
+pub fn field_conflicts(src: &Record, dst: &Record) -> Result<Vec<FieldConflict>> {
+    if src.body.kind() != dst.body.kind() {
+        return Err(anyhow!(
+            "can't merge records of different kinds ({} and {})",
+            src.body,
+            dst.body
+        ));
+    }
+
+    let sensitive = dst.body.sensitive_field();
+    let mut conflicts: Vec<_> = dst
+        .body
+        .fields()
+        .iter()
+        .filter_map(|field| {
+            let src = src.body.field(field).unwrap_or_default();
+            let dst = dst.body.field(field).unwrap_or_default();
+
+            (src != dst).then(|| FieldConflict {
+                field,
+                src: src.into(),
+                dst: dst.into(),
+                sensitive: *field == sensitive,
+            })
+        })
+        .collect();
+
+    if src.totp != dst.totp {
+        conflicts.push(FieldConflict {
+            field: "totp",
+            src: src.totp.clone().unwrap_or_default(),
+            dst: dst.totp.clone().unwrap_or_default(),
+            sensitive: false,
+        });
+    }
+
+    if src.notes != dst.notes {
+        conflicts.push(FieldConflict {
+            field: "notes",
+            src: src.notes.clone().unwrap_or_default(),
+            dst: dst.notes.clone().unwrap_or_default(),
+            sensitive: false,
+        });
+    }
+
+    if src.icon != dst.icon {
+        conflicts.push(FieldConflict {
+            field: "icon",
+            src: src.icon.clone().unwrap_or_default(),
+            dst: dst.icon.clone().unwrap_or_default(),
+            sensitive: false,
+        });
+    }
+
+    // NOTE(ww): Recipients are a set, so they're compared without regard to their order.
+    let recipients = |record: &Record| {
+        let mut recipients = record.recipients.clone().unwrap_or_default();
+        recipients.sort();
+        recipients.join(", ")
+    };
+    let (src_recipients, dst_recipients) = (recipients(src), recipients(dst));
+    if src_recipients != dst_recipients {
+        conflicts.push(FieldConflict {
+            field: "recipients",
+            src: src_recipients,
+            dst: dst_recipients,
+            sensitive: false,
+        });
+    }
+
+    Ok(conflicts)
+}
+
+/// Merges `src` into `dst`, taking each conflicting field from the side chosen for it.
+///
+/// `choices` holds one side for each of `conflicts`, in order. The merged record keeps
+/// `dst`'s label, and the earlier of the two creation times. Envelope fields that this
+/// version of `kbs2` doesn't understand are kept from both records, preferring `dst`'s.
+pub fn merge(
+    src: Record,
+    mut dst: Record,
+    conflicts: &[FieldConflict],
+    choices: &[Side],
+) -> Result<Record> {
+    if conflicts.len() != choices.len() {
+        return Err(anyhow!(
+            "expected {} merge choices, got {}",
+            conflicts.len(),
+            choices.len()
+        ));
+    }
+
+    for (conflict, side) in conflicts.iter().zip(choices) {
+        if *side == Side::Dst {
+            continue;
+        }
+
+        if conflict.field == "totp" {
+            dst.totp = src.totp.clone();
+        } else if conflict.field == "notes" {
+            dst.notes = src.notes.clone();
+        } else if conflict.field == "icon" {
+            dst.icon = src.icon.clone();
+        } else if conflict.field == "recipients" {
+            dst.recipients = src.recipients.clone();
+        } else if let Some(field) = dst.body.field_mut(conflict.field) {
+            *field = conflict.src.clone();
+        }
+
+        if conflict.sensitive {
+            dst.generated_by = src.generated_by.clone();
+        }
+    }
+
+    dst.created = Some(src.created().min(dst.created()));
+    if let Some(src_fields) = src.custom_fields {
+        let dst_fields = dst.custom_fields.get_or_insert_with(Default::default);
+        for (name, value) in src_fields {
+            dst_fields.entry(name).or_insert(value);
+        }
+    }
+    for (key, value) in src.extra {
+        dst.extra.entry(key).or_insert(value);
+    }
+
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::kbs2::record::{EnvironmentFields, LoginFields, RecordBody};
+
+    fn dummy_login(label: &str, username: &str, password: &str, timestamp: u64) -> Record {
+        Record {
+            timestamp,
+            created: Some(timestamp),
+            label: label.into(),
+            body: RecordBody::Login(LoginFields {
+                username: username.into(),
+                password: password.into(),
+                extra: Default::default(),
+            }),
+            generated_by: None,
+            totp: None,
+            notes: None,
+            icon: None,
+            custom_fields: None,
+            recipients: None,
+            locked: false,
+            burn: false,
+            extra: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_field_conflicts() {
+        let src = dummy_login("github-2", "jdoe", "hunter2", 2);
+        let dst = dummy_login("github", "", "swordfish", 1);
+
+        let conflicts = field_conflicts(&src, &dst).unwrap();
+        assert_eq!(conflicts.len(), 2);
+
+        assert_eq!(conflicts[0].field, "username");
+        assert_eq!(conflicts[0].fill(), Some(Side::Src));
+        assert_eq!(conflicts[0].to_string(), "username: \"jdoe\" / (empty)");
+
+        assert_eq!(conflicts[1].field, "password");
+        assert!(conflicts[1].fill().is_none());
+        assert_eq!(
+            conflicts[1].to_string(),
+            "password: (hidden, 7 characters) / (hidden, 9 characters)"
+        );
+
+        // Identical records have nothing to resolve.
+        let same = dummy_login("github-3", "", "swordfish", 5);
+        assert!(field_conflicts(&same, &dst).unwrap().is_empty());
+
+        // Records of different kinds can't be merged at all.
+        let mut env = dummy_login("env", "", "", 1);
+        env.body = RecordBody::Environment(EnvironmentFields {
+            variable: "FOO".into(),
+            value: "bar".into(),
+            extra: Default::default(),
+        });
+        assert_eq!(
+            field_conflicts(&env, &dst).unwrap_err().to_string(),
+            "can't merge records of different kinds (environment and login)"
+        );
+    }
+
+    #[test]
+    fn test_preference_side() {
+        let older = dummy_login("a", "", "", 1);
+        let newer = dummy_login("b", "", "", 2);
+
+        assert_eq!(Preference::Src.side(&older, &newer), Side::Src);
+        assert_eq!(Preference::Dst.side(&newer, &older), Side::Dst);
+        assert_eq!(Preference::Newer.side(&newer, &older), Side::Src);
+        assert_eq!(Preference::Newer.side(&older, &newer), Side::Dst);
+        assert_eq!(Preference::Newer.side(&older, &older), Side::Dst);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut src = dummy_login("github-2", "jdoe", "hunter2", 2);
+        src.totp = Some("github-totp".into());
+        src.generated_by = Some("default".into());
+        src.custom_fields = Some(BTreeMap::from([
+            ("pin".into(), "1234".into()),
+            ("recovery email".into(), "old@example.com".into()),
+        ]));
+        let mut dst = dummy_login("github", "", "swordfish", 3);
+        dst.notes = Some("work account".into());
+        dst.custom_fields = Some(BTreeMap::from([(
+            "recovery email".into(),
+            "new@example.com".into(),
+        )]));
+
+        let conflicts = field_conflicts(&src, &dst).unwrap();
+        assert_eq!(conflicts.len(), 4);
+
+        let choices = [Side::Src, Side::Dst, Side::Src, Side::Dst];
+        let merged = merge(src, dst, &conflicts, &choices).unwrap();
+        assert_eq!(merged.label, "github");
+        assert_eq!(merged.created, Some(2));
+        assert_eq!(merged.body.field("username"), Some("jdoe"));
+        assert_eq!(merged.body.field("password"), Some("swordfish"));
+        assert_eq!(merged.totp.as_deref(), Some("github-totp"));
+        assert_eq!(merged.notes.as_deref(), Some("work account"));
+        assert!(merged.generated_by.is_none());
+
+        // Custom fields are combined, preferring the destination's.
+        let custom_fields = merged.custom_fields.unwrap();
+        assert_eq!(custom_fields["pin"], "1234");
+        assert_eq!(custom_fields["recovery email"], "new@example.com");
+    }
+
+    #[test]
+    fn test_merge_icon() {
+        let mut src = dummy_login("github-2", "jdoe", "hunter2", 2);
+        src.icon = Some("github".into());
+        let dst = dummy_login("github", "jdoe", "hunter2", 1);
+
+        // Like any other non-sensitive field, an icon fills in an empty one.
+        let conflicts = field_conflicts(&src, &dst).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].field, "icon");
+        assert_eq!(conflicts[0].fill(), Some(Side::Src));
+        assert_eq!(conflicts[0].to_string(), "icon: \"github\" / (empty)");
+
+        let merged = merge(src, dst, &conflicts, &[Side::Src]).unwrap();
+        assert_eq!(merged.icon.as_deref(), Some("github"));
+    }
+
+    #[test]
+    fn test_merge_recipients() {
+        let records = || {
+            let mut src = dummy_login("github-2", "jdoe", "hunter2", 2);
+            src.recipients = Some(vec!["age1alice".into(), "age1bob".into()]);
+            (src, dummy_login("github", "jdoe", "hunter2", 1))
+        };
+        let (src, mut dst) = records();
+
+        // Recipients never fill in automatically, even when only one side has any.
+        let conflicts = field_conflicts(&src, &dst).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].field, "recipients");
+        assert!(conflicts[0].fill().is_none());
+        assert_eq!(
+            conflicts[0].to_string(),
+            "recipients: \"age1alice, age1bob\" / (empty)"
+        );
+
+        let (a, b) = records();
+        let merged = merge(a, b, &conflicts, &[Side::Src]).unwrap();
+        assert_eq!(merged.recipients, src.recipients);
+
+        let (a, b) = records();
+        let merged = merge(a, b, &conflicts, &[Side::Dst]).unwrap();
+        assert!(merged.recipients.is_none());
+
+        // The order of the recipients doesn't matter.
+        dst.recipients = Some(vec!["age1bob".into(), "age1alice".into()]);
+        assert!(field_conflicts(&src, &dst).unwrap().is_empty());
+    }
+}
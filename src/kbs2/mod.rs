@@ -1,6 +1,9 @@
 /// Structures and routines for the `kbs2` authentication agent.
 pub mod agent;
 
+/// Structures and routines for `kbs2`'s audit log.
+pub mod audit;
+
 /// Structures and routines for interacting with age backends.
 pub mod backend;
 
@@ -10,17 +13,53 @@ pub mod command;
 /// Structures and routines for `kbs2`'s configuration.
 pub mod config;
 
+/// Routines for finding duplicate records.
+pub mod dedupe;
+
+/// Diagnostic checks for `kbs2 doctor`.
+pub mod doctor;
+
 /// Structures and routines for secret generators.
 pub mod generator;
 
+/// The catalog of localized, user-facing messages.
+pub mod i18n;
+
+/// Structures and routines shared by `kbs2`'s record importers.
+pub mod importer;
+
 /// Routines for handling user input.
 pub mod input;
 
+/// Structures and routines for the store's manifest of records.
+pub mod manifest;
+
+/// Structures and routines for merging one `kbs2` record into another.
+pub mod merge;
+
 /// Structures and routines for creating and managing individual `kbs2` records.
 pub mod record;
 
+/// Structures and routines for reporting on bulk operations.
+pub mod report;
+
 /// Structures and routines for creating and managing an active `kbs2` session.
 pub mod session;
 
+/// Structures and routines for the backends that records are stored in.
+pub mod store;
+
+/// Lightweight timing spans for `--timings`.
+pub mod timings;
+
+/// Routines for transient output to the terminal.
+pub mod tty;
+
+/// Opt-in checks for newer releases of `kbs2`.
+pub mod update;
+
 /// Reusable utility code for `kbs2`.
 pub mod util;
+
+/// Structured build and compatibility information for `kbs2 version`.
+pub mod version;
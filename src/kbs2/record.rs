@@ -1,31 +1,426 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+
+use anyhow::{anyhow, Result};
+use clap::builder::{PossibleValue, TypedValueParser};
+use clap::error::ErrorKind;
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
 use secrecy::Zeroize;
 use serde::{Deserialize, Serialize};
+use sha1::Sha1;
 
+use crate::kbs2::input::Input;
 use crate::kbs2::util;
 
-// TODO(ww): Figure out how to generate this from the RecordBody enum below.
-/// The stringified names of record kinds known to `kbs2`.
-pub static RECORD_KINDS: &[&str] = &["login", "environment", "unstructured"];
+/// The version of the record format that this version of `kbs2` reads and writes.
+///
+/// NOTE(ww): Records don't carry this themselves; fields added since the first version are
+/// all optional, and unknown fields are preserved (see `ExtraFields`), so older records
+/// remain readable without migration. This should be bumped if that ever stops being true.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The stringified names of record kinds known to `kbs2`, in the order of `RecordKind::ALL`.
+pub static RECORD_KINDS: &[&str] = &RECORD_KIND_NAMES;
+
+#[doc(hidden)]
+const RECORD_KIND_NAMES: [&str; RecordKind::ALL.len()] = {
+    let mut names = [""; RecordKind::ALL.len()];
+    let mut i = 0;
+    while i < names.len() {
+        names[i] = RecordKind::ALL[i].name();
+        i += 1;
+    }
+    names
+};
+
+/// Defines `RecordKind` from its variants and their names, so that `RecordKind::ALL`
+/// can't fall out of sync with the variants themselves.
+macro_rules! record_kinds {
+    ($($kind:ident => $name:literal),* $(,)?) => {
+        /// The kinds of records known to `kbs2`, i.e. the variants of `RecordBody`.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum RecordKind {
+            $($kind),*
+        }
+
+        impl RecordKind {
+            /// Every record kind.
+            pub const ALL: &'static [RecordKind] = &[$(RecordKind::$kind),*];
+
+            /// Returns this kind's name, e.g. `login`.
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $(RecordKind::$kind => $name),*
+                }
+            }
+        }
+    };
+}
+
+record_kinds! {
+    Login => "login",
+    Environment => "environment",
+    Unstructured => "unstructured",
+    Totp => "totp",
+}
+
+impl RecordKind {
+    /// Resolves a kind as given by a user (e.g. with `--kind`): case-insensitively, and
+    /// allowing any unambiguous prefix of a kind's name (e.g. `Env` for `environment`).
+    pub fn resolve(value: &str) -> Result<Self> {
+        resolve_name(value, RECORD_KINDS)?.parse()
+    }
+}
+
+#[doc(hidden)]
+fn resolve_name<'a>(value: &str, names: &[&'a str]) -> Result<&'a str> {
+    let lowered = value.to_lowercase();
+    if let Some(name) = names.iter().find(|name| **name == lowered) {
+        return Ok(name);
+    }
+
+    let candidates = names
+        .iter()
+        .copied()
+        .filter(|name| !lowered.is_empty() && name.starts_with(&lowered))
+        .collect::<Vec<_>>();
+    match candidates.as_slice() {
+        [name] => Ok(name),
+        [] => Err(anyhow!(
+            "unknown record kind: {} (expected one of: {})",
+            value,
+            names.join(", ")
+        )),
+        _ => Err(anyhow!(
+            "ambiguous record kind: {} (could be any of: {})",
+            value,
+            candidates.join(", ")
+        )),
+    }
+}
+
+impl std::fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for RecordKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        RecordKind::ALL
+            .iter()
+            .find(|kind| kind.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown record kind: {} (expected one of: {})",
+                    s,
+                    RECORD_KINDS.join(", ")
+                )
+            })
+    }
+}
+
+impl ValueEnum for RecordKind {
+    fn value_variants<'a>() -> &'a [Self] {
+        RecordKind::ALL
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(PossibleValue::new(self.name()))
+    }
+}
+
+/// Parses record kinds given on the command line with `RecordKind::resolve`, while still
+/// offering only their canonical names for help and completions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RecordKindParser;
+
+impl TypedValueParser for RecordKindParser {
+    type Value = RecordKind;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        let value = value
+            .to_str()
+            .ok_or_else(|| clap::Error::new(ErrorKind::InvalidUtf8).with_cmd(cmd))?;
+
+        RecordKind::resolve(value).map_err(|e| {
+            let arg = arg.map_or_else(|| "...".into(), |arg| arg.to_string());
+            clap::Error::raw(
+                ErrorKind::InvalidValue,
+                format!("invalid value '{value}' for '{arg}': {e}\n"),
+            )
+            .with_cmd(cmd)
+        })
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(
+            RecordKind::value_variants()
+                .iter()
+                .filter_map(RecordKind::to_possible_value),
+        ))
+    }
+}
+
+/// Fields that this version of `kbs2` doesn't understand, keyed by name.
+///
+/// These are typically added by newer versions of `kbs2`, and are preserved
+/// verbatim so that they survive being round-tripped through an older version.
+pub type ExtraFields = BTreeMap<String, serde_json::Value>;
+
+/// The number of digits in each TOTP code.
+const TOTP_DIGITS: u32 = 6;
+
+/// The number of seconds that each TOTP code is valid for.
+const TOTP_PERIOD: u64 = 30;
+
+/// A problem with one of a record's fields, as found by `field_anomalies`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FieldAnomaly {
+    /// The field is absent (or isn't a string).
+    Missing(&'static str),
+
+    /// The field is present, but empty.
+    Empty(&'static str),
+}
+
+impl FieldAnomaly {
+    /// Returns the name of the anomalous field.
+    pub fn field(&self) -> &'static str {
+        match self {
+            FieldAnomaly::Missing(field) | FieldAnomaly::Empty(field) => field,
+        }
+    }
+}
+
+impl std::fmt::Display for FieldAnomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FieldAnomaly::Missing(field) => write!(f, "missing field: {field}"),
+            FieldAnomaly::Empty(field) => write!(f, "empty field: {field}"),
+        }
+    }
+}
+
+/// Checks a record's JSON form against the fields that its kind expects, returning
+/// any that are missing or empty.
+///
+/// This works on JSON rather than a `Record`, since a record that's missing a field
+/// can't be deserialized in the first place.
+pub fn field_anomalies(record: &serde_json::Value) -> Result<Vec<FieldAnomaly>> {
+    let kind = record["body"]["kind"]
+        .as_str()
+        .ok_or_else(|| anyhow!("record has no kind"))?;
+
+    let expected = match kind {
+        "Login" => LoginFields::FIELDS,
+        "Environment" => EnvironmentFields::FIELDS,
+        "Unstructured" => UnstructuredFields::FIELDS,
+        "Totp" => TotpFields::FIELDS,
+        _ => return Err(anyhow!("unknown record kind: {kind}")),
+    };
+
+    let fields = &record["body"]["fields"];
+    Ok(expected
+        .iter()
+        .filter_map(|field| match fields[field].as_str() {
+            None => Some(FieldAnomaly::Missing(field)),
+            Some("") => Some(FieldAnomaly::Empty(field)),
+            Some(_) => None,
+        })
+        .collect())
+}
 
 /// Represents the envelope of a `kbs2` record.
 #[derive(Debug, Deserialize, PartialEq, Eq, Serialize)]
 pub struct Record {
-    /// When the record was created, as seconds since the Unix epoch.
+    /// When the record was last written, as seconds since the Unix epoch.
     pub timestamp: u64,
 
+    /// When the record was first created, as seconds since the Unix epoch.
+    ///
+    /// Records created by older versions of `kbs2` don't have this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+
     /// The identifying label of the record.
     pub label: String,
 
     /// The type contents of the record.
     pub body: RecordBody,
+
+    /// The name of the generator that produced this record's secret, if it was generated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generated_by: Option<String>,
+
+    /// The label of a TOTP record linked to this record, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<String>,
+
+    /// Free-form notes attached to the record, whatever its kind.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+
+    /// A short display hint for the record (e.g. an emoji or a freedesktop icon name),
+    /// for launchers and other frontends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+
+    /// Ad-hoc fields attached to the record (e.g. a recovery email), keyed by name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub custom_fields: Option<BTreeMap<String, String>>,
+
+    /// The age recipients that this record is encrypted to, if not just the store's own
+    /// public key. The store's public key must be among them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipients: Option<Vec<String>>,
+
+    /// Whether the record is locked against modification (e.g. by `kbs2 edit` or `kbs2 rm`),
+    /// short of `--force-locked`.
+    ///
+    /// Records written by older versions of `kbs2` are unlocked.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub locked: bool,
+
+    /// Whether the record is deleted from the store once it's been read (e.g. by
+    /// `kbs2 pass` or `kbs2 dump`).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub burn: bool,
+
+    /// Any envelope fields not understood by this version of `kbs2`.
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Zeroize for Record {
     fn zeroize(&mut self) {
         self.timestamp.zeroize();
+        self.created.zeroize();
         self.label.zeroize();
         self.body.zeroize();
+        self.generated_by.zeroize();
+        self.totp.zeroize();
+        self.notes.zeroize();
+        self.icon.zeroize();
+        if let Some(custom_fields) = &mut self.custom_fields {
+            custom_fields.values_mut().for_each(Zeroize::zeroize);
+            custom_fields.clear();
+        }
+        self.recipients.zeroize();
+        self.locked.zeroize();
+        self.burn.zeroize();
+        // NOTE(ww): `serde_json::Value` doesn't support zeroization, so the best
+        // we can do for unknown fields is to drop them.
+        self.extra.clear();
+    }
+}
+
+/// A record as serialized for output (e.g. by `kbs2 dump --json --iso-timestamps`), with
+/// its timestamps as RFC 3339 strings rather than seconds since the Unix epoch.
+///
+/// This mirrors `Record`'s fields, and is never deserialized: records themselves always
+/// keep numeric timestamps.
+#[derive(Serialize)]
+pub struct IsoTimestamps<'a> {
+    #[serde(serialize_with = "serialize_rfc3339")]
+    timestamp: u64,
+    #[serde(serialize_with = "serialize_optional_rfc3339")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created: Option<u64>,
+    label: &'a str,
+    body: &'a RecordBody,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generated_by: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    totp: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notes: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: &'a Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    custom_fields: &'a Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recipients: &'a Option<Vec<String>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    locked: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    burn: bool,
+    #[serde(flatten)]
+    extra: &'a ExtraFields,
+}
+
+impl<'a> From<&'a Record> for IsoTimestamps<'a> {
+    fn from(record: &'a Record) -> Self {
+        Self {
+            timestamp: record.timestamp,
+            created: record.created,
+            label: &record.label,
+            body: &record.body,
+            generated_by: &record.generated_by,
+            totp: &record.totp,
+            notes: &record.notes,
+            icon: &record.icon,
+            custom_fields: &record.custom_fields,
+            recipients: &record.recipients,
+            locked: record.locked,
+            burn: record.burn,
+            extra: &record.extra,
+        }
+    }
+}
+
+#[doc(hidden)]
+fn serialize_rfc3339<S: serde::Serializer>(
+    timestamp: &u64,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(*timestamp);
+    serializer.collect_str(&humantime::format_rfc3339_seconds(time))
+}
+
+#[doc(hidden)]
+fn serialize_optional_rfc3339<S: serde::Serializer>(
+    timestamp: &Option<u64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match timestamp {
+        Some(timestamp) => serialize_rfc3339(timestamp, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// The maximum length of a record's icon, in characters.
+pub const MAX_ICON_LEN: usize = 32;
+
+/// Checks that the given icon is suitable for a record: short, non-empty, and without
+/// any control characters (which would break e.g. the launcher's tab-separated menu).
+pub fn check_icon(icon: &str) -> Result<()> {
+    if icon.is_empty() {
+        return Err(anyhow!("record icons can't be empty"));
+    }
+
+    if icon.chars().count() > MAX_ICON_LEN {
+        return Err(anyhow!(
+            "record icons can be at most {MAX_ICON_LEN} characters long: {icon}"
+        ));
+    }
+
+    if icon.chars().any(char::is_control) {
+        return Err(anyhow!(
+            "record icons can't contain control characters: {icon:?}"
+        ));
     }
+
+    Ok(())
 }
 
 /// Represents the core contents of a `kbs2` record.
@@ -35,6 +430,7 @@ pub enum RecordBody {
     Login(LoginFields),
     Environment(EnvironmentFields),
     Unstructured(UnstructuredFields),
+    Totp(TotpFields),
 }
 
 impl Zeroize for RecordBody {
@@ -43,17 +439,164 @@ impl Zeroize for RecordBody {
             RecordBody::Login(l) => l.zeroize(),
             RecordBody::Environment(e) => e.zeroize(),
             RecordBody::Unstructured(u) => u.zeroize(),
+            RecordBody::Totp(t) => t.zeroize(),
         };
     }
 }
 
+impl RecordBody {
+    /// Returns this record's kind.
+    pub fn kind(&self) -> RecordKind {
+        match self {
+            RecordBody::Login(_) => RecordKind::Login,
+            RecordBody::Environment(_) => RecordKind::Environment,
+            RecordBody::Unstructured(_) => RecordKind::Unstructured,
+            RecordBody::Totp(_) => RecordKind::Totp,
+        }
+    }
+
+    /// Returns the name of this record's primary sensitive field, i.e. the field
+    /// that a generated secret belongs in.
+    pub fn sensitive_field(&self) -> &'static str {
+        match self {
+            RecordBody::Login(_) => "password",
+            RecordBody::Environment(_) => "value",
+            RecordBody::Unstructured(_) => "contents",
+            RecordBody::Totp(_) => "secret",
+        }
+    }
+
+    /// Returns the fields not understood by this version of `kbs2`.
+    pub fn extra(&self) -> &ExtraFields {
+        match self {
+            RecordBody::Login(l) => &l.extra,
+            RecordBody::Environment(e) => &e.extra,
+            RecordBody::Unstructured(u) => &u.extra,
+            RecordBody::Totp(t) => &t.extra,
+        }
+    }
+
+    /// Returns a mutable reference to the fields not understood by this version of `kbs2`.
+    pub fn extra_mut(&mut self) -> &mut ExtraFields {
+        match self {
+            RecordBody::Login(l) => &mut l.extra,
+            RecordBody::Environment(e) => &mut e.extra,
+            RecordBody::Unstructured(u) => &mut u.extra,
+            RecordBody::Totp(t) => &mut t.extra,
+        }
+    }
+
+    /// Returns an empty body of the given kind.
+    pub fn empty(kind: RecordKind) -> RecordBody {
+        match kind {
+            RecordKind::Login => RecordBody::Login(LoginFields {
+                username: Default::default(),
+                password: Default::default(),
+                extra: Default::default(),
+            }),
+            RecordKind::Environment => RecordBody::Environment(EnvironmentFields {
+                variable: Default::default(),
+                value: Default::default(),
+                extra: Default::default(),
+            }),
+            RecordKind::Unstructured => RecordBody::Unstructured(UnstructuredFields {
+                contents: Default::default(),
+                extra: Default::default(),
+            }),
+            RecordKind::Totp => RecordBody::Totp(TotpFields {
+                secret: Default::default(),
+                extra: Default::default(),
+            }),
+        }
+    }
+
+    /// Maps this body onto a body of the given kind, as a starting point for the user
+    /// to complete.
+    ///
+    /// Sensitive fields map onto each other, as do login usernames and environment
+    /// variables. Fields without a counterpart in the new kind are kept as extra fields
+    /// under their old names, so that nothing is dropped without the user's say-so.
+    pub fn convert(&self, kind: RecordKind) -> RecordBody {
+        let mut body = RecordBody::empty(kind);
+        let mut leftovers = self.fields().to_vec();
+
+        for &field in body.fields() {
+            let counterpart = if field == body.sensitive_field() {
+                self.sensitive_field()
+            } else {
+                match field {
+                    "username" => "variable",
+                    "variable" => "username",
+                    field => field,
+                }
+            };
+
+            let source = if self.field(field).is_some() {
+                field
+            } else {
+                counterpart
+            };
+
+            if let Some(value) = self.field(source) {
+                leftovers.retain(|f| *f != source);
+
+                // NOTE(ww): Every field named by `fields()` is accessible with `field_mut()`.
+                #[allow(clippy::unwrap_used)]
+                value.clone_into(body.field_mut(field).unwrap());
+            }
+        }
+
+        let mut extra = self.extra().clone();
+        for field in leftovers {
+            // NOTE(ww): Every field named by `fields()` is accessible with `field()`.
+            #[allow(clippy::unwrap_used)]
+            extra.insert(field.into(), self.field(field).unwrap().into());
+        }
+        *body.extra_mut() = extra;
+
+        body
+    }
+
+    /// Returns the names of this record's fields.
+    pub fn fields(&self) -> &'static [&'static str] {
+        match self {
+            RecordBody::Login(_) => LoginFields::FIELDS,
+            RecordBody::Environment(_) => EnvironmentFields::FIELDS,
+            RecordBody::Unstructured(_) => UnstructuredFields::FIELDS,
+            RecordBody::Totp(_) => TotpFields::FIELDS,
+        }
+    }
+
+    /// Returns the named field, if this record has it.
+    pub fn field(&self, field: &str) -> Option<&str> {
+        match (self, field) {
+            (RecordBody::Login(l), "username") => Some(&l.username),
+            (RecordBody::Login(l), "password") => Some(&l.password),
+            (RecordBody::Environment(e), "variable") => Some(&e.variable),
+            (RecordBody::Environment(e), "value") => Some(&e.value),
+            (RecordBody::Unstructured(u), "contents") => Some(&u.contents),
+            (RecordBody::Totp(t), "secret") => Some(&t.secret),
+            _ => None,
+        }
+    }
+
+    /// Returns a mutable reference to the named field, if this record has it.
+    pub fn field_mut(&mut self, field: &str) -> Option<&mut String> {
+        match (self, field) {
+            (RecordBody::Login(l), "username") => Some(&mut l.username),
+            (RecordBody::Login(l), "password") => Some(&mut l.password),
+            (RecordBody::Environment(e), "variable") => Some(&mut e.variable),
+            (RecordBody::Environment(e), "value") => Some(&mut e.value),
+            (RecordBody::Unstructured(u), "contents") => Some(&mut u.contents),
+            (RecordBody::Totp(t), "secret") => Some(&mut t.secret),
+            _ => None,
+        }
+    }
+}
+
 impl std::fmt::Display for RecordBody {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match *self {
-            RecordBody::Login(_) => write!(f, "login"),
-            RecordBody::Environment(_) => write!(f, "environment"),
-            RecordBody::Unstructured(_) => write!(f, "unstructured"),
-        }
+        write!(f, "{}", self.kind())
     }
 }
 
@@ -65,12 +608,17 @@ pub struct LoginFields {
 
     /// The password associated with the login.
     pub password: String,
+
+    /// Any fields not understood by this version of `kbs2`.
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Zeroize for LoginFields {
     fn zeroize(&mut self) {
         self.username.zeroize();
         self.password.zeroize();
+        self.extra.clear();
     }
 }
 
@@ -82,12 +630,17 @@ pub struct EnvironmentFields {
 
     /// The value associated with the environment.
     pub value: String,
+
+    /// Any fields not understood by this version of `kbs2`.
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Zeroize for EnvironmentFields {
     fn zeroize(&mut self) {
         self.variable.zeroize();
         self.value.zeroize();
+        self.extra.clear();
     }
 }
 
@@ -96,20 +649,356 @@ impl Zeroize for EnvironmentFields {
 pub struct UnstructuredFields {
     /// The contents associated with the record.
     pub contents: String,
+
+    /// Any fields not understood by this version of `kbs2`.
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Zeroize for UnstructuredFields {
     fn zeroize(&mut self) {
         self.contents.zeroize();
+        self.extra.clear();
+    }
+}
+
+/// Represents the fields of a TOTP record.
+#[derive(Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct TotpFields {
+    /// The base32-encoded TOTP secret.
+    pub secret: String,
+
+    /// Any fields not understood by this version of `kbs2`.
+    #[serde(flatten)]
+    pub extra: ExtraFields,
+}
+
+impl Zeroize for TotpFields {
+    fn zeroize(&mut self) {
+        self.secret.zeroize();
+        self.extra.clear();
+    }
+}
+
+impl TotpFields {
+    /// Returns the TOTP code for the given time, as seconds since the Unix epoch.
+    ///
+    /// Codes are generated per RFC 6238, with the common parameters: HMAC-SHA1,
+    /// 6 digits, and a 30 second period.
+    pub fn code(&self, timestamp: u64) -> Result<String> {
+        // NOTE(ww): Secrets are frequently presented with spaces, in lowercase, and without
+        // padding, so we normalize all of those away before decoding.
+        let normalized: String = self
+            .secret
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '=')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+
+        let key = data_encoding::BASE32_NOPAD
+            .decode(normalized.as_bytes())
+            .map_err(|_| anyhow!("TOTP secret is not valid base32"))?;
+
+        let mut mac = Hmac::<Sha1>::new_from_slice(&key)
+            .map_err(|_| anyhow!("TOTP secret has an invalid length"))?;
+        mac.update(&(timestamp / TOTP_PERIOD).to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        // Dynamic truncation, per RFC 4226 5.3.
+        let offset = (hash[hash.len() - 1] & 0xf) as usize;
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+
+        Ok(format!(
+            "{:0width$}",
+            binary % 10u32.pow(TOTP_DIGITS),
+            width = TOTP_DIGITS as usize
+        ))
     }
 }
 
 impl Record {
     pub fn new(label: &str, body: RecordBody) -> Record {
+        let timestamp = util::current_timestamp();
+
         Record {
-            timestamp: util::current_timestamp(),
+            timestamp,
+            created: Some(timestamp),
             label: label.into(),
             body,
+            generated_by: None,
+            totp: None,
+            notes: None,
+            icon: None,
+            custom_fields: None,
+            recipients: None,
+            locked: false,
+            burn: false,
+            extra: Default::default(),
+        }
+    }
+
+    /// Returns when this record was created, falling back on when it was last written
+    /// for records that predate creation times.
+    pub fn created(&self) -> u64 {
+        self.created.unwrap_or(self.timestamp)
+    }
+
+    /// Returns the names of any fields in this record (including its body) that this
+    /// version of `kbs2` doesn't understand, e.g. because a newer version added them.
+    ///
+    /// Body fields are prefixed with `fields.`.
+    pub fn unknown_fields(&self) -> Vec<String> {
+        self.extra
+            .keys()
+            .cloned()
+            .chain(self.body.extra().keys().map(|k| format!("fields.{k}")))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_body_convert() {
+        let login = RecordBody::Login(LoginFields {
+            username: "user".into(),
+            password: "pass".into(),
+            extra: Default::default(),
+        });
+
+        // Sensitive fields map onto each other, as do usernames and variables.
+        let environment = login.convert(RecordKind::Environment);
+        assert_eq!(environment.field("variable"), Some("user"));
+        assert_eq!(environment.field("value"), Some("pass"));
+        assert!(environment.extra().is_empty());
+
+        // Fields without a counterpart are kept as extra fields.
+        let unstructured = login.convert(RecordKind::Unstructured);
+        assert_eq!(unstructured.field("contents"), Some("pass"));
+        assert_eq!(unstructured.extra()["username"], "user");
+
+        // Fields without a source are left empty.
+        let login = unstructured.convert(RecordKind::Login);
+        assert_eq!(login.field("username"), Some(""));
+        assert_eq!(login.field("password"), Some("pass"));
+        assert_eq!(login.extra()["username"], "user");
+    }
+
+    #[test]
+    fn test_record_kind() {
+        assert_eq!(
+            RECORD_KINDS,
+            ["login", "environment", "unstructured", "totp"]
+        );
+
+        for kind in RecordKind::ALL {
+            assert_eq!(kind.name().parse::<RecordKind>().unwrap(), *kind);
+            assert_eq!(RecordBody::empty(*kind).kind(), *kind);
+            assert_eq!(RecordBody::empty(*kind).to_string(), kind.name());
+        }
+
+        assert!("nonexistent".parse::<RecordKind>().is_err());
+        assert!("Login".parse::<RecordKind>().is_err());
+    }
+
+    #[test]
+    fn test_record_kind_resolve() {
+        for (value, kind) in [
+            ("login", RecordKind::Login),
+            ("Login", RecordKind::Login),
+            ("ENV", RecordKind::Environment),
+            ("unstr", RecordKind::Unstructured),
+            ("t", RecordKind::Totp),
+        ] {
+            assert_eq!(RecordKind::resolve(value).unwrap(), kind, "{value}");
         }
+
+        for value in ["", "nonexistent", "logins"] {
+            assert!(RecordKind::resolve(value)
+                .unwrap_err()
+                .to_string()
+                .starts_with("unknown record kind"));
+        }
+
+        // No two kinds share a prefix today, but exact names still win over prefixes,
+        // and ambiguous prefixes list every candidate.
+        let names = ["env", "environment", "envelope"];
+        assert_eq!(resolve_name("Env", &names).unwrap(), "env");
+        assert_eq!(resolve_name("envi", &names).unwrap(), "environment");
+        assert_eq!(
+            resolve_name("enviro", &["environment", "environs"])
+                .unwrap_err()
+                .to_string(),
+            "ambiguous record kind: enviro (could be any of: environment, environs)"
+        );
+    }
+
+    #[test]
+    fn test_check_icon() {
+        for icon in ["🔑", "dialog-password", "🏦 bank"] {
+            assert!(check_icon(icon).is_ok(), "{icon}");
+        }
+
+        assert!(check_icon("").is_err());
+        assert!(check_icon(&"x".repeat(MAX_ICON_LEN + 1)).is_err());
+        assert!(check_icon("a\tb").is_err());
+        assert!(check_icon("a\nb").is_err());
+    }
+
+    #[test]
+    fn test_record_body_field_mut() {
+        let mut body = RecordBody::Login(LoginFields {
+            username: "user".into(),
+            password: "pass".into(),
+            extra: Default::default(),
+        });
+
+        assert_eq!(body.sensitive_field(), "password");
+
+        *body.field_mut("password").unwrap() = "newpass".into();
+        assert_eq!(
+            body,
+            RecordBody::Login(LoginFields {
+                username: "user".into(),
+                password: "newpass".into(),
+                extra: Default::default(),
+            })
+        );
+
+        assert!(body.field_mut("contents").is_none());
+        assert!(body.field_mut("nonexistent").is_none());
+
+        assert_eq!(body.fields(), &["username", "password"]);
+        assert_eq!(body.field("password"), Some("newpass"));
+        assert!(body.field("contents").is_none());
+    }
+
+    #[test]
+    fn test_field_anomalies() {
+        let record = serde_json::json!({
+            "timestamp": 1,
+            "label": "foo",
+            "body": {"kind": "Login", "fields": {"username": "user", "password": "pass"}},
+        });
+        assert!(field_anomalies(&record).unwrap().is_empty());
+
+        let record = serde_json::json!({
+            "timestamp": 1,
+            "label": "foo",
+            "body": {"kind": "Login", "fields": {"username": ""}},
+        });
+        assert_eq!(
+            field_anomalies(&record).unwrap(),
+            vec![
+                FieldAnomaly::Empty("username"),
+                FieldAnomaly::Missing("password")
+            ]
+        );
+
+        let record = serde_json::json!({
+            "timestamp": 1,
+            "label": "foo",
+            "body": {"kind": "Environment", "fields": {"variable": "FOO", "value": 1}},
+        });
+        assert_eq!(
+            field_anomalies(&record).unwrap(),
+            vec![FieldAnomaly::Missing("value")]
+        );
+
+        assert!(field_anomalies(&serde_json::json!({"body": {"kind": "Bogus"}})).is_err());
+        assert!(field_anomalies(&serde_json::json!({})).is_err());
+    }
+
+    #[test]
+    fn test_record_unknown_fields_roundtrip() {
+        let json = r#"{
+            "timestamp": 1,
+            "label": "foo",
+            "url": "https://example.com",
+            "body": {
+                "kind": "Login",
+                "fields": {"username": "user", "password": "pass", "tags": ["a"]}
+            }
+        }"#;
+
+        let record: Record = serde_json::from_str(json).unwrap();
+        assert_eq!(record.unknown_fields(), vec!["url", "fields.tags"]);
+
+        let reserialized = serde_json::to_value(&record).unwrap();
+        assert_eq!(
+            reserialized,
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        );
+
+        let record = Record::new(
+            "bar",
+            RecordBody::Unstructured(UnstructuredFields {
+                contents: "baz".into(),
+                extra: Default::default(),
+            }),
+        );
+        assert!(record.unknown_fields().is_empty());
+    }
+
+    #[test]
+    fn test_iso_timestamps() {
+        let mut record = Record::new(
+            "test",
+            RecordBody::Unstructured(UnstructuredFields {
+                contents: "contents".into(),
+                extra: Default::default(),
+            }),
+        );
+        record.timestamp = 1_700_000_000;
+        record.created = Some(0);
+        record.notes = Some("notes".into());
+        record.extra.insert("future".into(), 1.into());
+
+        let mut expected = serde_json::to_value(&record).unwrap();
+        expected["timestamp"] = "2023-11-14T22:13:20Z".into();
+        expected["created"] = "1970-01-01T00:00:00Z".into();
+        assert_eq!(
+            serde_json::to_value(IsoTimestamps::from(&record)).unwrap(),
+            expected
+        );
+
+        record.created = None;
+        let json = serde_json::to_value(IsoTimestamps::from(&record)).unwrap();
+        assert!(json.get("created").is_none());
+    }
+
+    #[test]
+    fn test_totp_code() {
+        // Test vectors from RFC 6238, Appendix B (truncated to 6 digits).
+        // The secret is the ASCII string "12345678901234567890".
+        let fields = TotpFields {
+            secret: "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".into(),
+            extra: Default::default(),
+        };
+
+        assert_eq!(fields.code(59).unwrap(), "287082");
+        assert_eq!(fields.code(1111111109).unwrap(), "081804");
+        assert_eq!(fields.code(1234567890).unwrap(), "005924");
+        assert_eq!(fields.code(20000000000).unwrap(), "353130");
+
+        // Whitespace, lowercase, and padding are all tolerated.
+        let fields = TotpFields {
+            secret: "gezd gnbv gy3t qojq gezd gnbv gy3t qojq====".into(),
+            extra: Default::default(),
+        };
+        assert_eq!(fields.code(59).unwrap(), "287082");
+
+        let fields = TotpFields {
+            secret: "not base32!".into(),
+            extra: Default::default(),
+        };
+        assert!(fields.code(59).is_err());
     }
 }
@@ -0,0 +1,185 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::kbs2::util;
+
+/// What happened to a single record during a bulk operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// The operation succeeded.
+    Ok,
+
+    /// The record was deliberately left alone.
+    Skipped,
+
+    /// The operation failed.
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Ok => write!(f, "ok"),
+            Outcome::Skipped => write!(f, "skipped"),
+            Outcome::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// A single record's outcome.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    /// The record's label.
+    pub label: String,
+
+    /// What happened to the record.
+    pub outcome: Outcome,
+
+    /// Why it happened, if there's more to say (e.g. the error that the record failed with).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A per-record account of a bulk operation (e.g. `kbs2 rekey`), for `--report`.
+///
+/// Reports only ever contain labels, outcomes, and reasons; never record contents.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// The operation, e.g. `rekey`.
+    pub operation: &'static str,
+
+    /// When the operation was started, as seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// Each record's outcome, in the order that they were processed.
+    pub records: Vec<Entry>,
+}
+
+impl Report {
+    /// Creates a new, empty report for the given operation.
+    pub fn new(operation: &'static str) -> Self {
+        Self {
+            operation,
+            timestamp: util::current_timestamp(),
+            records: vec![],
+        }
+    }
+
+    /// Records the outcome of a single record.
+    pub fn record(&mut self, label: &str, outcome: Outcome, reason: Option<String>) {
+        self.records.push(Entry {
+            label: label.into(),
+            outcome,
+            reason,
+        });
+    }
+
+    /// Records the result of operating on a single record: `Ok` or `Failed` with the error.
+    pub fn result<T>(&mut self, label: &str, result: &Result<T>) {
+        match result {
+            Ok(_) => self.record(label, Outcome::Ok, None),
+            Err(e) => self.record(label, Outcome::Failed, Some(format!("{e:#}"))),
+        }
+    }
+
+    /// Returns the number of records with the given outcome.
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.records.iter().filter(|e| e.outcome == outcome).count()
+    }
+
+    /// Returns a table of how many records had each outcome, followed by the failed records.
+    pub fn summary(&self) -> Vec<String> {
+        let rows = [Outcome::Ok, Outcome::Skipped, Outcome::Failed]
+            .iter()
+            .map(|outcome| vec![outcome.to_string(), self.count(*outcome).to_string()])
+            .collect::<Vec<_>>();
+
+        let mut summary = util::render_table(&rows);
+        summary.extend(
+            self.records
+                .iter()
+                .filter(|e| e.outcome == Outcome::Failed)
+                .map(|e| format!("{}: {}", e.label, e.reason.as_deref().unwrap_or_default())),
+        );
+        summary
+    }
+
+    /// Writes the report as JSON to the given path, readable only by the current user.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        util::write_secret_file(path, serde_json::to_string_pretty(self)?, true)
+    }
+
+    /// Writes the report to `path` (if given), and then fails if any record failed.
+    ///
+    /// `verb` describes the operation for the failure, e.g. `re-encrypted`.
+    pub fn finish(&self, path: Option<&Path>, verb: &str) -> Result<()> {
+        if let Some(path) = path {
+            self.write(path)?;
+        }
+
+        match self.count(Outcome::Failed) {
+            0 => Ok(()),
+            failed => Err(anyhow!(
+                "{failed} of {} records couldn't be {verb}",
+                self.records.len()
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::new("test");
+        report.result("foo", &Ok(()));
+        report.record("bar", Outcome::Skipped, Some("already present".into()));
+        report.result::<()>("baz", &Err(anyhow!("no such record: baz")));
+
+        assert_eq!(report.count(Outcome::Ok), 1);
+        assert_eq!(report.count(Outcome::Skipped), 1);
+        assert_eq!(report.count(Outcome::Failed), 1);
+        assert_eq!(
+            report.summary(),
+            [
+                "ok      1",
+                "skipped 1",
+                "failed  1",
+                "baz: no such record: baz"
+            ]
+        );
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("report.json");
+        let err = report.finish(Some(&path), "tested").unwrap_err();
+        assert_eq!(err.to_string(), "1 of 3 records couldn't be tested");
+
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["operation"], "test");
+        assert_eq!(
+            json["records"],
+            serde_json::json!([
+                {"label": "foo", "outcome": "ok"},
+                {"label": "bar", "outcome": "skipped", "reason": "already present"},
+                {"label": "baz", "outcome": "failed", "reason": "no such record: baz"},
+            ])
+        );
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        assert!(Report::new("test").finish(None, "tested").is_ok());
+    }
+}
@@ -1,14 +1,180 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs;
-use std::io;
-use std::path::Path;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
 
 use crate::kbs2::agent::Agent;
+use crate::kbs2::audit::{self, Operation};
 use crate::kbs2::backend::{Backend, RageLib};
 use crate::kbs2::config;
+use crate::kbs2::i18n::{self, Message};
+use crate::kbs2::importer::{self, ConflictStrategy, Resolution};
+use crate::kbs2::manifest::{Discrepancy, Manifest};
 use crate::kbs2::record;
+use crate::kbs2::store::{self, StoreBackend};
+use crate::kbs2::timings;
+use crate::kbs2::util;
+
+/// The basename of the label cache, within the config directory.
+const LABEL_CACHE_BASENAME: &str = "label-cache.json";
+
+/// How long a cached set of labels is used for, even when the store hasn't changed.
+const LABEL_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How old the store's mtime must be before we trust it to reflect every change.
+///
+/// Filesystems with coarse timestamps can record two successive modifications with
+/// the same mtime, so we don't cache labels for a store that changed very recently.
+const LABEL_CACHE_MIN_AGE: Duration = Duration::from_secs(2);
+
+/// The basename of the record access log, within the config directory.
+const ACCESS_LOG_BASENAME: &str = "access.log";
+
+/// Set once any burn-after-reading record has been burned by this process.
+static RECORDS_BURNED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether this process has burned any burn-after-reading records, i.e. whether
+/// a command that otherwise only reads the store has modified it.
+pub fn records_burned() -> bool {
+    RECORDS_BURNED.load(Ordering::SeqCst)
+}
+
+/// A writer that fails (and says so in `exceeded`) rather than write more than `remaining`
+/// bytes to `inner`.
+#[doc(hidden)]
+struct LimitedWriter<'a, W: Write> {
+    inner: W,
+    remaining: u64,
+    exceeded: &'a mut bool,
+}
+
+impl<W: Write> Write for LimitedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            *self.exceeded = true;
+            return Err(io::Error::other("record exceeds max-record-size"));
+        }
+
+        let written = self.inner.write(buf)?;
+        self.remaining -= written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns the size of the given record's serialized form, without serializing it
+/// into memory.
+#[doc(hidden)]
+fn serialized_len(record: &record::Record) -> Result<u64> {
+    struct Counter(u64);
+
+    impl Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    serde_json::to_writer(&mut counter, record)?;
+
+    Ok(counter.0)
+}
+
+/// The error produced when a requested record isn't in the store.
+#[derive(Debug)]
+pub struct NoSuchRecord(pub String);
+
+/// How `Session::iter_records` handles records that can't be retrieved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnRecordError {
+    /// Yield the error, leaving it to the caller (typically to stop at it).
+    Fail,
+    /// Warn about the error and skip the record.
+    Warn,
+}
+
+impl fmt::Display for NoSuchRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            i18n::format(Message::ErrorNoSuchRecord, &[&self.0])
+        )
+    }
+}
+
+impl std::error::Error for NoSuchRecord {}
+
+/// The error produced when modifying a locked record without `--force-locked`.
+#[derive(Debug)]
+pub struct LockedRecord(pub String);
+
+impl fmt::Display for LockedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            i18n::format(Message::ErrorLockedRecord, &[&self.0])
+        )
+    }
+}
+
+impl std::error::Error for LockedRecord {}
+
+/// The error produced when a record's file isn't valid UTF-8, and so can't be ASCII armor.
+#[derive(Debug)]
+pub struct InvalidArmor {
+    /// The record's label.
+    pub label: String,
+
+    /// The file that the record was read from.
+    pub path: PathBuf,
+}
+
+impl fmt::Display for InvalidArmor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            i18n::format(
+                Message::ErrorInvalidArmor,
+                &[&self.label, &self.path.display()]
+            )
+        )
+    }
+}
+
+impl std::error::Error for InvalidArmor {}
+
+/// A cached set of record labels, keyed on the store's path and mtime.
+#[derive(Deserialize, Serialize)]
+struct LabelCache {
+    store: String,
+    mtime: SystemTime,
+    created: SystemTime,
+    labels: Vec<String>,
+}
 
 /// Encapsulates the context needed by `kbs2` to interact with records.
 pub struct Session<'a> {
@@ -17,55 +183,123 @@ pub struct Session<'a> {
 
     /// The configuration that `kbs2` was invoked with.
     pub config: &'a config::Config,
+
+    /// The backend that records are stored in.
+    store: Box<dyn StoreBackend + 'a>,
+
+    /// The manifest being updated by the batch in progress, if any; see `Session::batch`.
+    batch: RefCell<Option<Manifest>>,
+
+    /// Whether the store is locked by this session; see `exclusively`.
+    locked: Cell<bool>,
 }
 
 impl<'a> Session<'a> {
     /// Creates a new session, given a `Config`.
-    fn new(config: &'a config::Config) -> Result<Session> {
+    fn new(config: &'a config::Config) -> Result<Session<'a>> {
         // NOTE(ww): I don't like that we do this here, but I'm not sure where else to put it.
-        if config.wrapped && config.agent_autostart {
-            Agent::spawn()?;
+        if config.wrapped && config.agent_autostart && !config.no_agent {
+            Agent::spawn(
+                config.agent_binary.as_deref().map(Path::new),
+                Duration::from_millis(config.agent_spawn_timeout_ms),
+            )?;
         }
 
-        fs::create_dir_all(&config.store)?;
+        let store = store::open(config)?;
 
         #[allow(clippy::redundant_field_names)]
-        Ok(Session {
+        let session = Session {
             backend: RageLib::new(config)?,
             config: config,
-        })
+            store: store,
+            batch: Default::default(),
+            locked: Default::default(),
+        };
+
+        if config.manifest && config.verify_manifest {
+            match session.manifest_discrepancies() {
+                Ok(Some(discrepancies)) if discrepancies.is_empty() => {}
+                Ok(Some(discrepancies)) => util::warn(&format!(
+                    "the store doesn't match its manifest ({} discrepancies); run `kbs2 fsck` for details",
+                    discrepancies.len()
+                )),
+                Ok(None) => util::warn("the store has no manifest; run `kbs2 fsck` for details"),
+                Err(e) => util::warn(&format!("couldn't check the store's manifest: {e:#}")),
+            }
+        }
+
+        Ok(session)
     }
 
     /// Returns the label of every record available in the store.
     pub fn record_labels(&self) -> Result<Vec<String>> {
-        let store = Path::new(&self.config.store);
+        self.store.labels()
+    }
+
+    /// Returns the names of any files in the store that were skipped by `record_labels`
+    /// for not being valid UTF-8.
+    pub fn invalid_names(&self) -> Result<Vec<OsString>> {
+        self.store.invalid_names()
+    }
 
-        if !store.is_dir() {
-            return Err(anyhow!("secret store is not a directory"));
+    /// Like `record_labels`, but served from a short-lived cache when
+    /// `commands.list.cache-labels` is enabled.
+    ///
+    /// The cache is invalidated whenever the store's mtime changes, and is bypassed
+    /// entirely when the store's mtime can't be trusted.
+    pub fn cached_record_labels(&self) -> Result<Vec<String>> {
+        if !self.config.commands.list.cache_labels {
+            return self.record_labels();
         }
 
-        let mut labels = vec![];
-        for entry in fs::read_dir(store)? {
-            let path = entry?.path();
-            if !path.is_file() {
-                log::debug!("skipping non-file in store: {:?}", path);
-                continue;
+        let mtime = match self.store.modified() {
+            Some(mtime) if mtime > UNIX_EPOCH => mtime,
+            _ => {
+                log::debug!("store mtime unavailable; not using the label cache");
+                return self.record_labels();
             }
+        };
 
-            // NOTE(ww): This unwrap is safe, since file_name always returns Some
-            // for non-directories.
-            #[allow(clippy::expect_used)]
-            let label = path
-                .file_name()
-                .expect("impossible: is_file=true for path but file_name=None");
+        let now = SystemTime::now();
+        let cache_path = Path::new(&self.config.config_dir).join(LABEL_CACHE_BASENAME);
+
+        let cache = fs::read(&cache_path)
+            .ok()
+            .and_then(|c| serde_json::from_slice::<LabelCache>(&c).ok());
+        if let Some(cache) = cache {
+            if cache.store == self.config.store
+                && cache.mtime == mtime
+                && now
+                    .duration_since(cache.created)
+                    .is_ok_and(|age| age < LABEL_CACHE_TTL)
+            {
+                log::debug!("using cached labels");
+                return Ok(cache.labels);
+            }
+        }
 
-            // NOTE(ww): This one isn't safe, but we don't care. Non-UTF-8 labels aren't supported.
-            labels.push(
-                label
-                    .to_str()
-                    .ok_or_else(|| anyhow!("unrepresentable record label: {:?}", label))?
-                    .into(),
-            );
+        let labels = self.record_labels()?;
+
+        if now
+            .duration_since(mtime)
+            .is_ok_and(|age| age >= LABEL_CACHE_MIN_AGE)
+        {
+            let cache = LabelCache {
+                store: self.config.store.clone(),
+                mtime,
+                created: now,
+                labels,
+            };
+
+            // NOTE(ww): The cache is purely an optimization, so failing to write it isn't an error.
+            if let Err(e) = serde_json::to_vec(&cache)
+                .map_err(anyhow::Error::from)
+                .and_then(|c| Ok(fs::write(&cache_path, c)?))
+            {
+                log::debug!("failed to write the label cache: {}", e);
+            }
+
+            return Ok(cache.labels);
         }
 
         Ok(labels)
@@ -73,59 +307,749 @@ impl<'a> Session<'a> {
 
     /// Returns whether or not the store contains a given record.
     pub fn has_record(&self, label: &str) -> bool {
-        let record_path = Path::new(&self.config.store).join(label);
+        self.store.exists(&self.resolve_label(label))
+    }
+
+    /// Returns the label of the record in the store that the given label refers to, under
+    /// the configured `label-normalization`.
+    ///
+    /// That's the normalized label, unless the store only has a record whose label
+    /// normalizes to the same thing (e.g. one stored before normalization was enabled),
+    /// in which case it's that record's label.
+    pub fn resolve_label(&self, label: &str) -> String {
+        let policy = self.config.label_normalization;
+        if policy == config::LabelNormalization::None {
+            return label.into();
+        }
+
+        let normalized = policy.normalize(label);
+        if self.store.exists(&normalized) {
+            return normalized;
+        }
 
-        record_path.is_file()
+        if self.store.exists(label) {
+            return label.into();
+        }
+
+        self.store
+            .labels()
+            .ok()
+            .and_then(|labels| {
+                labels
+                    .into_iter()
+                    .find(|l| policy.normalize(l) == normalized)
+            })
+            .unwrap_or(normalized)
     }
 
     /// Retrieves a record from the store by its label.
     pub fn get_record(&self, label: &str) -> Result<record::Record> {
-        if !self.has_record(label) {
-            return Err(anyhow!("no such record: {}", label));
+        let label = self.resolve_label(label);
+        let record = self.decrypt_record(&label);
+        self.audit(&label, Operation::Read, &record);
+
+        record
+    }
+
+    /// Retrieves a record from the store by its label, without auditing or tracking the
+    /// read. This is for reads that kbs2 makes on its own behalf, like checking whether a
+    /// record is locked before removing it, rather than on the user's.
+    pub fn peek_record(&self, label: &str) -> Result<record::Record> {
+        self.decrypt_record(&self.resolve_label(label))
+    }
+
+    #[doc(hidden)]
+    fn decrypt_record(&self, label: &str) -> Result<record::Record> {
+        match self.store.record_len(label) {
+            Some(len) if len > self.config.stream_threshold => self
+                .read_record_stream(label)
+                .and_then(|stream| self.backend.decrypt_stream(stream)),
+            _ => self
+                .read_record(label)
+                .and_then(|encrypted| self.backend.decrypt(&encrypted)),
+        }
+    }
+
+    /// Retrieves several records from the store by their labels, decrypting them on up
+    /// to `jobs` threads.
+    ///
+    /// The records are returned in the same order as `labels`.
+    pub fn get_records(&self, labels: &[String], jobs: usize) -> Result<Vec<record::Record>> {
+        let records = self.get_records_unaudited(labels, jobs);
+
+        // NOTE(ww): A failure here is logged against every record in the batch, since
+        // we don't know which one(s) failed.
+        for label in labels {
+            self.audit(label, Operation::Read, &records);
+        }
+
+        records
+    }
+
+    #[doc(hidden)]
+    fn get_records_unaudited(&self, labels: &[String], jobs: usize) -> Result<Vec<record::Record>> {
+        let encrypted = labels
+            .iter()
+            .map(|label| self.read_record(label))
+            .collect::<Result<Vec<_>>>()?;
+
+        // NOTE(ww): Only the backend is shared between threads: the config isn't `Sync`,
+        // and decryption is the only part of reading a record that's worth parallelizing.
+        let backend = &self.backend;
+        let chunk_size = encrypted.len().div_ceil(jobs.max(1)).max(1);
+        let records = thread::scope(|scope| {
+            let workers = encrypted
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|encrypted| backend.decrypt(encrypted))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        Ok(records.into_iter().flatten().collect())
+    }
+
+    /// Lazily retrieves records from the store by their labels, in the same order as `labels`.
+    ///
+    /// Each record is only read and decrypted when the iterator reaches it. Records that
+    /// can't be retrieved are handled according to `on_error`.
+    pub fn iter_records<'s>(
+        &'s self,
+        labels: &'s [String],
+        on_error: OnRecordError,
+    ) -> impl Iterator<Item = Result<record::Record>> + 's {
+        labels
+            .iter()
+            .filter_map(move |label| match (self.get_record(label), on_error) {
+                (Err(e), OnRecordError::Warn) => {
+                    util::warn(&format!("skipping {label}: {e}"));
+                    None
+                }
+                (result, _) => Some(result),
+            })
+    }
+
+    /// Retrieves a record from the store by its label, as JSON, without interpreting it.
+    ///
+    /// Unlike `get_record`, this succeeds for records that are malformed, and doesn't
+    /// count as an access to the record.
+    pub fn get_record_json(&self, label: &str) -> Result<serde_json::Value> {
+        let plaintext = self
+            .read_record(label)
+            .and_then(|encrypted| self.backend.decrypt_plaintext(&encrypted));
+        self.audit(&self.resolve_label(label), Operation::Read, &plaintext);
+
+        serde_json::from_str(&plaintext?)
+            .with_context(|| format!("record is not valid JSON: {label}"))
+    }
+
+    /// Re-encrypts a record in the store to its recipients, decrypting it with the given
+    /// backend rather than the session's own. This is how records encrypted to some other
+    /// keypair are brought back under the current one.
+    ///
+    /// In a dry run, the record is only decrypted and checked.
+    pub fn reencrypt_record(&self, label: &str, backend: &RageLib) -> Result<()> {
+        let label = self.resolve_label(label);
+        let record = backend.decrypt(&self.read_record(&label)?)?;
+        if record.label != label {
+            return Err(anyhow!(
+                "record's embedded label doesn't match its filename: {} (expected {})",
+                record.label,
+                label
+            ));
+        }
+
+        if self.config.dry_run {
+            return Ok(());
         }
 
-        let record_path = Path::new(&self.config.store).join(label);
-        let record_contents = fs::read_to_string(record_path).map_err(|e| match e.kind() {
-            io::ErrorKind::NotFound => anyhow!("no such record: {}", label),
-            _ => e.into(),
+        self.add_record(&record)
+    }
+
+    #[doc(hidden)]
+    fn read_record(&self, label: &str) -> Result<String> {
+        let label = &self.resolve_label(label);
+        if !self.store.exists(label) {
+            return Err(NoSuchRecord(label.into()).into());
+        }
+
+        let record_contents = timings::span("record read", || {
+            self.store.read(label, self.config.max_record_size)
+        })
+        .with_context(|| {
+            format!(
+                "failed to read record {} (max-record-size is {} bytes)",
+                label, self.config.max_record_size
+            )
         })?;
 
-        match self.backend.decrypt(&record_contents) {
-            Ok(record) => Ok(record),
-            Err(e) => Err(e),
+        String::from_utf8(record_contents).map_err(|_| {
+            InvalidArmor {
+                label: label.into(),
+                path: self.config.record_path(label),
+            }
+            .into()
+        })
+    }
+
+    /// Like `read_record`, but for records large enough to be streamed.
+    #[doc(hidden)]
+    fn read_record_stream(&self, label: &str) -> Result<Box<dyn io::Read + '_>> {
+        if !self.store.exists(label) {
+            return Err(NoSuchRecord(label.into()).into());
+        }
+
+        timings::span("record read", || {
+            self.store.read_stream(label, self.config.max_record_size)
+        })
+        .with_context(|| {
+            format!(
+                "failed to read record {} (max-record-size is {} bytes)",
+                label, self.config.max_record_size
+            )
+        })
+    }
+
+    /// Checks that the given (encrypted) record is small enough to be read back, i.e.
+    /// doesn't exceed `max-record-size`.
+    #[doc(hidden)]
+    fn check_record_size(&self, label: &str, encrypted: &str) -> Result<()> {
+        if encrypted.len() as u64 > self.config.max_record_size {
+            return Err(anyhow!(
+                "record is too large: {} ({} bytes encrypted, but max-record-size is {} bytes)",
+                label,
+                encrypted.len(),
+                self.config.max_record_size
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Records the given operation on a record in the audit log, if `audit-records` is set.
+    #[doc(hidden)]
+    fn audit<T>(&self, label: &str, operation: Operation, result: &Result<T>) {
+        if self.config.audit_records {
+            self.config.audit(audit::Event::Record {
+                label: label.into(),
+                operation,
+                outcome: audit::Outcome::of(result),
+            });
+        }
+    }
+
+    /// Logs an access of the record with the given label, if `track-access` is set.
+    ///
+    /// Reading a record doesn't do this by itself: only the commands that show a record to
+    /// the user (rather than e.g. scanning the whole store) count as accessing it.
+    pub fn track_access(&self, label: &str) {
+        if self.config.track_access && !self.config.dry_run {
+            // NOTE(ww): Failing to track an access shouldn't prevent it.
+            if let Err(e) = self.log_access(label) {
+                util::warn(&format!("failed to log access to {label}: {e}"));
+            }
+        }
+    }
+
+    /// Returns the path to the record access log.
+    fn access_log_path(&self) -> PathBuf {
+        Path::new(&self.config.config_dir).join(ACCESS_LOG_BASENAME)
+    }
+
+    /// Appends an access of the given label to the access log.
+    fn log_access(&self, label: &str) -> Result<()> {
+        let mut log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(self.access_log_path())?;
+
+        writeln!(log, "{} {}", util::current_timestamp(), label)?;
+
+        Ok(())
+    }
+
+    /// Returns the most recent access time of each record in the access log,
+    /// as seconds since the Unix epoch.
+    ///
+    /// Records that have never been accessed (or were accessed while access tracking
+    /// was disabled) are absent.
+    pub fn last_accesses(&self) -> Result<HashMap<String, u64>> {
+        let log = match fs::read_to_string(self.access_log_path()) {
+            Ok(log) => log,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut accesses = HashMap::new();
+        for line in log.lines() {
+            let Some((timestamp, label)) = line
+                .split_once(' ')
+                .and_then(|(t, l)| Some((t.parse::<u64>().ok()?, l)))
+            else {
+                log::debug!("skipping malformed access log line: {:?}", line);
+                continue;
+            };
+
+            let last = accesses.entry(label.to_string()).or_insert(timestamp);
+            *last = (*last).max(timestamp);
+        }
+
+        Ok(accesses)
+    }
+
+    /// Removes the access log, forgetting every tracked access.
+    pub fn reset_access_log(&self) -> Result<()> {
+        match fs::remove_file(self.access_log_path()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
         }
     }
 
     /// Adds the given record to the store.
+    ///
+    /// The record is stored under its label as resolved by `resolve_label`, i.e. normalized
+    /// or replacing an existing record whose label normalizes to the same thing.
     pub fn add_record(&self, record: &record::Record) -> anyhow::Result<()> {
-        let record_path = Path::new(&self.config.store).join(&record.label);
+        let label = self.resolve_label(&record.label);
+        if label != record.label {
+            // NOTE(ww): Records deliberately aren't `Clone`, so we relabel a copy by way of
+            // its serialized form.
+            let mut relabeled = serde_json::to_value(record)?;
+            relabeled["label"] = label.into();
+            return self.write_record(&serde_json::from_value(relabeled)?);
+        }
+
+        self.write_record(record)
+    }
 
-        let record_contents = self.backend.encrypt(record)?;
-        std::fs::write(record_path, record_contents)?;
+    /// Adds the given records to the store, encrypting them on up to `jobs` threads.
+    ///
+    /// This behaves like calling `add_record` on each record, but is considerably faster
+    /// for large batches. Nothing is written unless every record can be encrypted.
+    pub fn add_records(&self, records: &[record::Record], jobs: usize) -> Result<()> {
+        // NOTE(ww): Records whose labels resolve differently need relabeling, which
+        // `add_record` already knows how to do. That's rare enough to not parallelize.
+        let (records, relabeled): (Vec<_>, Vec<_>) = records
+            .iter()
+            .partition(|record| self.resolve_label(&record.label) == record.label);
+
+        for record in &records {
+            self.check_recipients(record)?;
+        }
+
+        // NOTE(ww): As in `get_records`, only the backend is shared between threads.
+        let backend = &self.backend;
+        let chunk_size = records.len().div_ceil(jobs.max(1)).max(1);
+        let encrypted = thread::scope(|scope| {
+            let workers = records
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|record| backend.encrypt(record))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let encrypted = encrypted.into_iter().flatten().collect::<Vec<_>>();
+        for (record, contents) in records.iter().zip(&encrypted) {
+            self.check_record_size(&record.label, contents)?;
+        }
+
+        self.batch(|| {
+            for (record, contents) in records.iter().zip(&encrypted) {
+                self.tracked(&record.label, Operation::Write, || {
+                    self.store.write(&record.label, contents)
+                })?;
+            }
+
+            for record in relabeled {
+                self.add_record(record)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Encrypts and writes the given record under exactly its label.
+    #[doc(hidden)]
+    fn write_record(&self, record: &record::Record) -> Result<()> {
+        self.check_recipients(record)?;
+
+        self.tracked(&record.label, Operation::Write, || {
+            if serialized_len(record)? > self.config.stream_threshold {
+                return self.write_record_stream(record);
+            }
+
+            let record_contents = self.backend.encrypt(record)?;
+            self.check_record_size(&record.label, &record_contents)?;
+            self.store.write(&record.label, &record_contents)
+        })
+    }
+
+    /// Like `write_record`, but streams the encrypted record into the store.
+    #[doc(hidden)]
+    fn write_record_stream(&self, record: &record::Record) -> Result<()> {
+        let mut exceeded = false;
+        let result = self.store.write_stream(&record.label, &mut |writer| {
+            let mut writer = LimitedWriter {
+                inner: writer,
+                remaining: self.config.max_record_size,
+                exceeded: &mut exceeded,
+            };
+            self.backend.encrypt_stream(record, &mut writer)
+        });
+
+        // NOTE(ww): Unlike `check_record_size`, we stop at the limit rather than encrypting
+        // the whole record, so we only know that it's too large, not by how much.
+        if exceeded {
+            return Err(anyhow!(
+                "record is too large: {} (its encrypted form exceeds max-record-size, {} bytes)",
+                record.label,
+                self.config.max_record_size
+            ));
+        }
+
+        result
+    }
+
+    #[doc(hidden)]
+    fn check_recipients(&self, record: &record::Record) -> Result<()> {
+        // NOTE(ww): We'd otherwise happily write a record that we can't read back.
+        if let Some(recipients) = &record.recipients {
+            if !recipients.contains(&self.config.public_key) {
+                return Err(anyhow!(
+                    "refusing to encrypt {} without this store's public key as a recipient",
+                    record.label
+                ));
+            }
+        }
 
         Ok(())
     }
 
+    /// Adds the given armored ciphertext to the store as-is, under the given label.
+    ///
+    /// The ciphertext must decrypt with the current identity, and the label embedded
+    /// within it must match `label`. If `label` is already in the store, `strategy`
+    /// decides what happens; see `importer::resolve_conflict`. A record imported under
+    /// a fresh label is necessarily re-encrypted, since its label is part of the ciphertext.
+    ///
+    /// In a dry run, the record is checked and its resolution returned, but nothing is written.
+    pub fn import_raw_record(
+        &self,
+        label: &str,
+        armored: &str,
+        strategy: Option<ConflictStrategy>,
+    ) -> Result<Resolution> {
+        let mut record = self
+            .backend
+            .decrypt(armored)
+            .map_err(|e| anyhow!("imported record doesn't decrypt: {}", e))?;
+
+        if record.label != label {
+            return Err(anyhow!(
+                "label mismatch: expected {}, but the record is labeled {}",
+                label,
+                record.label
+            ));
+        }
+
+        let resolution = importer::resolve_conflict(self, label, Some(record.timestamp), strategy)?;
+
+        // NOTE(ww): In a dry run, the resolution is the whole plan.
+        if self.config.dry_run {
+            if let Resolution::Add(label) | Resolution::Overwrite(label) = &resolution {
+                self.check_record_size(label, armored)?;
+            }
+            return Ok(resolution);
+        }
+
+        match &resolution {
+            Resolution::Add(label) | Resolution::Overwrite(label) => {
+                self.check_record_size(label, armored)?;
+                self.tracked(label, Operation::Write, || self.store.write(label, armored))?;
+            }
+            Resolution::Rename(new_label) => {
+                record.label = new_label.clone();
+                self.add_record(&record)?;
+            }
+            Resolution::Skip => {}
+        }
+
+        Ok(resolution)
+    }
+
+    /// Returns when the given record last changed, if the store can tell.
+    pub fn record_modified(&self, label: &str) -> Option<SystemTime> {
+        self.store.record_modified(&self.resolve_label(label))
+    }
+
     /// Deletes a record from the store by label.
     pub fn delete_record(&self, label: &str) -> Result<()> {
-        let record_path = Path::new(&self.config.store).join(label);
-
-        std::fs::remove_file(record_path).map_err(|e| match e.kind() {
-            io::ErrorKind::NotFound => anyhow!("no such record: {}", label),
-            _ => e.into(),
-        })
+        let label = self.resolve_label(label);
+        self.tracked(&label, Operation::Delete, || self.store.delete(&label))
     }
 
     /// Renames a record.
+    ///
+    /// The new label is normalized, but not otherwise resolved: this is also how records
+    /// stored under unnormalized labels are brought into line.
     pub fn rename_record(&self, old_label: &str, new_label: &str) -> Result<()> {
-        let mut record = self.get_record(old_label)?;
+        self.batch(|| self.rename_record_unbatched(old_label, new_label))
+    }
+
+    #[doc(hidden)]
+    fn rename_record_unbatched(&self, old_label: &str, new_label: &str) -> Result<()> {
+        let old_label = self.resolve_label(old_label);
+        let mut record = self.get_record(&old_label)?;
 
-        record.label = new_label.into();
-        self.add_record(&record)?;
-        self.delete_record(old_label)?;
+        record.label = self.config.label_normalization.normalize(new_label);
+        if record.label == old_label {
+            return Ok(());
+        }
+
+        // NOTE(ww): On a case-insensitive filesystem, labels that differ only by case can
+        // share a file, so we can't write the new one before deleting the old one. If the
+        // write then fails, we put the old record back.
+        if record.label.to_lowercase() == old_label.to_lowercase() {
+            self.tracked(&old_label, Operation::Delete, || {
+                self.store.delete(&old_label)
+            })?;
+            return self.write_record(&record).inspect_err(|_| {
+                record.label = old_label.clone();
+                if let Err(e) = self.write_record(&record) {
+                    log::error!(
+                        "failed to restore {} after a failed rename: {}",
+                        old_label,
+                        e
+                    );
+                }
+            });
+        }
+
+        self.write_record(&record)?;
+        self.tracked(&old_label, Operation::Delete, || {
+            self.store.delete(&old_label)
+        })?;
 
         Ok(())
     }
+
+    /// Runs `op` as a single batch of changes to the store.
+    ///
+    /// When the manifest is enabled, the store is locked while `op` runs, and the manifest
+    /// is written once afterwards rather than after every change. Nested batches are part
+    /// of the outermost one.
+    pub fn batch<T>(&self, op: impl FnOnce() -> Result<T>) -> Result<T> {
+        if !self.config.manifest || self.batch.borrow().is_some() {
+            return op();
+        }
+
+        self.exclusively(|| {
+            let manifest = match self.manifest()? {
+                Some(manifest) => manifest,
+                None => {
+                    let manifest = self.current_manifest()?;
+                    if !manifest.records.is_empty() {
+                        util::warn(
+                            "the store has no manifest; creating one from its current contents",
+                        );
+                    }
+                    manifest
+                }
+            };
+
+            *self.batch.borrow_mut() = Some(manifest);
+            let result = op();
+
+            let manifest = self.batch.borrow_mut().take();
+            if let Some(manifest) = manifest {
+                self.write_manifest(&manifest)?;
+            }
+
+            result
+        })
+    }
+
+    /// Runs `op` with the store locked against other `kbs2` processes, whether or not the
+    /// manifest is enabled. Batches (and other exclusive operations) within `op` share
+    /// the lock, rather than waiting on it.
+    pub fn exclusively<T>(&self, op: impl FnOnce() -> Result<T>) -> Result<T> {
+        if self.locked.get() {
+            return op();
+        }
+
+        let _lock = self.store.lock()?;
+        self.locked.set(true);
+        let result = op();
+        self.locked.set(false);
+
+        result
+    }
+
+    /// Runs `op` (e.g. printing the given record) and then, if the record is marked
+    /// burn-after-reading, deletes it from the store. Returns whether the record was burned.
+    ///
+    /// The record is kept if `op` fails. The store stays locked throughout, so only one
+    /// of several concurrent reads of the record can succeed.
+    pub fn burn_after(
+        &self,
+        record: &record::Record,
+        op: impl FnOnce() -> Result<()>,
+    ) -> Result<bool> {
+        if !record.burn {
+            op()?;
+            return Ok(false);
+        }
+
+        self.exclusively(|| {
+            // NOTE(ww): Another `kbs2` process may have burned the record in between
+            // our reading it and taking the lock.
+            if !self.has_record(&record.label) {
+                return Err(NoSuchRecord(record.label.clone()).into());
+            }
+
+            op()?;
+            self.delete_record(&record.label)?;
+            RECORDS_BURNED.store(true, Ordering::SeqCst);
+            Ok(true)
+        })
+    }
+
+    /// Runs `op`, which changes the record with the given label, and then brings the
+    /// manifest up to date with the change (when the manifest is enabled) and records
+    /// it in the audit log.
+    #[doc(hidden)]
+    fn tracked<T>(
+        &self,
+        label: &str,
+        operation: Operation,
+        op: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        // NOTE(ww): Dry runs are planned by each command, which shouldn't ever get here
+        // with a write. This makes sure that nothing slips through if one does.
+        if self.config.dry_run && operation != Operation::Read {
+            return Err(anyhow!("refusing to modify {label} during a dry run"));
+        }
+
+        let result = self.manifested(label, op);
+        self.audit(label, operation, &result);
+        result
+    }
+
+    #[doc(hidden)]
+    fn manifested<T>(&self, label: &str, op: impl FnOnce() -> Result<T>) -> Result<T> {
+        if !self.config.manifest {
+            return op();
+        }
+
+        self.batch(|| {
+            // NOTE(ww): We update the manifest even when `op` fails, since it may have
+            // changed the record before failing.
+            let result = op();
+
+            let hash = match self.store.exists(label) {
+                true => Some(Manifest::hash(
+                    self.store.read_stream(label, self.config.max_record_size)?,
+                )?),
+                false => None,
+            };
+
+            if let Some(manifest) = self.batch.borrow_mut().as_mut() {
+                match hash {
+                    Some(hash) => manifest.records.insert(label.into(), hash),
+                    None => manifest.records.remove(label),
+                };
+            }
+
+            result
+        })
+    }
+
+    /// Returns the store's manifest, or `None` if it doesn't have one.
+    pub fn manifest(&self) -> Result<Option<Manifest>> {
+        let Some(encrypted) = self.store.read_manifest()? else {
+            return Ok(None);
+        };
+
+        let manifest = self
+            .backend
+            .decrypt_plaintext(&encrypted)
+            .context("failed to decrypt the store's manifest")?;
+
+        Ok(Some(
+            serde_json::from_str(&manifest).context("the store's manifest is corrupt")?,
+        ))
+    }
+
+    /// Compares the store against its manifest, returning every discrepancy between them,
+    /// or `None` if the store has no manifest.
+    pub fn manifest_discrepancies(&self) -> Result<Option<Vec<Discrepancy>>> {
+        let Some(manifest) = self.manifest()? else {
+            return Ok(None);
+        };
+
+        Ok(Some(manifest.discrepancies(&self.current_manifest()?)))
+    }
+
+    /// Replaces the store's manifest with one that reflects the store as it is now.
+    ///
+    /// This accepts any changes that were made outside of `kbs2`, so it's only appropriate
+    /// once the store has been checked (or after deliberately changing it by hand).
+    pub fn rebuild_manifest(&self) -> Result<()> {
+        let _lock = self.store.lock()?;
+        self.write_manifest(&self.current_manifest()?)
+    }
+
+    /// Returns a manifest of the store's current contents.
+    #[doc(hidden)]
+    fn current_manifest(&self) -> Result<Manifest> {
+        let mut records = BTreeMap::new();
+        for label in self.store.labels()? {
+            let contents = self
+                .store
+                .read_stream(&label, self.config.max_record_size)
+                .with_context(|| format!("failed to read record {label} for the manifest"))?;
+
+            let hash = Manifest::hash(contents)?;
+            records.insert(label, hash);
+        }
+
+        Ok(Manifest { records })
+    }
+
+    #[doc(hidden)]
+    fn write_manifest(&self, manifest: &Manifest) -> Result<()> {
+        if self.config.dry_run {
+            return Err(anyhow!("refusing to modify the manifest during a dry run"));
+        }
+
+        let encrypted = self
+            .backend
+            .encrypt_plaintext(&serde_json::to_vec(manifest)?)?;
+
+        self.store.write_manifest(&encrypted)
+    }
 }
 
 impl<'a> TryFrom<&'a config::Config> for Session<'a> {
@@ -137,11 +1061,14 @@ impl<'a> TryFrom<&'a config::Config> for Session<'a> {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
     use tempfile::{tempdir, TempDir};
 
     use super::*;
     use crate::kbs2::record::{LoginFields, Record, RecordBody};
+    use crate::kbs2::store::{FsStore, MemoryStore};
 
     fn dummy_login(label: &str, username: &str, password: &str) -> Record {
         Record::new(
@@ -149,6 +1076,7 @@ mod tests {
             RecordBody::Login(LoginFields {
                 username: username.into(),
                 password: password.into(),
+                extra: Default::default(),
             }),
         )
     }
@@ -156,37 +1084,99 @@ mod tests {
     // NOTE: We pass store in here instead of creating it for lifetime reasons:
     // the temp dir is unlinked when its TempDir object is destructed, so we need
     // to keep it alive long enough for each unit test.
-    fn dummy_config(store: &TempDir) -> config::Config {
+    pub(crate) fn dummy_config(store: &TempDir) -> config::Config {
+        dummy_config_at(store.path().to_str().unwrap())
+    }
+
+    /// Returns a dummy config for a session whose store is in memory; see `memory_session`.
+    fn memory_config() -> config::Config {
+        dummy_config_at("/not/a/real/store")
+    }
+
+    pub(crate) fn dummy_config_at(store: &str) -> config::Config {
         config::Config {
             config_dir: "/not/a/real/dir".into(),
+            no_agent: false,
+            force_retry: false,
+            dry_run: false,
             // NOTE: We create the backend above manually, so the public_key and keyfile
             // here are dummy values that shouldn't need to be interacted with.
             public_key: "not a real public key".into(),
             keyfile: "not a real private key file".into(),
+            escrow_recipients: vec![],
             agent_autostart: false,
+            agent_spawn_timeout_ms: config::DEFAULT_AGENT_SPAWN_TIMEOUT_MS,
+            agent_binary: None,
+            agent_strict: false,
             wrapped: false,
-            store: store.path().to_str().unwrap().into(),
+            store: store.into(),
+            store_backend: config::StoreBackendKind::Fs,
+            record_filenames: config::RecordFilenames::Direct,
+            label_normalization: config::LabelNormalization::None,
+            record_extension: None,
+            compress: Default::default(),
             pinentry: Default::default(),
+            unlock_max_attempts: config::DEFAULT_UNLOCK_MAX_ATTEMPTS,
+            unlock_backoff_ms: config::DEFAULT_UNLOCK_BACKOFF_MS,
+            locale: Default::default(),
+            check_for_updates: false,
             pre_hook: None,
             post_hook: None,
             error_hook: None,
             reentrant_hooks: false,
+            dry_run_pre_hooks: false,
+            hook_cwd: Default::default(),
+            track_access: false,
+            max_record_size: config::DEFAULT_MAX_RECORD_SIZE,
+            stream_threshold: config::DEFAULT_STREAM_THRESHOLD,
+            manifest: false,
+            verify_manifest: false,
+            min_generator_entropy: config::DEFAULT_MIN_GENERATOR_ENTROPY,
+            forbid_seeded_generation: false,
+            confirm_threshold: None,
+            audit_log: None,
+            audit_log_max_bytes: crate::kbs2::audit::DEFAULT_AUDIT_LOG_MAX_BYTES,
+            audit_records: true,
+            list_usernames: true,
+            confirm_dump: true,
             generators: vec![Default::default()],
+            sync: Default::default(),
+            ui: Default::default(),
             commands: Default::default(),
         }
     }
 
-    fn dummy_session(config: &config::Config) -> Session {
-        let backend = {
-            let key = age::x25519::Identity::generate();
+    fn dummy_backend() -> RageLib {
+        let key = age::x25519::Identity::generate();
 
-            RageLib {
-                pubkey: key.to_public(),
-                identities: vec![key],
-            }
-        };
+        RageLib {
+            pubkey: key.to_public().into(),
+            identities: vec![Box::new(key)],
+            escrow: vec![],
+            compression: Default::default(),
+            max_record_size: config::DEFAULT_MAX_RECORD_SIZE,
+        }
+    }
 
-        Session { backend, config }
+    pub(crate) fn dummy_session(config: &config::Config) -> Session<'_> {
+        Session {
+            backend: dummy_backend(),
+            config,
+            store: Box::new(FsStore::new(config)),
+            batch: Default::default(),
+            locked: Default::default(),
+        }
+    }
+
+    /// Like `dummy_session`, but with the store kept in memory rather than on disk.
+    fn memory_session(config: &config::Config) -> Session<'_> {
+        Session {
+            backend: dummy_backend(),
+            config,
+            store: Box::new(MemoryStore::default()),
+            batch: Default::default(),
+            locked: Default::default(),
+        }
     }
 
     // TODO: Figure out how to test Session::new. Doing so will require an interface for
@@ -196,17 +1186,15 @@ mod tests {
     #[test]
     fn test_record_labels() {
         {
-            let store = tempdir().unwrap();
-            let config = dummy_config(&store);
-            let session = dummy_session(&config);
+            let config = memory_config();
+            let session = memory_session(&config);
 
             assert_eq!(session.record_labels().unwrap(), Vec::<String>::new());
         }
 
         {
-            let store = tempdir().unwrap();
-            let config = dummy_config(&store);
-            let session = dummy_session(&config);
+            let config = memory_config();
+            let session = memory_session(&config);
             let record = dummy_login("foo", "bar", "baz");
 
             session.add_record(&record).unwrap();
@@ -215,11 +1203,327 @@ mod tests {
     }
 
     #[test]
-    fn test_has_record() {
+    fn test_label_normalization() {
+        let mut config = memory_config();
+        config.label_normalization = config::LabelNormalization::Lower;
+        let session = memory_session(&config);
+
+        // New records are stored under their normalized labels...
+        session
+            .add_record(&dummy_login("GitHub", "bar", "baz"))
+            .unwrap();
+        assert_eq!(session.record_labels().unwrap(), vec!["github"]);
+        assert!(session.has_record("GITHUB"));
+        assert_eq!(session.get_record("GitHub").unwrap().label, "github");
+
+        // ...while records stored under unnormalized labels are still found, and updated
+        // in place.
+        session
+            .write_record(&dummy_login("Legacy", "bar", "baz"))
+            .unwrap();
+        assert!(session.has_record("legacy"));
+        assert_eq!(session.get_record("LEGACY").unwrap().label, "Legacy");
+
+        session
+            .add_record(&dummy_login("legacy", "quux", "baz"))
+            .unwrap();
+        assert_eq!(session.record_labels().unwrap(), vec!["Legacy", "github"]);
+
+        // Renaming normalizes the new label, even when it only differs by case.
+        session.rename_record("legacy", "Legacy").unwrap();
+        assert_eq!(session.record_labels().unwrap(), vec!["github", "legacy"]);
+        assert_eq!(
+            session.get_record("legacy").unwrap().body,
+            dummy_login("legacy", "quux", "baz").body
+        );
+
+        session.delete_record("GITHUB").unwrap();
+        assert_eq!(session.record_labels().unwrap(), vec!["legacy"]);
+    }
+
+    #[test]
+    fn test_label_normalization_nfc() {
+        let mut config = memory_config();
+        config.label_normalization = config::LabelNormalization::Nfc;
+        let session = memory_session(&config);
+
+        // A combining accent is composed, so both spellings find the same record.
+        session
+            .add_record(&dummy_login("cafe\u{301}", "bar", "baz"))
+            .unwrap();
+        assert_eq!(session.record_labels().unwrap(), vec!["caf\u{e9}"]);
+        assert!(session.has_record("cafe\u{301}"));
+        assert_eq!(session.get_record("caf\u{e9}").unwrap().label, "caf\u{e9}");
+
+        // Case is left alone.
+        assert!(!session.has_record("CAF\u{c9}"));
+
+        // Records stored decomposed are still found.
+        session
+            .write_record(&dummy_login("re\u{301}sume\u{301}", "bar", "baz"))
+            .unwrap();
+        assert!(session.has_record("r\u{e9}sum\u{e9}"));
+    }
+
+    #[test]
+    fn test_cached_record_labels() {
+        let store = tempdir().unwrap();
+        let config_dir = tempdir().unwrap();
+        let mut config = dummy_config(&store);
+        config.config_dir = config_dir.path().to_str().unwrap().into();
+        config.commands.list.cache_labels = true;
+        let session = dummy_session(&config);
+
+        let set_store_mtime = |mtime| {
+            fs::File::open(store.path())
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+        let (earlier, earliest) = (
+            SystemTime::now() - Duration::from_secs(10),
+            SystemTime::now() - Duration::from_secs(20),
+        );
+
+        session
+            .add_record(&dummy_login("foo", "bar", "baz"))
+            .unwrap();
+
+        // A store that was just modified isn't cached, since its mtime can't be trusted yet.
+        assert_eq!(session.cached_record_labels().unwrap(), vec!["foo"]);
+        assert!(!config_dir.path().join(LABEL_CACHE_BASENAME).exists());
+
+        set_store_mtime(earlier);
+        assert_eq!(session.cached_record_labels().unwrap(), vec!["foo"]);
+        assert!(config_dir.path().join(LABEL_CACHE_BASENAME).exists());
+
+        // Sneak a record in behind the cache's back: the cache is used, since
+        // the store's mtime is unchanged.
+        session
+            .add_record(&dummy_login("quux", "bar", "baz"))
+            .unwrap();
+        set_store_mtime(earlier);
+        assert_eq!(session.cached_record_labels().unwrap(), vec!["foo"]);
+
+        // Any change to the store's mtime invalidates the cache.
+        set_store_mtime(earliest);
+        let mut labels = session.cached_record_labels().unwrap();
+        labels.sort();
+        assert_eq!(labels, vec!["foo", "quux"]);
+    }
+
+    #[test]
+    fn test_access_tracking() {
+        // Nothing is tracked by default.
         {
             let store = tempdir().unwrap();
-            let config = dummy_config(&store);
+            let config_dir = tempdir().unwrap();
+            let mut config = dummy_config(&store);
+            config.config_dir = config_dir.path().to_str().unwrap().into();
+            let session = dummy_session(&config);
+
+            session
+                .add_record(&dummy_login("foo", "bar", "baz"))
+                .unwrap();
+            session.get_record("foo").unwrap();
+            session.track_access("foo");
+            assert!(session.last_accesses().unwrap().is_empty());
+            assert!(!config_dir.path().join(ACCESS_LOG_BASENAME).exists());
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let config_dir = tempdir().unwrap();
+            let mut config = dummy_config(&store);
+            config.config_dir = config_dir.path().to_str().unwrap().into();
+            config.track_access = true;
             let session = dummy_session(&config);
+
+            session
+                .add_record(&dummy_login("foo", "bar", "baz"))
+                .unwrap();
+            session
+                .add_record(&dummy_login("quux", "bar", "baz"))
+                .unwrap();
+
+            // Reads alone aren't accesses.
+            session.get_record("foo").unwrap();
+            session
+                .get_records(&["foo".into(), "quux".into()], 1)
+                .unwrap();
+            assert!(session.last_accesses().unwrap().is_empty());
+
+            session.track_access("foo");
+            session.track_access("foo");
+
+            let accesses = session.last_accesses().unwrap();
+            assert_eq!(accesses.len(), 1);
+            assert!(accesses["foo"] <= util::current_timestamp());
+            assert_eq!(
+                fs::metadata(session.access_log_path())
+                    .unwrap()
+                    .permissions()
+                    .mode()
+                    & 0o777,
+                0o600
+            );
+
+            // The log doesn't show up as a record.
+            let mut labels = session.record_labels().unwrap();
+            labels.sort();
+            assert_eq!(labels, vec!["foo", "quux"]);
+
+            session.reset_access_log().unwrap();
+            assert!(session.last_accesses().unwrap().is_empty());
+
+            // Resetting twice is fine.
+            session.reset_access_log().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_audit_records() {
+        let store = tempdir().unwrap();
+        let log_dir = tempdir().unwrap();
+        let audit_log = log_dir.path().join("audit.log");
+        let mut config = dummy_config(&store);
+        config.audit_log = Some(audit_log.to_str().unwrap().into());
+        let session = dummy_session(&config);
+
+        session
+            .add_record(&dummy_login("foo", "bar", "hunter2"))
+            .unwrap();
+        session.get_record("foo").unwrap();
+        session.get_records(&["foo".into()], 1).unwrap();
+        session.rename_record("foo", "quux").unwrap();
+        assert!(session.get_record("foo").is_err());
+        session.delete_record("quux").unwrap();
+
+        let logged = audit::entries(&audit_log)
+            .unwrap()
+            .into_iter()
+            .map(|e| match e.event {
+                audit::Event::Record {
+                    label,
+                    operation,
+                    outcome,
+                } => (label, operation, outcome),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+
+        let success = audit::Outcome::Success;
+        assert_eq!(
+            logged,
+            [
+                ("foo".into(), Operation::Write, success),
+                ("foo".into(), Operation::Read, success),
+                ("foo".into(), Operation::Read, success),
+                // Renaming reads the old record, writes the new one, and deletes the old one.
+                ("foo".into(), Operation::Read, success),
+                ("quux".into(), Operation::Write, success),
+                ("foo".into(), Operation::Delete, success),
+                ("foo".into(), Operation::Read, audit::Outcome::Failure),
+                ("quux".into(), Operation::Delete, success),
+            ]
+        );
+
+        // Record contents never end up in the log.
+        assert!(!std::fs::read_to_string(&audit_log)
+            .unwrap()
+            .contains("hunter2"));
+
+        // Record operations can be left out of the log.
+        drop(session);
+        std::fs::remove_file(&audit_log).unwrap();
+        config.audit_records = false;
+        let session = dummy_session(&config);
+        session
+            .add_record(&dummy_login("foo", "bar", "hunter2"))
+            .unwrap();
+        session.get_record("foo").unwrap();
+        assert!(!audit_log.exists());
+    }
+
+    #[test]
+    fn test_record_labels_bad_store() {
+        let store = tempdir().unwrap();
+        let mut config = dummy_config(&store);
+
+        // A store that's a symlink loop produces an error that names the store.
+        let looped = store.path().join("loop");
+        std::os::unix::fs::symlink(&looped, &looped).unwrap();
+        config.store = looped.to_str().unwrap().into();
+
+        let session = dummy_session(&config);
+        let err = session.record_labels().unwrap_err().to_string();
+        assert!(err.contains(&config.store));
+        assert!(err.contains("unmounted"));
+    }
+
+    #[test]
+    fn test_escaped_record_filenames() {
+        let store = tempdir().unwrap();
+        let mut config = dummy_config(&store);
+        config.record_filenames = config::RecordFilenames::Escaped;
+        let session = dummy_session(&config);
+
+        session
+            .add_record(&dummy_login("GitHub", "bar", "baz"))
+            .unwrap();
+        session
+            .add_record(&dummy_login("github", "quux", "zap"))
+            .unwrap();
+
+        let mut labels = session.record_labels().unwrap();
+        labels.sort();
+        assert_eq!(labels, vec!["GitHub", "github"]);
+
+        assert_eq!(session.get_record("GitHub").unwrap().label, "GitHub");
+        assert_eq!(session.get_record("github").unwrap().label, "github");
+
+        session.delete_record("GitHub").unwrap();
+        assert!(!session.has_record("GitHub"));
+        assert!(session.has_record("github"));
+    }
+
+    #[test]
+    fn test_record_extension() {
+        let store = tempdir().unwrap();
+        let mut config = dummy_config(&store);
+        config.record_extension = Some("age".into());
+        let session = dummy_session(&config);
+
+        session
+            .add_record(&dummy_login("new", "quux", "zap"))
+            .unwrap();
+
+        // A record written before the extension was configured.
+        session
+            .add_record(&dummy_login("old", "bar", "baz"))
+            .unwrap();
+        fs::rename(store.path().join("old.age"), store.path().join("old")).unwrap();
+
+        assert!(store.path().join("new.age").is_file());
+        assert!(store.path().join("old").is_file());
+
+        let mut labels = session.record_labels().unwrap();
+        labels.sort();
+        assert_eq!(labels, vec!["new", "old"]);
+
+        assert_eq!(session.get_record("old").unwrap().label, "old");
+        assert_eq!(session.get_record("new").unwrap().label, "new");
+
+        session.delete_record("new").unwrap();
+        assert!(!session.has_record("new"));
+        assert!(!store.path().join("new.age").exists());
+    }
+
+    #[test]
+    fn test_has_record() {
+        {
+            let config = memory_config();
+            let session = memory_session(&config);
             let record = dummy_login("foo", "bar", "baz");
 
             session.add_record(&record).unwrap();
@@ -227,9 +1531,8 @@ mod tests {
         }
 
         {
-            let store = tempdir().unwrap();
-            let config = dummy_config(&store);
-            let session = dummy_session(&config);
+            let config = memory_config();
+            let session = memory_session(&config);
 
             assert!(!session.has_record("does-not-exist"));
         }
@@ -238,9 +1541,8 @@ mod tests {
     #[test]
     fn test_get_record() {
         {
-            let store = tempdir().unwrap();
-            let config = dummy_config(&store);
-            let session = dummy_session(&config);
+            let config = memory_config();
+            let session = memory_session(&config);
             let record = dummy_login("foo", "bar", "baz");
 
             session.add_record(&record).unwrap();
@@ -251,21 +1553,331 @@ mod tests {
         }
 
         {
-            let store = tempdir().unwrap();
-            let config = dummy_config(&store);
-            let session = dummy_session(&config);
+            let config = memory_config();
+            let session = memory_session(&config);
 
             let err = session.get_record("foo").unwrap_err();
             assert_eq!(err.to_string(), "no such record: foo");
         }
     }
 
+    #[test]
+    fn test_get_records() {
+        let config = memory_config();
+        let session = memory_session(&config);
+
+        let labels = (0..10).map(|i| format!("record-{i}")).collect::<Vec<_>>();
+        for label in &labels {
+            session
+                .add_record(&dummy_login(label, "bar", "baz"))
+                .unwrap();
+        }
+
+        // Records come back in the order requested, however many threads decrypt them.
+        for jobs in [1, 3, 10, 16] {
+            let records = session.get_records(&labels, jobs).unwrap();
+            let retrieved = records.iter().map(|r| &r.label).collect::<Vec<_>>();
+            assert_eq!(retrieved, labels.iter().collect::<Vec<_>>());
+        }
+
+        assert!(session.get_records(&[], 4).unwrap().is_empty());
+
+        let err = session
+            .get_records(&["record-0".into(), "nope".into()], 2)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no such record: nope");
+    }
+
+    #[test]
+    fn test_add_records() {
+        let config = memory_config();
+        let session = memory_session(&config);
+
+        let records = (0..10)
+            .map(|i| dummy_login(&format!("record-{i}"), "bar", &format!("baz{i}")))
+            .collect::<Vec<_>>();
+        session.add_records(&records, 3).unwrap();
+
+        let labels = records.iter().map(|r| r.label.clone()).collect::<Vec<_>>();
+        let retrieved = session.get_records(&labels, 1).unwrap();
+        assert_eq!(retrieved, records);
+
+        assert!(session.add_records(&[], 4).is_ok());
+
+        // A single unencryptable record means that nothing gets written.
+        let mut bad = dummy_login("bad", "bar", "baz");
+        bad.recipients = Some(vec!["age1notthisstore".into()]);
+        let records = [dummy_login("good", "bar", "baz"), bad];
+        assert!(session.add_records(&records, 2).is_err());
+        assert!(!session.has_record("good"));
+    }
+
+    #[test]
+    fn test_iter_records() {
+        let config = memory_config();
+        let session = memory_session(&config);
+
+        for label in ["foo", "bar"] {
+            session
+                .add_record(&dummy_login(label, "baz", "quux"))
+                .unwrap();
+        }
+
+        let labels = vec!["foo".to_string(), "nope".into(), "bar".into()];
+
+        // Failing stops at the missing record.
+        let mut records = session.iter_records(&labels, OnRecordError::Fail);
+        assert_eq!(records.next().unwrap().unwrap().label, "foo");
+        assert_eq!(
+            records.next().unwrap().unwrap_err().to_string(),
+            "no such record: nope"
+        );
+        assert!(session
+            .iter_records(&labels, OnRecordError::Fail)
+            .collect::<Result<Vec<_>>>()
+            .is_err());
+
+        // Warning skips it.
+        let records = session
+            .iter_records(&labels, OnRecordError::Warn)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.label.as_str()).collect::<Vec<_>>(),
+            ["foo", "bar"]
+        );
+    }
+
+    #[test]
+    fn test_get_record_max_size() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        dummy_session(&config)
+            .add_record(&dummy_login("foo", "bar", "baz"))
+            .unwrap();
+
+        // Every record's ciphertext is well over 64 bytes.
+        let mut config = dummy_config(&store);
+        config.max_record_size = 64;
+        let err = dummy_session(&config).get_record("foo").unwrap_err();
+        assert!(format!("{err:#}").contains("max-record-size"));
+    }
+
+    #[test]
+    fn test_record_streaming() {
+        let store = tempdir().unwrap();
+        let mut config = dummy_config(&store);
+        config.max_record_size = 16 * 1024 * 1024;
+
+        // NOTE: Each config gets its own session, but they all share a key.
+        fn session_at<'a>(config: &'a config::Config, key: &age::x25519::Identity) -> Session<'a> {
+            Session {
+                backend: RageLib {
+                    pubkey: key.to_public().into(),
+                    identities: vec![Box::new(key.clone())],
+                    escrow: vec![],
+                    compression: Default::default(),
+                    max_record_size: config::DEFAULT_MAX_RECORD_SIZE,
+                },
+                config,
+                store: Box::new(FsStore::new(config)),
+                batch: Default::default(),
+                locked: Default::default(),
+            }
+        }
+
+        let key = age::x25519::Identity::generate();
+        let session = session_at(&config, &key);
+
+        let big = |label: &str, len: usize| {
+            Record::new(
+                label,
+                RecordBody::Unstructured(record::UnstructuredFields {
+                    contents: "x".repeat(len),
+                    extra: Default::default(),
+                }),
+            )
+        };
+
+        assert_eq!(serialized_len(&big("foo", 0)).unwrap(), {
+            serde_json::to_string(&big("foo", 0)).unwrap().len() as u64
+        });
+
+        // Records above the threshold are streamed, and read back either way.
+        session.add_record(&big("big", 4 * 1024 * 1024)).unwrap();
+        session.add_record(&big("small", 1024)).unwrap();
+        assert_eq!(
+            session.get_record("big").unwrap().body,
+            big("big", 4 * 1024 * 1024).body
+        );
+        assert_eq!(
+            session.get_record("small").unwrap().body,
+            big("small", 1024).body
+        );
+
+        let mut buffered = dummy_config(&store);
+        buffered.max_record_size = config.max_record_size;
+        buffered.stream_threshold = u64::MAX;
+        let buffered = session_at(&buffered, &key);
+        assert_eq!(
+            buffered.get_record("big").unwrap().body,
+            big("big", 4 * 1024 * 1024).body
+        );
+
+        // A streamed record over max-record-size isn't written, and doesn't clobber the
+        // record it would have replaced.
+        let mut limited = dummy_config(&store);
+        limited.max_record_size = 2 * 1024 * 1024;
+        let limited = session_at(&limited, &key);
+        let err = limited
+            .add_record(&big("small", 4 * 1024 * 1024))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("record is too large: small ("));
+        assert_eq!(
+            session.get_record("small").unwrap().body,
+            big("small", 1024).body
+        );
+
+        let mut labels = session.record_labels().unwrap();
+        labels.sort();
+        assert_eq!(labels, ["big", "small"]);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let mut config = memory_config();
+        config.manifest = true;
+        let session = memory_session(&config);
+
+        assert!(session.manifest().unwrap().is_none());
+        assert!(session.manifest_discrepancies().unwrap().is_none());
+
+        // Every change through the session is recorded in the manifest.
+        session
+            .add_record(&dummy_login("foo", "bar", "baz"))
+            .unwrap();
+        session
+            .add_records(
+                &[
+                    dummy_login("quux", "bar", "baz"),
+                    dummy_login("zap", "bar", "baz"),
+                ],
+                2,
+            )
+            .unwrap();
+        session.rename_record("quux", "quuz").unwrap();
+        session.delete_record("zap").unwrap();
+
+        let manifest = session.manifest().unwrap().unwrap();
+        assert_eq!(manifest.records.keys().collect::<Vec<_>>(), ["foo", "quuz"]);
+        assert_eq!(
+            manifest.records["foo"],
+            Manifest::hash(session.store.read("foo", u64::MAX).unwrap().as_slice()).unwrap()
+        );
+        assert!(session
+            .manifest_discrepancies()
+            .unwrap()
+            .unwrap()
+            .is_empty());
+
+        // Changes made behind the session's back aren't.
+        let foo = session.store.read("foo", u64::MAX).unwrap();
+        session
+            .store
+            .write(
+                "foo",
+                &session
+                    .backend
+                    .encrypt(&dummy_login("foo", "bar", "evil"))
+                    .unwrap(),
+            )
+            .unwrap();
+        session.store.delete("quuz").unwrap();
+        session
+            .store
+            .write("new", std::str::from_utf8(&foo).unwrap())
+            .unwrap();
+        assert_eq!(
+            session.manifest_discrepancies().unwrap().unwrap(),
+            vec![
+                Discrepancy::Modified("foo".into()),
+                Discrepancy::Unexpected("new".into()),
+                Discrepancy::Missing("quuz".into()),
+            ]
+        );
+
+        session.rebuild_manifest().unwrap();
+        assert!(session
+            .manifest_discrepancies()
+            .unwrap()
+            .unwrap()
+            .is_empty());
+
+        // Without the manifest enabled, nothing is tracked.
+        let config = memory_config();
+        let session = memory_session(&config);
+        session
+            .add_record(&dummy_login("foo", "bar", "baz"))
+            .unwrap();
+        assert!(session.manifest().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_add_record_recipients() {
+        let key = age::x25519::Identity::generate();
+        let mut config = memory_config();
+        config.public_key = key.to_public().to_string();
+        let session = Session {
+            backend: RageLib {
+                pubkey: key.to_public().into(),
+                identities: vec![Box::new(key)],
+                escrow: vec![],
+                compression: Default::default(),
+                max_record_size: config::DEFAULT_MAX_RECORD_SIZE,
+            },
+            config: &config,
+            store: Box::new(MemoryStore::default()),
+            batch: Default::default(),
+            locked: Default::default(),
+        };
+        let other = age::x25519::Identity::generate().to_public().to_string();
+
+        let mut record = dummy_login("foo", "bar", "baz");
+        record.recipients = Some(vec![config.public_key.clone(), other.clone()]);
+        session.add_record(&record).unwrap();
+        assert_eq!(session.get_record("foo").unwrap(), record);
+
+        // We refuse to write a record that we couldn't read back.
+        record.recipients = Some(vec![other]);
+        let err = session.add_record(&record).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "refusing to encrypt foo without this store's public key as a recipient"
+        );
+    }
+
+    #[test]
+    fn test_add_record_max_size() {
+        let mut config = memory_config();
+        config.max_record_size = 1024;
+        let session = memory_session(&config);
+
+        session
+            .add_record(&dummy_login("small", "bar", "baz"))
+            .unwrap();
+
+        let err = session
+            .add_record(&dummy_login("big", "bar", &"x".repeat(1024)))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("record is too large: big ("));
+        assert!(!session.has_record("big"));
+    }
+
     #[test]
     fn test_add_record() {
         {
-            let store = tempdir().unwrap();
-            let config = dummy_config(&store);
-            let session = dummy_session(&config);
+            let config = memory_config();
+            let session = memory_session(&config);
 
             let record1 = dummy_login("foo", "bar", "baz");
             session.add_record(&record1).unwrap();
@@ -290,24 +1902,87 @@ mod tests {
     }
 
     #[test]
-    fn test_delete_record() {
+    fn test_import_raw_record() {
         {
             let store = tempdir().unwrap();
             let config = dummy_config(&store);
             let session = dummy_session(&config);
             let record = dummy_login("foo", "bar", "baz");
 
-            session.add_record(&record).unwrap();
+            let armored = session.backend.encrypt(&record).unwrap();
+            let resolution = session.import_raw_record("foo", &armored, None).unwrap();
 
-            assert!(session.delete_record("foo").is_ok());
+            assert_eq!(resolution, Resolution::Add("foo".into()));
+            assert_eq!(session.get_record("foo").unwrap(), record);
+            assert_eq!(
+                std::fs::read_to_string(store.path().join("foo")).unwrap(),
+                armored
+            );
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let config = dummy_config(&store);
+            let session = dummy_session(&config);
+            let record = dummy_login("foo", "bar", "baz");
+
+            let armored = session.backend.encrypt(&record).unwrap();
+            let err = session
+                .import_raw_record("quux", &armored, None)
+                .unwrap_err();
+
+            assert_eq!(
+                err.to_string(),
+                "label mismatch: expected quux, but the record is labeled foo"
+            );
+            assert!(!session.has_record("quux"));
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let config = dummy_config(&store);
+            let session = dummy_session(&config);
+
+            assert!(session.import_raw_record("foo", "garbage", None).is_err());
             assert!(!session.has_record("foo"));
-            assert_eq!(session.record_labels().unwrap(), Vec::<String>::new());
         }
 
         {
             let store = tempdir().unwrap();
             let config = dummy_config(&store);
             let session = dummy_session(&config);
+            let record = dummy_login("foo", "bar", "baz");
+
+            let armored = session.backend.encrypt(&record).unwrap();
+            session.import_raw_record("foo", &armored, None).unwrap();
+
+            assert!(session.import_raw_record("foo", &armored, None).is_err());
+
+            let resolution = session
+                .import_raw_record("foo", &armored, Some(ConflictStrategy::Rename))
+                .unwrap();
+            assert_eq!(resolution, Resolution::Rename("foo-1".into()));
+            assert_eq!(session.get_record("foo-1").unwrap().label, "foo-1");
+        }
+    }
+
+    #[test]
+    fn test_delete_record() {
+        {
+            let config = memory_config();
+            let session = memory_session(&config);
+            let record = dummy_login("foo", "bar", "baz");
+
+            session.add_record(&record).unwrap();
+
+            assert!(session.delete_record("foo").is_ok());
+            assert!(!session.has_record("foo"));
+            assert_eq!(session.record_labels().unwrap(), Vec::<String>::new());
+        }
+
+        {
+            let config = memory_config();
+            let session = memory_session(&config);
 
             let record1 = dummy_login("foo", "bar", "baz");
             session.add_record(&record1).unwrap();
@@ -320,21 +1995,63 @@ mod tests {
         }
 
         {
-            let store = tempdir().unwrap();
-            let config = dummy_config(&store);
-            let session = dummy_session(&config);
+            let config = memory_config();
+            let session = memory_session(&config);
 
             let err = session.delete_record("does-not-exist").unwrap_err();
             assert_eq!(err.to_string(), "no such record: does-not-exist");
         }
     }
 
+    #[test]
+    fn test_burn_after() {
+        let store = tempdir().unwrap();
+        let mut config = dummy_config(&store);
+        // NOTE: The manifest means that deleting the record takes the store's lock again,
+        // which must not deadlock with the lock that's already held.
+        config.manifest = true;
+        let session = dummy_session(&config);
+
+        let mut record = dummy_login("foo", "bar", "baz");
+        record.burn = true;
+        session.add_record(&record).unwrap();
+        session
+            .add_record(&dummy_login("kept", "bar", "baz"))
+            .unwrap();
+
+        // A failed read doesn't burn the record.
+        assert!(session
+            .burn_after(&record, || Err(anyhow!("broken pipe")))
+            .is_err());
+        assert!(session.has_record("foo"));
+
+        assert!(session.burn_after(&record, || Ok(())).unwrap());
+        assert!(!session.has_record("foo"));
+        assert!(!session
+            .manifest()
+            .unwrap()
+            .unwrap()
+            .records
+            .contains_key("foo"));
+
+        // A second read (e.g. by another process that read the record before it was
+        // burned) fails without running `op`.
+        let err = session
+            .burn_after(&record, || panic!("burned record read twice"))
+            .unwrap_err();
+        assert!(err.downcast_ref::<NoSuchRecord>().is_some());
+
+        // Other records are untouched.
+        let kept = session.get_record("kept").unwrap();
+        assert!(!session.burn_after(&kept, || Ok(())).unwrap());
+        assert!(session.has_record("kept"));
+    }
+
     #[test]
     fn test_rename_record() {
         {
-            let store = tempdir().unwrap();
-            let config = dummy_config(&store);
-            let session = dummy_session(&config);
+            let config = memory_config();
+            let session = memory_session(&config);
             let record = dummy_login("foo", "bar", "baz");
 
             session.add_record(&record).unwrap();
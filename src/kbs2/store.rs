@@ -0,0 +1,624 @@
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+
+use crate::kbs2::config;
+use crate::kbs2::manifest::MANIFEST_BASENAME;
+use crate::kbs2::session::NoSuchRecord;
+use crate::kbs2::util;
+
+/// How long to wait for another `kbs2` process that's changing the store.
+const STORE_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An exclusive lock on a store, released when dropped.
+pub struct StoreLock {
+    _guard: Option<Flock<File>>,
+}
+
+/// Represents the operations that every store backend supports.
+///
+/// A store maps record labels to their encrypted (ASCII-armored) contents. Backends know
+/// nothing about encryption: that's the `Session`'s job.
+///
+/// NOTE: There's deliberately no `rename`: a record's label is part of its ciphertext, so
+/// renaming a record always means writing it anew and deleting the old one.
+pub trait StoreBackend {
+    /// Returns the label of every record in the store.
+    fn labels(&self) -> Result<Vec<String>>;
+
+    /// Returns the names of any files in the store that can't be records, since their
+    /// names aren't valid UTF-8. `labels` skips these.
+    ///
+    /// By default, there are none.
+    fn invalid_names(&self) -> Result<Vec<OsString>> {
+        Ok(vec![])
+    }
+
+    /// Returns whether the store contains a record with the given label.
+    fn exists(&self, label: &str) -> bool;
+
+    /// Reads the encrypted contents of the given record, failing if they're larger
+    /// than `limit` bytes.
+    fn read(&self, label: &str, limit: u64) -> Result<Vec<u8>>;
+
+    /// Writes the given encrypted contents to the given record, replacing it if it exists.
+    fn write(&self, label: &str, contents: &str) -> Result<()>;
+
+    /// Deletes the given record, failing with `NoSuchRecord` if it doesn't exist.
+    fn delete(&self, label: &str) -> Result<()>;
+
+    /// Returns the size of the given record's encrypted contents, if the backend can tell
+    /// without reading them.
+    fn record_len(&self, _label: &str) -> Option<u64> {
+        None
+    }
+
+    /// Like `read`, but returns a reader for the encrypted contents instead of reading
+    /// them all up front.
+    ///
+    /// By default, this reads them all up front anyways.
+    fn read_stream(&self, label: &str, limit: u64) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(io::Cursor::new(self.read(label, limit)?)))
+    }
+
+    /// Like `write`, but writes the encrypted contents as `produce` emits them. If
+    /// `produce` fails, the record is left as it was.
+    ///
+    /// By default, this collects them all before writing them.
+    fn write_stream(
+        &self,
+        label: &str,
+        produce: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+    ) -> Result<()> {
+        let mut contents = vec![];
+        produce(&mut contents)?;
+
+        self.write(label, std::str::from_utf8(&contents)?)
+    }
+
+    /// Reads the store's encrypted manifest, if it has one.
+    fn read_manifest(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Writes the given encrypted manifest to the store, replacing any existing one.
+    fn write_manifest(&self, _contents: &str) -> Result<()> {
+        Err(anyhow!("this store backend doesn't support manifests"))
+    }
+
+    /// Takes an exclusive lock on the store, waiting for any other `kbs2` process that
+    /// holds it.
+    ///
+    /// By default, this does nothing.
+    fn lock(&self) -> Result<StoreLock> {
+        Ok(StoreLock { _guard: None })
+    }
+
+    /// Returns when the store last changed, if the backend can tell reliably.
+    fn modified(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Returns when the given record last changed, if the backend can tell.
+    fn record_modified(&self, _label: &str) -> Option<SystemTime> {
+        None
+    }
+}
+
+/// Opens the store backend selected by the given config.
+pub fn open(config: &config::Config) -> Result<Box<dyn StoreBackend + '_>> {
+    match config.store_backend {
+        config::StoreBackendKind::Fs => {
+            // NOTE(ww): `create_dir_all` fails with a cryptic "File exists" when the store
+            // is a file, which is an easy mistake to make in the config.
+            if fs::metadata(&config.store).is_ok_and(|m| !m.is_dir()) {
+                return Err(anyhow!(
+                    "the store path exists, but is a file rather than a directory: {}; \
+                     check the `store` setting in {}",
+                    config.store,
+                    config.config_path().display()
+                ));
+            }
+
+            fs::create_dir_all(&config.store).with_context(|| {
+                format!(
+                    "failed to create the store at {}; is it a broken link or on an unmounted drive?",
+                    config.store
+                )
+            })?;
+
+            Ok(Box::new(FsStore::new(config)))
+        }
+    }
+}
+
+/// A store backend that keeps each record in its own file, within the `store` directory.
+pub struct FsStore<'a> {
+    config: &'a config::Config,
+
+    /// The names of the files in the store, listed at most once and then kept up to date,
+    /// for finding records stored under their direct filenames.
+    filenames: RefCell<OnceCell<HashSet<OsString>>>,
+}
+
+impl<'a> FsStore<'a> {
+    /// Creates a filesystem store for the given config's `store` directory, which
+    /// should already exist.
+    pub fn new(config: &'a config::Config) -> Self {
+        Self {
+            config,
+            filenames: Default::default(),
+        }
+    }
+
+    /// Updates the cached filenames (if they've been listed) after `path` was written
+    /// or removed.
+    #[doc(hidden)]
+    fn touch_filename(&self, path: &Path, exists: bool) {
+        let mut filenames = self.filenames.borrow_mut();
+        let (Some(filenames), Some(name)) = (filenames.get_mut(), path.file_name()) else {
+            return;
+        };
+
+        if exists {
+            filenames.insert(name.to_owned());
+        } else {
+            filenames.remove(name);
+        }
+    }
+
+    /// Returns the path to the file that the given record is (or would be) stored in,
+    /// refusing paths that belong to `kbs2` itself, e.g. the keyfile in a store that
+    /// shares a directory with the config.
+    #[doc(hidden)]
+    fn record_path(&self, label: &str) -> Result<PathBuf> {
+        let path = self.config.record_path_in(label, &self.filenames.borrow());
+        if self.config.is_own_path(&path) {
+            return Err(anyhow!(
+                "refusing to use {} as a record, since it's one of kbs2's own files; pick another label",
+                path.display()
+            ));
+        }
+
+        Ok(path)
+    }
+
+    /// Like `record_path`, but also refuses labels that `labels` would never list, so that
+    /// records can't be created only to vanish from the store.
+    #[doc(hidden)]
+    fn writable_record_path(&self, label: &str) -> Result<PathBuf> {
+        if self.config.is_reserved_label(label) {
+            return Err(anyhow!(
+                "refusing to write a record named {label}, since it's named like one of kbs2's \
+                 own files; pick another label"
+            ));
+        }
+
+        self.record_path(label)
+    }
+
+    /// Returns the label of every record in the store, along with the names of any files
+    /// that were skipped for not being valid UTF-8 and of any (non-hidden) subdirectories.
+    #[doc(hidden)]
+    fn scan(&self) -> Result<(Vec<String>, Vec<OsString>, Vec<OsString>)> {
+        let store = self.config.store_path();
+
+        if !store.is_dir() {
+            return Err(anyhow!(
+                "secret store is not a directory: {}; is it missing or unmounted?",
+                store.display()
+            ));
+        }
+
+        let entries = fs::read_dir(&store).with_context(|| {
+            format!(
+                "failed to read the store at {}; is it missing or unmounted?",
+                store.display()
+            )
+        })?;
+
+        let mut labels = vec![];
+        let mut invalid = vec![];
+        let mut dirs = vec![];
+        let mut seen = HashSet::new();
+        let mut filenames = HashSet::new();
+        for entry in entries {
+            // NOTE(ww): One bad entry shouldn't make the rest of the store inaccessible.
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    util::warn(&format!("skipping unreadable entry in the store: {e}"));
+                    continue;
+                }
+            };
+            if !path.is_file() {
+                log::debug!("skipping non-file in store: {:?}", path);
+
+                // NOTE(ww): Hidden directories (e.g. `.git`, when syncing) are expected,
+                // but anything else probably holds records that someone expected to work.
+                if let Some(name) = path.file_name() {
+                    if path.is_dir() && !name.as_encoded_bytes().starts_with(b".") {
+                        dirs.push(name.to_owned());
+                    }
+                }
+                continue;
+            }
+
+            if path
+                .file_name()
+                .is_some_and(|name| name == MANIFEST_BASENAME)
+            {
+                log::debug!("skipping manifest in store: {:?}", path);
+                continue;
+            }
+
+            // NOTE(ww): This unwrap is safe, since file_name always returns Some
+            // for non-directories.
+            #[allow(clippy::expect_used)]
+            let filename = path
+                .file_name()
+                .expect("impossible: is_file=true for path but file_name=None");
+            filenames.insert(filename.to_owned());
+
+            // NOTE(ww): Non-UTF-8 labels aren't supported, but one stray file (e.g. an
+            // rsync temporary) shouldn't make the rest of the store inaccessible.
+            let Some(filename) = filename.to_str() else {
+                invalid.push(filename.to_owned());
+                continue;
+            };
+
+            let label = self.config.record_label(filename);
+
+            // NOTE(ww): The config and keyfile (and their backups) aren't records, even when
+            // the store shares a directory with them, so they're skipped by name.
+            if self.config.is_reserved_label(&label) {
+                log::debug!("skipping kbs2's own file in store: {:?}", path);
+                continue;
+            }
+
+            // NOTE(ww): With a record extension, a record can be present both with and
+            // without it (e.g. after syncing); `record_path` prefers the former.
+            if seen.insert(label.clone()) {
+                labels.push(label);
+            }
+        }
+
+        // NOTE(ww): We've just listed the store, so looking records up by label
+        // afterwards doesn't need to list it again.
+        *self.filenames.borrow_mut() = OnceCell::from(filenames);
+
+        Ok((labels, invalid, dirs))
+    }
+}
+
+impl StoreBackend for FsStore<'_> {
+    fn labels(&self) -> Result<Vec<String>> {
+        let (labels, invalid, dirs) = self.scan()?;
+        if !invalid.is_empty() {
+            util::warn(&format!(
+                "skipping {} file(s) in the store with non-UTF-8 names; run `kbs2 fsck` for details",
+                invalid.len()
+            ));
+        }
+
+        if !dirs.is_empty() {
+            let mut dirs = dirs
+                .iter()
+                .map(|dir| dir.to_string_lossy())
+                .collect::<Vec<_>>();
+            dirs.sort();
+            util::warn(&format!(
+                "skipping {} subdirectory(s) in the store ({}); kbs2 doesn't support nested records, \
+                 so move any records in them to the top level of the store",
+                dirs.len(),
+                dirs.join(", ")
+            ));
+        }
+
+        Ok(labels)
+    }
+
+    fn invalid_names(&self) -> Result<Vec<OsString>> {
+        self.scan().map(|(_, invalid, _)| invalid)
+    }
+
+    fn exists(&self, label: &str) -> bool {
+        self.record_path(label).is_ok_and(|path| path.is_file())
+    }
+
+    fn read(&self, label: &str, limit: u64) -> Result<Vec<u8>> {
+        util::read_guarded(self.record_path(label)?, limit)
+    }
+
+    fn write(&self, label: &str, contents: &str) -> Result<()> {
+        let path = self.writable_record_path(label)?;
+        fs::write(&path, contents)
+            .map_err(|e| util::write_context(e, &format!("writing record {label}"), &path))?;
+        self.touch_filename(&path, true);
+
+        Ok(())
+    }
+
+    fn delete(&self, label: &str) -> Result<()> {
+        let path = self.record_path(label)?;
+        fs::remove_file(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => anyhow::Error::from(NoSuchRecord(label.into())),
+            _ => util::write_context(e, &format!("removing record {label}"), &path),
+        })?;
+        self.touch_filename(&path, false);
+
+        Ok(())
+    }
+
+    fn record_len(&self, label: &str) -> Option<u64> {
+        fs::metadata(self.record_path(label).ok()?)
+            .map(|m| m.len())
+            .ok()
+    }
+
+    fn read_stream(&self, label: &str, limit: u64) -> Result<Box<dyn Read + '_>> {
+        let file = File::open(self.record_path(label)?)?;
+        if file.metadata()?.len() > limit {
+            return Err(anyhow!("requested file is suspiciously large, refusing"));
+        }
+
+        Ok(Box::new(BufReader::new(file)))
+    }
+
+    fn write_stream(
+        &self,
+        label: &str,
+        produce: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+    ) -> Result<()> {
+        let path = self.writable_record_path(label)?;
+
+        // NOTE(ww): We stream into a temporary file in the store and move it into place,
+        // so that a failure midway through doesn't clobber the existing record.
+        let mut write = || -> Result<()> {
+            let mut file = tempfile::NamedTempFile::new_in(&self.config.store)?;
+            {
+                let mut writer = BufWriter::new(file.as_file_mut());
+                produce(&mut writer)?;
+                writer.flush()?;
+            }
+
+            file.persist(&path)?;
+            Ok(())
+        };
+
+        write().map_err(|e| util::write_context(e, &format!("writing record {label}"), &path))?;
+        self.touch_filename(&path, true);
+
+        Ok(())
+    }
+
+    fn read_manifest(&self) -> Result<Option<String>> {
+        let path = self.config.store_path().join(MANIFEST_BASENAME);
+        match util::read_guarded(&path, self.config.max_record_size) {
+            Ok(contents) => {
+                Ok(Some(String::from_utf8(contents).map_err(|_| {
+                    anyhow!("manifest is not valid UTF-8: {}", path.display())
+                })?))
+            }
+            Err(e)
+                if e.downcast_ref::<io::Error>().map(|e| e.kind())
+                    == Some(io::ErrorKind::NotFound) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.context(format!("failed to read the manifest at {}", path.display()))),
+        }
+    }
+
+    fn write_manifest(&self, contents: &str) -> Result<()> {
+        let path = self.config.store_path().join(MANIFEST_BASENAME);
+        let write = || -> Result<()> {
+            let mut file = tempfile::NamedTempFile::new_in(&self.config.store)?;
+            file.write_all(contents.as_bytes())?;
+            file.persist(&path)?;
+            Ok(())
+        };
+
+        write().map_err(|e| util::write_context(e, "writing the manifest", &path))
+    }
+
+    fn lock(&self) -> Result<StoreLock> {
+        // NOTE(ww): We lock the store directory itself, rather than a lock file within it,
+        // so that there's nothing for `labels` to skip or for a sync to pick up.
+        let mut dir = File::open(&self.config.store)
+            .with_context(|| format!("failed to open the store at {}", self.config.store))?;
+
+        let start = Instant::now();
+        loop {
+            match Flock::lock(dir, FlockArg::LockExclusiveNonblock) {
+                Ok(lock) => return Ok(StoreLock { _guard: Some(lock) }),
+                Err((d, Errno::EWOULDBLOCK)) => dir = d,
+                Err((_, e)) => {
+                    return Err(anyhow!(
+                        "failed to lock the store at {}: {e}",
+                        self.config.store
+                    ))
+                }
+            }
+
+            if start.elapsed() >= STORE_LOCK_TIMEOUT {
+                return Err(anyhow!(
+                    "timed out after {}s waiting for another kbs2 process to finish changing \
+                     the store at {}",
+                    STORE_LOCK_TIMEOUT.as_secs(),
+                    self.config.store
+                ));
+            }
+
+            log::debug!("waiting for store lock...");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.config.store)
+            .and_then(|m| m.modified())
+            .ok()
+    }
+
+    fn record_modified(&self, label: &str) -> Option<SystemTime> {
+        fs::metadata(self.record_path(label).ok()?)
+            .and_then(|m| m.modified())
+            .ok()
+    }
+}
+
+/// A store backend that keeps records in memory, for tests that don't need a real store.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore {
+    records: std::cell::RefCell<std::collections::BTreeMap<String, String>>,
+    manifest: std::cell::RefCell<Option<String>>,
+}
+
+#[cfg(test)]
+impl StoreBackend for MemoryStore {
+    fn labels(&self) -> Result<Vec<String>> {
+        Ok(self.records.borrow().keys().cloned().collect())
+    }
+
+    fn exists(&self, label: &str) -> bool {
+        self.records.borrow().contains_key(label)
+    }
+
+    fn read(&self, label: &str, limit: u64) -> Result<Vec<u8>> {
+        let records = self.records.borrow();
+        let contents = records
+            .get(label)
+            .ok_or_else(|| NoSuchRecord(label.into()))?;
+
+        if contents.len() as u64 > limit {
+            return Err(anyhow!("requested record is suspiciously large, refusing"));
+        }
+
+        Ok(contents.as_bytes().to_vec())
+    }
+
+    fn write(&self, label: &str, contents: &str) -> Result<()> {
+        self.records
+            .borrow_mut()
+            .insert(label.into(), contents.into());
+        Ok(())
+    }
+
+    fn delete(&self, label: &str) -> Result<()> {
+        self.records
+            .borrow_mut()
+            .remove(label)
+            .map(|_| ())
+            .ok_or_else(|| NoSuchRecord(label.into()).into())
+    }
+
+    fn read_manifest(&self) -> Result<Option<String>> {
+        Ok(self.manifest.borrow().clone())
+    }
+
+    fn write_manifest(&self, contents: &str) -> Result<()> {
+        *self.manifest.borrow_mut() = Some(contents.into());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::kbs2::session::tests::{dummy_config, dummy_config_at};
+
+    fn exercise(store: &dyn StoreBackend) {
+        assert!(store.labels().unwrap().is_empty());
+        assert!(!store.exists("foo"));
+
+        store.write("foo", "contents").unwrap();
+        assert!(store.exists("foo"));
+        assert_eq!(store.labels().unwrap(), vec!["foo"]);
+        assert_eq!(store.read("foo", 1024).unwrap(), b"contents");
+        assert!(store.read("foo", 4).is_err());
+
+        store.write("foo", "new contents").unwrap();
+        assert_eq!(store.read("foo", 1024).unwrap(), b"new contents");
+
+        store.delete("foo").unwrap();
+        assert!(!store.exists("foo"));
+        assert!(store
+            .delete("foo")
+            .unwrap_err()
+            .downcast_ref::<NoSuchRecord>()
+            .is_some());
+    }
+
+    #[test]
+    fn test_fs_store() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+
+        exercise(&FsStore::new(&config));
+        assert!(FsStore::new(&config).modified().is_some());
+    }
+
+    #[test]
+    fn test_fs_store_direct_filenames() {
+        let store = tempdir().unwrap();
+        let mut config = dummy_config(&store);
+        config.record_filenames = config::RecordFilenames::Escaped;
+        let fs_store = FsStore::new(&config);
+
+        // A record stored under its direct filename is found once the store's been
+        // listed, and the listing is kept up to date as records come and go.
+        fs::write(store.path().join("Legacy"), "contents").unwrap();
+        assert_eq!(fs_store.labels().unwrap(), vec!["Legacy"]);
+        assert_eq!(fs_store.read("Legacy", 1024).unwrap(), b"contents");
+
+        fs_store.delete("Legacy").unwrap();
+        assert!(!fs_store.exists("Legacy"));
+
+        fs_store.write("Legacy", "new contents").unwrap();
+        assert!(store.path().join("^legacy").is_file());
+        assert!(!store.path().join("Legacy").exists());
+        assert_eq!(fs_store.read("Legacy", 1024).unwrap(), b"new contents");
+    }
+
+    #[test]
+    fn test_open_file_store() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("store");
+        std::fs::write(&file, "not a store").unwrap();
+
+        let config = dummy_config_at(file.to_str().unwrap());
+        let err = open(&config).err().unwrap().to_string();
+        assert!(
+            err.starts_with("the store path exists, but is a file rather than a directory: "),
+            "{err}"
+        );
+        assert!(err.ends_with("check the `store` setting in /not/a/real/dir/config.toml"));
+
+        // Missing stores are still created.
+        let missing = dir.path().join("missing");
+        let config = dummy_config_at(missing.to_str().unwrap());
+        assert!(open(&config).is_ok());
+        assert!(missing.is_dir());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryStore::default();
+
+        exercise(&store);
+        assert!(store.modified().is_none());
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::kbs2::util;
+
+/// The ID of the process that enabled timings, or `0` if they're disabled.
+///
+/// NOTE(ww): We keep the process ID rather than a flag so that forked children (e.g. the
+/// one that clears the clipboard) don't print a second, partial breakdown.
+static ENABLED_BY: AtomicU32 = AtomicU32::new(0);
+
+/// Every span recorded so far, in the order that each was first seen.
+static SPANS: Mutex<Vec<Span>> = Mutex::new(Vec::new());
+
+/// The accumulated time spent in a named phase of `kbs2`'s execution.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    /// The phase's name, e.g. `decrypt`.
+    pub name: &'static str,
+
+    /// The total time spent in the phase.
+    pub elapsed: Duration,
+
+    /// The number of times the phase was entered.
+    pub count: usize,
+}
+
+/// Starts recording spans, for `--timings`.
+pub fn enable() {
+    ENABLED_BY.store(std::process::id(), Ordering::Relaxed);
+}
+
+/// Returns whether spans are being recorded.
+pub fn enabled() -> bool {
+    ENABLED_BY.load(Ordering::Relaxed) != 0
+}
+
+/// Runs `op`, adding the time it takes to the span called `name` (if timings are enabled).
+///
+/// Spans can nest, in which case the inner span's time is counted in both.
+pub fn span<T>(name: &'static str, op: impl FnOnce() -> T) -> T {
+    if !enabled() {
+        return op();
+    }
+
+    let start = Instant::now();
+    let result = op();
+    record(name, start.elapsed());
+
+    result
+}
+
+#[doc(hidden)]
+fn record(name: &'static str, elapsed: Duration) {
+    let mut spans = SPANS.lock().unwrap_or_else(PoisonError::into_inner);
+    match spans.iter_mut().find(|span| span.name == name) {
+        Some(span) => {
+            span.elapsed += elapsed;
+            span.count += 1;
+        }
+        None => spans.push(Span {
+            name,
+            elapsed,
+            count: 1,
+        }),
+    }
+}
+
+/// Returns every span recorded so far, in the order that each was first seen.
+pub fn spans() -> Vec<Span> {
+    SPANS.lock().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+/// Prints a breakdown of the recorded spans to `stderr`, followed by `total`, if timings
+/// were enabled by this process.
+pub fn report(total: Duration) {
+    if ENABLED_BY.load(Ordering::Relaxed) != std::process::id() {
+        return;
+    }
+
+    let ms = |d: Duration| format!("{:.3}ms", d.as_secs_f64() * 1000.0);
+    let mut rows = spans()
+        .into_iter()
+        .map(|span| {
+            vec![
+                span.name.into(),
+                ms(span.elapsed),
+                format!("({}x)", span.count),
+            ]
+        })
+        .collect::<Vec<_>>();
+    rows.push(vec!["total".into(), ms(total), String::new()]);
+
+    eprintln!("Timings:");
+    for row in util::render_table(&rows) {
+        eprintln!("  {}", row.trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span() {
+        // NOTE(ww): Timings are process-wide, so other tests' spans may show up too.
+        enable();
+        assert!(enabled());
+
+        assert_eq!(span("test-span", || 1 + 1), 2);
+        span("test-span", || std::thread::sleep(Duration::from_millis(1)));
+
+        let spans = spans();
+        let span = spans.iter().find(|s| s.name == "test-span").unwrap();
+        assert_eq!(span.count, 2);
+        assert!(span.elapsed >= Duration::from_millis(1));
+    }
+}
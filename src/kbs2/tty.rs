@@ -0,0 +1,115 @@
+use std::io::{self, IsTerminal, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::termios::{self, FlushArg, LocalFlags, SetArg, Termios};
+
+nix::ioctl_read_bad!(tiocgwinsz, libc::TIOCGWINSZ, libc::winsize);
+
+/// Restores the terminal's original attributes when dropped.
+struct RawGuard {
+    original: Termios,
+}
+
+impl RawGuard {
+    /// Turns off line buffering and echo on stdin, so that a single keypress is readable.
+    fn new() -> Result<Self> {
+        let original = termios::tcgetattr(io::stdin())?;
+
+        let mut raw = original.clone();
+        raw.local_flags
+            .remove(LocalFlags::ICANON | LocalFlags::ECHO);
+        termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &raw)?;
+
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawGuard {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Returns the terminal's width in columns, if stdout is a terminal that can tell us.
+pub fn width() -> Option<usize> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // NOTE(ww): TIOCGWINSZ only writes to the winsize we give it.
+    unsafe { tiocgwinsz(io::stdout().as_raw_fd(), &mut size) }.ok()?;
+
+    (size.ws_col > 0).then_some(size.ws_col.into())
+}
+
+/// Returns how many terminal rows the given lines occupy, once wrapped to `width` columns.
+pub fn rendered_rows(lines: &[String], width: usize) -> usize {
+    lines
+        .iter()
+        .map(|line| line.chars().count().div_ceil(width.max(1)).max(1))
+        .sum()
+}
+
+/// Prints the given lines to the terminal, and erases them once `duration` has passed
+/// or a key is pressed, whichever comes first.
+///
+/// Erasure is best effort: the lines are re-measured against the terminal's width before
+/// they're erased, but terminals differ in how they reflow text on resize, so resizing
+/// the terminal while the lines are shown may leave some of them behind.
+pub fn show_transiently(lines: &[String], duration: Duration) -> Result<()> {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        return Err(anyhow!("refusing to show transiently without a terminal"));
+    }
+
+    let timeout = PollTimeout::try_from(duration)
+        .map_err(|_| anyhow!("duration is too long: {}s", duration.as_secs()))?;
+
+    let guard = RawGuard::new()?;
+
+    let mut stdout = io::stdout().lock();
+    for line in lines {
+        writeln!(stdout, "{line}")?;
+    }
+    stdout.flush()?;
+
+    let stdin = io::stdin();
+    let mut fds = [PollFd::new(stdin.as_fd(), PollFlags::POLLIN)];
+    poll(&mut fds, timeout)?;
+
+    // NOTE(ww): Discard the keypress (if any), so that it doesn't end up in the shell.
+    termios::tcflush(&stdin, FlushArg::TCIFLUSH)?;
+    drop(guard);
+
+    let rows = rendered_rows(lines, width().unwrap_or(usize::MAX));
+    if rows > 0 {
+        write!(stdout, "\r\x1b[{rows}A\x1b[J")?;
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rendered_rows() {
+        let lines = |ls: &[&str]| ls.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(rendered_rows(&[], 80), 0);
+        assert_eq!(rendered_rows(&lines(&[""]), 80), 1);
+        assert_eq!(rendered_rows(&lines(&["hunter2"]), 80), 1);
+        assert_eq!(rendered_rows(&lines(&["hunter2", "123456"]), 80), 2);
+        assert_eq!(rendered_rows(&lines(&["hunter2"]), 7), 1);
+        assert_eq!(rendered_rows(&lines(&["hunter2"]), 3), 3);
+        assert_eq!(rendered_rows(&lines(&["hunter2"]), 0), 7);
+        assert_eq!(rendered_rows(&lines(&["pässwörd"]), 4), 2);
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::kbs2::i18n::{self, Message};
+use crate::kbs2::util;
+
+/// The basename of the cached update check, within the config directory.
+const UPDATE_CACHE_BASENAME: &str = "update-check.json";
+
+/// How long a cached update check is used for, in seconds.
+const UPDATE_CHECK_INTERVAL: u64 = 24 * 60 * 60;
+
+/// The page that the latest release of `kbs2` can be downloaded from.
+pub const RELEASES_URL: &str = "https://github.com/woodruffw/kbs2/releases/latest";
+
+/// The comparison between the running version of `kbs2` and its latest release.
+#[derive(Debug, Serialize)]
+pub struct UpdateCheck {
+    /// The running version, e.g. `0.7.3`.
+    pub current: &'static str,
+
+    /// The latest release's version.
+    pub latest: String,
+
+    /// Whether the latest release is newer than the running version.
+    pub update_available: bool,
+}
+
+impl UpdateCheck {
+    /// Compares the running version against the given latest version.
+    fn new(latest: String) -> Self {
+        let current = env!("CARGO_PKG_VERSION");
+
+        Self {
+            current,
+            update_available: is_newer(&latest, current),
+            latest,
+        }
+    }
+}
+
+/// The last update check, as cached in the config directory.
+#[derive(Debug, Deserialize, Serialize)]
+struct UpdateCache {
+    /// When the check was made, as a UNIX timestamp.
+    checked_at: u64,
+
+    /// The latest release's version, or `None` if the check failed.
+    latest: Option<String>,
+}
+
+/// Compares the running version of `kbs2` against its latest release, and caches the
+/// result in the given config directory.
+pub fn check(config_dir: &Path) -> Result<UpdateCheck> {
+    check_with(config_dir, util::current_timestamp(), latest_release)
+}
+
+/// Like `check`, but fetches the latest version with `fetch`, and caches the result as
+/// having been checked at `now`.
+#[doc(hidden)]
+fn check_with(
+    config_dir: &Path,
+    now: u64,
+    fetch: impl FnOnce() -> Result<String>,
+) -> Result<UpdateCheck> {
+    let latest = fetch();
+
+    // NOTE(ww): Failed checks are cached too, so that being offline doesn't mean a request
+    // for every command. Like the label cache, the cache is best-effort.
+    let cache = UpdateCache {
+        checked_at: now,
+        latest: latest.as_ref().ok().cloned(),
+    };
+    if let Err(e) = serde_json::to_vec(&cache)
+        .map_err(anyhow::Error::from)
+        .and_then(|c| Ok(fs::write(config_dir.join(UPDATE_CACHE_BASENAME), c)?))
+    {
+        log::debug!("failed to write the update cache: {}", e);
+    }
+
+    latest.map(UpdateCheck::new)
+}
+
+/// Prints a one-line notice on stderr when a newer release of `kbs2` is available,
+/// checking at most once a day.
+///
+/// This never fails, since a missed update check shouldn't get in the way of anything.
+pub fn notify(config_dir: &Path) {
+    if let Some(check) = available_update(config_dir, util::current_timestamp(), latest_release) {
+        eprintln!(
+            "{}",
+            i18n::format(
+                Message::NoticeUpdateAvailable,
+                &[&check.latest, &check.current, &RELEASES_URL]
+            )
+        );
+    }
+}
+
+/// Returns the newer release of `kbs2` to tell the user about, if there is one.
+///
+/// The cached check is used while it's fresh, so that the notice keeps appearing until
+/// the user upgrades; otherwise, a new check is made (at `now`) with `fetch`.
+#[doc(hidden)]
+fn available_update(
+    config_dir: &Path,
+    now: u64,
+    fetch: impl FnOnce() -> Result<String>,
+) -> Option<UpdateCheck> {
+    let cache = fs::read(config_dir.join(UPDATE_CACHE_BASENAME))
+        .ok()
+        .and_then(|c| serde_json::from_slice::<UpdateCache>(&c).ok());
+
+    let check = match cache {
+        Some(cache) if !is_stale(cache.checked_at, now) => cache.latest.map(UpdateCheck::new),
+        _ => check_with(config_dir, now, fetch)
+            .map_err(|e| log::debug!("update check failed: {:#}", e))
+            .ok(),
+    };
+
+    check.filter(|check| check.update_available)
+}
+
+/// Returns whether an update check made at `checked_at` is due to be made again.
+#[doc(hidden)]
+fn is_stale(checked_at: u64, now: u64) -> bool {
+    now.saturating_sub(checked_at) >= UPDATE_CHECK_INTERVAL
+}
+
+/// Returns whether `latest` is a newer version than `current`.
+///
+/// Only the numeric `major.minor.patch` components are compared; pre-release and build
+/// suffixes are ignored.
+#[doc(hidden)]
+fn is_newer(latest: &str, current: &str) -> bool {
+    let components = |version: &str| {
+        let mut components = version
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|c| c.parse::<u64>().unwrap_or_default());
+
+        [(); 3].map(|_| components.next().unwrap_or_default())
+    };
+
+    components(latest) > components(current)
+}
+
+/// Returns the version of the latest release of `kbs2` on GitHub.
+#[cfg(feature = "network")]
+#[doc(hidden)]
+fn latest_release() -> Result<String> {
+    use std::io::Read;
+    use std::time::Duration;
+
+    /// The GitHub API endpoint for `kbs2`'s latest release.
+    const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/woodruffw/kbs2/releases/latest";
+
+    #[derive(Deserialize)]
+    struct Release {
+        tag_name: String,
+    }
+
+    let response = ureq::get(LATEST_RELEASE_URL)
+        .timeout(Duration::from_secs(3))
+        .set("Accept", "application/vnd.github+json")
+        .set("User-Agent", concat!("kbs2/", env!("CARGO_PKG_VERSION")))
+        .call()?;
+
+    // NOTE(ww): The response should be a few KB; don't read an unbounded amount of it.
+    let release: Release = serde_json::from_reader(response.into_reader().take(1024 * 1024))?;
+
+    Ok(release.tag_name.trim_start_matches('v').into())
+}
+
+/// Returns the version of the latest release of `kbs2` on GitHub.
+#[cfg(not(feature = "network"))]
+#[doc(hidden)]
+fn latest_release() -> Result<String> {
+    Err(anyhow::anyhow!(
+        "update checks need kbs2 to be built with the network feature"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_available_update() {
+        let dir = tempdir().unwrap();
+        let unused = || -> Result<String> { panic!("the cache should have been used") };
+
+        // Without a cache, we check, and cache the result.
+        let check = available_update(dir.path(), 1000, || Ok("999.0.0".into())).unwrap();
+        assert_eq!(check.latest, "999.0.0");
+        assert!(dir.path().join(UPDATE_CACHE_BASENAME).is_file());
+
+        // While the cache is fresh, the notice comes from it, without checking again.
+        let check = available_update(dir.path(), 1000 + UPDATE_CHECK_INTERVAL - 1, unused);
+        assert_eq!(check.unwrap().latest, "999.0.0");
+
+        // Once it's stale, we check again.
+        let now = 1000 + UPDATE_CHECK_INTERVAL;
+        let check = available_update(dir.path(), now, || Ok(env!("CARGO_PKG_VERSION").into()));
+        assert!(check.is_none());
+        assert!(available_update(dir.path(), now + 1, unused).is_none());
+
+        // A failed check is cached too, and doesn't produce a notice.
+        let now = now + UPDATE_CHECK_INTERVAL;
+        assert!(available_update(dir.path(), now, || Err(anyhow::anyhow!("offline"))).is_none());
+        assert!(available_update(dir.path(), now + 1, unused).is_none());
+        let check = available_update(dir.path(), now + UPDATE_CHECK_INTERVAL, || {
+            Ok("999.0.0".into())
+        });
+        assert_eq!(check.unwrap().latest, "999.0.0");
+    }
+
+    #[test]
+    fn test_is_stale() {
+        assert!(!is_stale(1000, 1000));
+        assert!(!is_stale(1000, 1000 + UPDATE_CHECK_INTERVAL - 1));
+        assert!(is_stale(1000, 1000 + UPDATE_CHECK_INTERVAL));
+
+        // A clock that went backwards doesn't cause a check.
+        assert!(!is_stale(1000, 0));
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("0.8.0", "0.7.3"));
+        assert!(is_newer("v0.7.4", "0.7.3"));
+        assert!(is_newer("1.0.0", "0.99.99"));
+        assert!(is_newer("0.10.0", "0.9.0"));
+        assert!(!is_newer("0.7.3", "0.7.3"));
+        assert!(!is_newer("0.7.2", "0.7.3"));
+        assert!(!is_newer("0.8", "0.8.0"));
+        assert!(!is_newer("0.8.0-rc.1", "0.8.0"));
+        assert!(!is_newer("garbage", "0.7.3"));
+    }
+}
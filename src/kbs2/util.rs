@@ -1,12 +1,17 @@
+use std::borrow::Cow;
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
 use pinentry::PassphraseInput;
-use secrecy::SecretString;
+use secrecy::{ExposeSecret, SecretString};
+
+use crate::kbs2::i18n::{self, Message};
 
 /// Given an input string formatted according to shell quoting rules,
 /// split it into its command and argument parts and return each.
@@ -24,30 +29,185 @@ pub fn parse_and_split_args(argv: &str) -> Result<(String, Vec<String>)> {
     Ok((command, args))
 }
 
+/// Expand `~` and environment variables in the given path, and make it absolute
+/// (relative to the current working directory) if it isn't already.
+///
+/// Unlike `std::fs::canonicalize`, the path does not need to exist.
+pub fn expand_path<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let unexpanded = path
+        .to_str()
+        .ok_or_else(|| anyhow!("unrepresentable path: {:?}", path))?;
+
+    let expanded = shellexpand::full(unexpanded)
+        .map_err(|e| anyhow!("failed to expand {}: {}", unexpanded, e))?;
+
+    std::path::absolute(expanded.as_ref())
+        .map_err(|e| anyhow!("failed to make {} absolute: {}", expanded, e))
+}
+
+/// GUI editors that return immediately unless told to wait, along with the flags that tell
+/// them to. The first flag for each editor is the one that `editor_wait_flag` suggests.
+const GUI_EDITOR_WAIT_FLAGS: &[(&str, &[&str])] = &[
+    ("atom", &["--wait", "-w"]),
+    ("bbedit", &["--wait", "-w"]),
+    ("code", &["--wait", "-w"]),
+    ("code-insiders", &["--wait", "-w"]),
+    ("codium", &["--wait", "-w"]),
+    ("gedit", &["--wait", "-w"]),
+    ("gvim", &["--nofork", "-f"]),
+    ("kate", &["--block", "-b"]),
+    ("mate", &["--wait", "-w"]),
+    ("mvim", &["--nofork", "-f"]),
+    ("subl", &["--wait", "-w"]),
+    ("zed", &["--wait", "-w"]),
+];
+
+/// Given an editor and its arguments (as split by `parse_and_split_args`), return the flag
+/// needed to make it wait for the edited file to be closed, if it's a GUI editor that's
+/// known to need one and the flag isn't already present.
+pub fn editor_wait_flag(editor: &str, args: &[String]) -> Option<&'static str> {
+    let name = Path::new(editor).file_name()?.to_str()?;
+    let (_, flags) = GUI_EDITOR_WAIT_FLAGS.iter().find(|(e, _)| *e == name)?;
+
+    if args.iter().any(|a| flags.contains(&a.as_str())) {
+        None
+    } else {
+        flags.first().copied()
+    }
+}
+
+/// The error returned by `get_password` when there's neither a working pinentry nor
+/// a terminal to prompt on.
+#[derive(Debug)]
+pub struct NoPasswordPrompt;
+
+impl std::fmt::Display for NoPasswordPrompt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", i18n::text(Message::ErrorNoPasswordPrompt))
+    }
+}
+
+impl std::error::Error for NoPasswordPrompt {}
+
 /// Securely retrieve a password from the user.
 ///
 /// NOTE: This function currently uses pinentry internally, which
 /// will delegate to the appropriate pinentry binary on the user's
-/// system.
+/// system. If that binary can't be found or run, it says why and falls
+/// back on prompting on the terminal; if there's no terminal either, it
+/// fails with `NoPasswordPrompt`.
+///
+/// `description` is shown by the pinentry, and should say which store the password is
+/// for; see `password_description`.
 pub fn get_password<S: AsRef<OsStr>>(
     prompt: Option<&'static str>,
+    description: &str,
     pinentry: S,
 ) -> Result<SecretString> {
-    let prompt = prompt.unwrap_or("Password: ");
-    if let Some(mut input) = PassphraseInput::with_binary(pinentry) {
-        input
-            .with_description("Enter your master kbs2 password")
-            .with_prompt(prompt)
-            .required("A non-empty password is required")
-            .interact()
-            .map_err(|e| anyhow!("pinentry failed: {}", e.to_string()))
-    } else {
-        log::debug!("no pinentry binary, falling back on rpassword");
+    get_password_with(prompt, description, pinentry.as_ref(), has_tty())
+}
+
+/// Reads a master password from the first line of stdin, for non-interactive use
+/// (e.g. `kbs2 init --password-stdin`).
+pub fn read_password_stdin() -> Result<SecretString> {
+    let mut password = String::new();
+    io::stdin().read_line(&mut password)?;
+
+    // NOTE(ww): We trim in place, rather than copying, so that the password only ever
+    // lives in the buffer that `SecretString` zeroes on drop.
+    let len = password.trim_end_matches(['\r', '\n']).len();
+    password.truncate(len);
+
+    let password = SecretString::new(password);
+    if password.expose_secret().is_empty() {
+        return Err(anyhow!("no password on stdin"));
+    }
+
+    Ok(password)
+}
+
+/// Returns the pinentry description for the master password of the store at `store`,
+/// configured in `config_dir`.
+///
+/// NOTE(ww): Other programs (e.g. GnuPG) use pinentry too, so the description names the
+/// store and config, rather than leaving the user to guess what's asking.
+pub fn password_description<P: AsRef<Path>, Q: AsRef<Path>>(store: P, config_dir: Q) -> String {
+    i18n::format(
+        Message::MasterPasswordDescription,
+        &[&tilde_path(store), &tilde_path(config_dir)],
+    )
+}
+
+/// Returns the given path for display, with the user's home directory abbreviated to `~`.
+pub fn tilde_path<P: AsRef<Path>>(path: P) -> String {
+    let path = path.as_ref();
+    match home::home_dir().and_then(|home| path.strip_prefix(home).ok().map(Path::to_owned)) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".into(),
+        Some(rest) => format!("~/{}", rest.display()),
+        None => path.display().to_string(),
+    }
+}
+
+#[doc(hidden)]
+fn get_password_with(
+    prompt: Option<&'static str>,
+    description: &str,
+    pinentry: &OsStr,
+    tty: bool,
+) -> Result<SecretString> {
+    let prompt = prompt.unwrap_or_else(|| i18n::text(Message::MasterPasswordPrompt));
+    let binary = pinentry.to_string_lossy();
+
+    let reason = match PassphraseInput::with_binary(pinentry) {
+        Some(mut input) => {
+            let result = input
+                .with_title("kbs2")
+                .with_description(description)
+                .with_prompt(prompt)
+                .required(i18n::text(Message::MasterPasswordRequired))
+                .interact();
 
-        rpassword::prompt_password(prompt)
-            .map(SecretString::new)
-            .map_err(|e| anyhow!("password prompt failed: {}", e.to_string()))
+            match result {
+                Ok(password) => return Ok(password),
+                // NOTE(ww): The user answered the pinentry, just not with a password;
+                // prompting them again on the terminal would be surprising.
+                Err(e @ (pinentry::Error::Cancelled | pinentry::Error::Timeout)) => {
+                    return Err(anyhow!("pinentry failed: {}", e))
+                }
+                Err(e) => {
+                    let mut reason = format!("pinentry `{binary}` failed: {e}");
+                    if std::env::var_os("DISPLAY").is_none()
+                        && std::env::var_os("WAYLAND_DISPLAY").is_none()
+                    {
+                        reason.push_str(" (DISPLAY is unset)");
+                    }
+                    reason
+                }
+            }
+        }
+        None => format!("pinentry `{binary}` not found or not executable"),
+    };
+
+    log::warn!("{reason}");
+    if !tty {
+        return Err(anyhow!(NoPasswordPrompt).context(reason));
     }
+
+    warn(&format!("{reason}; prompting on the terminal instead"));
+    rpassword::prompt_password(prompt)
+        .map(SecretString::new)
+        .map_err(|e| anyhow!("password prompt failed: {}", e.to_string()))
+}
+
+/// Returns whether there's a controlling terminal that `rpassword` can prompt on.
+#[doc(hidden)]
+fn has_tty() -> bool {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .is_ok()
 }
 
 /// Return the current timestamp as seconds since the UNIX epoch.
@@ -61,11 +221,230 @@ pub fn current_timestamp() -> u64 {
         .as_secs()
 }
 
-/// Print the given message on `stderr` with a warning prefix.
+/// Whether warnings are suppressed; see `set_quiet`.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Suppress (or re-enable) the messages emitted by `warn`.
+///
+/// Errors are unaffected.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Returns whether warnings (and other non-essential output) have been suppressed
+/// with `set_quiet`.
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Print the given message on `stderr` with a warning prefix, unless warnings
+/// have been suppressed with `set_quiet`.
 pub fn warn(msg: &str) {
+    if quiet() {
+        return;
+    }
+
     eprintln!("Warn: {msg}");
 }
 
+/// Parse a human-friendly duration like `90d` or `1y` into a number of seconds.
+///
+/// Supported units are `s`, `m` (minutes), `h`, `d`, `w`, `mo` (30 days), and `y` (365 days).
+pub fn parse_duration(duration: &str) -> Result<u64> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| anyhow!("missing unit in duration: {}", duration))?;
+    let (count, unit) = duration.split_at(split);
+
+    let count: u64 = count
+        .parse()
+        .map_err(|_| anyhow!("invalid duration: {}", duration))?;
+    let unit: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        "mo" => 60 * 60 * 24 * 30,
+        "y" => 60 * 60 * 24 * 365,
+        _ => return Err(anyhow!("invalid unit in duration: {}", duration)),
+    };
+
+    count
+        .checked_mul(unit)
+        .ok_or_else(|| anyhow!("duration is too large: {}", duration))
+}
+
+/// Parse a point in time, given either as a duration into the past (see `parse_duration`)
+/// or as an ISO 8601 date or UTC date and time, into seconds since the Unix epoch.
+pub fn parse_since(since: &str) -> Result<u64> {
+    if let Ok(duration) = parse_duration(since) {
+        return Ok(current_timestamp().saturating_sub(duration));
+    }
+
+    // NOTE(ww): humantime only understands full timestamps, so bare dates are
+    // taken to mean midnight (UTC) on that day.
+    let timestamp = if since.len() == "YYYY-MM-DD".len() {
+        humantime::parse_rfc3339_weak(&format!("{since} 00:00:00"))
+    } else {
+        humantime::parse_rfc3339_weak(since)
+    };
+
+    timestamp
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .ok_or_else(|| {
+            anyhow!(
+                "invalid time: {since} (expected a duration like 24h or 7d, \
+                 or an ISO 8601 date like 2024-01-31 or 2024-01-31T12:00:00Z)"
+            )
+        })
+}
+
+/// Expand the tokens in a label template, for naming rotated records:
+///
+/// * `{date}`: the (UTC) date at `timestamp`, e.g. `2024-06-30`
+/// * `{month}`: the (UTC) month at `timestamp`, e.g. `2024-06`
+/// * `{seq}`: one more than the highest number among the `existing` labels that match
+///   the rest of the (expanded) template, or `1` if there are none
+///
+/// Labels without any tokens (including those with other braces) are returned as-is.
+pub fn expand_label_template(
+    template: &str,
+    timestamp: u64,
+    existing: &[String],
+) -> Result<String> {
+    let date =
+        humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(timestamp)).to_string();
+    let label = template
+        .replace("{date}", &date[.."YYYY-MM-DD".len()])
+        .replace("{month}", &date[.."YYYY-MM".len()]);
+
+    let Some((prefix, suffix)) = label.split_once("{seq}") else {
+        return Ok(label);
+    };
+
+    if suffix.contains("{seq}") {
+        return Err(anyhow!(
+            "label template can only contain one {{seq}}: {template}"
+        ));
+    }
+
+    let seq = match existing
+        .iter()
+        .filter_map(|label| label.strip_prefix(prefix)?.strip_suffix(suffix))
+        .filter(|seq| !seq.is_empty() && seq.bytes().all(|b| b.is_ascii_digit()))
+        .filter_map(|seq| seq.parse::<u64>().ok())
+        .max()
+    {
+        Some(seq) => seq.checked_add(1).ok_or_else(|| {
+            anyhow!("label template's {{seq}} has run out of numbers: {template}")
+        })?,
+        None => 1,
+    };
+
+    Ok(format!("{prefix}{seq}{suffix}"))
+}
+
+/// Truncate the given string to at most `width` characters, replacing the last
+/// visible character with an ellipsis if any truncation occurs.
+pub fn truncate_with_ellipsis(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.into();
+    }
+
+    match width {
+        0 => String::new(),
+        _ => s.chars().take(width - 1).chain(Some('…')).collect(),
+    }
+}
+
+/// Escape the given string for use as a single field in `--porcelain` output, where fields
+/// are separated by tabs and entries by newlines: backslashes, tabs, and newlines are
+/// written as `\\`, `\t`, and `\n` respectively.
+pub fn porcelain_escape(s: &str) -> Cow<'_, str> {
+    if !s.contains(['\\', '\t', '\n']) {
+        return s.into();
+    }
+
+    s.chars()
+        .fold(String::with_capacity(s.len()), |mut escaped, c| {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                c => escaped.push(c),
+            }
+            escaped
+        })
+        .into()
+}
+
+/// Quote the given string for use as a single word in a POSIX shell, e.g. as the value
+/// in `export VAR=value`.
+///
+/// Strings made up entirely of characters that no shell treats specially are returned
+/// as-is. Everything else is single-quoted, with each embedded single quote written as
+/// `'\''` (close the quote, an escaped quote, reopen the quote).
+pub fn shell_quote(s: &str) -> Cow<'_, str> {
+    // NOTE(ww): This is deliberately conservative: `~` and `:` are left out because bash
+    // performs tilde expansion after `=` and `:` in assignments, and anything non-ASCII
+    // is quoted rather than trusting every shell to treat it as an ordinary character.
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-.,/+@%".contains(c);
+
+    if !s.is_empty() && s.chars().all(is_safe) {
+        return s.into();
+    }
+
+    format!("'{}'", s.replace('\'', r"'\''")).into()
+}
+
+/// Returns whether the given string is a valid POSIX shell variable name.
+pub fn is_shell_variable(s: &str) -> bool {
+    let mut chars = s.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Render the given rows as a table of left-aligned, space-separated columns.
+///
+/// Each column is padded to the width of its widest cell, except for the final
+/// column, which is never padded.
+pub fn render_table(rows: &[Vec<String>]) -> Vec<String> {
+    let mut widths: Vec<usize> = vec![];
+    for row in rows {
+        for (idx, cell) in row.iter().enumerate() {
+            let len = cell.chars().count();
+            match widths.get_mut(idx) {
+                Some(width) => *width = (*width).max(len),
+                None => widths.push(len),
+            }
+        }
+    }
+
+    rows.iter()
+        .map(|row| {
+            let mut line = String::new();
+            for (idx, cell) in row.iter().enumerate() {
+                if idx > 0 {
+                    line.push(' ');
+                }
+
+                line.push_str(cell);
+                if idx + 1 < row.len() {
+                    let padding = widths[idx] - cell.chars().count();
+                    line.extend(std::iter::repeat_n(' ', padding));
+                }
+            }
+            line
+        })
+        .collect()
+}
+
 /// Read the entire given file into a `Vec<u8>`, or fail if its on-disk size exceeds
 /// some limit.
 pub fn read_guarded<P: AsRef<Path>>(path: P, limit: u64) -> Result<Vec<u8>> {
@@ -81,11 +460,185 @@ pub fn read_guarded<P: AsRef<Path>>(path: P, limit: u64) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
+/// Writes `contents` to the file at `path`, which only its owner can read or write
+/// (regardless of the umask). Every file that contains a plaintext secret or key
+/// material should be written this way.
+///
+/// Unless `overwrite` is set, this refuses to replace an existing file. Either way, it
+/// refuses to write through a symlink.
+pub fn write_secret_file<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+    overwrite: bool,
+) -> Result<()> {
+    let path = path.as_ref();
+
+    // NOTE(ww): O_NOFOLLOW is what actually keeps us from writing through a symlink;
+    // this check just makes for a better error message.
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+        return Err(anyhow!(
+            "refusing to write a secret through a symlink: {}",
+            path.display()
+        ));
+    }
+
+    let mut options = OpenOptions::new();
+    options
+        .write(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => {
+            anyhow!("refusing to overwrite an existing file: {}", path.display())
+        }
+        _ => anyhow!("failed to write {}: {}", path.display(), e),
+    })?;
+
+    // NOTE(ww): The mode above only applies to newly created files.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents.as_ref())?;
+
+    Ok(())
+}
+
+/// A filesystem condition that keeps `kbs2` from writing, and that only the user can fix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unwritable {
+    /// The filesystem is mounted read-only (`EROFS`).
+    ReadOnly,
+
+    /// The filesystem (or the user's quota on it) is full (`ENOSPC`, `EDQUOT`).
+    Full,
+
+    /// The file or its directory isn't writable by us (`EACCES`, `EPERM`).
+    PermissionDenied,
+}
+
+impl Unwritable {
+    /// Returns the condition behind the given I/O error, if it's one of these.
+    pub fn of(e: &io::Error) -> Option<Self> {
+        match e.kind() {
+            io::ErrorKind::ReadOnlyFilesystem => Some(Unwritable::ReadOnly),
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => Some(Unwritable::Full),
+            io::ErrorKind::PermissionDenied => Some(Unwritable::PermissionDenied),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Unwritable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Unwritable::ReadOnly => write!(f, "filesystem is read-only"),
+            Unwritable::Full => write!(f, "no space left on device"),
+            Unwritable::PermissionDenied => write!(f, "permission denied"),
+        }
+    }
+}
+
+/// The error produced when a write fails because of an `Unwritable` condition.
+#[derive(Debug)]
+pub struct WriteFailed {
+    /// Why the write failed.
+    pub reason: Unwritable,
+
+    /// What was being done, e.g. `writing record foo`.
+    pub action: String,
+
+    /// The path that was being written to.
+    pub path: PathBuf,
+
+    source: anyhow::Error,
+}
+
+impl std::fmt::Display for WriteFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} while {}: {}",
+            self.reason,
+            self.action,
+            self.path.display()
+        )
+    }
+}
+
+impl std::error::Error for WriteFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// Adds `action` (e.g. `writing record foo`) and `path` to the given write error.
+///
+/// Errors caused by an `Unwritable` condition become `WriteFailed`, so that they can be
+/// told apart from everything else (e.g. by their exit code).
+pub fn write_context<E: Into<anyhow::Error>>(e: E, action: &str, path: &Path) -> anyhow::Error {
+    let e = e.into();
+
+    let reason = e
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+        .and_then(Unwritable::of);
+
+    match reason {
+        Some(reason) => WriteFailed {
+            reason,
+            action: action.into(),
+            path: path.into(),
+            source: e,
+        }
+        .into(),
+        None => e.context(format!("failed while {action}: {}", path.display())),
+    }
+}
+
+/// Checks that new files can be written to the given directory, by writing (and then
+/// removing) a small temporary file in it.
+pub fn probe_writable(dir: &Path) -> Result<()> {
+    // NOTE(ww): A few KiB rather than a few bytes, since a nearly full filesystem can
+    // often still fit the latter.
+    tempfile::NamedTempFile::new_in(dir)
+        .and_then(|mut file| {
+            file.write_all(&[0; 4096])?;
+            file.as_file().sync_all()
+        })
+        .map_err(|e| write_context(e, "checking that it's writable", dir))
+}
+
+/// Clipboard managers that keep their own history of the clipboard, by process name.
+static CLIPBOARD_MANAGERS: &[&str] = &[
+    "klipper",
+    "gpaste-daemon",
+    "copyq",
+    "xfce4-clipman",
+    "parcellite",
+    "clipit",
+    "diodon",
+];
+
+/// Returns the name of a running clipboard manager that keeps a history, if any.
+///
+/// This is best-effort: it only knows about a handful of managers, and only finds them
+/// where `/proc` is available.
+pub fn running_clipboard_manager() -> Option<&'static str> {
+    fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+        let comm = fs::read_to_string(entry.path().join("comm")).ok()?;
+        let comm = comm.trim_end().to_lowercase();
+        CLIPBOARD_MANAGERS.iter().find(|m| **m == comm).copied()
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
-
-    use tempfile::NamedTempFile;
+    use secrecy::ExposeSecret;
+    use tempfile::{tempdir, NamedTempFile};
 
     use super::*;
 
@@ -128,7 +681,98 @@ mod tests {
         }
     }
 
-    // TODO: Figure out a good way to test util::get_password.
+    #[test]
+    fn test_editor_wait_flag() {
+        assert_eq!(editor_wait_flag("vim", &[]), None);
+        assert_eq!(editor_wait_flag("code", &[]), Some("--wait"));
+        assert_eq!(editor_wait_flag("/usr/local/bin/subl", &[]), Some("--wait"));
+        assert_eq!(editor_wait_flag("subl", &["-w".into()]), None);
+        assert_eq!(editor_wait_flag("code", &["--wait".into()]), None);
+        assert_eq!(
+            editor_wait_flag("code", &["--new-window".into()]),
+            Some("--wait")
+        );
+        assert_eq!(editor_wait_flag("gvim", &[]), Some("--nofork"));
+        assert_eq!(editor_wait_flag("kate", &["-b".into()]), None);
+    }
+
+    #[test]
+    fn test_get_password_no_pinentry_no_tty() {
+        let err =
+            get_password_with(None, "", OsStr::new("/nonexistent/pinentry"), false).unwrap_err();
+        assert!(err.is::<NoPasswordPrompt>());
+        assert_eq!(
+            err.to_string(),
+            "pinentry `/nonexistent/pinentry` not found or not executable"
+        );
+        assert!(format!("{err:#}").contains("kbs2 agent unwrap"));
+    }
+
+    #[test]
+    fn test_get_password_broken_pinentry_no_tty() {
+        // NOTE(ww): `false` exists, but exits without ever speaking the pinentry protocol.
+        let err = get_password_with(None, "", OsStr::new("false"), false).unwrap_err();
+        assert!(err.is::<NoPasswordPrompt>());
+        assert!(err.to_string().starts_with("pinentry `false` failed: "));
+    }
+
+    #[test]
+    fn test_get_password_pinentry() {
+        // NOTE(ww): Just enough of the pinentry protocol to answer with a password, while
+        // logging everything that we're asked.
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("log");
+        let pinentry = dir.path().join("pinentry");
+        fs::write(
+            &pinentry,
+            format!(
+                "#!/bin/sh\n\
+                 echo OK\n\
+                 while read -r line; do\n\
+                 echo \"$line\" >> {log}\n\
+                 case \"$line\" in\n\
+                 GETPIN*) echo 'D hunter2'; echo OK;;\n\
+                 BYE*) echo OK; exit 0;;\n\
+                 *) echo OK;;\n\
+                 esac\n\
+                 done\n",
+                log = log.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&pinentry, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let description = password_description("/tmp/store", "/tmp/config");
+        let password = get_password_with(None, &description, pinentry.as_os_str(), false).unwrap();
+        assert_eq!(password.expose_secret(), "hunter2");
+
+        let log = fs::read_to_string(&log).unwrap();
+        assert!(log.contains("SETTITLE kbs2"));
+        assert!(log.contains("/tmp/store"));
+        assert!(log.contains("/tmp/config"));
+    }
+
+    #[test]
+    fn test_tilde_path() {
+        let home = home::home_dir().unwrap();
+
+        assert_eq!(tilde_path(&home), "~");
+        assert_eq!(tilde_path(home.join(".config/kbs2")), "~/.config/kbs2");
+        assert_eq!(tilde_path("/nonexistent/kbs2"), "/nonexistent/kbs2");
+    }
+
+    #[test]
+    fn test_expand_path() {
+        let home = home::home_dir().unwrap();
+        let cwd = std::env::current_dir().unwrap();
+
+        assert_eq!(expand_path("~/foo").unwrap(), home.join("foo"));
+        assert_eq!(expand_path("$HOME/foo").unwrap(), home.join("foo"));
+        assert_eq!(expand_path("foo/bar").unwrap(), cwd.join("foo/bar"));
+        assert_eq!(expand_path("/foo/bar").unwrap(), Path::new("/foo/bar"));
+
+        assert!(expand_path("$KBS2_DOES_NOT_EXIST/foo").is_err());
+    }
 
     #[test]
     fn test_current_timestamp() {
@@ -147,6 +791,147 @@ mod tests {
 
     // TODO: Figure out a good way to test util::warn.
 
+    #[test]
+    fn test_truncate_with_ellipsis() {
+        assert_eq!(truncate_with_ellipsis("short", 10), "short");
+        assert_eq!(truncate_with_ellipsis("exactly", 7), "exactly");
+        assert_eq!(truncate_with_ellipsis("much-too-long", 5), "much…");
+        assert_eq!(truncate_with_ellipsis("ünïcödé", 4), "ünï…");
+        assert_eq!(truncate_with_ellipsis("anything", 0), "");
+    }
+
+    #[test]
+    fn test_porcelain_escape() {
+        assert_eq!(porcelain_escape("github"), "github");
+        assert_eq!(porcelain_escape("a\tb\nc"), "a\\tb\\nc");
+        assert_eq!(porcelain_escape("back\\slash"), "back\\\\slash");
+        assert_eq!(porcelain_escape(""), "");
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("hunter2"), "hunter2");
+        assert_eq!(shell_quote("/usr/local/bin"), "/usr/local/bin");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("$(rm -rf ~)"), "'$(rm -rf ~)'");
+        assert_eq!(shell_quote("`id`"), "'`id`'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("a\nb"), "'a\nb'");
+        assert_eq!(shell_quote("~root"), "'~root'");
+        assert_eq!(shell_quote("a:~root"), "'a:~root'");
+        assert_eq!(shell_quote("h\u{e9}llo"), "'h\u{e9}llo'");
+    }
+
+    #[test]
+    fn test_is_shell_variable() {
+        for valid in ["FOO", "_foo", "FOO_BAR2", "_"] {
+            assert!(is_shell_variable(valid), "{valid}");
+        }
+
+        for invalid in ["", "2FOO", "FOO-BAR", "FOO BAR", "FOO;id", "F\u{d6}O"] {
+            assert!(!is_shell_variable(invalid), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_render_table() {
+        {
+            assert!(render_table(&[]).is_empty());
+        }
+
+        {
+            let rows = vec![
+                vec!["LABEL".into(), "KIND".into(), "TIMESTAMP".into()],
+                vec!["a".into(), "login".into(), "1".into()],
+                vec!["longer-label".into(), "environment".into(), "2".into()],
+            ];
+
+            assert_eq!(
+                render_table(&rows),
+                vec![
+                    "LABEL        KIND        TIMESTAMP",
+                    "a            login       1",
+                    "longer-label environment 2",
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s").unwrap(), 30);
+        assert_eq!(parse_duration("5m").unwrap(), 300);
+        assert_eq!(parse_duration("2h").unwrap(), 7200);
+        assert_eq!(parse_duration("1d").unwrap(), 86400);
+        assert_eq!(parse_duration("1w").unwrap(), 604800);
+        assert_eq!(parse_duration("6mo").unwrap(), 15552000);
+        assert_eq!(parse_duration("1y").unwrap(), 31536000);
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("1x").is_err());
+        assert!(parse_duration("-1d").is_err());
+        assert!(parse_duration("99999999999999999999y").is_err());
+    }
+
+    #[test]
+    fn test_parse_since() {
+        let now = current_timestamp();
+        let since = parse_since("1d").unwrap();
+        assert!(since <= now - 86400 && since >= now - 86400 - 5);
+
+        assert_eq!(parse_since("2024-01-31").unwrap(), 1706659200);
+        assert_eq!(parse_since("2024-01-31T12:00:00Z").unwrap(), 1706702400);
+        assert_eq!(parse_since("2024-01-31 12:00:00").unwrap(), 1706702400);
+
+        assert!(parse_since("").is_err());
+        assert!(parse_since("yesterday").is_err());
+        assert!(parse_since("2024-13-01").is_err());
+    }
+
+    #[test]
+    fn test_expand_label_template() {
+        // 2024-06-30T12:00:00Z
+        let timestamp = 1719748800;
+        let existing = [
+            "apikey-1",
+            "apikey-3",
+            "apikey-x",
+            "apikey-",
+            "other-7",
+            "apikey-2-old",
+        ]
+        .map(String::from);
+
+        for (template, expanded) in [
+            ("apikey", "apikey"),
+            ("apikey-{date}", "apikey-2024-06-30"),
+            ("apikey-{month}", "apikey-2024-06"),
+            ("apikey-{seq}", "apikey-4"),
+            ("apikey-{seq}-old", "apikey-3-old"),
+            ("fresh-{seq}", "fresh-1"),
+            ("{month}-{seq}", "2024-06-1"),
+            ("literal-{braces}", "literal-{braces}"),
+        ] {
+            assert_eq!(
+                expand_label_template(template, timestamp, &existing).unwrap(),
+                expanded
+            );
+        }
+
+        assert!(expand_label_template("{seq}-{seq}", timestamp, &existing).is_err());
+
+        let existing = [format!("apikey-{}", u64::MAX)];
+        assert_eq!(
+            expand_label_template("apikey-{seq}", timestamp, &existing)
+                .unwrap_err()
+                .to_string(),
+            "label template's {seq} has run out of numbers: apikey-{seq}"
+        );
+    }
+
     #[test]
     fn test_read_guarded() {
         {
@@ -167,4 +952,89 @@ mod tests {
             assert!(read_guarded(toobig.path(), 10).is_err());
         }
     }
+
+    #[test]
+    fn test_write_secret_file() {
+        let dir = tempdir().unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let path = dir.path().join("secret");
+        write_secret_file(&path, "hunter2", false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hunter2");
+        assert_eq!(mode(&path), 0o600);
+
+        // Existing files are only replaced when asked, and lose any looser permissions.
+        assert!(write_secret_file(&path, "hunter3", false).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hunter2");
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        write_secret_file(&path, "hunter3", true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hunter3");
+        assert_eq!(mode(&path), 0o600);
+
+        // Symlinks are never followed, whether or not they dangle.
+        let target = dir.path().join("target");
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        for overwrite in [false, true] {
+            let err = write_secret_file(&link, "hunter2", overwrite).unwrap_err();
+            assert!(err.to_string().contains("through a symlink"));
+            assert!(!target.exists());
+        }
+
+        fs::write(&target, "public").unwrap();
+        assert!(write_secret_file(&link, "hunter2", true).is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "public");
+    }
+
+    #[test]
+    fn test_write_context() {
+        let path = Path::new("/store/foo");
+
+        for (errno, reason) in [
+            (libc::EROFS, Unwritable::ReadOnly),
+            (libc::ENOSPC, Unwritable::Full),
+            (libc::EDQUOT, Unwritable::Full),
+            (libc::EACCES, Unwritable::PermissionDenied),
+        ] {
+            let e = write_context(
+                io::Error::from_raw_os_error(errno),
+                "writing record foo",
+                path,
+            );
+            let failed = e.downcast_ref::<WriteFailed>().unwrap();
+            assert_eq!(failed.reason, reason);
+            assert_eq!(
+                e.to_string(),
+                format!("{reason} while writing record foo: /store/foo")
+            );
+            assert!(e.chain().any(|cause| cause.is::<io::Error>()));
+        }
+
+        // Unwritable conditions are found beneath other context, too.
+        let e = write_context(
+            anyhow::Error::from(io::Error::from_raw_os_error(libc::EROFS)).context("oops"),
+            "writing record foo",
+            path,
+        );
+        assert!(e.is::<WriteFailed>());
+
+        // Anything else just gets the context.
+        let e = write_context(
+            io::Error::from_raw_os_error(libc::EIO),
+            "writing record foo",
+            path,
+        );
+        assert!(!e.is::<WriteFailed>());
+        assert_eq!(e.to_string(), "failed while writing record foo: /store/foo");
+    }
+
+    #[test]
+    fn test_probe_writable() {
+        let dir = tempdir().unwrap();
+        probe_writable(dir.path()).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        assert!(probe_writable(&dir.path().join("nonexistent")).is_err());
+    }
 }
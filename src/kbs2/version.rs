@@ -0,0 +1,67 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::kbs2::agent;
+use crate::kbs2::record;
+
+/// The optional cargo features that `kbs2` can be built with.
+#[doc(hidden)]
+const FEATURES: &[(&str, bool)] = &[
+    ("nushell", cfg!(feature = "nushell")),
+    ("network", cfg!(feature = "network")),
+];
+
+/// Everything that packagers and compatibility checks need to know about this build of `kbs2`.
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    /// The crate version, e.g. `0.7.3`.
+    pub version: &'static str,
+
+    /// The (short) git commit that `kbs2` was built from, if it was built from a checkout.
+    pub commit: Option<&'static str>,
+
+    /// The version of the agent protocol.
+    pub protocol: u32,
+
+    /// The version of the record format.
+    pub record_schema: u32,
+
+    /// The optional cargo features that this build was made with.
+    pub features: Vec<&'static str>,
+}
+
+impl VersionInfo {
+    /// Returns the version information for the running build of `kbs2`.
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: option_env!("KBS2_BUILD_COMMIT"),
+            protocol: agent::PROTOCOL_VERSION,
+            record_schema: record::SCHEMA_VERSION,
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| *name)
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "kbs2 {}", self.version)?;
+        if let Some(commit) = self.commit {
+            write!(f, " ({commit})")?;
+        }
+        write!(
+            f,
+            ", agent protocol {}, record schema {}",
+            self.protocol, self.record_schema
+        )?;
+        if !self.features.is_empty() {
+            write!(f, ", features: {}", self.features.join(", "))?;
+        }
+        Ok(())
+    }
+}
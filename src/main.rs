@@ -5,23 +5,103 @@
 #![deny(clippy::unwrap_used)]
 #![deny(clippy::expect_used)]
 #![deny(clippy::panic)]
+#![cfg_attr(test, allow(clippy::unwrap_used, clippy::expect_used, clippy::panic))]
 
-use std::ffi::{OsStr, OsString};
+use std::ffi::OsStr;
+use std::io::IsTerminal;
 use std::process;
 use std::{io, path::PathBuf};
 
 use anyhow::{anyhow, Context, Result};
-use clap::builder::{EnumValueParser, PossibleValuesParser, ValueParser};
-use clap::{Arg, ArgAction, ArgMatches, Command, ValueHint};
-use clap_complete::{generate, Shell};
+use clap::builder::{
+    EnumValueParser, FalseyValueParser, NonEmptyStringValueParser, PossibleValue,
+    PossibleValuesParser, RangedU64ValueParser, ValueParser,
+};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command, ValueEnum, ValueHint};
+use clap_complete::{generate, Generator, Shell};
 
 mod kbs2;
 
+/// The exit code used when a requested record doesn't exist.
+///
+/// Every other failure exits with 1 (or 130, when the user aborts a prompt).
+const NO_SUCH_RECORD_EXIT_CODE: i32 = 3;
+
+/// The exit code used by `kbs2 list --fail-if-empty` when the store is empty.
+const EMPTY_STORE_EXIT_CODE: i32 = 4;
+
+/// The exit code used by `kbs2 list --fail-if-empty` when no records match the filters.
+const NO_MATCHES_EXIT_CODE: i32 = 5;
+
+/// The exit code used when a write fails because the filesystem is read-only.
+const READ_ONLY_EXIT_CODE: i32 = 6;
+
+/// The exit code used when a write fails because the filesystem is full.
+const NO_SPACE_EXIT_CODE: i32 = 7;
+
+/// The exit code used when a write fails because of the filesystem's permissions.
+const PERMISSION_DENIED_EXIT_CODE: i32 = 8;
+
+/// A shell that `--completions` can emit tab completions for.
+#[derive(Clone, Copy, Debug)]
+enum CompletionShell {
+    /// A shell supported by `clap_complete` itself.
+    Builtin(Shell),
+
+    /// Nushell, which is supported by a separate crate.
+    #[cfg(feature = "nushell")]
+    Nushell,
+}
+
+impl ValueEnum for CompletionShell {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            CompletionShell::Builtin(Shell::Bash),
+            CompletionShell::Builtin(Shell::Elvish),
+            CompletionShell::Builtin(Shell::Fish),
+            CompletionShell::Builtin(Shell::PowerShell),
+            CompletionShell::Builtin(Shell::Zsh),
+            #[cfg(feature = "nushell")]
+            CompletionShell::Nushell,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            CompletionShell::Builtin(shell) => shell.to_possible_value(),
+            #[cfg(feature = "nushell")]
+            CompletionShell::Nushell => Some(PossibleValue::new("nushell")),
+        }
+    }
+}
+
+impl Generator for CompletionShell {
+    fn file_name(&self, name: &str) -> String {
+        match self {
+            CompletionShell::Builtin(shell) => shell.file_name(name),
+            #[cfg(feature = "nushell")]
+            CompletionShell::Nushell => clap_complete_nushell::Nushell.file_name(name),
+        }
+    }
+
+    fn generate(&self, cmd: &Command, buf: &mut dyn io::Write) {
+        match self {
+            CompletionShell::Builtin(shell) => shell.generate(cmd, buf),
+            #[cfg(feature = "nushell")]
+            CompletionShell::Nushell => clap_complete_nushell::Nushell.generate(cmd, buf),
+        }
+    }
+}
+
 fn app() -> Command {
     // TODO(ww): Put this in a separate file, or switch to YAML.
     // The latter probably won't work with env!, though.
     Command::new(env!("CARGO_PKG_NAME"))
         .allow_external_subcommands(true)
+        // NOTE(ww): External commands get their arguments verbatim, even when they aren't
+        // UTF-8 or look like our own flags (e.g. `kbs2 foo --help`).
+        .external_subcommand_value_parser(ValueParser::os_string())
         .version(env!("KBS2_BUILD_VERSION"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .arg(
@@ -37,12 +117,49 @@ fn app() -> Command {
                 ))
                 .value_hint(ValueHint::DirPath),
         )
+        .arg(
+            Arg::new("no-agent")
+                .help("don't use the kbs2 agent; unwrap the keyfile directly")
+                .long("no-agent")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("force-retry")
+                .help("retry the master password even if the agent has locked it out")
+                .long("force-retry")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("quiet")
+                .help("suppress warnings (errors are still reported)")
+                .long("quiet")
+                .env("KBS2_QUIET")
+                .value_parser(FalseyValueParser::new())
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("timings")
+                .help("print a breakdown of where time was spent to stderr at exit")
+                .long("timings")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .help("show what a command would change, without changing anything (rm, rename, import raw, reencrypt, and generate --update)")
+                .long("dry-run")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("completions")
                 .help("emit shell tab completions")
                 .long("completions")
                 .value_name("SHELL")
-                .value_parser(EnumValueParser::<Shell>::new()),
+                .value_parser(EnumValueParser::<CompletionShell>::new()),
         )
         .subcommand(
             Command::new("agent")
@@ -54,6 +171,13 @@ fn app() -> Command {
                         .long("foreground")
                         .action(ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("systemd-notify")
+                        .help("tell systemd (via NOTIFY_SOCKET) when the agent is ready and stopping")
+                        .long("systemd-notify")
+                        .requires("foreground")
+                        .action(ArgAction::SetTrue),
+                )
                 .subcommand(
                     Command::new("flush")
                         .about("remove all unwrapped keys from the running agent")
@@ -65,13 +189,69 @@ fn app() -> Command {
                                 .action(ArgAction::SetTrue),
                         ),
                 )
+                .subcommand(
+                    Command::new("lock")
+                        .about("remove all unwrapped keys from the running agent, if any")
+                        .arg(
+                            Arg::new("quit")
+                                .help("quit the agent after locking")
+                                .short('q')
+                                .long("quit")
+                                .action(ArgAction::SetTrue),
+                        ),
+                )
+                .subcommand(
+                    Command::new("protocol")
+                        .about("describe the agent protocol, with an example of each message")
+                        .arg(
+                            Arg::new("example")
+                                .help("print only the example message for this request or response")
+                                .long("example")
+                                .value_name("VARIANT"),
+                        ),
+                )
                 .subcommand(
                     Command::new("query")
-                        .about("ask the current agent whether it has the current config's key"),
+                        .about("ask the current agent whether it has the current config's key")
+                        .arg(
+                            Arg::new("json")
+                                .help("print the agent's status as JSON")
+                                .short('j')
+                                .long("json")
+                                .action(ArgAction::SetTrue),
+                        ),
                 )
                 .subcommand(
                     Command::new("unwrap")
-                        .about("unwrap the current config's key in the running agent"),
+                        .about("unwrap the current config's key in the running agent")
+                        .arg(
+                            Arg::new("all")
+                                .help(
+                                    "unwrap the keys of every config in DIR (or in its \
+                                     subdirectories) instead",
+                                )
+                                .long("all")
+                                .value_name("DIR")
+                                .value_parser(ValueParser::path_buf())
+                                .value_hint(ValueHint::DirPath),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("unlock").about(
+                "unwrap the current config's key, starting the agent if needed \
+                 (like `agent unwrap`)",
+            ),
+        )
+        .subcommand(
+            Command::new("lock")
+                .about("remove all unwrapped keys from the agent, if any (like `agent lock`)")
+                .arg(
+                    Arg::new("quit")
+                        .help("quit the agent after locking")
+                        .short('q')
+                        .long("quit")
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -84,6 +264,13 @@ fn app() -> Command {
                         .long("force")
                         .action(ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("if-not-exists")
+                        .help("do nothing (successfully) if a config is already present")
+                        .long("if-not-exists")
+                        .conflicts_with("force")
+                        .action(ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("store-dir")
                         .help("the directory to store encrypted kbs2 records in")
@@ -96,11 +283,39 @@ fn app() -> Command {
                         ))
                         .value_hint(ValueHint::DirPath),
                 )
+                .arg(
+                    Arg::new("allow-overlapping-store")
+                        .help("allow the store and config directories to be the same, or nested")
+                        .long("allow-overlapping-store")
+                        .action(ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("insecure-not-wrapped")
                         .help("don't wrap the keypair with a master password")
                         .long("insecure-not-wrapped")
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("password-stdin")
+                        .help("read the master password from the first line of stdin")
+                        .long("password-stdin")
+                        .conflicts_with("insecure-not-wrapped")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("identity")
+                        .help("use an existing age identity file as the keyfile")
+                        .long("identity")
+                        .value_name("PATH")
+                        .value_parser(ValueParser::path_buf())
+                        .value_hint(ValueHint::FilePath),
+                )
+                .arg(
+                    Arg::new("public-key")
+                        .help("the public key of the identity given with --identity")
+                        .long("public-key")
+                        .value_name("KEY")
+                        .requires("identity"),
                 ),
         )
         .subcommand(
@@ -108,7 +323,7 @@ fn app() -> Command {
                 .about("create a new record")
                 .arg(
                     Arg::new("label")
-                        .help("the record's label")
+                        .help("the record's label, with any {date}, {month}, or {seq} expanded")
                         .index(1)
                         .required(true),
                 )
@@ -117,7 +332,7 @@ fn app() -> Command {
                         .help("the kind of record to create")
                         .short('k')
                         .long("kind")
-                        .value_parser(PossibleValuesParser::new(kbs2::record::RECORD_KINDS))
+                        .value_parser(kbs2::record::RecordKindParser)
                         .default_value("login"),
                 )
                 .arg(
@@ -127,19 +342,96 @@ fn app() -> Command {
                         .long("force")
                         .action(ArgAction::SetTrue),
                 )
+                .arg(force_locked_arg().requires("force"))
                 .arg(
                     Arg::new("terse")
                         .help("read fields in a terse format, even when connected to a tty")
                         .short('t')
                         .long("terse")
+                        .conflicts_with("fields")
                         .action(ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("terse-separator")
+                        .help("separate terse fields with this instead of \\x01")
+                        .long("terse-separator")
+                        .value_name("SEPARATOR")
+                        .conflicts_with("fields")
+                        .value_parser(NonEmptyStringValueParser::new()),
+                )
                 .arg(
                     Arg::new("generator")
                         .help("use the given generator to generate sensitive fields")
                         .short('G')
                         .long("generator")
                         .default_value("default"),
+                )
+                .arg(
+                    Arg::new("totp")
+                        .help("link the given TOTP record to this record")
+                        .long("totp")
+                        .value_name("LABEL"),
+                )
+                .arg(
+                    Arg::new("notes")
+                        .help("attach a free-form note to the record")
+                        .long("notes")
+                        .value_name("TEXT"),
+                )
+                .arg(
+                    Arg::new("recipient")
+                        .help("also encrypt the record to this age public key (repeatable)")
+                        .long("recipient")
+                        .value_name("KEY")
+                        .value_parser(parse_recipient)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("burn-after-reading")
+                        .help("delete the record from the store once it's read with pass or dump")
+                        .long("burn-after-reading")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("notes-file")
+                        .help("read the record's note from a file")
+                        .long("notes-file")
+                        .value_name("PATH")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .conflicts_with("notes"),
+                )
+                .arg(
+                    Arg::new("interactive-fields")
+                        .help("prompt for extra fields to add to the record")
+                        .long("interactive-fields")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("generate")
+                        .help("generate any sensitive field not supplied as an argument")
+                        .long("generate")
+                        .requires("fields")
+                        .action(ArgAction::SetTrue),
+                )
+                .args(field_args())
+                .arg(
+                    Arg::new("field-file")
+                        .help("read a record field from a file, e.g. contents=server.pem")
+                        .long("field-file")
+                        .value_name("FIELD=PATH")
+                        .value_parser(parse_field_file)
+                        .action(ArgAction::Append),
+                )
+                .group(
+                    ArgGroup::new("fields")
+                        .args(kbs2::input::FIELD_ARGS.iter().flat_map(|(f, s)| [f, s]))
+                        .arg("field-file")
+                        .multiple(true),
+                )
+                .group(
+                    ArgGroup::new("fields-stdin")
+                        .args(kbs2::input::FIELD_ARGS.iter().map(|(_, s)| s))
+                        .multiple(false),
                 ),
         )
         .subcommand(
@@ -152,22 +444,115 @@ fn app() -> Command {
                         .long("details")
                         .action(ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("no-header")
+                        .help("don't print a header row with --details")
+                        .short('H')
+                        .long("no-header")
+                        .requires("detailed")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("show-username")
+                        .help("also print each login's username (implies --details, unless --json)")
+                        .short('u')
+                        .long("show-username")
+                        .action(ArgAction::SetTrue),
+                )
+                .group(
+                    ArgGroup::new("detailed")
+                        .args(["details", "show-username"])
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::new("null")
+                        .help("separate labels with NUL bytes instead of newlines")
+                        .short('0')
+                        .long("null")
+                        .conflicts_with("detailed")
+                        .action(ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("kind")
                         .help("list only records of this kind")
                         .short('k')
                         .long("kind")
-                        .value_parser(PossibleValuesParser::new(kbs2::record::RECORD_KINDS)),
+                        .value_parser(kbs2::record::RecordKindParser),
+                )
+                .arg(
+                    Arg::new("json")
+                        .help("list each record's details as JSON (JSONL)")
+                        .short('j')
+                        .long("json")
+                        .conflicts_with_all(["details", "null"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("porcelain")
+                        .help("list each record's details in a stable, tab-separated format")
+                        .long("porcelain")
+                        .conflicts_with_all(["detailed", "json", "null"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("fail-if-empty")
+                        .help("exit with an error if no records are listed")
+                        .long("fail-if-empty")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .help("decrypt records on at most this many threads [default: one per CPU]")
+                        .short('J')
+                        .long("jobs")
+                        .value_name("N")
+                        .value_parser(RangedU64ValueParser::<usize>::new().range(1..)),
+                )
+                .args(since_args()),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("summarize the store and agent, e.g. for a shell prompt")
+                .arg(
+                    Arg::new("porcelain")
+                        .help("print the summary in a stable, tab-separated format")
+                        .long("porcelain")
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
-            Command::new("rm").about("remove one or more records").arg(
-                Arg::new("label")
-                    .help("the labels of the records to remove")
-                    .index(1)
-                    .required(true)
-                    .num_args(1..),
-            ),
+            Command::new("rm")
+                .about("remove one or more records")
+                .arg(
+                    Arg::new("label")
+                        .help("the labels of the records to remove")
+                        .index(1)
+                        .required_unless_present("stdin-labels")
+                        .num_args(1..),
+                )
+                .arg(stdin_labels_arg())
+                .arg(
+                    Arg::new("kind")
+                        .help("refuse to remove any records not of this kind")
+                        .short('k')
+                        .long("kind")
+                        .value_parser(kbs2::record::RecordKindParser),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .help("refuse to remove more than this many records")
+                        .long("limit")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .help("don't ask for confirmation, even past confirm-threshold")
+                        .short('y')
+                        .long("yes")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(force_locked_arg()),
         )
         .subcommand(
             Command::new("rename")
@@ -179,36 +564,475 @@ fn app() -> Command {
                         .required(true),
                 )
                 .arg(
-                    Arg::new("new-label")
-                        .help("the new record label")
-                        .index(2)
-                        .required(true),
+                    Arg::new("new-label")
+                        .help("the new record label")
+                        .index(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("force")
+                        .help("overwrite, if already present")
+                        .short('f')
+                        .long("force")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(force_locked_arg())
+                .arg(
+                    Arg::new("merge")
+                        .help("merge into the new label's record, if already present")
+                        .short('m')
+                        .long("merge")
+                        .conflicts_with("force")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("prefer")
+                        .help("resolve merge conflicts automatically, in favor of the given record")
+                        .long("prefer")
+                        .value_name("RECORD")
+                        .value_parser(PossibleValuesParser::new(kbs2::merge::MERGE_PREFERENCES))
+                        .requires("merge"),
+                ),
+        )
+        .subcommand(
+            Command::new("lock-record")
+                .about("lock one or more records against modification")
+                .arg(
+                    Arg::new("label")
+                        .help("the labels of the records to lock")
+                        .index(1)
+                        .required(true)
+                        .num_args(1..),
+                ),
+        )
+        .subcommand(
+            Command::new("unlock-record")
+                .about("unlock one or more locked records")
+                .arg(
+                    Arg::new("label")
+                        .help("the labels of the records to unlock")
+                        .index(1)
+                        .required(true)
+                        .num_args(1..),
+                ),
+        )
+        .subcommand(
+            Command::new("dump")
+                .about("dump one or more records")
+                .arg(
+                    Arg::new("label")
+                        .help("the labels of the records to dump")
+                        .index(1)
+                        .required_unless_present_any(["all", "last", "stdin-labels"])
+                        .num_args(1..),
+                )
+                .arg(stdin_labels_arg().conflicts_with_all(["all", "last"]))
+                .arg(
+                    Arg::new("last")
+                        .help("dump the most recently modified record (decrypts every record)")
+                        .long("last")
+                        .conflicts_with_all(["label", "all", "encrypted"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("all")
+                        .help("dump every record in the store")
+                        .short('a')
+                        .long("all")
+                        .conflicts_with_all(["label", "encrypted"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("json")
+                        .help("dump in JSON format (JSONL when multiple)")
+                        .short('j')
+                        .long("json")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("iso-timestamps")
+                        .help("format timestamps in JSON as RFC 3339 strings, rather than numbers")
+                        .long("iso-timestamps")
+                        .requires("json")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("encrypted")
+                        .help("dump the armored ciphertext exactly as stored, without decrypting")
+                        .short('e')
+                        .long("encrypted")
+                        .conflicts_with("json")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("fields-only")
+                        .help("print only each record's field values, one per line")
+                        .long("fields-only")
+                        .conflicts_with_all(["json", "encrypted"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .help("don't ask for confirmation before printing secrets to a terminal")
+                        .short('y')
+                        .long("yes")
+                        .action(ArgAction::SetTrue),
+                )
+                .args(since_args().map(|arg| arg.conflicts_with("encrypted"))),
+        )
+        .subcommand(
+            Command::new("get")
+                .about("print a record's fields, for other programs")
+                .arg(
+                    Arg::new("label")
+                        .help("the label of the record to get")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("json")
+                        .help("print the fields as a flat JSON object, along with the label and kind")
+                        .short('j')
+                        .long("json")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("field")
+                        .help("only print the given field (may be repeated)")
+                        .short('f')
+                        .long("field")
+                        .value_name("NAME")
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .subcommand_required(true)
+                .about("import records into the store")
+                .subcommand(
+                    Command::new("raw")
+                        .about("import a record's armored ciphertext from stdin")
+                        .arg(
+                            Arg::new("label")
+                                .help("the record's label")
+                                .index(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("force")
+                                .help("overwrite, if already present")
+                                .short('f')
+                                .long("force")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("on-conflict")
+                                .help("what to do if the record is already present")
+                                .long("on-conflict")
+                                .value_name("STRATEGY")
+                                .value_parser(PossibleValuesParser::new(
+                                    kbs2::importer::CONFLICT_STRATEGIES.iter().map(|s| {
+                                        match *s {
+                                            "newer" => PossibleValue::new(s).alias("newest"),
+                                            _ => PossibleValue::new(s),
+                                        }
+                                    }),
+                                ))
+                                .conflicts_with("force"),
+                        )
+                        .arg(
+                            Arg::new("report")
+                                .help("write a JSON report of each record's outcome to this path")
+                                .long("report")
+                                .value_name("PATH")
+                                .value_parser(ValueParser::path_buf())
+                                .value_hint(ValueHint::FilePath),
+                        )
+                        .arg(force_locked_arg()),
+                ),
+        )
+        .subcommand(
+            Command::new("share")
+                .about("re-encrypt a single record to someone else's public key")
+                .arg(
+                    Arg::new("label")
+                        .help("the record's label")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .help("the public key (age recipient) to encrypt the record to")
+                        .long("to")
+                        .value_name("RECIPIENT")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .help("write the encrypted record to this file, instead of stdout")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .value_parser(ValueParser::path_buf())
+                        .value_hint(ValueHint::FilePath),
+                ),
+        )
+        .subcommand(
+            Command::new("receive")
+                .about("add a record shared with `kbs2 share` to the store")
+                .arg(
+                    Arg::new("as")
+                        .help("the label to store the record under")
+                        .long("as")
+                        .value_name("LABEL")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("input")
+                        .help("the shared record to read, instead of stdin")
+                        .index(1)
+                        .value_name("FILE")
+                        .value_parser(ValueParser::path_buf())
+                        .value_hint(ValueHint::FilePath),
+                )
+                .arg(
+                    Arg::new("force")
+                        .help("overwrite, if already present")
+                        .short('f')
+                        .long("force")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("fsck")
+                .about("check records for missing or empty fields")
+                .arg(
+                    Arg::new("label")
+                        .help("the labels of the records to check (default: all)")
+                        .index(1)
+                        .num_args(1..),
+                )
+                .arg(
+                    Arg::new("repair")
+                        .help("interactively fill in missing or empty fields")
+                        .long("repair")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("fix")
+                        .help("rename records whose labels don't follow label-normalization")
+                        .long("fix")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("identify-foreign")
+                        .help("report records encrypted to another key, and who they're for")
+                        .long("identify-foreign")
+                        .conflicts_with("repair")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("accept-manifest")
+                        .help("update the store's manifest to match the store, accepting any changes")
+                        .long("accept-manifest")
+                        .conflicts_with_all(["label", "repair", "fix", "identify-foreign"])
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("reencrypt")
+                .about("re-encrypt records to their current recipients, or rescue records encrypted to another key")
+                .arg(
+                    Arg::new("with-identity")
+                        .help("the keyfile (wrapped or not) to decrypt the records with")
+                        .long("with-identity")
+                        .value_name("KEYFILE")
+                        .value_parser(ValueParser::path_buf())
+                        .value_hint(ValueHint::FilePath),
+                )
+                .arg(
+                    Arg::new("label")
+                        .help(
+                            "the labels of the records to re-encrypt \
+                             (default with --with-identity: all that the current key can't decrypt)",
+                        )
+                        .index(1)
+                        .required_unless_present_any(["with-identity", "stdin-labels"])
+                        .num_args(1..),
+                )
+                .arg(stdin_labels_arg())
+                .arg(
+                    Arg::new("report")
+                        .help("write a JSON report of each record's outcome to this path")
+                        .long("report")
+                        .value_name("PATH")
+                        .value_parser(ValueParser::path_buf())
+                        .value_hint(ValueHint::FilePath),
+                ),
+        )
+        .subcommand(
+            Command::new("__launcher")
+                .about("list logins for a launcher, and copy the password of the one picked")
+                .hide(true)
+                .arg(
+                    Arg::new("menu-cmd")
+                        .help("run this menu program (e.g. `rofi -dmenu`) to pick a login")
+                        .long("menu-cmd")
+                        .value_name("COMMAND"),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .help("decrypt records on at most this many threads [default: one per CPU]")
+                        .short('J')
+                        .long("jobs")
+                        .value_name("N")
+                        .value_parser(RangedU64ValueParser::<usize>::new().range(1..)),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("measure store performance with synthetic records")
+                .hide(true)
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("populate")
+                        .about("add synthetic login records to the store")
+                        .arg(
+                            Arg::new("count")
+                                .help("the number of records to add")
+                                .short('c')
+                                .long("count")
+                                .value_name("N")
+                                .required(true)
+                                .value_parser(RangedU64ValueParser::<usize>::new().range(1..)),
+                        )
+                        .arg(
+                            Arg::new("prefix")
+                                .help("the prefix for each record's label")
+                                .long("prefix")
+                                .value_name("PREFIX")
+                                .default_value("bench-"),
+                        )
+                        .arg(
+                            Arg::new("jobs")
+                                .help("encrypt records on at most this many threads [default: one per CPU]")
+                                .short('J')
+                                .long("jobs")
+                                .value_name("N")
+                                .value_parser(RangedU64ValueParser::<usize>::new().range(1..)),
+                        ),
+                )
+                .subcommand(
+                    Command::new("read")
+                        .about("time decrypting every record in the store")
+                        .arg(
+                            Arg::new("jobs")
+                                .help("decrypt records on at most this many threads [default: one per CPU]")
+                                .short('J')
+                                .long("jobs")
+                                .value_name("N")
+                                .value_parser(RangedU64ValueParser::<usize>::new().range(1..)),
+                        ),
+                )
+                .subcommand(
+                    Command::new("labels")
+                        .about("time listing the labels of every record in the store")
+                        .arg(
+                            Arg::new("iterations")
+                                .help("the number of times to list the store")
+                                .short('n')
+                                .long("iterations")
+                                .value_name("N")
+                                .default_value("1")
+                                .value_parser(RangedU64ValueParser::<usize>::new().range(1..)),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("check kbs2's configuration, keys, agent, store, and hooks for problems")
+                .arg(
+                    Arg::new("json")
+                        .help("report the checks in JSON format (e.g. for bug reports)")
+                        .short('j')
+                        .long("json")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("version")
+                .about("print kbs2's version, agent protocol, and record schema")
+                .arg(
+                    Arg::new("json")
+                        .help("print the version information in JSON format")
+                        .short('j')
+                        .long("json")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("upgrade")
+                .about("check for a newer release of kbs2 (kbs2 never upgrades itself)")
+                .arg(
+                    Arg::new("check")
+                        .help("compare the running version against the latest release")
+                        .long("check")
+                        .required(true)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("json")
+                        .help("print the comparison in JSON format")
+                        .short('j')
+                        .long("json")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("unused")
+                .about("list records that haven't been accessed recently")
+                .arg(
+                    Arg::new("since")
+                        .help("list records not accessed within this long (e.g. 90d, 1y)")
+                        .long("since")
+                        .value_name("DURATION")
+                        .value_parser(kbs2::util::parse_duration),
                 )
                 .arg(
-                    Arg::new("force")
-                        .help("overwrite, if already present")
-                        .short('f')
-                        .long("force")
+                    Arg::new("reset")
+                        .help("forget all tracked accesses")
+                        .long("reset")
+                        .conflicts_with("since")
                         .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
-            Command::new("dump")
-                .about("dump one or more records")
+            Command::new("dedupe")
+                .about("find login records that look like duplicates of each other")
                 .arg(
-                    Arg::new("label")
-                        .help("the labels of the records to dump")
-                        .index(1)
-                        .required(true)
-                        .num_args(1..),
+                    Arg::new("interactive")
+                        .help("walk through each group of duplicates, keeping or merging them")
+                        .short('i')
+                        .long("interactive")
+                        .action(ArgAction::SetTrue),
                 )
                 .arg(
                     Arg::new("json")
-                        .help("dump in JSON format (JSONL when multiple)")
+                        .help("list the groups of duplicates in JSON format")
                         .short('j')
                         .long("json")
+                        .conflicts_with("interactive")
                         .action(ArgAction::SetTrue),
-                ),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .help("decrypt records on at most this many threads [default: one per CPU]")
+                        .short('J')
+                        .long("jobs")
+                        .value_name("N")
+                        .value_parser(RangedU64ValueParser::<usize>::new().range(1..)),
+                )
+                .arg(force_locked_arg().requires("interactive")),
         )
         .subcommand(
             Command::new("pass")
@@ -217,7 +1041,14 @@ fn app() -> Command {
                     Arg::new("label")
                         .help("the record's label")
                         .index(1)
-                        .required(true),
+                        .required_unless_present("last"),
+                )
+                .arg(
+                    Arg::new("last")
+                        .help("use the most recently modified login (decrypts every record)")
+                        .long("last")
+                        .conflicts_with("label")
+                        .action(ArgAction::SetTrue),
                 )
                 .arg(
                     Arg::new("clipboard")
@@ -225,16 +1056,61 @@ fn app() -> Command {
                         .short('c')
                         .long("clipboard")
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("force")
+                        .help("copy the password even if it exceeds clipboard-max-bytes")
+                        .long("force")
+                        .requires("clipboard")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("allow-multiline")
+                        .help("copy the password even if it spans multiple lines")
+                        .long("allow-multiline")
+                        .requires("clipboard")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("with-totp")
+                        .help("also print the current code from the linked TOTP record")
+                        .short('t')
+                        .long("with-totp")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("totp")
+                        .help("after the password, emit the linked TOTP record's code")
+                        .long("totp")
+                        .conflicts_with("with-totp")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("show-for")
+                        .help("show the password for this many seconds (or until a keypress), then erase it")
+                        .long("show-for")
+                        .value_name("SECONDS")
+                        .conflicts_with_all(["clipboard", "totp"])
+                        .value_parser(RangedU64ValueParser::<u64>::new().range(1..)),
                 ),
         )
         .subcommand(
             Command::new("env")
-                .about("get an environment record")
+                .about("get one or more environment records")
                 .arg(
                     Arg::new("label")
-                        .help("the record's label")
+                        .help("the labels of the records")
                         .index(1)
-                        .required(true),
+                        .required_unless_present("all")
+                        .num_args(1..),
+                )
+                .arg(
+                    Arg::new("all")
+                        .help("get every environment record in the store")
+                        .short('a')
+                        .long("all")
+                        .conflicts_with("label")
+                        .action(ArgAction::SetTrue),
                 )
                 .arg(
                     Arg::new("value-only")
@@ -249,6 +1125,29 @@ fn app() -> Command {
                         .short('n')
                         .long("no-export")
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .help("prepend a prefix to the environment variable's name")
+                        .short('p')
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .conflicts_with("value-only"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .help("print the variables as a JSON object")
+                        .short('j')
+                        .long("json")
+                        .conflicts_with_all(["value-only", "no-export"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("with-meta")
+                        .help("include each variable's source label in the JSON")
+                        .long("with-meta")
+                        .requires("json")
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -265,7 +1164,36 @@ fn app() -> Command {
                         .help("don't update the record's timestamp")
                         .short('p')
                         .long("preserve-timestamp"),
-                ),
+                )
+                .arg(
+                    Arg::new("allow-rename")
+                        .help("rename the record if its label is changed in the editor")
+                        .short('r')
+                        .long("allow-rename")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("to-kind")
+                        .help("convert the record to another kind of record")
+                        .long("to-kind")
+                        .value_name("KIND")
+                        .value_parser(kbs2::record::RecordKindParser),
+                )
+                .arg(
+                    Arg::new("stdin")
+                        .help("read the edited record's JSON from stdin, instead of an editor")
+                        .long("stdin")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("set")
+                        .help("set one of the record's settings (e.g. icon=🔑), instead of using an editor")
+                        .long("set")
+                        .value_name("KEY=VALUE")
+                        .action(ArgAction::Append)
+                        .conflicts_with_all(["stdin", "to-kind", "allow-rename"]),
+                )
+                .arg(force_locked_arg()),
         )
         .subcommand(
             Command::new("generate")
@@ -275,6 +1203,49 @@ fn app() -> Command {
                         .help("the generator to use")
                         .index(1)
                         .default_value("default"),
+                )
+                .arg(
+                    Arg::new("update")
+                        .help("store the generated secret in the given record")
+                        .short('u')
+                        .long("update")
+                        .value_name("LABEL"),
+                )
+                .arg(
+                    Arg::new("field")
+                        .help("the field to store the secret in (default: the sensitive field)")
+                        .short('f')
+                        .long("field")
+                        .value_parser(PossibleValuesParser::new(
+                            kbs2::input::FIELD_ARGS.iter().map(|(f, _)| f),
+                        ))
+                        .requires("update"),
+                )
+                .arg(
+                    Arg::new("print")
+                        .help("also print the generated secret when updating")
+                        .short('p')
+                        .long("print")
+                        .requires("update")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("clipboard")
+                        .help("also copy the generated secret to the clipboard when updating")
+                        .short('c')
+                        .long("clipboard")
+                        .requires("update")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(force_locked_arg().requires("update"))
+                .arg(
+                    // NOTE(ww): `-n` predates the global `--dry-run`, so it's kept as a
+                    // short form of it here.
+                    Arg::new("dry-run")
+                        .help("show which field would be updated, without changing anything")
+                        .short('n')
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -305,6 +1276,111 @@ fn app() -> Command {
                         .short('n')
                         .long("no-backup")
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("backup-dir")
+                        .help("save the backups in this directory, rather than next to the originals")
+                        .long("backup-dir")
+                        .value_name("PATH")
+                        .value_parser(ValueParser::path_buf())
+                        .value_hint(ValueHint::DirPath)
+                        .conflicts_with("no-backup"),
+                )
+                .arg(
+                    Arg::new("new-keyfile")
+                        .help(
+                            "write the new wrapped key to this path, leaving the old key in place",
+                        )
+                        .long("new-keyfile")
+                        .value_name("PATH")
+                        .value_parser(ValueParser::path_buf())
+                        .value_hint(ValueHint::FilePath),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .help("don't ask for confirmation")
+                        .short('y')
+                        .long("yes")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("report")
+                        .help("write a JSON report of each record's outcome to this path")
+                        .long("report")
+                        .value_name("PATH")
+                        .value_parser(ValueParser::path_buf())
+                        .value_hint(ValueHint::FilePath),
+                ),
+        )
+        .subcommand(
+            Command::new("migrate-store")
+                .about("rewrite every record in the store in a different format")
+                .arg(
+                    Arg::new("to")
+                        .help("the record-filenames format to migrate to")
+                        .long("to")
+                        .value_name("FORMAT")
+                        .value_parser(EnumValueParser::<kbs2::config::RecordFilenames>::new()),
+                )
+                .arg(
+                    Arg::new("compress")
+                        .help("the compression to migrate to")
+                        .long("compress")
+                        .value_name("CODEC")
+                        .value_parser(EnumValueParser::<kbs2::config::Compression>::new()),
+                )
+                .group(
+                    ArgGroup::new("migration")
+                        .args(["to", "compress"])
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("no-backup")
+                        .help("don't make a backup of the old config or store")
+                        .short('n')
+                        .long("no-backup")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .help("don't ask for confirmation")
+                        .short('y')
+                        .long("yes")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("sync")
+                .about("synchronize the store with its remote")
+                .arg(
+                    Arg::new("pull")
+                        .help("only pull the store")
+                        .long("pull")
+                        .conflicts_with("push")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("push")
+                        .help("only push the store")
+                        .long("push")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("hooks")
+                .subcommand_required(true)
+                .about("inspect and test configured hooks")
+                .subcommand(Command::new("list").about("list every configured hook"))
+                .subcommand(
+                    Command::new("run")
+                        .about("run a hook with representative arguments")
+                        .arg(
+                            Arg::new("name")
+                                .help("the hook's name, e.g. pass.clear-hook")
+                                .index(1)
+                                .required(true),
+                        ),
                 ),
         )
         .subcommand(
@@ -320,11 +1396,222 @@ fn app() -> Command {
                                 .short('p')
                                 .long("pretty")
                                 .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("ext")
+                                .help("dump only the configuration for the given external command")
+                                .long("ext")
+                                .value_name("NAME")
+                                .conflicts_with("pretty"),
+                        )
+                        .arg(
+                            Arg::new("explicit-only")
+                                .help("dump only the settings set in the configuration file")
+                                .long("explicit-only")
+                                .conflicts_with("ext")
+                                .action(ArgAction::SetTrue),
+                        ),
+                )
+                .subcommand(
+                    Command::new("escrow")
+                        .about("list or add escrow recipients, which every record is also encrypted to")
+                        .arg(
+                            Arg::new("add")
+                                .help("add this recipient, and re-encrypt every record to include it")
+                                .long("add")
+                                .value_name("RECIPIENT"),
+                        )
+                        .arg(
+                            Arg::new("no-backup")
+                                .help("don't make a backup of the old config or store")
+                                .short('n')
+                                .long("no-backup")
+                                .requires("add")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("yes")
+                                .help("don't ask for confirmation")
+                                .short('y')
+                                .long("yes")
+                                .requires("add")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("report")
+                                .help("write a JSON report of each record's outcome to this path")
+                                .long("report")
+                                .value_name("PATH")
+                                .requires("add")
+                                .value_parser(ValueParser::path_buf())
+                                .value_hint(ValueHint::FilePath),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("audit-log")
+                .subcommand_required(true)
+                .about("read kbs2's audit log")
+                .subcommand(
+                    Command::new("tail")
+                        .about("show the most recent entries in the audit log")
+                        .arg(
+                            Arg::new("since")
+                                .help("only entries since TIME (a duration like 1d, or an ISO 8601 date)")
+                                .long("since")
+                                .value_name("TIME")
+                                .value_parser(parse_since),
+                        )
+                        .arg(
+                            Arg::new("lines")
+                                .help("show at most this many entries")
+                                .short('n')
+                                .long("lines")
+                                .value_name("N")
+                                .value_parser(clap::value_parser!(usize))
+                                .default_value("10"),
+                        )
+                        .arg(
+                            Arg::new("json")
+                                .help("print each entry as JSON")
+                                .short('j')
+                                .long("json")
+                                .action(ArgAction::SetTrue),
                         ),
                 ),
         )
 }
 
+/// Returns the `--<field>` and `--<field>-stdin` arguments for each record field.
+fn field_args() -> Vec<Arg> {
+    kbs2::input::FIELD_ARGS
+        .iter()
+        .flat_map(|(field, stdin_field)| {
+            [
+                Arg::new(field)
+                    .help(format!("the record's {field} field"))
+                    .long(field)
+                    .conflicts_with(stdin_field),
+                Arg::new(stdin_field)
+                    .help(format!("read the record's {field} field from stdin"))
+                    .long(stdin_field)
+                    .action(ArgAction::SetTrue),
+            ]
+        })
+        .collect()
+}
+
+/// Returns the `--modified-since` and `--created-since` record filters.
+fn since_args() -> [Arg; 2] {
+    [("modified-since", "modified"), ("created-since", "created")].map(|(name, event)| {
+        Arg::new(name)
+            .help(format!(
+                "only records {event} since TIME (a duration like 7d, or an ISO 8601 date)"
+            ))
+            .long(name)
+            .value_name("TIME")
+            .value_parser(parse_since)
+    })
+}
+
+/// Returns the `--stdin-labels` argument, for the commands that take any number of labels.
+fn stdin_labels_arg() -> Arg {
+    Arg::new("stdin-labels")
+        .help("read the labels from stdin, one per line, instead of the command line")
+        .long("stdin-labels")
+        .conflicts_with("label")
+        .action(ArgAction::SetTrue)
+}
+
+/// Returns the `--force-locked` argument, for the commands that refuse to modify locked records.
+fn force_locked_arg() -> Arg {
+    Arg::new("force-locked")
+        .help("modify the record(s) even if locked")
+        .long("force-locked")
+        .action(ArgAction::SetTrue)
+}
+
+/// Parses a `--modified-since` or `--created-since` value into a Unix timestamp.
+fn parse_since(value: &str) -> Result<u64, String> {
+    kbs2::util::parse_since(value).map_err(|e| e.to_string())
+}
+
+/// Parses a `--field-file` value of the form `FIELD=PATH`.
+fn parse_field_file(value: &str) -> Result<(String, PathBuf), String> {
+    let (field, path) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected FIELD=PATH, got {value}"))?;
+
+    if !kbs2::input::FIELD_ARGS.iter().any(|(f, _)| *f == field) {
+        return Err(format!("unknown record field: {field}"));
+    }
+
+    if path.is_empty() {
+        return Err(format!("missing path for field: {field}"));
+    }
+
+    Ok((field.into(), path.into()))
+}
+
+/// Parses a `--recipient` value, which must be an age public key.
+fn parse_recipient(value: &str) -> Result<String, String> {
+    value
+        .parse::<kbs2::backend::RageRecipient>()
+        .map(|_| value.into())
+        .map_err(|_| format!("not an age public key: {value}"))
+}
+
+/// Fails if the given subcommand doesn't support `--dry-run`, so that the flag is never
+/// silently ignored.
+///
+/// NOTE(ww): clap only enforces conflicts with a global flag when it comes after the
+/// subcommand, so the conflicting flags are checked here instead.
+fn check_dry_run(cmd: &str, matches: &ArgMatches) -> Result<()> {
+    let conflicts: &[&str] = match cmd {
+        "rm" | "rename" => &[],
+        "import" | "reencrypt" => &["report"],
+        "generate" if matches.contains_id("update") => &["print", "clipboard"],
+        "generate" => {
+            return Err(anyhow!(
+                "kbs2 generate only supports --dry-run with --update"
+            ))
+        }
+        _ => return Err(anyhow!("kbs2 {cmd} doesn't support --dry-run")),
+    };
+
+    // `import`'s flags live on its subcommands.
+    let matches = match (cmd, matches.subcommand()) {
+        ("import", Some((_, matches))) => matches,
+        _ => matches,
+    };
+
+    match conflicts
+        .iter()
+        .find(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+    {
+        Some(id) => Err(anyhow!("--{id} can't be used with --dry-run")),
+        None => Ok(()),
+    }
+}
+
+/// Returns whether the given subcommand reads from and/or modifies the store, as
+/// `(reads, modifies)`. Used to decide when to automatically sync.
+fn store_access(cmd: &str, matches: &ArgMatches) -> (bool, bool) {
+    match cmd {
+        "list" | "dump" | "share" | "unused" | "pass" | "get" | "env" => (true, false),
+        "new" | "rm" | "rename" | "edit" | "import" | "receive" | "reencrypt" | "lock-record"
+        | "unlock-record" | "rekey" | "migrate-store" => (true, true),
+        "fsck" => (true, matches.get_flag("repair") || matches.get_flag("fix")),
+        "bench" => (true, matches.subcommand_name() == Some("populate")),
+        "dedupe" => (true, matches.get_flag("interactive")),
+        "generate" => {
+            let updating = matches.contains_id("update");
+            (updating, updating)
+        }
+        _ => (false, false),
+    }
+}
+
 fn run(matches: &ArgMatches, config: &kbs2::config::Config) -> Result<()> {
     // Subcommand dispatch happens here. All subcommands handled here take a `Config`.
     //
@@ -332,14 +1619,38 @@ fn run(matches: &ArgMatches, config: &kbs2::config::Config) -> Result<()> {
     // `Config` argument. This `Session` is in turn used to perform record and encryption
     // operations.
 
-    // Special case: `kbs2 agent` does not receive pre- or post-hooks.
-    if let Some(("agent", matches)) = matches.subcommand() {
-        return kbs2::command::agent(matches, config);
+    // Special case: `kbs2 agent` does not receive pre- or post-hooks, and neither do
+    // its top-level aliases.
+    match matches.subcommand() {
+        Some(("agent", matches)) => return kbs2::command::agent(matches, config),
+        Some(("unlock", matches)) => return kbs2::command::unlock(matches, config),
+        Some(("lock", matches)) => return kbs2::command::lock(matches, config),
+        _ => {}
+    }
+
+    // Special case: neither does `kbs2 hooks`, since it's used to test them, or
+    // `kbs2 status`, since it's run by shell prompts and status bars.
+    match matches.subcommand() {
+        Some(("hooks", matches)) => return kbs2::command::hooks(matches, config),
+        Some(("status", matches)) => return kbs2::command::status(matches, config),
+        _ => {}
     }
 
     if let Some(pre_hook) = &config.pre_hook {
-        log::debug!("pre-hook: {}", pre_hook);
-        config.call_hook(pre_hook, &[])?;
+        if !config.dry_run || config.dry_run_pre_hooks {
+            log::debug!("pre-hook: {}", pre_hook);
+            config.call_hook(pre_hook, &[])?;
+        }
+    }
+
+    // NOTE(ww): Pulling modifies the store too, so dry runs don't sync at all.
+    let (reads, modifies) = match matches.subcommand() {
+        Some((cmd, matches)) if config.sync.auto && !config.dry_run => store_access(cmd, matches),
+        _ => (false, false),
+    };
+
+    if reads {
+        config.sync_pull()?;
     }
 
     match matches.subcommand() {
@@ -347,7 +1658,19 @@ fn run(matches: &ArgMatches, config: &kbs2::config::Config) -> Result<()> {
         Some(("list", matches)) => kbs2::command::list(matches, config)?,
         Some(("rm", matches)) => kbs2::command::rm(matches, config)?,
         Some(("rename", matches)) => kbs2::command::rename(matches, config)?,
+        Some(("lock-record", matches)) => kbs2::command::lock_record(matches, config, true)?,
+        Some(("unlock-record", matches)) => kbs2::command::lock_record(matches, config, false)?,
         Some(("dump", matches)) => kbs2::command::dump(matches, config)?,
+        Some(("get", matches)) => kbs2::command::get(matches, config)?,
+        Some(("import", matches)) => kbs2::command::import(matches, config)?,
+        Some(("share", matches)) => kbs2::command::share(matches, config)?,
+        Some(("receive", matches)) => kbs2::command::receive(matches, config)?,
+        Some(("fsck", matches)) => kbs2::command::fsck(matches, config)?,
+        Some(("reencrypt", matches)) => kbs2::command::reencrypt(matches, config)?,
+        Some(("__launcher", matches)) => kbs2::command::launcher(matches, config)?,
+        Some(("bench", matches)) => kbs2::command::bench(matches, config)?,
+        Some(("unused", matches)) => kbs2::command::unused(matches, config)?,
+        Some(("dedupe", matches)) => kbs2::command::dedupe(matches, config)?,
         Some(("pass", matches)) => kbs2::command::pass(matches, config)?,
         Some(("env", matches)) => kbs2::command::env(matches, config)?,
         Some(("edit", matches)) => kbs2::command::edit(matches, config)?,
@@ -355,24 +1678,47 @@ fn run(matches: &ArgMatches, config: &kbs2::config::Config) -> Result<()> {
         Some(("rewrap", matches)) => kbs2::command::rewrap(matches, config)?,
         Some(("rekey", matches)) => kbs2::command::rekey(matches, config)?,
         Some(("config", matches)) => kbs2::command::config(matches, config)?,
-        Some((cmd, matches)) => {
-            let cmd = format!("kbs2-{cmd}");
+        Some(("migrate-store", matches)) => kbs2::command::migrate_store(matches, config)?,
+        Some(("sync", matches)) => kbs2::command::sync(matches, config)?,
+        Some(("audit-log", matches)) => kbs2::command::audit_log(matches, config)?,
+        Some((name, matches)) => {
+            let cmd = format!("kbs2-{name}");
 
-            let ext_args: Vec<_> = match matches.get_many::<OsString>("") {
-                Some(values) => values.collect(),
-                None => vec![],
-            };
+            let ext_args: Vec<_> = matches
+                .get_raw("")
+                .map(|values| values.collect())
+                .unwrap_or_default();
 
             log::debug!("external command requested: {} (args: {:?})", cmd, ext_args);
 
-            let status = process::Command::new(&cmd)
+            let mut command = process::Command::new(&cmd);
+
+            // NOTE(ww): The agent's state is informational, so failing to query it shouldn't
+            // keep the external command from running.
+            match kbs2::agent::Status::query(&config.public_key, config.wrapped) {
+                Ok(status) if status.running => {
+                    command.env("KBS2_AGENT_SOCKET", &status.socket).env(
+                        "KBS2_AGENT_KEY_LOADED",
+                        if status.key_loaded { "1" } else { "0" },
+                    );
+                }
+                Ok(_) => {}
+                Err(e) => log::debug!("couldn't query the agent: {}", e),
+            }
+
+            let status = command
                 .args(&ext_args)
                 .env("KBS2_CONFIG_DIR", &config.config_dir)
                 .env("KBS2_STORE", &config.store)
                 .env("KBS2_SUBCOMMAND", "1")
+                .env("KBS2_EXT_CONFIG", config.ext_config(name)?)
                 .env("KBS2_MAJOR_VERSION", env!("CARGO_PKG_VERSION_MAJOR"))
                 .env("KBS2_MINOR_VERSION", env!("CARGO_PKG_VERSION_MINOR"))
                 .env("KBS2_PATCH_VERSION", env!("CARGO_PKG_VERSION_PATCH"))
+                .env(
+                    "KBS2_PROTOCOL_VERSION",
+                    kbs2::agent::PROTOCOL_VERSION.to_string(),
+                )
                 .status()
                 .with_context(|| format!("no such command: {cmd}"))?;
 
@@ -386,55 +1732,218 @@ fn run(matches: &ArgMatches, config: &kbs2::config::Config) -> Result<()> {
         _ => unreachable!(),
     }
 
+    // NOTE(ww): Reading a burn-after-reading record removes it, so even a read-only
+    // command can leave something to push.
+    if modifies || (reads && kbs2::session::records_burned()) {
+        config.sync_push()?;
+    }
+
     if let Some(post_hook) = &config.post_hook {
-        log::debug!("post-hook: {}", post_hook);
-        config.call_hook(post_hook, &[])?;
+        if !config.dry_run {
+            log::debug!("post-hook: {}", post_hook);
+            config.call_hook(post_hook, &[])?;
+        }
     }
 
     Ok(())
 }
 
+/// Returns the full name of the given subcommand (e.g. `agent unwrap`), and the record
+/// labels given to it, for the audit log.
+fn audited_command(cmd: &str, matches: &ArgMatches) -> (String, Vec<String>) {
+    let (name, matches) = match matches.subcommand() {
+        Some((subcmd, matches)) => (format!("{cmd} {subcmd}"), matches),
+        None => (cmd.into(), matches),
+    };
+
+    // NOTE(ww): Only arguments that name records are logged; other arguments
+    // (e.g. field values) can contain secrets.
+    let labels = ["label", "old-label", "new-label", "update"]
+        .iter()
+        .filter_map(|id| matches.try_get_many::<String>(id).ok().flatten())
+        .flatten()
+        .cloned()
+        .collect();
+
+    (name, labels)
+}
+
+/// Returns whether the given error was caused by a missing record.
+fn is_no_such_record(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| cause.is::<kbs2::session::NoSuchRecord>())
+}
+
+/// Returns whether the given error was caused by writing to a closed pipe.
+fn is_broken_pipe(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::BrokenPipe)
+}
+
 fn main() -> Result<()> {
+    let start = std::time::Instant::now();
     env_logger::init();
 
     let mut app = app();
     let matches = app.clone().get_matches();
 
+    #[allow(clippy::unwrap_used)]
+    kbs2::util::set_quiet(*matches.get_one::<bool>("quiet").unwrap());
+
+    #[allow(clippy::unwrap_used)]
+    if *matches.get_one::<bool>("timings").unwrap() {
+        kbs2::timings::enable();
+    }
+
+    // NOTE(ww): Commands that run without a config (e.g. `kbs2 init`) can only be
+    // localized with `$KBS2_LOCALE`; everything else also honors `locale`.
+    kbs2::i18n::set_locale(Default::default());
+
     // Shell completion generation is completely independent, so perform it before
     // any config or subcommand operations.
-    if let Some(shell) = matches.get_one::<Shell>("completions") {
+    if let Some(shell) = matches.get_one::<CompletionShell>("completions") {
         generate(*shell, &mut app, env!("CARGO_PKG_NAME"), &mut io::stdout());
         return Ok(());
     }
 
     #[allow(clippy::unwrap_used)]
-    let config_dir = matches.get_one::<PathBuf>("config-dir").unwrap();
+    let dry_run = *matches.get_one::<bool>("dry-run").unwrap();
+    if let (true, Some((cmd, sub_matches))) = (dry_run, matches.subcommand()) {
+        check_dry_run(cmd, sub_matches)?;
+    }
+
+    // NOTE(ww): The shell normally expands `~` for us, but not when the config dir
+    // comes from `KBS2_CONFIG_DIR` in e.g. a systemd unit or `.env` file.
+    #[allow(clippy::unwrap_used)]
+    let config_dir = kbs2::util::expand_path(matches.get_one::<PathBuf>("config-dir").unwrap())?;
     log::debug!("config dir: {:?}", config_dir);
-    std::fs::create_dir_all(config_dir)?;
+    std::fs::create_dir_all(&config_dir)?;
 
-    // There are two special cases that are not handled in `run`:
+    // There are five special cases that are not handled in `run`:
     //
     // * `kbs2` (no subcommand): Act as if a long --help message was requested and exit.
     // * `kbs2 init`: We're initializing a config instead of loading one.
+    // * `kbs2 doctor`: We're diagnosing the config, which might not load.
+    // * `kbs2 version`: We're describing the build, which doesn't need a config.
+    // * `kbs2 upgrade`: We're checking for a newer build, which doesn't need one either.
+    #[allow(clippy::unwrap_used)]
     if matches.subcommand().is_none() {
         return app
             .clone()
             .print_long_help()
             .with_context(|| "failed to print help".to_string());
     } else if let Some(("init", matches)) = matches.subcommand() {
-        return kbs2::command::init(matches, config_dir);
+        return kbs2::command::init(matches, &config_dir);
+    } else if let Some(("doctor", sub_matches)) = matches.subcommand() {
+        let no_agent = *matches.get_one::<bool>("no-agent").unwrap();
+        return kbs2::command::doctor(sub_matches, &config_dir, no_agent);
+    } else if let Some(("version", matches)) = matches.subcommand() {
+        return kbs2::command::version(matches);
+    } else if let Some(("upgrade", matches)) = matches.subcommand() {
+        return kbs2::command::upgrade(matches, &config_dir);
     }
 
     // Everything else (i.e., all other subcommands) go through here.
-    let config = kbs2::config::load(config_dir)?;
-    match run(&matches, &config) {
+    let mut config = kbs2::timings::span("config load", || kbs2::config::load(&config_dir))?;
+    kbs2::i18n::set_locale(config.locale);
+    kbs2::input::configure_prompts(&config.ui);
+
+    #[allow(clippy::unwrap_used)]
+    {
+        config.no_agent = *matches.get_one::<bool>("no-agent").unwrap();
+        config.force_retry = *matches.get_one::<bool>("force-retry").unwrap();
+    }
+    config.dry_run = dry_run;
+
+    let result = run(&matches, &config);
+
+    if let Some((cmd, sub_matches)) = matches.subcommand() {
+        // NOTE(ww): Reading the audit log isn't itself audited, since every
+        // `kbs2 audit-log tail` would otherwise show up in its own output.
+        if cmd != "audit-log" {
+            let (subcommand, labels) = audited_command(cmd, sub_matches);
+            let outcome = match &result {
+                Err(e) if is_broken_pipe(e) => kbs2::audit::Outcome::Success,
+                result => kbs2::audit::Outcome::of(result),
+            };
+            let error_kind = match (&result, outcome) {
+                (Err(e), kbs2::audit::Outcome::Failure) => Some(kbs2::audit::error_kind(e)),
+                _ => None,
+            };
+
+            config.audit(kbs2::audit::Event::Command {
+                subcommand,
+                labels,
+                outcome,
+                error_kind,
+            });
+        }
+    }
+
+    // NOTE(ww): Update notices are for people, so they're skipped for hooks, scripts, and
+    // `kbs2 status` (which shell prompts run constantly).
+    if cfg!(feature = "network")
+        && config.check_for_updates
+        && !kbs2::util::quiet()
+        && io::stderr().is_terminal()
+        && std::env::var("KBS2_HOOK").is_err()
+        && !matches!(matches.subcommand_name(), Some("status"))
+    {
+        kbs2::update::notify(&config_dir);
+    }
+
+    // NOTE(ww): Report before handling the result, since some errors exit directly.
+    kbs2::timings::report(start.elapsed());
+
+    match result {
         Ok(()) => Ok(()),
+        // Our reader went away before we finished writing (e.g. `kbs2 list | head`).
+        // That's not an error from the user's perspective, so exit cleanly.
+        Err(e) if is_broken_pipe(&e) => {
+            log::debug!("stdout closed early: {}", e);
+            Ok(())
+        }
+        // The user bailed out of an interactive prompt, so don't treat it as a failure
+        // (or run the error hook); just exit like we were interrupted.
+        Err(e) if e.is::<kbs2::input::Aborted>() => {
+            eprintln!("Aborted.");
+            std::process::exit(130);
+        }
         Err(e) => {
             if let Some(error_hook) = &config.error_hook {
                 log::debug!("error-hook: {}", error_hook);
                 config.call_hook(error_hook, &[&e.to_string()])?;
             }
 
+            // Scripts need to be able to tell a missing record apart from everything else.
+            if is_no_such_record(&e) {
+                eprintln!("Error: {e:?}");
+                std::process::exit(NO_SUCH_RECORD_EXIT_CODE);
+            }
+
+            // ...and writes that the filesystem refused (e.g. in a read-only or full store).
+            if let Some(failed) = e
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<kbs2::util::WriteFailed>())
+            {
+                eprintln!("Error: {e:?}");
+                std::process::exit(match failed.reason {
+                    kbs2::util::Unwritable::ReadOnly => READ_ONLY_EXIT_CODE,
+                    kbs2::util::Unwritable::Full => NO_SPACE_EXIT_CODE,
+                    kbs2::util::Unwritable::PermissionDenied => PERMISSION_DENIED_EXIT_CODE,
+                });
+            }
+
+            // ...and, with `kbs2 list --fail-if-empty`, an empty store from a filter miss.
+            if let Some(nothing) = e.downcast_ref::<kbs2::command::NothingListed>() {
+                eprintln!("Error: {e}");
+                std::process::exit(match nothing {
+                    kbs2::command::NothingListed::EmptyStore => EMPTY_STORE_EXIT_CODE,
+                    kbs2::command::NothingListed::NoMatches => NO_MATCHES_EXIT_CODE,
+                });
+            }
+
             Err(e)
         }
     }
@@ -448,4 +1957,15 @@ mod tests {
     fn test_app() {
         app().debug_assert();
     }
+
+    #[test]
+    fn test_is_broken_pipe() {
+        let e: anyhow::Error = io::Error::from(io::ErrorKind::BrokenPipe).into();
+        assert!(is_broken_pipe(&e));
+        assert!(is_broken_pipe(&e.context("failed to write")));
+
+        let e: anyhow::Error = io::Error::from(io::ErrorKind::PermissionDenied).into();
+        assert!(!is_broken_pipe(&e));
+        assert!(!is_broken_pipe(&anyhow::anyhow!("Broken pipe")));
+    }
 }
@@ -2,16 +2,56 @@
 // https://github.com/rust-lang/rust/issues/46379
 #![allow(dead_code)]
 
-use std::process::Output;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Child, Output, Stdio};
+use std::thread;
+use std::time::Duration;
 
 use assert_cmd::Command;
 use serde_json::Value;
 use tempfile::TempDir;
 
+/// The master password of every `CliSession::new_wrapped` key.
+pub const PASSWORD: &str = "hunter2";
+
 #[derive(Debug)]
 pub struct CliSession {
     pub config_dir: TempDir,
     pub store_dir: TempDir,
+    pub agent: Option<TestAgent>,
+}
+
+/// An agent that belongs to a single `CliSession`, listening on a socket of its own
+/// (via `$KBS2_AGENT_SOCK`) so that it never touches the user's real agent.
+#[derive(Debug)]
+pub struct TestAgent {
+    /// Holds the agent's socket, along with a fake pinentry and the passwords it answers with.
+    pub dir: TempDir,
+    pub process: Child,
+}
+
+impl TestAgent {
+    pub fn socket(&self) -> PathBuf {
+        self.dir.path().join("agent.sock")
+    }
+
+    /// Queues up passwords for the fake pinentry to answer with, one per prompt, before it
+    /// goes back to answering with `PASSWORD`.
+    pub fn queue_passwords(&self, passwords: &[&str]) {
+        let queue = passwords
+            .iter()
+            .map(|p| format!("{p}\n"))
+            .collect::<String>();
+        std::fs::write(self.dir.path().join("passwords"), queue).unwrap();
+    }
+}
+
+impl Drop for TestAgent {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
 }
 
 impl CliSession {
@@ -35,6 +75,81 @@ impl CliSession {
         Self {
             config_dir,
             store_dir,
+            agent: None,
+        }
+    }
+
+    /// Like `new`, but with a key wrapped with `PASSWORD` and an agent of its own, which
+    /// is stopped when the session is dropped.
+    ///
+    /// Password prompts go to a fake pinentry, which answers with `PASSWORD` unless
+    /// other passwords are queued with `TestAgent::queue_passwords`.
+    pub fn new_wrapped() -> Self {
+        let config_dir = TempDir::new().unwrap();
+        let store_dir = TempDir::new().unwrap();
+        let agent_dir = TempDir::new().unwrap();
+        let socket = agent_dir.path().join("agent.sock");
+
+        kbs2()
+            .arg("--config-dir")
+            .arg(config_dir.path())
+            .args(["init", "--password-stdin", "--store-dir"])
+            .arg(store_dir.path())
+            .write_stdin(format!("{PASSWORD}\n"))
+            .assert()
+            .success();
+
+        let pinentry = agent_dir.path().join("pinentry");
+        std::fs::write(
+            &pinentry,
+            format!(
+                "#!/bin/sh\nqueue={queue}\necho OK\nwhile read -r cmd rest; do\n  \
+                 case \"$cmd\" in\n    \
+                 GETPIN)\n      pw=$(head -n 1 \"$queue\" 2>/dev/null)\n      \
+                 [ -n \"$pw\" ] && tail -n +2 \"$queue\" > \"$queue.tmp\" && mv \"$queue.tmp\" \"$queue\"\n      \
+                 echo \"D ${{pw:-{PASSWORD}}}\"; echo OK ;;\n    \
+                 BYE) echo OK; exit 0 ;;\n    \
+                 *) echo OK ;;\n  esac\ndone\n",
+                queue = agent_dir.path().join("passwords").display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&pinentry, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        // NOTE(ww): The session's agent is started by hand, so that commands fail loudly
+        // rather than daemonizing a replacement if it ever goes away.
+        let config_path = config_dir.path().join("config.toml");
+        let config = std::fs::read_to_string(&config_path)
+            .unwrap()
+            .replace("agent-autostart = true", "agent-autostart = false")
+            .replace(
+                "pinentry = \"pinentry\"",
+                &format!("pinentry = {:?}", pinentry.to_str().unwrap()),
+            );
+        std::fs::write(&config_path, config).unwrap();
+
+        let mut process =
+            std::process::Command::new(assert_cmd::cargo::cargo_bin(env!("CARGO_PKG_NAME")))
+                .env("KBS2_AGENT_SOCK", &socket)
+                .arg("--config-dir")
+                .arg(config_dir.path())
+                .args(["agent", "--foreground"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+        while !socket.exists() {
+            assert!(process.try_wait().unwrap().is_none(), "agent exited early");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Self {
+            config_dir,
+            store_dir,
+            agent: Some(TestAgent {
+                dir: agent_dir,
+                process,
+            }),
         }
     }
 
@@ -42,6 +157,9 @@ impl CliSession {
         let mut kbs2 = kbs2();
 
         kbs2.arg("--config-dir").arg(self.config_dir.path());
+        if let Some(agent) = &self.agent {
+            kbs2.env("KBS2_AGENT_SOCK", agent.socket());
+        }
 
         kbs2
     }
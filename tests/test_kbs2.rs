@@ -2,7 +2,7 @@ mod common;
 
 use clap::ValueEnum;
 use clap_complete::Shell;
-use common::kbs2;
+use common::{kbs2, CliSession, ToJson};
 
 #[test]
 fn test_kbs2_help() {
@@ -22,11 +22,17 @@ fn test_kbs2_help() {
 fn test_kbs2_completions() {
     // Tab completion generation works
 
-    for shell in Shell::value_variants() {
-        let output = kbs2()
-            .args(["--completions", &shell.to_string()])
-            .output()
-            .unwrap();
+    #[allow(unused_mut)]
+    let mut shells = Shell::value_variants()
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    #[cfg(feature = "nushell")]
+    shells.push("nushell".into());
+
+    for shell in shells {
+        let output = kbs2().args(["--completions", &shell]).output().unwrap();
         assert!(output.status.success());
         assert!(!output.stdout.is_empty());
     }
@@ -44,3 +50,145 @@ fn test_kbs2_version() {
         .unwrap()
         .starts_with(&version));
 }
+
+#[test]
+fn test_kbs2_version_subcommand() {
+    // kbs2 version doesn't need a config, and agrees with kbs2 --version.
+    let version = format!("kbs2 {}", env!("CARGO_PKG_VERSION"));
+
+    let output = kbs2().arg("version").output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with(&version));
+    assert!(stdout.contains(", agent protocol "));
+
+    let json = kbs2().args(["version", "--json"]).output().unwrap().json();
+    assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+    assert!(json["protocol"].is_u64());
+    assert_eq!(json["record_schema"], 1);
+    assert_eq!(
+        json["features"]
+            .as_array()
+            .unwrap()
+            .contains(&"nushell".into()),
+        cfg!(feature = "nushell")
+    );
+}
+
+#[test]
+fn test_kbs2_exit_codes() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // A missing record gets its own exit code, however it's requested.
+    for args in [
+        &["pass", "nonexistent"][..],
+        &["dump", "nonexistent"][..],
+        &["dump", "--encrypted", "nonexistent"][..],
+        &["rm", "nonexistent"][..],
+        &["rename", "nonexistent", "elsewhere"][..],
+    ] {
+        session.command().args(args).assert().code(3);
+    }
+
+    // Other failures don't.
+    session
+        .command()
+        .args(["pass", "test-record", "--totp"])
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn test_kbs2_missing_config() {
+    let config_dir = tempfile::TempDir::new().unwrap();
+
+    // A missing config is an error that says which file is missing, rather than
+    // a bare I/O error.
+    let output = kbs2()
+        .arg("-c")
+        .arg(config_dir.path())
+        .arg("list")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains(&format!(
+        "failed to read {}; create it with `kbs2 init`",
+        config_dir.path().join("config.toml").display()
+    )));
+}
+
+#[test]
+fn test_kbs2_locale() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let stderr = |output: std::process::Output| String::from_utf8(output.stderr).unwrap();
+
+    // Messages are in English by default...
+    let output = session
+        .command()
+        .env_remove("KBS2_LOCALE")
+        .args(["pass", "nonexistent"])
+        .output()
+        .unwrap();
+    assert!(stderr(output).contains("no such record: nonexistent"));
+
+    // ...but `$KBS2_LOCALE` selects another locale.
+    let output = session
+        .command()
+        .env("KBS2_LOCALE", "de_DE.UTF-8")
+        .args(["pass", "nonexistent"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(output).contains("kein solcher Eintrag: nonexistent"));
+
+    // So does `locale` in the config, although `$KBS2_LOCALE` still takes precedence.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config.replace("locale = \"en\"", "locale = \"de\""),
+    )
+    .unwrap();
+
+    let output = session
+        .command()
+        .env_remove("KBS2_LOCALE")
+        .args(["pass", "nonexistent"])
+        .output()
+        .unwrap();
+    assert!(stderr(output).contains("kein solcher Eintrag: nonexistent"));
+
+    let output = session
+        .command()
+        .env("KBS2_LOCALE", "en")
+        .args(["pass", "nonexistent"])
+        .output()
+        .unwrap();
+    assert!(stderr(output).contains("no such record: nonexistent"));
+
+    // Machine-facing output isn't localized.
+    let output = session
+        .command()
+        .env("KBS2_LOCALE", "de")
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.json()["body"]["kind"], "Login");
+}
@@ -0,0 +1,626 @@
+mod common;
+
+use std::sync::Mutex;
+
+use common::{CliSession, ToJson};
+
+/// Serializes the tests that run a real agent, since its socket is per-user.
+static AGENT_LOCK: Mutex<()> = Mutex::new(());
+
+#[test]
+fn test_kbs2_agent_protocol() {
+    let session = CliSession::new();
+
+    let description = session
+        .command()
+        .args(["agent", "protocol"])
+        .output()
+        .unwrap()
+        .json();
+
+    let protocol = description["protocol"].as_u64().unwrap();
+    let requests = description["requests"].as_object().unwrap();
+    let responses = description["responses"].as_object().unwrap();
+    assert!(requests.contains_key("UnwrapKey"));
+    assert!(responses.contains_key("Success"));
+
+    // Every request carries the protocol version.
+    for request in requests.values() {
+        assert_eq!(request["protocol"].as_u64().unwrap(), protocol);
+    }
+
+    // Each example is available individually, as a single line of JSON.
+    for (name, example) in requests.iter().chain(responses.iter()) {
+        let output = session
+            .command()
+            .args(["agent", "protocol", "--example", name])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout.iter().filter(|b| **b == b'\n').count(), 1);
+        assert_eq!(&output.json(), example);
+    }
+
+    session
+        .command()
+        .args(["agent", "protocol", "--example", "Nonexistent"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_agent_query() {
+    let session = CliSession::new();
+
+    // Bare keys aren't managed by the agent, whether or not one is running.
+    let output = session
+        .command()
+        .args(["agent", "query", "--json"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+
+    let status = output.json();
+    assert_eq!(status["wrapped"], false);
+    assert_eq!(status["key_loaded"], false);
+    assert!(status["running"].is_boolean());
+    assert!(status["socket"]
+        .as_str()
+        .unwrap()
+        .starts_with("/tmp/kbs2-agent-"));
+
+    let output = session.command().args(["agent", "query"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("bare key (not managed by the agent)"));
+}
+
+#[test]
+fn test_kbs2_agent_query_flush_wrapped() {
+    let session = CliSession::new_wrapped();
+    let agent = session.agent.as_ref().unwrap();
+
+    let query = || {
+        session
+            .command()
+            .args(["agent", "query", "--json"])
+            .output()
+            .unwrap()
+    };
+
+    // The session's agent is running, on its own socket, but doesn't have the key yet.
+    let output = query();
+    let status = output.json();
+    assert_eq!(status["wrapped"], true);
+    assert_eq!(status["running"], true);
+    assert_eq!(status["key_loaded"], false);
+    assert_eq!(status["socket"], agent.socket().to_str().unwrap());
+
+    session
+        .command()
+        .args(["agent", "unwrap"])
+        .assert()
+        .success();
+    let output = query();
+    assert!(output.status.success());
+    assert_eq!(output.json()["key_loaded"], true);
+
+    session
+        .command()
+        .args(["agent", "flush"])
+        .assert()
+        .success();
+    assert_eq!(query().json()["key_loaded"], false);
+
+    // Once the agent quits, there's nothing to query (or to flush).
+    session
+        .command()
+        .args(["agent", "flush", "--quit"])
+        .assert()
+        .success();
+    assert_eq!(query().json()["running"], false);
+    session
+        .command()
+        .args(["agent", "flush"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_agent_single_prompt() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::Duration;
+
+    let _lock = AGENT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    // NOTE(ww): The agent's socket is per-user, so we can't run alongside a real agent.
+    let socket = PathBuf::from(format!("/tmp/kbs2-agent-{}", whoami::username()));
+    if socket.exists() {
+        eprintln!(
+            "skipping: an agent is already running at {}",
+            socket.display()
+        );
+        return;
+    }
+
+    let config_dir = tempfile::TempDir::new().unwrap();
+    let store_dir = tempfile::TempDir::new().unwrap();
+
+    // A fake pinentry that records each password request, and is slow to answer so that
+    // concurrent clients overlap.
+    let bin_dir = config_dir.path().join("bin");
+    let prompts = config_dir.path().join("prompts");
+    std::fs::create_dir(&bin_dir).unwrap();
+    let pinentry = bin_dir.join("pinentry");
+    std::fs::write(
+        &pinentry,
+        format!(
+            "#!/bin/sh\necho OK\nwhile read -r cmd rest; do\n  case \"$cmd\" in\n    \
+             GETPIN) echo x >> {prompts}; sleep 1; echo 'D hunter2'; echo OK ;;\n    \
+             BYE) echo OK; exit 0 ;;\n    \
+             *) echo OK ;;\n  esac\ndone\n",
+            prompts = prompts.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&pinentry, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!(
+        "{}:{}",
+        bin_dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    let kbs2 = || {
+        let mut kbs2 = common::kbs2();
+        kbs2.env("PATH", &path)
+            .arg("--config-dir")
+            .arg(config_dir.path());
+        kbs2
+    };
+
+    kbs2()
+        .arg("init")
+        .arg("--store-dir")
+        .arg(store_dir.path())
+        .assert()
+        .success();
+
+    let mut agent = Command::new(assert_cmd::cargo::cargo_bin(env!("CARGO_PKG_NAME")))
+        .env("PATH", &path)
+        .arg("--config-dir")
+        .arg(config_dir.path())
+        .args(["agent", "--foreground"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    while !socket.exists() {
+        assert!(agent.try_wait().unwrap().is_none(), "agent exited early");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    kbs2()
+        .args(["new", "-k", "environment", "-t", "foo"])
+        .write_stdin("FOO\x01bar")
+        .assert()
+        .success();
+
+    // Forget the key, then race several clients that all need it unwrapped.
+    kbs2().args(["agent", "flush"]).assert().success();
+    std::fs::remove_file(&prompts).unwrap();
+
+    let clients = (0..3)
+        .map(|_| {
+            let mut client = kbs2();
+            client.args(["env", "-v", "foo"]);
+            thread::spawn(move || client.output().unwrap())
+        })
+        .collect::<Vec<_>>();
+    let outputs = clients
+        .into_iter()
+        .map(|c| c.join().unwrap())
+        .collect::<Vec<_>>();
+
+    agent.kill().unwrap();
+    agent.wait().unwrap();
+    let _ = std::fs::remove_file(&socket);
+
+    for output in outputs {
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"bar\n");
+    }
+    assert_eq!(
+        std::fs::read_to_string(&prompts).unwrap().lines().count(),
+        1
+    );
+}
+
+#[test]
+fn test_kbs2_agent_systemd_notify() {
+    use std::os::unix::net::UnixDatagram;
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+
+    let _lock = AGENT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    // NOTE(ww): The agent's socket is per-user, so we can't run alongside a real agent.
+    let socket = PathBuf::from(format!("/tmp/kbs2-agent-{}", whoami::username()));
+    if socket.exists() {
+        eprintln!(
+            "skipping: an agent is already running at {}",
+            socket.display()
+        );
+        return;
+    }
+
+    let session = CliSession::new();
+
+    // --systemd-notify only makes sense for an agent that systemd can see.
+    session
+        .command()
+        .args(["agent", "--systemd-notify"])
+        .assert()
+        .failure();
+
+    // Stand in for systemd's notification socket.
+    let notify_path = session.config_dir.path().join("notify");
+    let notify = UnixDatagram::bind(&notify_path).unwrap();
+    notify
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    let mut agent = Command::new(assert_cmd::cargo::cargo_bin(env!("CARGO_PKG_NAME")))
+        .env("NOTIFY_SOCKET", &notify_path)
+        .arg("--config-dir")
+        .arg(session.config_dir.path())
+        .args(["agent", "--foreground", "--systemd-notify"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // By the time the agent says it's ready, its socket has to exist.
+    let mut buf = [0; 64];
+    let len = notify.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+    assert!(socket.exists());
+
+    session
+        .command()
+        .args(["agent", "flush", "--quit"])
+        .assert()
+        .success();
+
+    let len = notify.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"STOPPING=1");
+
+    assert!(agent.wait().unwrap().success());
+}
+
+#[test]
+fn test_kbs2_agent_unwrap_all() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::Duration;
+
+    let _lock = AGENT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    // NOTE(ww): The agent's socket is per-user, so we can't run alongside a real agent.
+    let socket = PathBuf::from(format!("/tmp/kbs2-agent-{}", whoami::username()));
+    if socket.exists() {
+        eprintln!(
+            "skipping: an agent is already running at {}",
+            socket.display()
+        );
+        return;
+    }
+
+    let configs = tempfile::TempDir::new().unwrap();
+    let store_dir = tempfile::TempDir::new().unwrap();
+
+    // A fake pinentry that records each password request.
+    let bin_dir = store_dir.path().join("bin");
+    let prompts = store_dir.path().join("prompts");
+    std::fs::create_dir(&bin_dir).unwrap();
+    let pinentry = bin_dir.join("pinentry");
+    std::fs::write(
+        &pinentry,
+        format!(
+            "#!/bin/sh\necho OK\nwhile read -r cmd rest; do\n  case \"$cmd\" in\n    \
+             GETPIN) echo x >> {prompts}; echo 'D hunter2'; echo OK ;;\n    \
+             BYE) echo OK; exit 0 ;;\n    \
+             *) echo OK ;;\n  esac\ndone\n",
+            prompts = prompts.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&pinentry, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!(
+        "{}:{}",
+        bin_dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    let kbs2 = |config_dir: &str| {
+        let mut kbs2 = common::kbs2();
+        kbs2.env("PATH", &path)
+            .arg("--config-dir")
+            .arg(configs.path().join(config_dir));
+        kbs2
+    };
+
+    // Two wrapped configs, a bare one, and a directory that isn't a config at all.
+    for (name, wrapped) in [("personal", true), ("work", true), ("bare", false)] {
+        let mut init = kbs2(name);
+        init.arg("init").arg("--store-dir").arg(store_dir.path());
+        if !wrapped {
+            init.arg("--insecure-not-wrapped");
+        }
+        init.assert().success();
+    }
+    std::fs::create_dir(configs.path().join("junk")).unwrap();
+    let _ = std::fs::remove_file(&prompts);
+
+    let mut agent = Command::new(assert_cmd::cargo::cargo_bin(env!("CARGO_PKG_NAME")))
+        .env("PATH", &path)
+        .arg("--config-dir")
+        .arg(configs.path().join("personal"))
+        .args(["agent", "--foreground"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    while !socket.exists() {
+        assert!(agent.try_wait().unwrap().is_none(), "agent exited early");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let unwrap_all = || {
+        kbs2("personal")
+            .args(["agent", "unwrap", "--all"])
+            .arg(configs.path())
+            .output()
+            .unwrap()
+    };
+
+    let first = unwrap_all();
+    let second = unwrap_all();
+
+    let empty = tempfile::TempDir::new().unwrap();
+    let none = kbs2("personal")
+        .args(["agent", "unwrap", "--all"])
+        .arg(empty.path())
+        .output()
+        .unwrap();
+
+    agent.kill().unwrap();
+    agent.wait().unwrap();
+    let _ = std::fs::remove_file(&socket);
+
+    let lines = |output: &std::process::Output| {
+        String::from_utf8(output.stdout.clone())
+            .unwrap()
+            .lines()
+            .map(|line| line.rsplit_once(": ").unwrap().1.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert!(first.status.success());
+    assert_eq!(
+        lines(&first),
+        ["bare key; skipped", "key unwrapped", "key unwrapped"]
+    );
+    assert_eq!(
+        std::fs::read_to_string(&prompts).unwrap().lines().count(),
+        2
+    );
+
+    assert!(second.status.success());
+    assert_eq!(
+        lines(&second),
+        [
+            "bare key; skipped",
+            "key already loaded",
+            "key already loaded"
+        ]
+    );
+
+    assert!(!none.status.success());
+}
+
+#[test]
+fn test_kbs2_unlock_retries() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let config_dir = tempfile::TempDir::new().unwrap();
+    let store_dir = tempfile::TempDir::new().unwrap();
+
+    // A fake pinentry that records each password request, and answers with whatever
+    // password is in `answer`.
+    let bin_dir = config_dir.path().join("bin");
+    let prompts = config_dir.path().join("prompts");
+    let answer = config_dir.path().join("answer");
+    std::fs::create_dir(&bin_dir).unwrap();
+    let pinentry = bin_dir.join("pinentry");
+    std::fs::write(
+        &pinentry,
+        format!(
+            "#!/bin/sh\necho OK\nwhile read -r cmd rest; do\n  case \"$cmd\" in\n    \
+             GETPIN) echo x >> {prompts}; echo \"D $(cat {answer})\"; echo OK ;;\n    \
+             BYE) echo OK; exit 0 ;;\n    \
+             *) echo OK ;;\n  esac\ndone\n",
+            prompts = prompts.display(),
+            answer = answer.display(),
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&pinentry, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!(
+        "{}:{}",
+        bin_dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    let kbs2 = || {
+        let mut kbs2 = common::kbs2();
+        kbs2.env("PATH", &path)
+            .arg("--config-dir")
+            .arg(config_dir.path());
+        kbs2
+    };
+
+    std::fs::write(&answer, "hunter2").unwrap();
+    kbs2()
+        .arg("init")
+        .arg("--store-dir")
+        .arg(store_dir.path())
+        .assert()
+        .success();
+
+    let config_file = config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_file)
+        .unwrap()
+        .replace("unlock-max-attempts = 3", "unlock-max-attempts = 2")
+        .replace("unlock-backoff-ms = 500", "unlock-backoff-ms = 1");
+    std::fs::write(&config_file, config).unwrap();
+    std::fs::remove_file(&prompts).unwrap();
+
+    // Every attempt is wrong, so we give up after `unlock-max-attempts` prompts.
+    std::fs::write(&answer, "wrong").unwrap();
+    let output = kbs2().args(["--no-agent", "list"]).output().unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("incorrect password; 1 attempt(s) left"));
+    assert!(stderr.contains("too many incorrect passwords"));
+    assert_eq!(
+        std::fs::read_to_string(&prompts).unwrap().lines().count(),
+        2
+    );
+
+    // The right password still works on the first try.
+    std::fs::remove_file(&prompts).unwrap();
+    std::fs::write(&answer, "hunter2").unwrap();
+    kbs2().args(["--no-agent", "list"]).assert().success();
+    assert_eq!(
+        std::fs::read_to_string(&prompts).unwrap().lines().count(),
+        1
+    );
+}
+
+#[test]
+fn test_kbs2_unlock_lock() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::Duration;
+
+    let _lock = AGENT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    // NOTE(ww): The agent's socket is per-user, so we can't run alongside a real agent.
+    let socket = PathBuf::from(format!("/tmp/kbs2-agent-{}", whoami::username()));
+    if socket.exists() {
+        eprintln!(
+            "skipping: an agent is already running at {}",
+            socket.display()
+        );
+        return;
+    }
+
+    let config_dir = tempfile::TempDir::new().unwrap();
+    let store_dir = tempfile::TempDir::new().unwrap();
+
+    let bin_dir = config_dir.path().join("bin");
+    std::fs::create_dir(&bin_dir).unwrap();
+    let pinentry = bin_dir.join("pinentry");
+    std::fs::write(
+        &pinentry,
+        "#!/bin/sh\necho OK\nwhile read -r cmd rest; do\n  case \"$cmd\" in\n    \
+         GETPIN) echo 'D hunter2'; echo OK ;;\n    \
+         BYE) echo OK; exit 0 ;;\n    \
+         *) echo OK ;;\n  esac\ndone\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&pinentry, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!(
+        "{}:{}",
+        bin_dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    let kbs2 = || {
+        let mut kbs2 = common::kbs2();
+        kbs2.env("PATH", &path)
+            .arg("--config-dir")
+            .arg(config_dir.path());
+        kbs2
+    };
+
+    kbs2()
+        .arg("init")
+        .arg("--store-dir")
+        .arg(store_dir.path())
+        .assert()
+        .success();
+
+    // Locking without an agent is a no-op.
+    kbs2()
+        .arg("lock")
+        .assert()
+        .success()
+        .stdout("no agent running\n");
+
+    // NOTE(ww): `kbs2 unlock` would start the agent itself, but refuses to when run as
+    // root (e.g. in CI containers), so we start it ourselves.
+    let mut agent = Command::new(assert_cmd::cargo::cargo_bin(env!("CARGO_PKG_NAME")))
+        .env("PATH", &path)
+        .arg("--config-dir")
+        .arg(config_dir.path())
+        .args(["agent", "--foreground"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    while !socket.exists() {
+        assert!(agent.try_wait().unwrap().is_none(), "agent exited early");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Unlocking only prompts when the key isn't already loaded.
+    kbs2()
+        .arg("unlock")
+        .assert()
+        .success()
+        .stdout("key loaded\n");
+    kbs2()
+        .arg("unlock")
+        .assert()
+        .success()
+        .stdout("key already loaded\n");
+    kbs2()
+        .args(["--quiet", "unlock"])
+        .assert()
+        .success()
+        .stdout("");
+
+    kbs2()
+        .arg("lock")
+        .assert()
+        .success()
+        .stdout("1 key flushed\n");
+    kbs2()
+        .args(["lock", "--quit"])
+        .assert()
+        .success()
+        .stdout("0 keys flushed\n");
+
+    assert!(agent.wait().unwrap().success());
+    assert!(!socket.exists());
+}
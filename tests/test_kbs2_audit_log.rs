@@ -0,0 +1,95 @@
+mod common;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_audit_log() {
+    let session = CliSession::new();
+
+    let audit_log = session.config_dir.path().join("audit.log");
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config.replace(
+            "audit-log-max-bytes = ",
+            &format!(
+                "audit-log = \"{}\"\npost-hook = \"true\"\naudit-log-max-bytes = ",
+                audit_log.display()
+            ),
+        ),
+    )
+    .unwrap();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01hunter2")
+        .assert()
+        .success();
+    session
+        .command()
+        .args(["pass", "test-record"])
+        .assert()
+        .success();
+    session
+        .command()
+        .args(["rename", "nonexistent", "elsewhere"])
+        .assert()
+        .failure();
+
+    // Nothing secret is ever logged, no matter the outcome.
+    let contents = std::fs::read_to_string(&audit_log).unwrap();
+    assert!(!contents.contains("hunter2"));
+    assert!(!contents.contains("fakeuser"));
+
+    let entries: Vec<serde_json::Value> = contents
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    let commands: Vec<_> = entries.iter().filter(|e| e["event"] == "command").collect();
+    assert_eq!(commands.len(), 3);
+    assert_eq!(commands[0]["subcommand"], "new");
+    assert_eq!(commands[0]["labels"], serde_json::json!(["test-record"]));
+    assert_eq!(commands[1]["subcommand"], "pass");
+    assert_eq!(commands[1]["outcome"], "success");
+    assert_eq!(commands[2]["subcommand"], "rename");
+    assert_eq!(commands[2]["outcome"], "failure");
+    assert!(commands[2]["error_kind"].is_string());
+
+    // So is each record operation.
+    assert!(entries.iter().any(|e| e["event"] == "record"
+        && e["label"] == "test-record"
+        && e["operation"] == "read"
+        && e["outcome"] == "success"));
+
+    // The post-hook runs after each successful command.
+    assert!(entries
+        .iter()
+        .any(|e| e["event"] == "hook" && e["command"] == "true" && e["exit_code"] == 0));
+
+    // `tail` shows the most recent entries, and isn't logged itself.
+    let output = session
+        .command()
+        .args(["audit-log", "tail", "-n", "1", "--since", "1d"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let tail = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(tail.lines().count(), 1);
+    assert!(tail.contains("command: rename (nonexistent, elsewhere): failure"));
+    assert!(!std::fs::read_to_string(&audit_log)
+        .unwrap()
+        .contains("audit-log"));
+}
+
+#[test]
+fn test_kbs2_audit_log_unconfigured() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["audit-log", "tail"])
+        .assert()
+        .failure();
+}
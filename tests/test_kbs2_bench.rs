@@ -0,0 +1,76 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_bench() {
+    let session = CliSession::new();
+
+    // `kbs2 bench` is hidden.
+    let help = session.command().arg("--help").output().unwrap();
+    assert!(!String::from_utf8(help.stdout).unwrap().contains("bench"));
+
+    let output = session
+        .command()
+        .args(["bench", "populate", "--count", "5", "--jobs", "2"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("added 5 records in "));
+
+    let mut labels = String::from_utf8(session.command().arg("list").output().unwrap().stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    labels.sort();
+    assert_eq!(
+        labels,
+        ["bench-0", "bench-1", "bench-2", "bench-3", "bench-4"]
+    );
+
+    // Each synthetic record is a login with random contents.
+    let first = session
+        .command()
+        .args(["dump", "-j", "bench-0"])
+        .output()
+        .unwrap()
+        .json();
+    let second = session
+        .command()
+        .args(["dump", "-j", "bench-1"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(first["body"]["kind"], "Login");
+    assert_ne!(
+        first["body"]["fields"]["password"],
+        second["body"]["fields"]["password"]
+    );
+
+    let output = session.command().args(["bench", "read"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("decrypted 5 records in "));
+
+    let output = session
+        .command()
+        .args(["bench", "labels", "--iterations", "3"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("listed 15 records in "));
+
+    // Populating never overwrites existing records.
+    let output = session
+        .command()
+        .args(["bench", "populate", "--count", "1"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_burn_after_reading() {
+    let session = CliSession::new();
+
+    for label in ["burned-by-pass", "burned-by-dump"] {
+        session
+            .command()
+            .args(["new", "-k", "login", "--burn-after-reading", label])
+            .write_stdin("jdoe\x01hunter2")
+            .assert()
+            .success();
+    }
+
+    session
+        .command()
+        .args(["new", "-k", "login", "kept"])
+        .write_stdin("jdoe\x01hunter2")
+        .assert()
+        .success();
+
+    // Burn-after-reading records are marked in detailed listings.
+    let output = session
+        .command()
+        .args(["list", "--details", "--no-header"])
+        .output()
+        .unwrap();
+    let mut details = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    details.sort();
+    assert!(details[0].starts_with("burned-by-dump login (burn) "));
+    assert!(details[2].starts_with("kept           login "));
+
+    let output = session
+        .command()
+        .args(["list", "--json", "--kind", "login"])
+        .output()
+        .unwrap();
+    let burns = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .filter(|json| json["burn"] == true)
+        .count();
+    assert_eq!(burns, 2);
+
+    // Copying to the clipboard can't be confirmed, so it's refused (and nothing is burned).
+    session
+        .command()
+        .args(["pass", "-c", "burned-by-pass"])
+        .assert()
+        .failure();
+
+    // The first read succeeds, and burns the record...
+    let output = session
+        .command()
+        .args(["pass", "burned-by-pass"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"hunter2");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("burned-by-pass was burn-after-reading, and has been deleted from the store"));
+
+    // ...so the second one fails.
+    session
+        .command()
+        .args(["pass", "burned-by-pass"])
+        .assert()
+        .failure();
+
+    // Records filtered out of a dump aren't read, and so aren't burned.
+    session
+        .command()
+        .args(["dump", "--modified-since", "2999-01-01", "burned-by-dump"])
+        .assert()
+        .success()
+        .stdout("");
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", "burned-by-dump"])
+        .output()
+        .unwrap();
+    assert!(dump.status.success());
+    assert_eq!(dump.json()["burn"], true);
+
+    session
+        .command()
+        .args(["dump", "-j", "burned-by-dump"])
+        .assert()
+        .failure();
+
+    // Ordinary records can be read as often as ever, and rm works as usual.
+    for _ in 0..2 {
+        session
+            .command()
+            .args(["pass", "kept"])
+            .assert()
+            .success()
+            .stdout("hunter2");
+    }
+
+    session
+        .command()
+        .args(["new", "-k", "login", "--burn-after-reading", "removed"])
+        .write_stdin("jdoe\x01hunter2")
+        .assert()
+        .success();
+    session.command().args(["rm", "removed"]).assert().success();
+
+    let output = session.command().arg("list").output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "kept\n");
+}
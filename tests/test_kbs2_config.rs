@@ -0,0 +1,188 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_config_dump_explicit_only() {
+    let session = CliSession::new();
+
+    // Pare the config down to the bare minimum, plus one nested setting.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    let mut minimal: Vec<_> = config
+        .lines()
+        .filter(|l| {
+            ["public-key ", "keyfile ", "store "]
+                .iter()
+                .any(|k| l.starts_with(k))
+        })
+        .collect();
+    minimal.extend(["[commands.pass]", "clipboard-duration = 5"]);
+    std::fs::write(&config_path, minimal.join("\n")).unwrap();
+
+    // The full dump includes defaulted settings...
+    let dump = session
+        .command()
+        .args(["config", "dump"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["wrapped"], true);
+    assert_eq!(dump["commands"]["pass"]["clear-after"], true);
+
+    // ...but the explicit one only includes what's in the file.
+    let dump = session
+        .command()
+        .args(["config", "dump", "--explicit-only"])
+        .output()
+        .unwrap()
+        .json();
+    let dump = dump.as_object().unwrap();
+    assert_eq!(
+        dump.keys().collect::<Vec<_>>(),
+        ["commands", "keyfile", "public-key", "store"]
+    );
+    assert_eq!(
+        dump["commands"],
+        serde_json::json!({"pass": {"clipboard-duration": 5}})
+    );
+}
+
+#[test]
+fn test_kbs2_config_escrow() {
+    let session = CliSession::new();
+    let escrow = CliSession::new();
+
+    let escrow_key = escrow
+        .command()
+        .args(["config", "dump"])
+        .output()
+        .unwrap()
+        .json()["public-key"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "before"])
+        .write_stdin("jdoe\x01hunter2")
+        .assert()
+        .success();
+
+    // No escrow recipients to begin with.
+    session
+        .command()
+        .args(["config", "escrow"])
+        .assert()
+        .success()
+        .stdout("");
+
+    // Invalid recipients and the store's own key are rejected.
+    session
+        .command()
+        .args(["config", "escrow", "--add", "not-a-key", "--yes"])
+        .assert()
+        .failure();
+
+    let own_key = session
+        .command()
+        .args(["config", "dump"])
+        .output()
+        .unwrap()
+        .json()["public-key"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    session
+        .command()
+        .args(["config", "escrow", "--add", &own_key, "--yes"])
+        .assert()
+        .failure();
+
+    // Without --yes (and without a terminal), nothing happens.
+    session
+        .command()
+        .args(["config", "escrow", "--add", &escrow_key])
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["config", "escrow", "--add", &escrow_key, "--yes", "-n"])
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["config", "escrow"])
+        .assert()
+        .success()
+        .stdout(format!("{escrow_key}\n"));
+
+    session
+        .command()
+        .args(["new", "-k", "login", "after"])
+        .write_stdin("jdoe\x01hunter3")
+        .assert()
+        .success();
+
+    // Both the re-encrypted record and the new one can be decrypted with the escrow key.
+    for (label, password) in [("before", "hunter2"), ("after", "hunter3")] {
+        std::fs::copy(
+            session.store_dir.path().join(label),
+            escrow.store_dir.path().join(label),
+        )
+        .unwrap();
+
+        let dump = escrow
+            .command()
+            .args(["dump", "--json", label])
+            .output()
+            .unwrap()
+            .json();
+        assert_eq!(dump["body"]["fields"]["password"], password);
+    }
+
+    // ...while the store's own key still works.
+    session
+        .command()
+        .args(["dump", "--json", "before"])
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_kbs2_config_escrow_always_confirms() {
+    let session = CliSession::new();
+    let escrow = CliSession::new();
+
+    let escrow_key = escrow
+        .command()
+        .args(["config", "dump"])
+        .output()
+        .unwrap()
+        .json()["public-key"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // Neither an empty store nor a generous `confirm-threshold` lets a new escrow recipient
+    // be added without confirmation.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(&config_path, format!("confirm-threshold = 100\n{config}")).unwrap();
+
+    session
+        .command()
+        .args(["config", "escrow", "--add", &escrow_key])
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["config", "escrow"])
+        .assert()
+        .success()
+        .stdout("");
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_dedupe() {
+    let session = CliSession::new();
+
+    // Nothing to report in an empty store.
+    session
+        .command()
+        .arg("dedupe")
+        .assert()
+        .success()
+        .stdout("");
+
+    for (kind, label, input) in [
+        ("login", "github", "jdoe\x01hunter2"),
+        ("login", "github-old", "jdoe\x01hunter2"),
+        ("login", "email", "jdoe\x01hunter3"),
+        ("environment", "api-key", "jdoe\x01hunter2"),
+    ] {
+        session
+            .command()
+            .args(["new", "-k", kind, label])
+            .write_stdin(input)
+            .assert()
+            .success();
+    }
+
+    let output = session.command().arg("dedupe").output().unwrap();
+    assert!(output.status.success());
+
+    // Only labels are ever printed.
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "same username and password: github, github-old\n");
+    assert!(!stdout.contains("jdoe") && !stdout.contains("hunter"));
+
+    let json = session
+        .command()
+        .args(["dedupe", "--json"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(
+        json,
+        serde_json::json!([{"reason": "credentials", "labels": ["github", "github-old"]}])
+    );
+
+    // Walking through the groups needs a terminal.
+    let output = session
+        .command()
+        .args(["dedupe", "--interactive"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("interactive prompt required but stdin is not a tty"));
+
+    session
+        .command()
+        .args(["dedupe", "--interactive", "--json"])
+        .assert()
+        .failure();
+}
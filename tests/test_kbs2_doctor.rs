@@ -0,0 +1,101 @@
+mod common;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use common::{CliSession, ToJson};
+
+fn statuses(output: &std::process::Output) -> Vec<(String, String)> {
+    output.json()["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| {
+            (
+                c["name"].as_str().unwrap().into(),
+                c["status"].as_str().unwrap().into(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_kbs2_doctor() {
+    let session = CliSession::new();
+
+    let keyfile = session.config_dir.path().join("key");
+    fs::set_permissions(&keyfile, fs::Permissions::from_mode(0o600)).unwrap();
+
+    let output = session
+        .command()
+        .args(["doctor", "--json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        statuses(&output),
+        ["config", "keyfile", "agent", "crypto", "store", "hooks", "escrow"]
+            .iter()
+            .map(|name| (name.to_string(), "pass".to_string()))
+            .collect::<Vec<_>>()
+    );
+
+    // A keyfile that others can read is only a warning.
+    fs::set_permissions(&keyfile, fs::Permissions::from_mode(0o644)).unwrap();
+    let output = session
+        .command()
+        .args(["doctor", "--json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(statuses(&output)[1], ("keyfile".into(), "warn".into()));
+    assert_eq!(
+        output.json()["checks"][1]["hint"],
+        format!("chmod 600 {}", keyfile.display())
+    );
+
+    // A hook that doesn't exist is a failure.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = fs::read_to_string(&config_path).unwrap();
+    fs::write(
+        &config_path,
+        format!("post-hook = \"kbs2-no-such-hook\"\n{config}"),
+    )
+    .unwrap();
+
+    let output = session
+        .command()
+        .args(["doctor", "--json"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(statuses(&output)[5], ("hooks".into(), "fail".into()));
+
+    // So is a missing keyfile, although everything else still gets checked.
+    fs::remove_file(&keyfile).unwrap();
+    let output = session.command().arg("doctor").output().unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("[fail] keyfile: "));
+    assert!(stdout.contains("[fail] crypto: "));
+    assert!(stdout.contains("[pass] store: "));
+}
+
+#[test]
+fn test_kbs2_doctor_bad_config() {
+    let session = CliSession::new();
+
+    // Commands other than `doctor` fail outright on a bad config...
+    let config_path = session.config_dir.path().join("config.toml");
+    fs::write(&config_path, "this isn't toml").unwrap();
+    session.command().arg("list").assert().failure();
+
+    // ...while `doctor` reports it.
+    let output = session
+        .command()
+        .args(["doctor", "--json"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(statuses(&output), [("config".into(), "fail".into())]);
+}
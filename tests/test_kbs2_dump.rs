@@ -0,0 +1,241 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_dump_fields_only() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-login"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["new", "-k", "environment", "test-env"])
+        .write_stdin("FAKEVAR\x01fakevalue")
+        .assert()
+        .success();
+
+    // The default layout labels each line.
+    session
+        .command()
+        .args(["dump", "test-login"])
+        .assert()
+        .success()
+        .stdout("Label test-login\nKind login\nUsername fakeuser\nPassword fakepass\n");
+
+    session
+        .command()
+        .args(["dump", "--fields-only", "test-login"])
+        .assert()
+        .success()
+        .stdout("fakeuser\nfakepass\n");
+
+    session
+        .command()
+        .args(["dump", "--fields-only", "test-login", "test-env"])
+        .assert()
+        .success()
+        .stdout("fakeuser\nfakepass\nFAKEVAR\nfakevalue\n");
+
+    session
+        .command()
+        .args(["dump", "--fields-only", "--json", "test-login"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_dump_all() {
+    let session = CliSession::new();
+
+    for label in ["a", "b"] {
+        session
+            .command()
+            .args(["new", "-k", "unstructured", label])
+            .write_stdin(format!("contents-{label}"))
+            .assert()
+            .success();
+    }
+
+    session
+        .command()
+        .args(["dump", "--all", "--fields-only"])
+        .assert()
+        .success()
+        .stdout("contents-a\ncontents-b\n");
+
+    session
+        .command()
+        .args(["dump", "--all", "--created-since", "2999-01-01"])
+        .assert()
+        .success()
+        .stdout("");
+
+    // The time filters also apply to explicit labels.
+    session
+        .command()
+        .args(["dump", "--fields-only", "--modified-since", "1d", "a"])
+        .assert()
+        .success()
+        .stdout("contents-a\n");
+
+    // --all can't be combined with labels.
+    session
+        .command()
+        .args(["dump", "--all", "a"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_dump_iso_timestamps() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-login"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // Timestamps are numbers by default...
+    let numeric = session
+        .command()
+        .args(["dump", "--json", "test-login"])
+        .output()
+        .unwrap()
+        .json();
+    let timestamp = numeric["timestamp"].as_u64().unwrap();
+
+    // ...and RFC 3339 strings on request, with everything else unchanged.
+    let mut iso = session
+        .command()
+        .args(["dump", "--json", "--iso-timestamps", "test-login"])
+        .output()
+        .unwrap()
+        .json();
+    let formatted = iso["timestamp"].as_str().unwrap();
+    let parsed = humantime::parse_rfc3339(formatted).unwrap();
+    assert_eq!(
+        parsed
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        timestamp
+    );
+    assert!(iso["created"].is_string());
+
+    iso["timestamp"] = numeric["timestamp"].clone();
+    iso["created"] = numeric["created"].clone();
+    assert_eq!(iso, numeric);
+
+    // The flag only makes sense with JSON.
+    session
+        .command()
+        .args(["dump", "--iso-timestamps", "test-login"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_dump_last() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["dump", "--last"])
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "newer"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // NOTE: Timestamps have a resolution of a second.
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+
+    session
+        .command()
+        .args(["new", "-k", "login", "older"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", "--last"])
+        .output()
+        .unwrap();
+    assert_eq!(dump.json()["label"], "older");
+
+    // Modifying a record makes it the newest.
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    session
+        .command()
+        .args(["new", "--force", "-k", "login", "newer"])
+        .write_stdin("fakeuser\x01otherpass")
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", "--last"])
+        .output()
+        .unwrap();
+    assert_eq!(dump.json()["label"], "newer");
+
+    for args in [&["--last", "--all"][..], &["--last", "newer"]] {
+        session.command().arg("dump").args(args).assert().failure();
+    }
+}
+
+#[test]
+fn test_kbs2_dump_stdin_labels() {
+    let session = CliSession::new();
+
+    for label in ["foo", "bar"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fakeuser\x01fakepass")
+            .assert()
+            .success();
+    }
+
+    let output = session
+        .command()
+        .args(["dump", "-j", "--stdin-labels"])
+        .write_stdin("bar\nfoo\n\nbar\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let labels = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["label"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(labels, ["bar", "foo"]);
+
+    // A missing record fails the whole dump before anything is printed, encrypted or not.
+    for args in [
+        &["dump", "--stdin-labels"][..],
+        &["dump", "-e", "--stdin-labels"],
+    ] {
+        let output = session
+            .command()
+            .args(args)
+            .write_stdin("foo\nnonexistent\n")
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+    }
+}
@@ -0,0 +1,535 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+/// Removes the changes left behind by a failed edit, returning them.
+fn take_kept_changes(stderr: &str) -> String {
+    let (_, rest) = stderr.split_once("your changes were left in ").unwrap();
+    let (path, _) = rest.split_once(';').unwrap();
+
+    let changes = std::fs::read_to_string(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    changes
+}
+
+#[test]
+fn test_kbs2_edit_rename() {
+    let session = CliSession::new();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap().replace(
+        "\nmanifest = false\nverify-manifest = false",
+        "\nmanifest = true\nverify-manifest = true",
+    );
+    std::fs::write(&config_path, config).unwrap();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // By default, label changes made in the editor are discarded.
+    session
+        .command()
+        .env("EDITOR", "perl -pi -e s/test-record/renamed-record/")
+        .args(["edit", "test-record"])
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["dump", "test-record"])
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["dump", "renamed-record"])
+        .assert()
+        .failure();
+
+    // With `--allow-rename`, the record is renamed.
+    session
+        .command()
+        .env("EDITOR", "perl -pi -e s/test-record/renamed-record/")
+        .args(["edit", "--allow-rename", "test-record"])
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["dump", "test-record"])
+        .assert()
+        .failure();
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "renamed-record"])
+        .output()
+        .unwrap()
+        .json();
+
+    assert_eq!(dump.get("label").unwrap(), "renamed-record");
+
+    // The rename is a single change to the store, as far as the manifest is concerned.
+    session.command().arg("fsck").assert().success().stdout("");
+}
+
+#[test]
+fn test_kbs2_edit_preserves_unknown_fields() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // Simulate a record written by a newer kbs2, with fields we don't know about
+    // in both the envelope and the body.
+    session
+        .command()
+        .env(
+            "EDITOR",
+            r#"perl -pi -e 's/("label")/"url": "https:\/\/example.com", $1/; s/("username")/"tags": ["a", "b"], $1/'"#,
+        )
+        .args(["edit", "test-record"])
+        .assert()
+        .success();
+
+    // Round-trip the record through another edit, without changing anything.
+    session
+        .command()
+        .env("EDITOR", "true")
+        .args(["edit", "test-record"])
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap()
+        .json();
+
+    assert_eq!(dump["url"], "https://example.com");
+    assert_eq!(
+        dump["body"]["fields"]["tags"],
+        serde_json::json!(["a", "b"])
+    );
+    assert_eq!(dump["body"]["fields"]["username"], "fakeuser");
+    assert_eq!(dump["body"]["fields"]["password"], "fakepass");
+
+    // Listing the record's details warns about the unknown fields.
+    let output = session.command().args(["list", "-d"]).output().unwrap();
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Warn: test-record"));
+    assert!(stderr.contains("url, fields.tags"));
+}
+
+#[test]
+fn test_kbs2_edit_failures() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let output = session
+        .command()
+        .env("EDITOR", "kbs2-no-such-editor")
+        .args(["edit", "test-record"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("editor not found: kbs2-no-such-editor"));
+
+    // An editor that never started has no changes to leave behind, so the record's
+    // plaintext doesn't linger on disk.
+    assert!(!stderr.contains("your changes were left in"));
+
+    // An editor that exits unsuccessfully leaves its changes behind, and the record untouched.
+    let output = session
+        .command()
+        .env(
+            "EDITOR",
+            "perl -pi -e 's/fakepass/newpass/; END { exit 1 }'",
+        )
+        .args(["edit", "test-record"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("editor exited unsuccessfully"));
+    assert!(take_kept_changes(&stderr).contains("newpass"));
+
+    // The same goes for an editor that leaves behind something that isn't a record.
+    let output = session
+        .command()
+        .env("EDITOR", "perl -pi -e 's/\\{/garbage/'")
+        .args(["edit", "test-record"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("edited record is not a valid record"));
+    assert!(take_kept_changes(&stderr).contains("garbage"));
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["body"]["fields"]["password"], "fakepass");
+}
+
+#[test]
+fn test_kbs2_edit_to_kind() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "unstructured", "test-record"])
+        .write_stdin("fakepass")
+        .assert()
+        .success();
+
+    // Kind changes made in the editor are refused without --to-kind.
+    let output = session
+        .command()
+        .env(
+            "EDITOR",
+            r#"perl -pi -e 's/"Unstructured"/"Totp"/; s/"contents"/"secret"/'"#,
+        )
+        .args(["edit", "test-record"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("use --to-kind to convert it"));
+    take_kept_changes(&stderr);
+
+    session
+        .command()
+        .env("EDITOR", "true")
+        .args(["edit", "--to-kind", "unstructured", "test-record"])
+        .assert()
+        .failure();
+
+    // With --to-kind, the user completes the record's fields as the new kind.
+    session
+        .command()
+        .env(
+            "EDITOR",
+            r#"perl -pi -e 's/"username": ""/"username": "fakeuser"/'"#,
+        )
+        .args(["edit", "--to-kind", "login", "test-record"])
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["body"]["kind"], "Login");
+    assert_eq!(dump["body"]["fields"]["username"], "fakeuser");
+    assert_eq!(dump["body"]["fields"]["password"], "fakepass");
+
+    // Fields without a counterpart in the new kind have to be dealt with.
+    let output = session
+        .command()
+        .env("EDITOR", "true")
+        .args(["edit", "--to-kind", "unstructured", "test-record"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("field left over from the login record: username"));
+    take_kept_changes(&stderr);
+
+    session
+        .command()
+        .env("EDITOR", r#"perl -0pi -e 's/,\s*"username": "[^"]*"//'"#)
+        .args(["edit", "--to-kind", "unstructured", "test-record"])
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["body"]["kind"], "Unstructured");
+    assert_eq!(dump["body"]["fields"]["contents"], "fakepass");
+    assert!(dump["body"]["fields"].get("username").is_none());
+}
+
+#[test]
+fn test_kbs2_edit_custom_fields() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let mut record = session
+        .command()
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap()
+        .json();
+
+    record["custom_fields"] = serde_json::json!({"pin": "1234", "recovery email": "a@b.c"});
+    session
+        .command()
+        .args(["edit", "--stdin", "test-record"])
+        .write_stdin(record.to_string())
+        .assert()
+        .success();
+
+    // Custom fields are stored, and shown by dump.
+    let output = session
+        .command()
+        .args(["dump", "test-record"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Field pin: 1234\nField recovery email: a@b.c\n"));
+
+    // They survive a round trip through the editor, too.
+    session
+        .command()
+        .env("EDITOR", "perl -pi -e s/fakepass/newpass/")
+        .args(["edit", "test-record"])
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["body"]["fields"]["password"], "newpass");
+    assert_eq!(dump["custom_fields"], record["custom_fields"]);
+}
+
+#[test]
+fn test_kbs2_edit_stdin() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let mut record = session
+        .command()
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap()
+        .json();
+
+    // With --stdin, the edited record is read from stdin; no editor is needed.
+    record["label"] = "renamed-record".into();
+    record["body"]["fields"]["password"] = "newpass".into();
+    session
+        .command()
+        .env_remove("EDITOR")
+        .args(["edit", "--stdin", "test-record"])
+        .write_stdin(record.to_string())
+        .assert()
+        .success();
+
+    // The label change is discarded, just like in the editor.
+    let dump = session
+        .command()
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["body"]["fields"]["username"], "fakeuser");
+    assert_eq!(dump["body"]["fields"]["password"], "newpass");
+
+    // Invalid records and kind changes are refused, leaving the record unchanged.
+    session
+        .command()
+        .args(["edit", "--stdin", "test-record"])
+        .write_stdin("not a record")
+        .assert()
+        .failure();
+
+    let output = session
+        .command()
+        .args(["edit", "--stdin", "test-record"])
+        .write_stdin(
+            serde_json::json!({
+                "timestamp": 0,
+                "label": "test-record",
+                "body": {"kind": "Unstructured", "fields": {"contents": "oops"}},
+            })
+            .to_string(),
+        )
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("use --to-kind to convert it"));
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["body"]["fields"]["password"], "newpass");
+}
+
+#[test]
+fn test_kbs2_edit_gui_editor_waits() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // A stand-in for a GUI editor, which only edits the file when told to wait for it.
+    let bindir = tempfile::tempdir().unwrap();
+    let code = bindir.path().join("code");
+    std::fs::write(
+        &code,
+        "#!/bin/sh\n[ $# -eq 2 ] && [ \"$1\" = --wait ] || exit 1\nperl -pi -e s/fakepass/waitedpass/ \"$2\"\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&code, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    // The wait flag is added when it's missing, and left alone when it's already there.
+    for editor in [
+        code.display().to_string(),
+        format!("{} --wait", code.display()),
+    ] {
+        session
+            .command()
+            .env("EDITOR", &editor)
+            .args(["edit", "test-record"])
+            .assert()
+            .success();
+
+        let dump = session
+            .command()
+            .args(["dump", "--json", "test-record"])
+            .output()
+            .unwrap()
+            .json();
+        assert_eq!(dump["body"]["fields"]["password"], "waitedpass");
+
+        session
+            .command()
+            .env("EDITOR", "perl -pi -e s/waitedpass/fakepass/")
+            .args(["edit", "test-record"])
+            .assert()
+            .success();
+    }
+}
+
+#[test]
+fn test_kbs2_edit_set_icon() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "github"])
+        .write_stdin("jdoe\x01hunter2")
+        .assert()
+        .success();
+
+    // Records start out without an icon.
+    let dump = session
+        .command()
+        .args(["dump", "-j", "github"])
+        .output()
+        .unwrap();
+    assert!(dump.json().get("icon").is_none());
+
+    session
+        .command()
+        .args(["edit", "--set", "icon=🔑", "github"])
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", "github"])
+        .output()
+        .unwrap();
+    let dump = dump.json();
+    assert_eq!(dump["icon"], "🔑");
+    assert_eq!(dump["body"]["fields"]["password"], "hunter2");
+
+    let list = session.command().args(["list", "--json"]).output().unwrap();
+    assert_eq!(list.json()["icon"], "🔑");
+
+    session
+        .command()
+        .args(["dump", "--yes", "github"])
+        .assert()
+        .success()
+        .stdout("Label github\nKind login\nIcon 🔑\nUsername jdoe\nPassword hunter2\n");
+
+    // The launcher lists the icon as an extra column.
+    session
+        .command()
+        .arg("__launcher")
+        .write_stdin("")
+        .assert()
+        .success()
+        .stdout("github\tjdoe\tlogin\t🔑\n");
+
+    // Bad settings and icons are rejected, leaving the record alone.
+    for setting in ["icon", "color=red", "icon=a\tb"] {
+        session
+            .command()
+            .args(["edit", "--set", setting, "github"])
+            .assert()
+            .failure();
+    }
+
+    // An empty value clears the icon.
+    session
+        .command()
+        .args(["edit", "--set", "icon=", "github"])
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", "github"])
+        .output()
+        .unwrap();
+    assert!(dump.json().get("icon").is_none());
+}
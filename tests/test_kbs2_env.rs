@@ -0,0 +1,228 @@
+mod common;
+
+use common::{CliSession, ToJson};
+use serde_json::json;
+
+#[test]
+fn test_kbs2_env() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "environment", "api"])
+        .write_stdin("API_KEY\x01hunter2")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["env", "api"])
+        .assert()
+        .success()
+        .stdout("export API_KEY=hunter2\n");
+
+    session
+        .command()
+        .args(["env", "--prefix", "PROJ_", "api"])
+        .assert()
+        .success()
+        .stdout("export PROJ_API_KEY=hunter2\n");
+
+    session
+        .command()
+        .args(["env", "-n", "-p", "PROJ_", "api"])
+        .assert()
+        .success()
+        .stdout("PROJ_API_KEY=hunter2\n");
+
+    // A prefix is meaningless without the variable name.
+    session
+        .command()
+        .args(["env", "-v", "-p", "PROJ_", "api"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_env_json() {
+    let session = CliSession::new();
+
+    for (label, input) in [
+        ("multiline", "MULTI\x01first line\nsecond line"),
+        ("unicode", "UNICODE\x01h\u{e9}llo \u{2603}"),
+        ("shadowed", "MULTI\x01shadowed"),
+    ] {
+        session
+            .command()
+            .args(["new", "-k", "environment", label])
+            .write_stdin(input)
+            .assert()
+            .success();
+    }
+
+    session
+        .command()
+        .args(["new", "-k", "login", "not-env"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let output = session
+        .command()
+        .args(["env", "--json", "multiline", "unicode"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        output.json(),
+        json!({ "MULTI": "first line\nsecond line", "UNICODE": "h\u{e9}llo \u{2603}" })
+    );
+
+    // Later labels override earlier ones, with a warning.
+    let output = session
+        .command()
+        .args([
+            "env",
+            "--json",
+            "--with-meta",
+            "-p",
+            "X_",
+            "shadowed",
+            "multiline",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr.clone())
+        .unwrap()
+        .contains("X_MULTI is set by both shadowed and multiline; using multiline"));
+    assert_eq!(
+        output.json(),
+        json!({ "X_MULTI": { "value": "first line\nsecond line", "label": "multiline" } })
+    );
+
+    // --all skips non-environment records.
+    let output = session
+        .command()
+        .args(["env", "--json", "--all"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let variables = output.json();
+    assert_eq!(variables.as_object().unwrap().len(), 2);
+    assert_eq!(variables["UNICODE"], "h\u{e9}llo \u{2603}");
+
+    // --all also skips (and warns about) unreadable records.
+    std::fs::write(session.store_dir.path().join("broken"), "not a record").unwrap();
+    let output = session
+        .command()
+        .args(["env", "--json", "--all"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.json().as_object().unwrap().len(), 2);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("skipping broken"));
+
+    session
+        .command()
+        .args(["env", "--json", "broken"])
+        .assert()
+        .failure();
+
+    // ...but explicitly requested ones are still an error.
+    session
+        .command()
+        .args(["env", "--json", "unicode", "not-env"])
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["env", "--with-meta", "unicode"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_env_quoting() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let session = CliSession::new();
+
+    let hostile = [
+        ("spaces", "two words"),
+        ("dollar", "$HOME and ${HOME}"),
+        ("subshell", "$(touch pwned) `touch pwned`"),
+        ("quotes", "it's \"quoted\" ''"),
+        ("newline", "first line\nsecond line"),
+        ("unicode", "h\u{e9}llo \u{2603}"),
+        ("tilde", "~root:~"),
+        ("semicolon", "x; touch pwned"),
+        ("empty", ""),
+    ];
+
+    for (label, value) in hostile {
+        session
+            .command()
+            .args(["new", "-k", "environment", label, "--variable"])
+            .arg(format!("KBS2_TEST_{}", label.to_uppercase()))
+            .args(["--value", value])
+            .assert()
+            .success();
+    }
+
+    // Evaluating the assignments in a real shell recovers each value exactly.
+    for args in [&["env"][..], &["env", "--no-export"]] {
+        for (label, value) in hostile {
+            let output = session.command().args(args).arg(label).output().unwrap();
+            assert!(output.status.success());
+
+            let variable = format!("KBS2_TEST_{}", label.to_uppercase());
+            let workdir = tempfile::tempdir().unwrap();
+            let mut sh = Command::new("sh")
+                .arg("-c")
+                .arg(format!("eval \"$(cat)\" && printf %s \"${variable}\""))
+                .current_dir(workdir.path())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            sh.stdin.take().unwrap().write_all(&output.stdout).unwrap();
+            let evaluated = sh.wait_with_output().unwrap();
+
+            assert!(evaluated.status.success());
+            assert_eq!(String::from_utf8(evaluated.stdout).unwrap(), value);
+            assert!(!workdir.path().join("pwned").exists());
+        }
+    }
+
+    // Values are printed verbatim with --value-only.
+    session
+        .command()
+        .args(["env", "-v", "quotes"])
+        .assert()
+        .success()
+        .stdout("it's \"quoted\" ''\n");
+
+    // Variable names can't be quoted, so invalid ones are refused.
+    session
+        .command()
+        .args(["new", "-k", "environment", "bad-name"])
+        .args(["--variable", "FOO;touch pwned", "--value", "x"])
+        .assert()
+        .success();
+
+    let output = session
+        .command()
+        .args(["env", "bad-name"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("not a valid shell variable name"));
+}
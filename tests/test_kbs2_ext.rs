@@ -0,0 +1,193 @@
+mod common;
+
+use std::fs;
+
+use assert_cmd::Command;
+use common::{CliSession, ToJson};
+use serde_json::json;
+use tempfile::TempDir;
+
+#[test]
+fn test_kbs2_ext_config() {
+    let session = CliSession::new();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = fs::read_to_string(&config_path).unwrap();
+    fs::write(
+        &config_path,
+        format!("{config}\n[commands.ext.echoconfig]\nfoo = \"bar\"\nbaz = [1, 2]\n"),
+    )
+    .unwrap();
+
+    // A plugin that echoes its configuration back to us.
+    let bin_dir = TempDir::new().unwrap();
+    let plugin = bin_dir.path().join("kbs2-echoconfig");
+    fs::write(&plugin, "#!/bin/sh\nprintf '%s' \"$KBS2_EXT_CONFIG\"\n").unwrap();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&plugin, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let path = format!(
+        "{}:{}",
+        bin_dir.path().display(),
+        std::env::var("PATH").unwrap()
+    );
+
+    let output = session
+        .command()
+        .env("PATH", &path)
+        .arg("echoconfig")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.json(), json!({"foo": "bar", "baz": [1, 2]}));
+
+    // The same configuration is available on demand.
+    let output = session
+        .command()
+        .args(["config", "dump", "--ext", "echoconfig"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.json(), json!({"foo": "bar", "baz": [1, 2]}));
+
+    // Plugins without any configuration get an empty object.
+    fs::copy(&plugin, bin_dir.path().join("kbs2-unconfigured")).unwrap();
+    let output = session
+        .command()
+        .env("PATH", &path)
+        .arg("unconfigured")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.json(), json!({}));
+}
+
+#[test]
+fn test_kbs2_ext_args() {
+    let session = CliSession::new();
+
+    // A plugin that echoes its arguments back to us, one per line.
+    let bin_dir = TempDir::new().unwrap();
+    let plugin = bin_dir.path().join("kbs2-echoargs");
+    fs::write(&plugin, "#!/bin/sh\nprintf '%s\\n' \"$@\"\n").unwrap();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&plugin, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let path = format!(
+        "{}:{}",
+        bin_dir.path().display(),
+        std::env::var("PATH").unwrap()
+    );
+
+    // Everything after the command's name is the plugin's, even when it looks like
+    // one of kbs2's own flags.
+    for args in [
+        &["--help"][..],
+        &["-h"],
+        &["--version", "-V"],
+        &["-c", "foo", "--config-dir", "bar"],
+        &["--", "--help"],
+        &["a", "--", "b", ""],
+        &[],
+    ] {
+        let output = session
+            .command()
+            .env("PATH", &path)
+            .arg("echoargs")
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let expected = args.iter().map(|a| format!("{a}\n")).collect::<String>();
+        // NOTE: printf runs its format once even without arguments.
+        let expected = if args.is_empty() {
+            "\n".into()
+        } else {
+            expected
+        };
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    }
+
+    // Arguments that aren't UTF-8 are passed through untouched, too.
+    #[cfg(unix)]
+    {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let output = session
+            .command()
+            .env("PATH", &path)
+            .arg("echoargs")
+            .arg(OsStr::from_bytes(b"\xff--help"))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"\xff--help\n");
+    }
+}
+
+#[test]
+fn test_kbs2_ext_agent_env() {
+    // A plugin that echoes the agent's state back to us.
+    let bin_dir = TempDir::new().unwrap();
+    let plugin = bin_dir.path().join("kbs2-echoagent");
+    fs::write(
+        &plugin,
+        "#!/bin/sh\nprintf '%s\\n%s\\n' \"${KBS2_AGENT_SOCKET-unset}\" \"${KBS2_AGENT_KEY_LOADED-unset}\"\n",
+    )
+    .unwrap();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&plugin, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let path = format!(
+        "{}:{}",
+        bin_dir.path().display(),
+        std::env::var("PATH").unwrap()
+    );
+
+    let echoagent = |mut command: Command| {
+        let output = command
+            .env("PATH", &path)
+            .arg("echoagent")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let (socket, key_loaded) = stdout.trim_end().split_once('\n').unwrap();
+        (socket.to_string(), key_loaded.to_string())
+    };
+
+    // Without a running agent, neither variable is set.
+    let session = CliSession::new();
+    let no_agent = TempDir::new().unwrap();
+    let mut command = session.command();
+    command.env("KBS2_AGENT_SOCK", no_agent.path().join("agent.sock"));
+    assert_eq!(echoagent(command), ("unset".into(), "unset".into()));
+
+    // With one, both are, and track whether the key is loaded.
+    let session = CliSession::new_wrapped();
+    let socket = session.agent.as_ref().unwrap().socket();
+    let socket = socket.to_str().unwrap();
+    assert_eq!(echoagent(session.command()), (socket.into(), "0".into()));
+
+    session
+        .command()
+        .args(["agent", "unwrap"])
+        .assert()
+        .success();
+    assert_eq!(echoagent(session.command()), (socket.into(), "1".into()));
+}
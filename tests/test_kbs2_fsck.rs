@@ -0,0 +1,411 @@
+mod common;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_fsck() {
+    let session = CliSession::new();
+
+    for label in ["healthy", "unhealthy"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fakeuser\x01fakepass")
+            .assert()
+            .success();
+    }
+
+    session.command().arg("fsck").assert().success().stdout("");
+
+    // Blank out one record's password.
+    session
+        .command()
+        .env("EDITOR", r#"perl -pi -e 's/"fakepass"/""/'"#)
+        .args(["edit", "unhealthy"])
+        .assert()
+        .success();
+
+    let output = session.command().arg("fsck").output().unwrap();
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "unhealthy: empty field: password\n"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("1 of 2 records have problems"));
+
+    // Individual records can be checked.
+    session
+        .command()
+        .args(["fsck", "healthy"])
+        .assert()
+        .success();
+
+    // Repairs are interactive.
+    session
+        .command()
+        .args(["fsck", "--repair"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_fsck_oversized() {
+    let session = CliSession::new();
+
+    for (label, contents) in [("small", "x".into()), ("large", "x".repeat(2048))] {
+        session
+            .command()
+            .args(["new", "-k", "unstructured", label, "--contents", &contents])
+            .assert()
+            .success();
+    }
+
+    // Shrink the limit so that only the large record exceeds it.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config.replace("max-record-size = 1048576", "max-record-size = 2048"),
+    )
+    .unwrap();
+
+    let output = session.command().arg("fsck").output().unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("large: failed to read record large (max-record-size is 2048 bytes)")
+    );
+    assert!(!stdout.contains("small"));
+
+    // Records over the limit can't be written, either.
+    let output = session
+        .command()
+        .args(["new", "-k", "unstructured", "larger", "--contents"])
+        .arg("x".repeat(4096))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("record is too large: larger"));
+}
+
+#[test]
+fn test_kbs2_fsck_identify_foreign() {
+    let session = CliSession::new();
+    let other = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "native"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // A record from another store, encrypted to that store's key.
+    other
+        .command()
+        .args(["new", "-k", "login", "foreign"])
+        .write_stdin("otheruser\x01otherpass")
+        .assert()
+        .success();
+    std::fs::copy(
+        other.store_dir.path().join("foreign"),
+        session.store_dir.path().join("foreign"),
+    )
+    .unwrap();
+
+    let output = session
+        .command()
+        .args(["fsck", "--identify-foreign"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "foreign: encrypted to an X25519 key\n"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("1 of 2 records are encrypted to another key"));
+
+    // The same information is in the error for the record itself.
+    let output = session
+        .command()
+        .args(["dump", "foreign"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("it's encrypted to an X25519 key"));
+
+    // The record can be rescued with the other store's key.
+    session
+        .command()
+        .args(["reencrypt", "--with-identity"])
+        .arg(other.config_dir.path().join("key"))
+        .assert()
+        .success()
+        .stdout("foreign: re-encrypted\nSummary:\n  ok      1\n  skipped 0\n  failed  0\n");
+
+    session
+        .command()
+        .args(["fsck", "--identify-foreign"])
+        .assert()
+        .success()
+        .stdout("");
+
+    let output = session
+        .command()
+        .args(["pass", "foreign"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().trim(),
+        "otherpass"
+    );
+
+    // ...but only with a key that can actually decrypt it.
+    let report = session.config_dir.path().join("report.json");
+    let output = session
+        .command()
+        .args(["reencrypt", "--with-identity"])
+        .arg(other.config_dir.path().join("key"))
+        .args(["native", "--report"])
+        .arg(&report)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("1 of 1 records couldn't be re-encrypted"));
+
+    // The report names the record, but nothing in it.
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&report).unwrap()).unwrap();
+    assert_eq!(report["operation"], "reencrypt");
+    assert_eq!(report["records"][0]["label"], "native");
+    assert_eq!(report["records"][0]["outcome"], "failed");
+    assert!(!report.to_string().contains("fakepass"));
+}
+
+#[test]
+fn test_kbs2_fsck_label_normalization() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "GitHub"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap().replace(
+        "label-normalization = \"none\"",
+        "label-normalization = \"lower\"",
+    );
+    std::fs::write(&config_path, config).unwrap();
+
+    // Existing mixed-case records are still found case-insensitively...
+    session
+        .command()
+        .args(["pass", "github"])
+        .assert()
+        .success()
+        .stdout("fakepass");
+
+    // ...but fsck flags them, and --fix renames them.
+    let output = session.command().arg("fsck").output().unwrap();
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "GitHub: label isn't normalized (expected github)\n"
+    );
+
+    session
+        .command()
+        .args(["fsck", "--fix"])
+        .assert()
+        .success()
+        .stdout("GitHub: renamed to github\n");
+
+    // New records are normalized from the start.
+    session
+        .command()
+        .args(["new", "-k", "login", "GitLab"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    session.command().arg("fsck").assert().success().stdout("");
+    let output = session.command().arg("list").output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut labels: Vec<_> = stdout.lines().collect();
+    labels.sort();
+    assert_eq!(labels, ["github", "gitlab"]);
+}
+
+#[test]
+fn test_kbs2_fsck_overlapping_store() {
+    let session = CliSession::new();
+
+    // Point the store at the config directory, as `kbs2 init` would have allowed
+    // before it refused overlapping directories.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap().replace(
+        session.store_dir.path().to_str().unwrap(),
+        session.config_dir.path().to_str().unwrap(),
+    );
+    std::fs::write(&config_path, config).unwrap();
+
+    let output = session.command().arg("fsck").output().unwrap();
+    assert!(!output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("store: the store is the config directory; move the records"));
+    assert_eq!(stdout.lines().count(), 1);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("the store's layout has problems"));
+}
+
+#[test]
+fn test_kbs2_fsck_manifest() {
+    let session = CliSession::new();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap().replace(
+        "\nmanifest = false\nverify-manifest = false",
+        "\nmanifest = true\nverify-manifest = true",
+    );
+    std::fs::write(&config_path, config).unwrap();
+
+    for label in ["a", "b", "c"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fakeuser\x01fakepass")
+            .assert()
+            .success();
+    }
+    session.command().args(["rm", "c"]).assert().success();
+
+    // The manifest lives in the store, but isn't a record.
+    assert!(session.store_dir.path().join(".manifest.age").is_file());
+    let output = session.command().arg("list").output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut labels: Vec<_> = stdout.lines().collect();
+    labels.sort();
+    assert_eq!(labels, ["a", "b"]);
+
+    // A clean check says what the manifest can't vouch for.
+    let output = session.command().arg("fsck").output().unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("the manifest isn't authenticated"));
+
+    // Records deleted, added, or changed behind kbs2's back are noticed.
+    let store = session.store_dir.path();
+    std::fs::remove_file(store.join("a")).unwrap();
+    std::fs::copy(store.join("b"), store.join("d")).unwrap();
+
+    let output = session.command().arg("fsck").output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("a: deleted outside of kbs2\nd: added outside of kbs2\n"));
+
+    // With verify-manifest, every command warns about it.
+    let output = session.command().arg("list").output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("the store doesn't match its manifest (2 discrepancies)"));
+
+    // Changes can be accepted, once they're known to be legitimate.
+    std::fs::remove_file(store.join("d")).unwrap();
+    session
+        .command()
+        .args(["fsck", "--accept-manifest"])
+        .assert()
+        .success()
+        .stdout("store: manifest updated (1 records)\n");
+    session.command().arg("fsck").assert().success().stdout("");
+
+    // A deleted manifest is noticed too.
+    std::fs::remove_file(store.join(".manifest.age")).unwrap();
+    let output = session.command().arg("fsck").output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("the manifest is missing"));
+}
+
+#[test]
+fn test_kbs2_fsck_non_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "healthy"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // A stray file with a non-UTF-8 name (e.g. an rsync temporary), and a record whose
+    // contents aren't UTF-8 (and so can't be ASCII armor).
+    let store = session.store_dir.path();
+    std::fs::write(store.join(OsStr::from_bytes(b"stray\xff")), "junk").unwrap();
+    std::fs::write(store.join("garbled"), b"\xff\xfe\xfd").unwrap();
+
+    // Listing skips the stray file (with a warning), rather than failing outright.
+    let output = session.command().arg("list").output().unwrap();
+    assert!(output.status.success());
+    let mut labels = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    labels.sort();
+    assert_eq!(labels, ["garbled", "healthy"]);
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("skipping 1 file(s) in the store with non-UTF-8 names"));
+
+    let output = session
+        .command()
+        .args(["dump", "garbled"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("record is not valid UTF-8 armor: garbled"));
+
+    // fsck reports both, with the stray file's name escaped.
+    let output = session.command().arg("fsck").output().unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains(r#"store: "stray\xFF": file name isn't valid UTF-8; rename or remove it"#),
+        "{stdout}"
+    );
+    assert!(stdout.contains("garbled: record is not valid UTF-8 armor: garbled ("));
+
+    // Once the record is removed, only the stray file is left to complain about.
+    std::fs::remove_file(store.join("garbled")).unwrap();
+    let output = session.command().arg("fsck").output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("the store contains 1 file(s) with non-UTF-8 names"));
+}
@@ -0,0 +1,354 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_generate() {
+    let session = CliSession::new();
+
+    let output = session.command().arg("generate").output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim().len(), 16);
+}
+
+#[test]
+fn test_kbs2_generate_update() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // `--dry-run` (or `-n`) changes nothing.
+    for dry_run in ["--dry-run", "-n"] {
+        session
+            .command()
+            .args(["generate", "--update", "test-record", dry_run])
+            .assert()
+            .success()
+            .stdout("would update the password field of 'test-record'\n");
+
+        let dump = session
+            .command()
+            .args(["dump", "-j", "test-record"])
+            .output()
+            .unwrap()
+            .json();
+        assert_eq!(dump["body"]["fields"]["password"], "fakepass");
+    }
+
+    // By default, the sensitive field is updated and nothing is printed.
+    {
+        session
+            .command()
+            .args(["generate", "--update", "test-record"])
+            .assert()
+            .success()
+            .stdout("");
+
+        let dump = session
+            .command()
+            .args(["dump", "-j", "test-record"])
+            .output()
+            .unwrap()
+            .json();
+        assert_eq!(dump["body"]["fields"]["username"], "fakeuser");
+        assert_ne!(dump["body"]["fields"]["password"], "fakepass");
+    }
+
+    // `--field` and `--print` select the field and print the new secret.
+    {
+        let output = session
+            .command()
+            .args(["generate", "-u", "test-record", "-f", "username", "-p"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let secret = String::from_utf8(output.stdout).unwrap();
+        let dump = session
+            .command()
+            .args(["dump", "-j", "test-record"])
+            .output()
+            .unwrap()
+            .json();
+        assert_eq!(dump["body"]["fields"]["username"], secret.trim_end());
+    }
+
+    // Fields that the record doesn't have are an error.
+    {
+        session
+            .command()
+            .args(["generate", "-u", "test-record", "-f", "contents"])
+            .assert()
+            .failure();
+    }
+
+    // Update-only flags require `--update`.
+    {
+        session
+            .command()
+            .args(["generate", "--print"])
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn test_kbs2_generate_low_entropy() {
+    let session = CliSession::new();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let mut config = std::fs::read_to_string(&config_path).unwrap();
+    config.push_str("\n[[generators]]\nname = \"pin\"\nalphabets = [\"0123456789\"]\nlength = 4\n");
+    std::fs::write(&config_path, &config).unwrap();
+
+    // The default generator is strong enough to generate quietly.
+    let output = session.command().arg("generate").output().unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    // A weak generator still works, but warns.
+    for (args, stdin) in [
+        (&["generate", "pin"][..], ""),
+        (&["new", "-G", "pin", "test-pin"][..], "fakeuser\x01"),
+    ] {
+        let output = session
+            .command()
+            .args(args)
+            .write_stdin(stdin)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("generator 'pin' produces secrets with only ~13 bits of entropy"));
+    }
+
+    // The warning can be turned off entirely.
+    std::fs::write(
+        &config_path,
+        config.replace("min-generator-entropy = 64", "min-generator-entropy = 0"),
+    )
+    .unwrap();
+    let output = session
+        .command()
+        .args(["generate", "pin"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn test_kbs2_generate_seeded() {
+    let session = CliSession::new();
+
+    // A seed makes generation reproducible, for every command that generates.
+    let output = session
+        .command()
+        .args(["generate"])
+        .env("KBS2_GENERATOR_SEED", "42")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().trim(),
+        "TO7FtHJImjPd}[uq"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("generated secrets are NOT random"));
+
+    for (args, stdin) in [
+        (&["new", "empty-password"][..], "user\x01"),
+        (&["new", "--username", "u", "--generate", "flag"][..], ""),
+    ] {
+        let output = session
+            .command()
+            .args(args)
+            .env("KBS2_GENERATOR_SEED", "42")
+            .write_stdin(stdin)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let label = args.last().unwrap();
+        let output = session.command().args(["pass", label]).output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap().trim(),
+            "TO7FtHJImjPd}[uq"
+        );
+    }
+
+    // Different seeds produce different secrets.
+    let output = session
+        .command()
+        .args(["generate"])
+        .env("KBS2_GENERATOR_SEED", "7")
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().trim(),
+        "403lL)9h8=DYZoQJ"
+    );
+
+    // The seed must be an integer.
+    let output = session
+        .command()
+        .args(["generate"])
+        .env("KBS2_GENERATOR_SEED", "nope")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("KBS2_GENERATOR_SEED must be an integer"));
+
+    // Seeded generation can be forbidden outright.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap().replace(
+        "forbid-seeded-generation = false",
+        "forbid-seeded-generation = true",
+    );
+    std::fs::write(&config_path, config).unwrap();
+
+    let output = session
+        .command()
+        .args(["generate"])
+        .env("KBS2_GENERATOR_SEED", "42")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("forbid-seeded-generation is enabled"));
+}
+
+#[test]
+fn test_kbs2_generate_spec() {
+    let session = CliSession::new();
+
+    let output = session
+        .command()
+        .args(["generate", "len=24"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().trim_end().len(),
+        24
+    );
+
+    // Specs work wherever generators do.
+    session
+        .command()
+        .args(["new", "-k", "login", "-G", "len=20", "spec"])
+        .write_stdin("fakeuser\x01")
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", "spec"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(
+        dump["body"]["fields"]["password"].as_str().unwrap().len(),
+        20
+    );
+    assert_eq!(dump["generated_by"], "len=20");
+
+    // A generator that's named like a spec wins.
+    let config_path = session.config_dir.path().join("config.toml");
+    let mut config = std::fs::read_to_string(&config_path).unwrap();
+    config
+        .push_str("\n[[generators]]\nname = \"len=24\"\nalphabets = [\"x\", \"x\"]\nlength = 3\n");
+    std::fs::write(&config_path, &config).unwrap();
+
+    session
+        .command()
+        .args(["generate", "len=24"])
+        .assert()
+        .success()
+        .stdout("xxx\n");
+
+    let output = session
+        .command()
+        .args(["generate", "len=many"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("no generator named len=many, and it isn't a valid spec: invalid length"));
+}
+
+#[test]
+fn test_kbs2_generate_update_generated_by() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let generated_by = || {
+        session
+            .command()
+            .args(["dump", "--json", "test-record"])
+            .output()
+            .unwrap()
+            .json()["generated_by"]
+            .clone()
+    };
+    assert!(generated_by().is_null());
+
+    // Generating the password records the generator...
+    session
+        .command()
+        .args(["generate", "len=20", "--update", "test-record"])
+        .assert()
+        .success();
+    assert_eq!(generated_by(), "len=20");
+
+    // ...while generating some other field doesn't.
+    session
+        .command()
+        .args(["generate", "--update", "test-record", "--field", "username"])
+        .assert()
+        .success();
+    assert_eq!(generated_by(), "len=20");
+
+    // Edits that leave the password alone keep the generator...
+    let mut record = session
+        .command()
+        .args(["dump", "--json", "test-record"])
+        .output()
+        .unwrap()
+        .json();
+    record["body"]["fields"]["username"] = "jdoe".into();
+    session
+        .command()
+        .args(["edit", "--stdin", "test-record"])
+        .write_stdin(record.to_string())
+        .assert()
+        .success();
+    assert_eq!(generated_by(), "len=20");
+
+    // ...but a password that's changed by hand wasn't generated.
+    record["body"]["fields"]["password"] = "hunter2".into();
+    session
+        .command()
+        .args(["edit", "--stdin", "test-record"])
+        .write_stdin(record.to_string())
+        .assert()
+        .success();
+    assert!(generated_by().is_null());
+}
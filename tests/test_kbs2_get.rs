@@ -0,0 +1,97 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_get() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "github"])
+        .write_stdin("jdoe\x01hunter2")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["new", "-k", "environment", "api-key"])
+        .write_stdin("API_KEY\x01secret")
+        .assert()
+        .success();
+
+    let output = session
+        .command()
+        .args(["get", "--json", "github"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        output.json(),
+        serde_json::json!({
+            "label": "github",
+            "kind": "login",
+            "username": "jdoe",
+            "password": "hunter2",
+        })
+    );
+
+    let output = session
+        .command()
+        .args(["get", "-j", "api-key"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.json(),
+        serde_json::json!({
+            "label": "api-key",
+            "kind": "environment",
+            "variable": "API_KEY",
+            "value": "secret",
+        })
+    );
+
+    // Fields can be selected, and are printed one per line without --json.
+    session
+        .command()
+        .args(["get", "github"])
+        .assert()
+        .success()
+        .stdout("jdoe\nhunter2\n");
+
+    session
+        .command()
+        .args(["get", "-f", "password", "github"])
+        .assert()
+        .success()
+        .stdout("hunter2\n");
+
+    let output = session
+        .command()
+        .args(["get", "-j", "-f", "username", "github"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.json(),
+        serde_json::json!({"label": "github", "kind": "login", "username": "jdoe"})
+    );
+
+    // Fields that the record's kind doesn't have fail, without printing anything.
+    let output = session
+        .command()
+        .args(["get", "-f", "password", "-f", "value", "github"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("login records have no field named value (expected one of: username, password)"));
+
+    session
+        .command()
+        .args(["get", "nonexistent"])
+        .assert()
+        .failure()
+        .stdout("");
+}
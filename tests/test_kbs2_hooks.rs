@@ -0,0 +1,200 @@
+mod common;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_hooks() {
+    let session = CliSession::new();
+
+    // Top-level keys have to come before any tables, so prepend the hooks.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        format!(
+            "post-hook = \"echo\"\nerror-hook = \"this-command-does-not-exist\"\n{}",
+            config.replace("[sync]\n", "[sync]\npull = \"true\"\npush = \"false\"\n")
+        ),
+    )
+    .unwrap();
+
+    // `kbs2 hooks list` shows only configured hooks (including the sync commands), and
+    // whether they resolve.
+    {
+        let output = session.command().args(["hooks", "list"]).output().unwrap();
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<_> = stdout.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[1].starts_with("post-hook") && lines[1].ends_with("/echo"));
+        assert!(lines[2].starts_with("error-hook") && lines[2].ends_with("(not found)"));
+        assert!(lines[3].starts_with("sync.pull") && lines[3].ends_with("/true"));
+        assert!(lines[4].starts_with("sync.push") && lines[4].ends_with("/false"));
+    }
+
+    // The sync commands can be run like any other hook.
+    {
+        session
+            .command()
+            .args(["hooks", "run", "sync.pull"])
+            .assert()
+            .success();
+
+        session
+            .command()
+            .args(["hooks", "run", "sync.push"])
+            .assert()
+            .failure();
+    }
+
+    // `kbs2 hooks run` runs the hook with dummy arguments and shows its output.
+    {
+        let output = session
+            .command()
+            .args(["hooks", "run", "error-hook"])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+
+        let output = session
+            .command()
+            .args(["hooks", "run", "post-hook"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("Exit status: 0"));
+    }
+
+    // Unknown and unconfigured hooks are errors.
+    {
+        session
+            .command()
+            .args(["hooks", "run", "not-a-hook"])
+            .assert()
+            .failure();
+
+        session
+            .command()
+            .args(["hooks", "run", "pass.clear-hook"])
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn test_kbs2_hooks_list_hook_cwd() {
+    let session = CliSession::new();
+
+    // Relative hooks are resolved in the directory that they're run in.
+    std::fs::write(session.config_dir.path().join("hook.sh"), "").unwrap();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path)
+        .unwrap()
+        .replace("hook-cwd = \"store\"", "hook-cwd = \"config\"");
+    std::fs::write(&config_path, format!("post-hook = \"./hook.sh\"\n{config}")).unwrap();
+
+    let output = session.command().args(["hooks", "list"]).output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].starts_with("post-hook"));
+    assert!(lines[1].ends_with(
+        &session
+            .config_dir
+            .path()
+            .join("./hook.sh")
+            .display()
+            .to_string()
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_kbs2_hooks_environment() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let session = CliSession::new();
+
+    // A hook that reports its working directory and the store it was given.
+    let hook = session.config_dir.path().join("hook.sh");
+    std::fs::write(&hook, "#!/bin/sh\npwd\necho \"store=$KBS2_STORE\"\n").unwrap();
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    let run_hook = |hook_cwd: &str| {
+        let config = config.replace(
+            "hook-cwd = \"store\"",
+            &format!("hook-cwd = \"{hook_cwd}\""),
+        );
+        std::fs::write(
+            &config_path,
+            format!("post-hook = \"{}\"\n{config}", hook.display()),
+        )
+        .unwrap();
+
+        let output = session
+            .command()
+            .current_dir(std::env::temp_dir())
+            .args(["hooks", "run", "post-hook"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let store = session.store_dir.path().canonicalize().unwrap();
+    let config_dir = session.config_dir.path().canonicalize().unwrap();
+    let temp_dir = std::env::temp_dir().canonicalize().unwrap();
+
+    // By default, hooks run in the store.
+    assert!(config.contains("hook-cwd = \"store\""));
+    let stdout = run_hook("store");
+    assert!(stdout.contains(&format!("{}\n", store.display())));
+    assert!(stdout.contains(&format!("store={}", session.store_dir.path().display())));
+
+    let stdout = run_hook("config");
+    assert!(stdout.contains(&format!("{}\n", config_dir.display())));
+
+    let stdout = run_hook("inherit");
+    assert!(stdout.contains(&format!("{}\n", temp_dir.display())));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_kbs2_hooks_run_veto() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let session = CliSession::new();
+
+    // A pre-hook that vetoes every record, by label.
+    let hook = session.config_dir.path().join("veto.sh");
+    std::fs::write(&hook, "#!/bin/sh\necho \"veto $KBS2_LABEL\"\nexit 100\n").unwrap();
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap().replace(
+        "[commands.new]\n",
+        &format!("[commands.new]\npre-hook = \"{}\"\n", hook.display()),
+    );
+    std::fs::write(&config_path, config).unwrap();
+
+    // `kbs2 hooks run` gives it a label, and reports the veto as a veto.
+    let output = session
+        .command()
+        .args(["hooks", "run", "new.pre-hook"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Running: KBS2_LABEL=label "));
+    assert!(stdout.contains("Exit status: 100 (vetoed)"));
+    assert!(stdout.contains("veto label\n"));
+}
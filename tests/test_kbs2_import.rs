@@ -0,0 +1,154 @@
+mod common;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_import_raw() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let armored = session
+        .command()
+        .args(["dump", "--encrypted", "test-record"])
+        .output()
+        .unwrap()
+        .stdout;
+
+    assert!(armored.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----"));
+
+    // The record already exists, so `import raw` refuses to overwrite it.
+    session
+        .command()
+        .args(["import", "raw", "test-record"])
+        .write_stdin(armored.clone())
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["rm", "test-record"])
+        .assert()
+        .success();
+
+    // The embedded label doesn't match.
+    session
+        .command()
+        .args(["import", "raw", "other-record"])
+        .write_stdin(armored.clone())
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["import", "raw", "test-record"])
+        .write_stdin(armored.clone())
+        .assert()
+        .success();
+
+    let reimported = session
+        .command()
+        .args(["dump", "--encrypted", "test-record"])
+        .output()
+        .unwrap()
+        .stdout;
+
+    assert_eq!(armored, reimported);
+}
+
+#[test]
+fn test_kbs2_import_raw_on_conflict() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let armored = session
+        .command()
+        .args(["dump", "--encrypted", "test-record"])
+        .output()
+        .unwrap()
+        .stdout;
+
+    session
+        .command()
+        .args(["import", "raw", "--on-conflict", "skip", "test-record"])
+        .write_stdin(armored.clone())
+        .assert()
+        .success()
+        .stdout("0 added, 0 overwritten, 0 renamed, 1 skipped\n");
+
+    session
+        .command()
+        .args(["import", "raw", "--on-conflict", "rename", "test-record"])
+        .write_stdin(armored.clone())
+        .assert()
+        .success()
+        .stdout("0 added, 0 overwritten, 1 renamed, 0 skipped\n");
+
+    // The outcome can also be written out as a report.
+    let report = session.config_dir.path().join("report.json");
+    session
+        .command()
+        .args([
+            "import",
+            "raw",
+            "--on-conflict",
+            "skip",
+            "test-record",
+            "--report",
+        ])
+        .arg(&report)
+        .write_stdin(armored.clone())
+        .assert()
+        .success();
+
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&report).unwrap()).unwrap();
+    assert_eq!(report["operation"], "import");
+    assert_eq!(
+        report["records"],
+        serde_json::json!([{"label": "test-record", "outcome": "skipped", "reason": "already present"}])
+    );
+
+    session
+        .command()
+        .args(["dump", "test-record-1"])
+        .assert()
+        .success();
+
+    // The incoming record is no newer than the stored one, so it's skipped.
+    for strategy in ["newer", "newest"] {
+        session
+            .command()
+            .args(["import", "raw", "--on-conflict", strategy, "test-record"])
+            .write_stdin(armored.clone())
+            .assert()
+            .success()
+            .stdout("0 added, 0 overwritten, 0 renamed, 1 skipped\n");
+    }
+
+    // `--force` and `--on-conflict` are mutually exclusive.
+    session
+        .command()
+        .args([
+            "import",
+            "raw",
+            "-f",
+            "--on-conflict",
+            "skip",
+            "test-record",
+        ])
+        .write_stdin(armored)
+        .assert()
+        .failure();
+}
@@ -1,6 +1,9 @@
 mod common;
 
-use common::CliSession;
+use std::os::unix::fs::PermissionsExt;
+
+use common::{kbs2, CliSession, ToJson};
+use tempfile::TempDir;
 
 #[test]
 fn test_kbs2_init() {
@@ -14,4 +17,336 @@ fn test_kbs2_init() {
     assert!(store_dir.is_dir());
     assert!(config_dir.join("config.toml").is_file());
     assert!(store_dir.read_dir().unwrap().next().is_none());
+
+    // The (unwrapped) key is only readable by its owner, regardless of the umask.
+    let mode = std::fs::metadata(config_dir.join("key"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn test_kbs2_init_if_not_exists() {
+    let session = CliSession::new();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let key_path = session.config_dir.path().join("key");
+    let (config, key) = (
+        std::fs::read(&config_path).unwrap(),
+        std::fs::read(&key_path).unwrap(),
+    );
+
+    // Without --force, an existing config is an error...
+    session
+        .command()
+        .args(["init", "--insecure-not-wrapped"])
+        .assert()
+        .failure();
+
+    // ...but not with --if-not-exists, which leaves everything alone.
+    session
+        .command()
+        .args(["init", "--insecure-not-wrapped", "--if-not-exists"])
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(std::fs::read(&config_path).unwrap(), config);
+    assert_eq!(std::fs::read(&key_path).unwrap(), key);
+
+    session
+        .command()
+        .args(["init", "--if-not-exists", "--force"])
+        .assert()
+        .failure();
+
+    // Without a config, it's just `init`.
+    let home = TempDir::new().unwrap();
+    kbs2()
+        .env("KBS2_CONFIG_DIR", home.path().join("config"))
+        .args([
+            "init",
+            "--insecure-not-wrapped",
+            "--if-not-exists",
+            "--store-dir",
+        ])
+        .arg(home.path().join("store"))
+        .assert()
+        .success();
+    assert!(home.path().join("config/config.toml").is_file());
+}
+
+#[test]
+fn test_kbs2_init_expands_paths() {
+    let home = TempDir::new().unwrap();
+
+    // `~` in `KBS2_CONFIG_DIR` and `--store-dir` is expanded, rather than
+    // being taken literally.
+    kbs2()
+        .env("HOME", home.path())
+        .env("KBS2_CONFIG_DIR", "~/kbs2-config")
+        .current_dir(home.path())
+        .args([
+            "init",
+            "--insecure-not-wrapped",
+            "--store-dir",
+            "~/kbs2-store",
+        ])
+        .assert()
+        .success();
+
+    assert!(home.path().join("kbs2-config/config.toml").is_file());
+    assert!(!home.path().join("~").exists());
+
+    let config = kbs2()
+        .env("HOME", home.path())
+        .env("KBS2_CONFIG_DIR", "~/kbs2-config")
+        .args(["config", "dump"])
+        .output()
+        .unwrap()
+        .json();
+
+    assert_eq!(
+        config["store"].as_str().unwrap(),
+        home.path().join("kbs2-store").to_str().unwrap()
+    );
+
+    // Relative paths are made absolute against the current directory.
+    kbs2()
+        .env("HOME", home.path())
+        .current_dir(home.path())
+        .args(["-c", "relative-config", "init", "--insecure-not-wrapped"])
+        .args(["--store-dir", "relative-store"])
+        .assert()
+        .success();
+
+    let config = kbs2()
+        .env("HOME", home.path())
+        .args(["-c"])
+        .arg(home.path().join("relative-config"))
+        .args(["config", "dump"])
+        .output()
+        .unwrap()
+        .json();
+
+    assert_eq!(
+        config["store"].as_str().unwrap(),
+        home.path().join("relative-store").to_str().unwrap()
+    );
+}
+
+#[test]
+fn test_kbs2_init_quiet() {
+    let home = TempDir::new().unwrap();
+    let store_dir = home.path().join("store");
+    std::fs::create_dir(&store_dir).unwrap();
+
+    // Initializing with an existing store directory produces a warning...
+    let output = kbs2()
+        .args(["-c"])
+        .arg(home.path().join("config"))
+        .args(["init", "--insecure-not-wrapped", "--store-dir"])
+        .arg(&store_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Warn: "));
+
+    // ...unless warnings are suppressed, either with --quiet...
+    kbs2()
+        .args(["-c"])
+        .arg(home.path().join("config"))
+        .args([
+            "--quiet",
+            "init",
+            "-f",
+            "--insecure-not-wrapped",
+            "--store-dir",
+        ])
+        .arg(&store_dir)
+        .assert()
+        .success()
+        .stderr("");
+
+    // ...or with KBS2_QUIET.
+    kbs2()
+        .env("KBS2_QUIET", "1")
+        .args(["-c"])
+        .arg(home.path().join("config"))
+        .args(["init", "-f", "--insecure-not-wrapped", "--store-dir"])
+        .arg(&store_dir)
+        .assert()
+        .success()
+        .stderr("");
+}
+
+#[test]
+fn test_kbs2_init_identity() {
+    let session = CliSession::new();
+    let keyfile = session.config_dir.path().join("key");
+
+    session
+        .command()
+        .args(["new", "-k", "login", "pets.com"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let public_key = session
+        .command()
+        .args(["config", "dump"])
+        .output()
+        .unwrap()
+        .json()["public-key"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let config_dir = TempDir::new().unwrap();
+    let init = || {
+        let mut kbs2 = kbs2();
+        kbs2.arg("-c")
+            .arg(config_dir.path())
+            .args(["init", "-f", "--store-dir"])
+            .arg(session.store_dir.path())
+            .arg("--identity")
+            .arg(&keyfile);
+        kbs2
+    };
+
+    // An unwrapped identity can only be used with --insecure-not-wrapped.
+    init().assert().failure();
+
+    // A mismatched public key is rejected.
+    init()
+        .args(["--insecure-not-wrapped", "--public-key"])
+        .arg("age1qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqs3290gq")
+        .assert()
+        .failure();
+
+    init()
+        .args(["--insecure-not-wrapped", "--public-key", &public_key])
+        .assert()
+        .success();
+
+    // The new config uses the existing identity in place, and can read its records.
+    let config = kbs2()
+        .arg("-c")
+        .arg(config_dir.path())
+        .args(["config", "dump"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(config["keyfile"], keyfile.to_str().unwrap());
+    assert_eq!(config["public-key"], public_key.as_str());
+    assert!(!config_dir.path().join("key").exists());
+
+    kbs2()
+        .arg("-c")
+        .arg(config_dir.path())
+        .args(["pass", "pets.com"])
+        .assert()
+        .success()
+        .stdout("fakepass");
+
+    // Identities that don't parse are rejected.
+    let bogus = config_dir.path().join("bogus");
+    std::fs::write(&bogus, "not an identity").unwrap();
+    kbs2()
+        .arg("-c")
+        .arg(config_dir.path())
+        .args(["init", "-f", "--insecure-not-wrapped", "--identity"])
+        .arg(&bogus)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_init_overlapping_store() {
+    let home = TempDir::new().unwrap();
+    let config_dir = home.path().join("kbs2");
+
+    // The store can't be the config directory, or nested within it in either direction...
+    for store_dir in [
+        config_dir.clone(),
+        config_dir.join("store"),
+        home.path().to_path_buf(),
+    ] {
+        let output = kbs2()
+            .arg("-c")
+            .arg(&config_dir)
+            .args(["init", "--insecure-not-wrapped", "--store-dir"])
+            .arg(&store_dir)
+            .output()
+            .unwrap();
+
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("--allow-overlapping-store"));
+        assert!(!config_dir.join("config.toml").exists());
+    }
+
+    // ...unless explicitly allowed.
+    kbs2()
+        .arg("-c")
+        .arg(&config_dir)
+        .args([
+            "init",
+            "--insecure-not-wrapped",
+            "--allow-overlapping-store",
+        ])
+        .arg("--store-dir")
+        .arg(&config_dir)
+        .assert()
+        .success();
+
+    // Even then, the config and keyfile (and their backups) are never treated as records.
+    let keyfile = std::fs::read(config_dir.join("key")).unwrap();
+    for label in ["key", "key.old", "config.toml"] {
+        kbs2()
+            .arg("-c")
+            .arg(&config_dir)
+            .args(["new", "-k", "unstructured", label])
+            .write_stdin("not a keyfile")
+            .assert()
+            .failure();
+    }
+    kbs2()
+        .arg("-c")
+        .arg(&config_dir)
+        .args(["rm", "key"])
+        .assert()
+        .failure();
+    assert_eq!(std::fs::read(config_dir.join("key")).unwrap(), keyfile);
+    assert!(!config_dir.join("key.old").exists());
+
+    kbs2()
+        .arg("-c")
+        .arg(&config_dir)
+        .arg("list")
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[test]
+fn test_kbs2_reserved_labels() {
+    let session = CliSession::new();
+
+    // Files named like kbs2's own are never records, even in a separate store...
+    for name in ["config.toml", "key", "backup.old"] {
+        std::fs::write(session.store_dir.path().join(name), "not a record").unwrap();
+    }
+    session.command().arg("list").assert().success().stdout("");
+
+    // ...so records can't be created with those names either.
+    for label in ["config.toml", "key", "foo.old"] {
+        session
+            .command()
+            .args(["new", "-k", "unstructured", label])
+            .write_stdin("contents")
+            .assert()
+            .failure();
+    }
 }
@@ -0,0 +1,88 @@
+mod common;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_launcher() {
+    let session = CliSession::new();
+
+    for (kind, label, input) in [
+        ("login", "github", "jdoe\x01hunter2"),
+        ("login", "email", "jdoe@example.com\x01hunter3"),
+        ("environment", "api-key", "API_KEY\x01hunter4"),
+    ] {
+        session
+            .command()
+            .args(["new", "-k", kind, label])
+            .write_stdin(input)
+            .assert()
+            .success();
+    }
+
+    // Only logins are listed, and nothing is copied without a selection.
+    let output = session
+        .command()
+        .arg("__launcher")
+        .write_stdin("")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let mut lines = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    lines.sort();
+    assert_eq!(
+        lines,
+        ["email\tjdoe@example.com\tlogin", "github\tjdoe\tlogin"]
+    );
+
+    // A menu that's dismissed (or picks nothing) is fine.
+    session
+        .command()
+        .args(["__launcher", "--menu-cmd", "false"])
+        .assert()
+        .success()
+        .stdout("");
+
+    session
+        .command()
+        .args(["__launcher", "--menu-cmd", "grep -F nothing-matches-this"])
+        .assert()
+        .success();
+
+    // Picking something that isn't a login is an error.
+    session
+        .command()
+        .args(["__launcher", "--menu-cmd", "echo api-key"])
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .arg("__launcher")
+        .write_stdin("nonexistent\tjdoe\tlogin\n")
+        .assert()
+        .failure();
+
+    // The launcher has no overrides for the clipboard checks, so it doesn't suggest any.
+    session
+        .command()
+        .args(["new", "-k", "login", "multi-line"])
+        .write_stdin("jdoe\x01hunter\n5")
+        .assert()
+        .success();
+
+    let output = session
+        .command()
+        .arg("__launcher")
+        .write_stdin("multi-line\tjdoe\tlogin\n")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("refusing to copy a multi-line value"));
+    assert!(!stderr.contains("--allow-multiline"));
+}
@@ -0,0 +1,538 @@
+mod common;
+
+use std::process::{Command, Stdio};
+
+use assert_cmd::cargo::CommandCargoExt;
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_list_details() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "a"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["new", "-k", "environment", "a-much-longer-label"])
+        .write_stdin("fakevariable\x01fakevalue")
+        .assert()
+        .success();
+
+    // `kbs2 list --details` emits a header row, followed by aligned columns.
+    {
+        let output = session.command().args(["list", "-d"]).output().unwrap();
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<_> = stdout.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("LABEL"));
+
+        let kind_column = lines[0].find("KIND").unwrap();
+        for line in &lines[1..] {
+            let kind = &line[kind_column..];
+            assert!(kind.starts_with("login") || kind.starts_with("environment"));
+        }
+    }
+
+    // `kbs2 list --details --no-header` omits the header row.
+    {
+        let output = session
+            .command()
+            .args(["list", "-d", "--no-header"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(stdout.lines().count(), 2);
+        assert!(!stdout.contains("LABEL"));
+    }
+
+    // `kbs2 list --no-header` without `--details` is rejected.
+    {
+        session
+            .command()
+            .args(["list", "--no-header"])
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn test_kbs2_list_null() {
+    let session = CliSession::new();
+
+    for label in ["a", "b"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fakeuser\x01fakepass")
+            .assert()
+            .success();
+    }
+
+    // `kbs2 list --null` terminates each label with a NUL instead of a newline.
+    {
+        let output = session.command().args(["list", "-0"]).output().unwrap();
+        assert!(output.status.success());
+
+        let mut labels: Vec<_> = output
+            .stdout
+            .split(|b| *b == b'\0')
+            .filter(|l| !l.is_empty())
+            .collect();
+        labels.sort();
+
+        assert!(output.stdout.ends_with(b"\0"));
+        assert!(!output.stdout.contains(&b'\n'));
+        assert_eq!(labels, vec![b"a", b"b"]);
+    }
+
+    // `kbs2 list --null` doesn't make sense with `--details`.
+    {
+        session
+            .command()
+            .args(["list", "-0", "-d"])
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn test_kbs2_list_closed_pipe() {
+    let session = CliSession::new();
+
+    for label in ["a", "b", "c"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fakeuser\x01fakepass")
+            .assert()
+            .success();
+    }
+
+    // Writing to a pipe whose reader has already gone away (e.g. `kbs2 list | head -0`)
+    // is a clean exit, not an error.
+    for args in [
+        &["list"][..],
+        &["list", "-d"],
+        &["dump", "--json", "a", "b", "c"],
+    ] {
+        let (reader, writer) = std::io::pipe().unwrap();
+        drop(reader);
+
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg("--config-dir")
+            .arg(session.config_dir.path())
+            .args(args)
+            .stdout(Stdio::from(writer))
+            .stderr(Stdio::piped())
+            .output()
+            .unwrap();
+
+        assert!(output.status.success(), "{args:?}");
+        assert!(output.stderr.is_empty(), "{args:?}");
+    }
+}
+
+#[test]
+fn test_kbs2_list_since() {
+    let session = CliSession::new();
+
+    for label in ["a", "b"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fakeuser\x01fakepass")
+            .assert()
+            .success();
+    }
+
+    // Both records were created and modified just now.
+    for filter in ["--modified-since", "--created-since"] {
+        let output = session
+            .command()
+            .args(["list", filter, "1h"])
+            .output()
+            .unwrap();
+        let mut labels = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        labels.sort();
+        assert_eq!(labels, ["a", "b"]);
+
+        session
+            .command()
+            .args(["list", filter, "2999-01-01"])
+            .assert()
+            .success()
+            .stdout("");
+    }
+
+    // The filters combine with --json.
+    let output = session
+        .command()
+        .args(["list", "--json", "--modified-since", "2020-01-01T00:00:00Z"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+
+    let details: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert!(details["label"] == "a" || details["label"] == "b");
+    assert_eq!(details["kind"], "login");
+    assert_eq!(details["timestamp"], details["created"]);
+
+    // Invalid times name the accepted formats.
+    let output = session
+        .command()
+        .args(["list", "--modified-since", "yesterday"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("expected a duration like 24h or 7d"));
+}
+
+#[test]
+fn test_kbs2_list_jobs() {
+    let session = CliSession::new();
+
+    for i in 0..8 {
+        session
+            .command()
+            .args(["new", "-k", "login", &format!("record-{i}")])
+            .write_stdin("fakeuser\x01fakepass")
+            .assert()
+            .success();
+    }
+
+    // The output doesn't depend on how many threads decrypt the records.
+    let list = |args: &[&str]| {
+        let output = session.command().arg("list").args(args).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    for args in [&["-d", "-H"][..], &["-j"], &["-k", "login"]] {
+        let serial = list(&[args, &["--jobs", "1"]].concat());
+        assert_eq!(serial.lines().count(), 8);
+        assert_eq!(list(&[args, &["--jobs", "4"]].concat()), serial);
+        assert_eq!(list(args), serial);
+    }
+
+    session
+        .command()
+        .args(["list", "-d", "--jobs", "0"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_list_fail_if_empty() {
+    let session = CliSession::new();
+
+    // By default, listing nothing isn't an error, and (outside of a terminal) is silent.
+    session
+        .command()
+        .arg("list")
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+
+    // An empty store.
+    let output = session
+        .command()
+        .args(["list", "--fail-if-empty"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("the store is empty"));
+
+    session
+        .command()
+        .args(["new", "-k", "login", "a"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["list", "--fail-if-empty"])
+        .assert()
+        .success()
+        .stdout("a\n");
+
+    // A filter that matches nothing, in every output format.
+    for format in [&[][..], &["--details"], &["--json"]] {
+        let output = session
+            .command()
+            .args(["list", "--fail-if-empty", "--kind", "totp"])
+            .args(format)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(5));
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("no records matched the filters"));
+    }
+
+    session
+        .command()
+        .args(["list", "--kind", "totp"])
+        .assert()
+        .success();
+
+    // An unreadable store is an ordinary failure.
+    let store = session.store_dir.path();
+    std::fs::remove_dir_all(store).unwrap();
+    std::fs::write(store, "not a directory").unwrap();
+    let output = session
+        .command()
+        .args(["list", "--fail-if-empty"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("the store path exists, but is a file rather than a directory"));
+    std::fs::remove_file(store).unwrap();
+}
+
+#[test]
+fn test_kbs2_list_show_username() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "a"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["new", "-k", "environment", "b"])
+        .write_stdin("fakevariable\x01fakevalue")
+        .assert()
+        .success();
+
+    // `--show-username` implies `--details`, and never shows passwords.
+    let output = session
+        .command()
+        .args(["list", "--show-username", "--no-header"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains("fakepass") && !stdout.contains("fakevalue"));
+
+    let mut usernames = stdout
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .map(|columns| (columns[0].to_string(), columns[3].to_string()))
+        .collect::<Vec<_>>();
+    usernames.sort();
+    assert_eq!(
+        usernames,
+        [
+            ("a".to_string(), "fakeuser".to_string()),
+            ("b".to_string(), "-".to_string())
+        ]
+    );
+
+    let output = session
+        .command()
+        .args(["list", "--show-username"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("LABEL KIND        TIMESTAMP  USERNAME\n"));
+
+    // With `--json`, it adds a `username` key instead.
+    let output = session
+        .command()
+        .args(["list", "--json", "--show-username", "--kind", "login"])
+        .output()
+        .unwrap();
+    let json = output.json();
+    assert_eq!(json["username"], "fakeuser");
+    assert!(json.get("password").is_none());
+
+    let output = session
+        .command()
+        .args(["list", "--json", "--show-username", "--kind", "environment"])
+        .output()
+        .unwrap();
+    assert!(output.json()["username"].is_null());
+
+    // ...and it conflicts with `--null`.
+    session
+        .command()
+        .args(["list", "--show-username", "--null"])
+        .assert()
+        .failure();
+
+    // `list-usernames = false` disables the flag entirely.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config.replace("list-usernames = true", "list-usernames = false"),
+    )
+    .unwrap();
+
+    for args in [&["--show-username"][..], &["--show-username", "--json"]] {
+        let output = session.command().arg("list").args(args).output().unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("list-usernames is disabled"));
+    }
+
+    session.command().args(["list", "-d"]).assert().success();
+}
+
+#[test]
+fn test_kbs2_list_porcelain() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "with\ttab"])
+        .write_stdin("jdoe\x01hunter2")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["new", "-k", "environment", "--burn-after-reading", "api"])
+        .write_stdin("API_KEY\x01hunter2")
+        .assert()
+        .success();
+
+    let output = session
+        .command()
+        .args(["list", "--porcelain"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let mut lines = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| line.split('\t').map(String::from).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    lines.sort();
+
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0][..2], ["api", "environment"]);
+    assert_eq!(lines[0][4], "burn");
+    assert_eq!(lines[1][..2], ["with\\ttab", "login"]);
+    assert_eq!(lines[1][4], "-");
+    for line in &lines {
+        assert_eq!(line.len(), 5);
+        assert!(line[2].parse::<u64>().is_ok());
+        assert!(line[3].parse::<u64>().is_ok());
+    }
+
+    // --porcelain has a format of its own, so it can't be combined with the others.
+    for args in [
+        &["--details"][..],
+        &["--json"],
+        &["--null"],
+        &["--show-username"],
+    ] {
+        session
+            .command()
+            .args(["list", "--porcelain"])
+            .args(args)
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn test_kbs2_list_subdirectories() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "toplevel"])
+        .write_stdin("jdoe\x01hunter2")
+        .assert()
+        .success();
+
+    // Records in subdirectories aren't listed, but they're warned about. Hidden
+    // directories (like a sync remote's `.git`) aren't.
+    let store = session.store_dir.path();
+    std::fs::create_dir(store.join("work")).unwrap();
+    std::fs::copy(store.join("toplevel"), store.join("work").join("nested")).unwrap();
+    std::fs::create_dir(store.join(".git")).unwrap();
+
+    let output = session.command().arg("list").output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"toplevel\n");
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("skipping 1 subdirectory(s) in the store (work)"));
+    assert!(!stderr.contains(".git"));
+}
+
+#[test]
+fn test_kbs2_list_kind_prefix() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "Env", "api"])
+        .write_stdin("API_KEY\x01hunter2")
+        .assert()
+        .success();
+
+    // Kinds are case-insensitive, and can be abbreviated to any unambiguous prefix.
+    for kind in ["environment", "Environment", "env", "E"] {
+        session
+            .command()
+            .args(["list", "-k", kind])
+            .assert()
+            .success()
+            .stdout("api\n");
+    }
+
+    session
+        .command()
+        .args(["list", "-k", "LOGIN"])
+        .assert()
+        .success()
+        .stdout("");
+
+    let output = session
+        .command()
+        .args(["list", "-k", "nonexistent"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("unknown record kind: nonexistent (expected one of: login, environment"));
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_lock_record() {
+    let session = CliSession::new();
+
+    for label in ["locked", "unlocked"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("jdoe\x01hunter2")
+            .assert()
+            .success();
+    }
+
+    session
+        .command()
+        .args(["lock-record", "locked"])
+        .assert()
+        .success();
+
+    // Locking is idempotent, and needs the record to exist.
+    session
+        .command()
+        .args(["lock-record", "locked"])
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["lock-record", "nonexistent"])
+        .assert()
+        .failure();
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", "locked"])
+        .output()
+        .unwrap();
+    assert_eq!(dump.json()["locked"], true);
+
+    // Unlocked records don't mention it at all.
+    let dump = session
+        .command()
+        .args(["dump", "-j", "unlocked"])
+        .output()
+        .unwrap();
+    assert!(dump.json().get("locked").is_none());
+
+    // Everything that would modify or remove the locked record refuses to.
+    for args in [
+        &["rm", "locked"][..],
+        &["rm", "unlocked", "locked"],
+        &["rename", "locked", "renamed"],
+        &["rename", "--force", "unlocked", "locked"],
+        &["edit", "--stdin", "locked"],
+        &["generate", "--update", "locked"],
+        &["new", "--force", "locked"],
+        &["import", "raw", "--force", "locked"],
+    ] {
+        let output = session
+            .command()
+            .args(args)
+            .write_stdin("jdoe\x01hunter3")
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{args:?}");
+        assert!(
+            String::from_utf8(output.stderr)
+                .unwrap()
+                .contains("kbs2 unlock-record locked"),
+            "{args:?}"
+        );
+    }
+
+    // ...and nothing was touched along the way.
+    let list = session.command().arg("list").output().unwrap();
+    let mut labels = String::from_utf8(list.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    labels.sort();
+    assert_eq!(labels, ["locked", "unlocked"]);
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", "locked"])
+        .output()
+        .unwrap();
+    assert_eq!(dump.json()["body"]["fields"]["password"], "hunter2");
+
+    // The lock shows up in detailed listings.
+    let output = session
+        .command()
+        .args(["list", "--details", "--no-header"])
+        .output()
+        .unwrap();
+    let mut details = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    details.sort();
+    assert!(details[0].starts_with("locked   login (locked) "));
+    assert!(details[1].starts_with("unlocked login "));
+
+    let output = session.command().args(["list", "--json"]).output().unwrap();
+    let mut listed = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|json| {
+            (
+                json["label"].as_str().unwrap().to_string(),
+                json["locked"].clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    listed.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        listed,
+        [
+            ("locked".into(), true.into()),
+            ("unlocked".into(), false.into())
+        ]
+    );
+
+    // fsck counts locked records, but they aren't a problem.
+    session
+        .command()
+        .arg("fsck")
+        .assert()
+        .success()
+        .stdout("store: 1 of 2 records are locked\n");
+
+    // --force-locked overrides the lock.
+    session
+        .command()
+        .args(["generate", "--update", "locked", "--force-locked"])
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", "locked"])
+        .output()
+        .unwrap();
+    let dump = dump.json();
+    assert_ne!(dump["body"]["fields"]["password"], "hunter2");
+    assert_eq!(dump["locked"], true);
+
+    // Once unlocked, the record can be removed as usual.
+    session
+        .command()
+        .args(["unlock-record", "locked"])
+        .assert()
+        .success();
+
+    session.command().args(["rm", "locked"]).assert().success();
+
+    // A record that can't be decrypted can't be checked for a lock, but can be removed.
+    std::fs::write(session.store_dir.path().join("broken"), "not a record").unwrap();
+    session.command().args(["rm", "broken"]).assert().success();
+}
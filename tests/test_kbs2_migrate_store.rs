@@ -0,0 +1,166 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_migrate_store() {
+    let session = CliSession::new();
+
+    for label in ["GitHub", "plain"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("jdoe\x01hunter2")
+            .assert()
+            .success();
+    }
+
+    // Without --yes or a tty, migration is refused.
+    session
+        .command()
+        .args(["migrate-store", "--to", "escaped"])
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["migrate-store", "--to", "escaped", "--yes"])
+        .assert()
+        .success();
+
+    let store = session.store_dir.path();
+    assert!(store.join("^git^hub").is_file());
+    assert!(store.join("plain").is_file());
+    assert!(!store.join("GitHub").exists());
+
+    // The old config and store are backed up.
+    let store_backup = PathBuf::from(format!("{}.old", store.display()));
+    assert!(store_backup.join("GitHub").is_file());
+    assert!(session.config_dir.path().join("config.toml.old").is_file());
+    fs::remove_dir_all(&store_backup).unwrap();
+
+    let config = fs::read_to_string(session.config_dir.path().join("config.toml")).unwrap();
+    assert!(config.contains("record-filenames = \"escaped\""));
+
+    for label in ["GitHub", "plain"] {
+        let output = session.command().args(["pass", label]).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hunter2");
+    }
+
+    // ...and back again, this time without a backup.
+    session
+        .command()
+        .args(["migrate-store", "--to", "direct", "--yes", "--no-backup"])
+        .assert()
+        .success();
+
+    assert!(store.join("GitHub").is_file());
+    assert!(!store.join("^git^hub").exists());
+    assert!(!store_backup.exists());
+
+    // Nothing is left behind in the store but the records.
+    let mut entries = fs::read_dir(store)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    entries.sort();
+    assert_eq!(entries, ["GitHub", "plain"]);
+}
+
+#[test]
+fn test_kbs2_migrate_store_compress() {
+    let session = CliSession::new();
+    let config_path = session.config_dir.path().join("config.toml");
+    let contents = "{\"key\": \"value\"}\n".repeat(256);
+
+    for label in ["big", "empty"] {
+        session
+            .command()
+            .args(["new", "-k", "unstructured", label])
+            .write_stdin(if label == "big" {
+                contents.as_str()
+            } else {
+                ""
+            })
+            .assert()
+            .success();
+    }
+
+    let store = session.store_dir.path();
+    let uncompressed = fs::metadata(store.join("big")).unwrap().len();
+
+    let output = session
+        .command()
+        .args(["migrate-store", "--compress", "deflate", "--yes", "-n"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("Records on disk: "));
+
+    let config = fs::read_to_string(&config_path).unwrap();
+    assert!(config.contains("compress = \"deflate\""));
+    assert!(config.contains("record-filenames = \"direct\""));
+    assert!(fs::metadata(store.join("big")).unwrap().len() < uncompressed / 4);
+
+    // Records written without compression sit alongside the compressed ones.
+    fs::write(
+        &config_path,
+        config.replace("compress = \"deflate\"", "compress = \"none\""),
+    )
+    .unwrap();
+    session
+        .command()
+        .args(["new", "-k", "unstructured", "uncompressed"])
+        .write_stdin(contents.as_str())
+        .assert()
+        .success();
+    assert!(fs::metadata(store.join("uncompressed")).unwrap().len() > uncompressed / 2);
+
+    for (label, expected) in [("big", &contents), ("uncompressed", &contents)] {
+        let output = session
+            .command()
+            .args(["dump", "--json", label])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let dump: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        assert_eq!(dump["body"]["fields"]["contents"], expected.trim_end());
+    }
+
+    session
+        .command()
+        .args(["dump", "--json", "empty"])
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_kbs2_migrate_store_always_confirms() {
+    let session = CliSession::new();
+
+    // Neither an empty store nor a generous `confirm-threshold` skips the confirmation,
+    // since the config is rewritten either way.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = fs::read_to_string(&config_path).unwrap();
+    fs::write(&config_path, format!("confirm-threshold = 100\n{config}")).unwrap();
+
+    let output = session
+        .command()
+        .args(["migrate-store", "--to", "escaped"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("pass --yes to confirm"));
+    assert_eq!(
+        fs::read_to_string(&config_path).unwrap(),
+        format!("confirm-threshold = 100\n{config}")
+    );
+}
@@ -1,5 +1,7 @@
 mod common;
 
+use std::fs;
+
 use common::{CliSession, ToJson};
 use serde_json::json;
 
@@ -32,6 +34,79 @@ fn test_kbs2_new_login() {
     );
 }
 
+#[test]
+fn test_kbs2_new_terse_separator() {
+    let session = CliSession::new();
+
+    let fields = |label: &str| {
+        session
+            .command()
+            .args(["dump", "--json", label])
+            .output()
+            .unwrap()
+            .json()["body"]["fields"]
+            .clone()
+    };
+
+    session
+        .command()
+        .args(["new", "--terse-separator", "\t", "tabbed"])
+        .write_stdin("fake\x01user\tfakepass")
+        .assert()
+        .success()
+        .stderr("");
+    assert_eq!(
+        fields("tabbed"),
+        json!({ "username": "fake\x01user", "password": "fakepass" })
+    );
+
+    // The last field keeps any extra separators, with a warning.
+    let output = session
+        .command()
+        .args(["new", "--terse-separator", "::", "extra"])
+        .write_stdin("fakeuser::fake::pass")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("the last field contains the terse separator"));
+    assert_eq!(
+        fields("extra"),
+        json!({ "username": "fakeuser", "password": "fake::pass" })
+    );
+
+    session
+        .command()
+        .args(["new", "--terse-separator", "", "empty"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .failure();
+
+    // The separator can also come from the config.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = fs::read_to_string(&config_path).unwrap();
+    fs::write(
+        &config_path,
+        config.replace(
+            "[commands.new]\n",
+            "[commands.new]\nterse-separator = \",\"\n",
+        ),
+    )
+    .unwrap();
+
+    session
+        .command()
+        .args(["new", "configured"])
+        .write_stdin("fakeuser,fakepass")
+        .assert()
+        .success();
+    assert_eq!(
+        fields("configured"),
+        json!({ "username": "fakeuser", "password": "fakepass" })
+    );
+}
+
 #[test]
 fn test_kbs2_new_environment() {
     let session = CliSession::new();
@@ -85,3 +160,630 @@ fn test_kbs2_new_unstructured() {
         &json!({ "contents": "fakevalue" }),
     );
 }
+
+#[test]
+fn test_kbs2_new_field_args() {
+    let session = CliSession::new();
+
+    // Fields can be mixed between arguments and stdin.
+    {
+        session
+            .command()
+            .args([
+                "new",
+                "-k",
+                "login",
+                "test-record",
+                "--username",
+                "fakeuser",
+                "--password-stdin",
+            ])
+            .write_stdin("fakepass\n")
+            .assert()
+            .success();
+
+        let dump = session
+            .command()
+            .args(["dump", "--json", "test-record"])
+            .output()
+            .unwrap()
+            .json();
+
+        let fields = dump.get("body").unwrap().get("fields").unwrap();
+        assert_eq!(
+            fields,
+            &json!({ "username": "fakeuser", "password": "fakepass" }),
+        );
+    }
+
+    // Sensitive fields are generated with --generate.
+    {
+        session
+            .command()
+            .args([
+                "new",
+                "-k",
+                "environment",
+                "test-record-2",
+                "--variable",
+                "FAKEVARIABLE",
+                "--generate",
+            ])
+            .assert()
+            .success();
+
+        let dump = session
+            .command()
+            .args(["dump", "--json", "test-record-2"])
+            .output()
+            .unwrap()
+            .json();
+
+        let fields = dump.get("body").unwrap().get("fields").unwrap();
+        assert_eq!(fields.get("variable").unwrap(), "FAKEVARIABLE");
+        assert!(!fields.get("value").unwrap().as_str().unwrap().is_empty());
+    }
+
+    // Missing fields are an error without --generate.
+    {
+        session
+            .command()
+            .args([
+                "new",
+                "-k",
+                "login",
+                "test-record-3",
+                "--username",
+                "fakeuser",
+            ])
+            .assert()
+            .failure();
+    }
+
+    // Fields that don't belong to the record's kind are an error.
+    {
+        session
+            .command()
+            .args([
+                "new",
+                "-k",
+                "unstructured",
+                "test-record-3",
+                "--username",
+                "x",
+            ])
+            .assert()
+            .failure();
+    }
+
+    // Terse input can't be combined with field arguments.
+    {
+        session
+            .command()
+            .args(["new", "-t", "test-record-3", "--username", "fakeuser"])
+            .write_stdin("fakeuser\x01fakepass")
+            .assert()
+            .failure();
+    }
+
+    // Only one field can be read from stdin.
+    {
+        session
+            .command()
+            .args([
+                "new",
+                "test-record-3",
+                "--username-stdin",
+                "--password-stdin",
+            ])
+            .write_stdin("fakepass")
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn test_kbs2_new_field_file() {
+    let session = CliSession::new();
+
+    let pem = "-----BEGIN CERTIFICATE-----\nMIIfake\n-----END CERTIFICATE-----\n";
+    let pem_file = session.config_dir.path().join("server.pem");
+    std::fs::write(&pem_file, pem).unwrap();
+
+    // Multi-line fields can be read from a file, verbatim.
+    {
+        session
+            .command()
+            .args(["new", "-k", "unstructured", "cert", "--field-file"])
+            .arg(format!("contents={}", pem_file.display()))
+            .assert()
+            .success();
+
+        let dump = session
+            .command()
+            .args(["dump", "--json", "cert"])
+            .output()
+            .unwrap()
+            .json();
+
+        let fields = dump.get("body").unwrap().get("fields").unwrap();
+        assert_eq!(fields, &json!({ "contents": pem }));
+    }
+
+    // The same field can't be supplied twice.
+    {
+        session
+            .command()
+            .args(["new", "-k", "unstructured", "cert-2", "--contents", "x"])
+            .arg("--field-file")
+            .arg(format!("contents={}", pem_file.display()))
+            .assert()
+            .failure();
+    }
+
+    // Unknown fields and fields from other kinds are rejected.
+    {
+        session
+            .command()
+            .args(["new", "-k", "unstructured", "cert-3", "--field-file"])
+            .arg(format!("bogus={}", pem_file.display()))
+            .assert()
+            .failure();
+
+        session
+            .command()
+            .args(["new", "-k", "unstructured", "cert-3", "--field-file"])
+            .arg(format!("password={}", pem_file.display()))
+            .assert()
+            .failure();
+    }
+
+    // Missing files are an error.
+    {
+        session
+            .command()
+            .args(["new", "-k", "unstructured", "cert-4", "--field-file"])
+            .arg("contents=/this/file/does/not/exist")
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn test_kbs2_new_force_generate() {
+    let session = CliSession::new();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config.replace("force-generate = false", "force-generate = true"),
+    )
+    .unwrap();
+
+    // Empty sensitive fields are generated, as usual.
+    session
+        .command()
+        .args(["new", "-k", "login", "generated"])
+        .write_stdin("fakeuser\x01")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args([
+            "new",
+            "-k",
+            "environment",
+            "generated-env",
+            "--variable",
+            "FOO",
+        ])
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "generated"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["body"]["fields"]["username"], "fakeuser");
+    assert!(!dump["body"]["fields"]["password"]
+        .as_str()
+        .unwrap()
+        .is_empty());
+
+    // Supplied sensitive fields are refused, however they're supplied.
+    session
+        .command()
+        .args(["new", "-k", "login", "chosen"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "chosen", "--username", "fakeuser"])
+        .args(["--password", "fakepass"])
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["new", "-k", "environment", "chosen", "--variable", "FOO"])
+        .arg("--value-stdin")
+        .write_stdin("fakevalue")
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["dump", "chosen"])
+        .assert()
+        .failure();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_kbs2_new_generated_by() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let session = CliSession::new();
+
+    // A post-hook that records the generator it was given, if any.
+    let hook = session.config_dir.path().join("hook.sh");
+    let log = session.config_dir.path().join("generator.log");
+    std::fs::write(
+        &hook,
+        format!(
+            "#!/bin/sh\necho \"$1=$KBS2_GENERATOR\" >> {}\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config.replace(
+            "[commands.new]\n",
+            &format!("[commands.new]\npost-hook = \"{}\"\n", hook.display()),
+        ),
+    )
+    .unwrap();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "generated"])
+        .write_stdin("fakeuser\x01")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "chosen"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "generated"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["generated_by"], "default");
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "chosen"])
+        .output()
+        .unwrap()
+        .json();
+    assert!(dump.get("generated_by").is_none());
+
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "generated=default\nchosen=\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_kbs2_new_pre_hook_veto() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let session = CliSession::new();
+
+    // A pre-hook that vetoes one label, fails on another, and allows the rest.
+    let hook = session.config_dir.path().join("hook.sh");
+    std::fs::write(
+        &hook,
+        "#!/bin/sh\ncase \"$KBS2_LABEL\" in\n  vetoed) exit 100 ;;\n  failed) exit 1 ;;\nesac\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(
+        &config_path,
+        config.replace(
+            "[commands.new]\n",
+            &format!("[commands.new]\npre-hook = \"{}\"\n", hook.display()),
+        ),
+    )
+    .unwrap();
+
+    let new = |label: &str| {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fakeuser\x01fakepass")
+            .output()
+            .unwrap()
+    };
+
+    // A veto skips creating the record, without failing.
+    let output = new("vetoed");
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("pre-hook vetoed creating record: vetoed"));
+
+    // Any other failure is still an error.
+    assert!(!new("failed").status.success());
+
+    assert!(new("allowed").status.success());
+
+    for (label, exists) in [("vetoed", false), ("failed", false), ("allowed", true)] {
+        let output = session.command().args(["dump", label]).output().unwrap();
+        assert_eq!(output.status.success(), exists);
+    }
+}
+
+#[test]
+fn test_kbs2_new_notes() {
+    let session = CliSession::new();
+
+    // Notes can be attached to any kind of record, and are shown by `dump`.
+    {
+        session
+            .command()
+            .args([
+                "new",
+                "-k",
+                "environment",
+                "api-key",
+                "--notes",
+                "rotate yearly",
+            ])
+            .write_stdin("API_KEY\x01hunter2")
+            .assert()
+            .success();
+
+        let dump = session
+            .command()
+            .args(["dump", "--json", "api-key"])
+            .output()
+            .unwrap()
+            .json();
+        assert_eq!(dump["notes"], "rotate yearly");
+
+        let output = session
+            .command()
+            .args(["dump", "api-key"])
+            .output()
+            .unwrap();
+        assert!(String::from_utf8(output.stdout)
+            .unwrap()
+            .contains("Notes rotate yearly"));
+    }
+
+    // Notes can be read from a file, verbatim.
+    {
+        let notes = "recovery codes:\n1234\n5678\n";
+        let notes_file = session.config_dir.path().join("notes.txt");
+        std::fs::write(&notes_file, notes).unwrap();
+
+        session
+            .command()
+            .args(["new", "-k", "login", "github", "--notes-file"])
+            .arg(&notes_file)
+            .write_stdin("jdoe\x01hunter2")
+            .assert()
+            .success();
+
+        let dump = session
+            .command()
+            .args(["dump", "--json", "github"])
+            .output()
+            .unwrap()
+            .json();
+        assert_eq!(dump["notes"], notes);
+    }
+
+    // Records without notes don't have the field at all.
+    {
+        session
+            .command()
+            .args(["new", "-k", "login", "plain"])
+            .write_stdin("jdoe\x01hunter2")
+            .assert()
+            .success();
+
+        let dump = session
+            .command()
+            .args(["dump", "--json", "plain"])
+            .output()
+            .unwrap()
+            .json();
+        assert!(dump.get("notes").is_none());
+    }
+}
+
+#[test]
+fn test_kbs2_new_recipients() {
+    let session = CliSession::new();
+
+    let config: toml::Value = toml::from_str(
+        &std::fs::read_to_string(session.config_dir.path().join("config.toml")).unwrap(),
+    )
+    .unwrap();
+    let public_key = config["public-key"].as_str().unwrap();
+    let teammate = age::x25519::Identity::generate().to_public().to_string();
+
+    // The store's own key is always a recipient, alongside any others.
+    session
+        .command()
+        .args(["new", "-k", "login", "shared", "--recipient", &teammate])
+        .write_stdin("jdoe\x01hunter2")
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "shared"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["recipients"], json!([public_key, teammate]));
+
+    // Records without their own recipients don't have the field at all.
+    session
+        .command()
+        .args(["new", "-k", "login", "private"])
+        .write_stdin("jdoe\x01hunter2")
+        .assert()
+        .success();
+
+    let dump = session
+        .command()
+        .args(["dump", "--json", "private"])
+        .output()
+        .unwrap()
+        .json();
+    assert!(dump.get("recipients").is_none());
+
+    // Recipients must be age public keys.
+    let output = session
+        .command()
+        .args(["new", "-k", "login", "bad", "--recipient", "nope"])
+        .write_stdin("jdoe\x01hunter2")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("not an age public key: nope"));
+}
+
+#[test]
+fn test_kbs2_new_interactive_fields() {
+    let session = CliSession::new();
+
+    // Extra fields are prompted for, so there has to be someone to prompt.
+    let output = session
+        .command()
+        .args(["new", "-k", "login", "--interactive-fields", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("--interactive-fields needs an interactive terminal"));
+
+    session
+        .command()
+        .args(["dump", "test-record"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_new_label_template() {
+    let session = CliSession::new();
+
+    // `{seq}` counts up from the existing matching labels, and the expanded label is printed.
+    for expected in ["apikey-1", "apikey-2"] {
+        session
+            .command()
+            .args([
+                "new",
+                "-k",
+                "unstructured",
+                "apikey-{seq}",
+                "--contents",
+                "x",
+            ])
+            .assert()
+            .success()
+            .stdout(format!("{expected}\n"));
+    }
+
+    // `{date}` is today's (UTC) date.
+    let output = session
+        .command()
+        .args([
+            "new",
+            "-k",
+            "unstructured",
+            "rotated-{date}",
+            "--contents",
+            "x",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let label = String::from_utf8(output.stdout).unwrap();
+    let label = label.trim_end();
+    assert_eq!(label.len(), "rotated-YYYY-MM-DD".len());
+    assert!(label.starts_with("rotated-20"));
+
+    // Templates are expanded before the overwrite check.
+    session
+        .command()
+        .args([
+            "new",
+            "-k",
+            "unstructured",
+            "rotated-{date}",
+            "--contents",
+            "y",
+        ])
+        .assert()
+        .failure();
+
+    // Literal labels are unchanged, and nothing extra is printed for them.
+    session
+        .command()
+        .args([
+            "new",
+            "-k",
+            "unstructured",
+            "literal-{braces}",
+            "--contents",
+            "x",
+        ])
+        .assert()
+        .success()
+        .stdout("");
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", "literal-{braces}"])
+        .output()
+        .unwrap();
+    assert_eq!(dump.json()["label"], "literal-{braces}");
+
+    let dump = session
+        .command()
+        .args(["dump", "-j", label])
+        .output()
+        .unwrap();
+    assert_eq!(dump.json()["body"]["fields"]["contents"], "x");
+}
@@ -0,0 +1,299 @@
+mod common;
+
+use std::fs;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_pass() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["pass", "test-record"])
+        .assert()
+        .success()
+        .stdout("fakepass");
+
+    // `--with-totp` requires a linked TOTP record.
+    session
+        .command()
+        .args(["pass", "--with-totp", "test-record"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_pass_wrapped() {
+    let session = CliSession::new_wrapped();
+    let agent = session.agent.as_ref().unwrap();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // The first use of the key unwraps it into the session's agent...
+    session
+        .command()
+        .args(["pass", "test-record"])
+        .assert()
+        .success()
+        .stdout("fakepass");
+
+    // ...and an incorrect password is retried, rather than failing outright.
+    session
+        .command()
+        .args(["agent", "flush"])
+        .assert()
+        .success();
+    agent.queue_passwords(&["incorrect"]);
+    let output = session
+        .command()
+        .args(["pass", "test-record"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"fakepass");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("attempt(s) left"));
+}
+
+#[test]
+fn test_kbs2_pass_with_totp() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "totp", "test-totp"])
+        .args(["--secret", "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"])
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record", "--totp", "test-totp"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let output = session
+        .command()
+        .args(["pass", "--with-totp", "test-record"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let (password, code) = stdout.split_once('\n').unwrap();
+    assert_eq!(password, "fakepass");
+    assert_eq!(code.len(), 6);
+    assert!(code.chars().all(|c| c.is_ascii_digit()));
+
+    // Without a tty, `--totp` emits the password and code on separate, unlabeled lines.
+    let output = session
+        .command()
+        .args(["pass", "--totp", "test-record"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], "fakepass");
+    assert_eq!(lines[1].len(), 6);
+    assert!(lines[1].chars().all(|c| c.is_ascii_digit()));
+
+    // `--totp` and `--with-totp` are mutually exclusive.
+    session
+        .command()
+        .args(["pass", "--totp", "--with-totp", "test-record"])
+        .assert()
+        .failure();
+
+    // A link to a non-TOTP record is an error.
+    session
+        .command()
+        .args(["new", "-k", "login", "bad-record", "--totp", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["pass", "--with-totp", "bad-record"])
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["pass", "--totp", "bad-record"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_pass_show_for() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // `--show-for` only makes sense on a terminal, and never prints the password otherwise.
+    session
+        .command()
+        .args(["pass", "--show-for", "5", "test-record"])
+        .assert()
+        .failure()
+        .stdout("");
+
+    session
+        .command()
+        .args(["pass", "--show-for", "5", "--clipboard", "test-record"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_pass_reveal_timeout() {
+    let session = CliSession::new();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = fs::read_to_string(&config_path).unwrap();
+    fs::write(
+        &config_path,
+        config.replace(
+            "clear-after = true",
+            "clear-after = true\nreveal-timeout = 5",
+        ),
+    )
+    .unwrap();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // Unlike `--show-for`, `reveal-timeout` quietly doesn't apply without a terminal.
+    session
+        .command()
+        .args(["pass", "test-record"])
+        .assert()
+        .success()
+        .stdout("fakepass");
+}
+
+#[test]
+fn test_kbs2_pass_last() {
+    let session = CliSession::new();
+
+    // With nothing to pick from, --last fails.
+    session
+        .command()
+        .args(["pass", "--last"])
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "older"])
+        .write_stdin("fakeuser\x01oldpass")
+        .assert()
+        .success();
+
+    // NOTE: Timestamps have a resolution of a second.
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+
+    session
+        .command()
+        .args(["new", "-k", "login", "newer"])
+        .write_stdin("fakeuser\x01newpass")
+        .assert()
+        .success();
+
+    // Only logins are considered, even if another kind of record is newer.
+    session
+        .command()
+        .args(["new", "-k", "environment", "newest"])
+        .write_stdin("VAR\x01value")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["pass", "--last"])
+        .assert()
+        .success()
+        .stdout("newpass");
+
+    session
+        .command()
+        .args(["pass", "--last", "older"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_pass_clipboard_refusals() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "multi-line"])
+        .write_stdin("fakeuser\x01fake\npass")
+        .assert()
+        .success();
+
+    // Multi-line passwords are refused before anything is copied.
+    let output = session
+        .command()
+        .args(["pass", "-c", "multi-line"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("(is it the right field?); pass --allow-multiline"));
+
+    session
+        .command()
+        .args(["new", "-k", "login", "large"])
+        .write_stdin(format!("fakeuser\x01{}", "x".repeat(9000)))
+        .assert()
+        .success();
+
+    // So are passwords larger than `clipboard-max-bytes`.
+    let output = session
+        .command()
+        .args(["pass", "-c", "large"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("more than clipboard-max-bytes 8192); pass --force"));
+
+    // The overrides only make sense with `--clipboard`.
+    session
+        .command()
+        .args(["pass", "--force", "large"])
+        .assert()
+        .failure();
+}
@@ -0,0 +1,108 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_reencrypt_labels() {
+    let session = CliSession::new();
+
+    for label in ["github", "email"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("jdoe\x01hunter2")
+            .assert()
+            .success();
+    }
+
+    let path = session.store_dir.path().join("github");
+    let before = std::fs::read(&path).unwrap();
+    let dumped = session
+        .command()
+        .args(["dump", "-j", "github"])
+        .output()
+        .unwrap()
+        .json();
+
+    // Without --with-identity, the records to re-encrypt have to be named.
+    session.command().arg("reencrypt").assert().failure();
+
+    // A dry run decrypts the record, but leaves it as it is.
+    session
+        .command()
+        .args(["--dry-run", "reencrypt", "github"])
+        .assert()
+        .success()
+        .stdout("would re-encrypt 'github'\nSummary:\n  ok      1\n  skipped 0\n  failed  0\n");
+    assert_eq!(std::fs::read(&path).unwrap(), before);
+
+    // Dry runs don't write reports, wherever `--dry-run` comes on the command line.
+    for args in [
+        [
+            "--dry-run",
+            "reencrypt",
+            "--report",
+            "report.json",
+            "github",
+        ],
+        [
+            "reencrypt",
+            "--report",
+            "report.json",
+            "--dry-run",
+            "github",
+        ],
+    ] {
+        session.command().args(args).assert().failure();
+    }
+
+    session
+        .command()
+        .args(["reencrypt", "github"])
+        .assert()
+        .success()
+        .stdout("github: re-encrypted\nSummary:\n  ok      1\n  skipped 0\n  failed  0\n");
+
+    // The record is encrypted anew, but is otherwise untouched (timestamp included).
+    assert_ne!(std::fs::read(&path).unwrap(), before);
+    assert_eq!(
+        session
+            .command()
+            .args(["dump", "-j", "github"])
+            .output()
+            .unwrap()
+            .json(),
+        dumped
+    );
+
+    // Missing records fail, without keeping the rest from being re-encrypted.
+    let output = session
+        .command()
+        .args(["reencrypt", "nonexistent", "email"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("email: re-encrypted"));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("1 of 2 records couldn't be re-encrypted"));
+
+    // Labels read from stdin are all checked up front, instead.
+    session
+        .command()
+        .args(["reencrypt", "--stdin-labels"])
+        .write_stdin("email\nnonexistent\n")
+        .assert()
+        .failure()
+        .stdout("");
+
+    session
+        .command()
+        .args(["reencrypt", "--stdin-labels"])
+        .write_stdin("email\ngithub\n")
+        .assert()
+        .success()
+        .stdout("email: re-encrypted\ngithub: re-encrypted\nSummary:\n  ok      2\n  skipped 0\n  failed  0\n");
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_rekey_always_confirms() {
+    let session = CliSession::new_wrapped();
+
+    // A generous `confirm-threshold` doesn't let rekey skip its confirmation, even in an
+    // empty store: the config and keyfile are rewritten either way.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(&config_path, format!("confirm-threshold = 100\n{config}")).unwrap();
+
+    let keyfile = session.config_dir.path().join("key");
+    let before = std::fs::read(&keyfile).unwrap();
+
+    let output = session.command().arg("rekey").output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("pass --yes to confirm"));
+
+    assert_eq!(std::fs::read(&keyfile).unwrap(), before);
+    assert!(!session.config_dir.path().join("key.old").exists());
+}
+
+#[test]
+fn test_kbs2_rekey_backup_dir() {
+    let session = CliSession::new_wrapped();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let backups = tempfile::tempdir().unwrap();
+    let backup_dir = backups.path().join("rekey");
+    let keyfile = session.config_dir.path().join("key");
+    let old_key = std::fs::read(&keyfile).unwrap();
+
+    session
+        .command()
+        .args(["rekey", "--yes", "--backup-dir"])
+        .arg(&backup_dir)
+        .assert()
+        .success();
+
+    // The backups all land in the backup directory (which is created if need be)...
+    assert_eq!(std::fs::read(backup_dir.join("key.old")).unwrap(), old_key);
+    assert!(backup_dir.join("config.toml.old").is_file());
+    let store_name = session.store_dir.path().file_name().unwrap();
+    let mut store_backup = store_name.to_os_string();
+    store_backup.push(".old");
+    assert!(backup_dir.join(store_backup).join("test-record").is_file());
+
+    // ...and none of them next to the originals.
+    assert!(!session.config_dir.path().join("key.old").exists());
+    assert!(!session.config_dir.path().join("config.toml.old").exists());
+    let mut beside_store = session.store_dir.path().as_os_str().to_os_string();
+    beside_store.push(".old");
+    assert!(!std::path::Path::new(&beside_store).exists());
+
+    assert_ne!(std::fs::read(&keyfile).unwrap(), old_key);
+    session
+        .command()
+        .args(["pass", "test-record"])
+        .assert()
+        .success()
+        .stdout("fakepass");
+}
@@ -1,6 +1,6 @@
 mod common;
 
-use common::CliSession;
+use common::{CliSession, ToJson};
 
 #[test]
 fn test_kbs2_rename() {
@@ -33,5 +33,123 @@ fn test_kbs2_rename() {
         .success();
 }
 
+#[test]
+fn test_kbs2_rename_merge() {
+    let session = CliSession::new();
+
+    for (label, input) in [("github", "\x01oldpass"), ("github-2", "jdoe\x01newpass")] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin(input)
+            .assert()
+            .success();
+    }
+
+    // Without a tty, merging needs a preference.
+    let output = session
+        .command()
+        .args(["rename", "--merge", "github-2", "github"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("requires --prefer"));
+
+    let output = session
+        .command()
+        .args(["rename", "--merge", "--prefer", "dst", "github-2", "github"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // The plan is printed, with secrets masked.
+    let plan = String::from_utf8(output.stdout).unwrap();
+    assert!(plan.contains("username: keeping \"jdoe\" from github-2"));
+    assert!(plan.contains("password: keeping (hidden, 7 characters) from github"));
+    assert!(!plan.contains("oldpass"));
+
+    // The empty username is filled in, and the destination's password is kept.
+    let dump = session
+        .command()
+        .args(["dump", "-j", "github"])
+        .output()
+        .unwrap()
+        .json();
+    assert_eq!(dump["body"]["fields"]["username"], "jdoe");
+    assert_eq!(dump["body"]["fields"]["password"], "oldpass");
+
+    session
+        .command()
+        .args(["dump", "github-2"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_rename_merge_kind_mismatch() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "foo"])
+        .write_stdin("user\x01pass")
+        .assert()
+        .success();
+    session
+        .command()
+        .args(["new", "-k", "environment", "bar"])
+        .write_stdin("BAR\x01baz")
+        .assert()
+        .success();
+
+    let output = session
+        .command()
+        .args(["rename", "--merge", "--prefer", "src", "bar", "foo"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("can't merge records of different kinds"));
+
+    // Nothing was changed.
+    for label in ["foo", "bar"] {
+        session.command().args(["dump", label]).assert().success();
+    }
+}
+
 // TODO: `kbs2 rename --force`
 // TODO: `kbs2 rename` with the same record twice
+
+#[test]
+fn test_kbs2_rename_dry_run() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["--dry-run", "rename", "test-record", "new-record"])
+        .assert()
+        .success()
+        .stdout("would rename 'test-record' to 'new-record'\n");
+
+    session
+        .command()
+        .args(["dump", "test-record"])
+        .assert()
+        .success();
+
+    session
+        .command()
+        .args(["dump", "new-record"])
+        .assert()
+        .failure();
+}
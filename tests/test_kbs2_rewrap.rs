@@ -0,0 +1,42 @@
+mod common;
+
+use common::{CliSession, PASSWORD};
+
+#[test]
+fn test_kbs2_rewrap() {
+    let session = CliSession::new_wrapped();
+    let agent = session.agent.as_ref().unwrap();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let keyfile = session.config_dir.path().join("key");
+    let before = std::fs::read(&keyfile).unwrap();
+
+    // The OLD password, then the NEW one.
+    agent.queue_passwords(&[PASSWORD, "correct horse"]);
+    session.command().arg("rewrap").assert().success();
+
+    assert_ne!(std::fs::read(&keyfile).unwrap(), before);
+    assert_eq!(
+        std::fs::read(session.config_dir.path().join("key.old")).unwrap(),
+        before
+    );
+
+    // The agent forgot the old key, and the new password unwraps the rewrapped one.
+    agent.queue_passwords(&["correct horse"]);
+    session
+        .command()
+        .args(["pass", "test-record"])
+        .assert()
+        .success()
+        .stdout("fakepass");
+
+    // Another rewrap won't clobber the backup without --force.
+    agent.queue_passwords(&["correct horse", PASSWORD]);
+    session.command().arg("rewrap").assert().failure();
+}
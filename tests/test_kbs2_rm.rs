@@ -66,3 +66,260 @@ fn test_kbs2_rm() {
             .failure();
     }
 }
+
+#[test]
+fn test_kbs2_rm_guardrails() {
+    let session = CliSession::new();
+
+    for (kind, label) in [
+        ("login", "login-1"),
+        ("login", "login-2"),
+        ("environment", "env-1"),
+    ] {
+        session
+            .command()
+            .args(["new", "-k", kind, label])
+            .write_stdin("fake\x01fake")
+            .assert()
+            .success();
+    }
+
+    let assert_present = |labels: &[&str]| {
+        for label in labels {
+            session.command().args(["dump", label]).assert().success();
+        }
+    };
+
+    // A nonexistent label aborts the removal before anything is removed.
+    session
+        .command()
+        .args(["rm", "login-1", "does-not-exist"])
+        .assert()
+        .failure();
+    assert_present(&["login-1"]);
+
+    // --kind refuses to remove records of other kinds, and removes nothing.
+    session
+        .command()
+        .args(["rm", "--kind", "login", "login-1", "env-1"])
+        .assert()
+        .failure();
+    assert_present(&["login-1", "env-1"]);
+
+    // --limit refuses to remove more than N records.
+    session
+        .command()
+        .args(["rm", "--limit", "1", "login-1", "login-2"])
+        .assert()
+        .failure();
+    assert_present(&["login-1", "login-2"]);
+
+    // When everything checks out, the plan is printed and the records are removed.
+    session
+        .command()
+        .args([
+            "rm", "--kind", "login", "--limit", "2", "login-1", "login-2",
+        ])
+        .assert()
+        .success()
+        .stdout("Removing 2 records:\n  login-1\n  login-2\n");
+    assert_present(&["env-1"]);
+
+    session
+        .command()
+        .args(["dump", "login-1"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_kbs2_rm_confirm_threshold() {
+    let session = CliSession::new();
+
+    // Without a tty, removals past the threshold need --yes.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(&config_path, format!("confirm-threshold = 1\n{config}")).unwrap();
+
+    for label in ["foo", "bar", "baz"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fake\x01fake")
+            .assert()
+            .success();
+    }
+
+    session.command().args(["rm", "foo"]).assert().success();
+
+    let output = session
+        .command()
+        .args(["rm", "bar", "baz"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("more than confirm-threshold 1"));
+    session.command().args(["dump", "bar"]).assert().success();
+
+    session
+        .command()
+        .args(["rm", "--yes", "bar", "baz"])
+        .assert()
+        .success();
+    session.command().args(["dump", "bar"]).assert().failure();
+}
+
+#[test]
+fn test_kbs2_rm_recent_window() {
+    let session = CliSession::new();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap().replace(
+        "[commands.rm]\n",
+        "[commands.rm]\nrecent-window-secs = 3600\n",
+    );
+    std::fs::write(&config_path, config).unwrap();
+
+    for label in ["foo", "bar"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fake\x01fake")
+            .assert()
+            .success();
+    }
+
+    // Without a tty, a record that was just modified needs --yes...
+    let output = session.command().args(["rm", "foo"]).output().unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("foo was modified 0s ago"));
+    assert!(stderr.contains("refusing to remove 1 recently modified records"));
+    session.command().args(["dump", "foo"]).assert().success();
+
+    session
+        .command()
+        .args(["rm", "--yes", "foo"])
+        .assert()
+        .success();
+    session.command().args(["dump", "foo"]).assert().failure();
+
+    // ...but one that was modified outside of the window doesn't.
+    let two_hours_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(7200);
+    std::fs::File::options()
+        .write(true)
+        .open(session.store_dir.path().join("bar"))
+        .unwrap()
+        .set_modified(two_hours_ago)
+        .unwrap();
+
+    session.command().args(["rm", "bar"]).assert().success();
+    session.command().args(["dump", "bar"]).assert().failure();
+}
+
+#[test]
+fn test_kbs2_rm_stdin_labels() {
+    let session = CliSession::new();
+
+    for label in ["foo", "bar", "baz"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fake\x01fake")
+            .assert()
+            .success();
+    }
+
+    // Every label is checked before anything is removed.
+    let output = session
+        .command()
+        .args(["rm", "--stdin-labels"])
+        .write_stdin("foo\nnonexistent\n")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("nonexistent"));
+    session.command().args(["dump", "foo"]).assert().success();
+
+    // Labels can't come from both places.
+    session
+        .command()
+        .args(["rm", "--stdin-labels", "foo"])
+        .write_stdin("bar\n")
+        .assert()
+        .failure();
+
+    // Empty lines and duplicates are skipped.
+    session
+        .command()
+        .args(["rm", "--stdin-labels"])
+        .write_stdin("foo\n\nbar\nfoo\n")
+        .assert()
+        .success();
+    session.command().args(["dump", "foo"]).assert().failure();
+    session.command().args(["dump", "bar"]).assert().failure();
+    session.command().args(["dump", "baz"]).assert().success();
+
+    // Stdin can't answer a confirmation once it's been read, so --yes has to be explicit.
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(&config_path, format!("confirm-threshold = 0\n{config}")).unwrap();
+
+    session
+        .command()
+        .args(["rm", "--stdin-labels"])
+        .write_stdin("baz\n")
+        .assert()
+        .failure();
+    session
+        .command()
+        .args(["rm", "--stdin-labels", "--yes"])
+        .write_stdin("baz\n")
+        .assert()
+        .success();
+    session.command().args(["dump", "baz"]).assert().failure();
+}
+
+#[test]
+fn test_kbs2_rm_dry_run() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    // `kbs2 --dry-run rm` reports the deletion without performing it.
+    session
+        .command()
+        .args(["--dry-run", "rm", "test-record"])
+        .assert()
+        .success()
+        .stdout("would delete 'test-record'\n");
+
+    session
+        .command()
+        .args(["dump", "test-record"])
+        .assert()
+        .success();
+
+    // `--dry-run` still validates the labels.
+    session
+        .command()
+        .args(["rm", "--dry-run", "does-not-exist"])
+        .assert()
+        .failure();
+
+    // Commands that don't support `--dry-run` reject it.
+    session
+        .command()
+        .args(["list", "--dry-run"])
+        .assert()
+        .failure();
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::{CliSession, ToJson};
+
+#[test]
+fn test_kbs2_share_receive() {
+    let alice = CliSession::new();
+    let bob = CliSession::new();
+
+    alice
+        .command()
+        .args(["new", "-k", "login", "pets.com"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+
+    let bob_key = bob
+        .command()
+        .args(["config", "dump"])
+        .output()
+        .unwrap()
+        .json()["public-key"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let shared = alice
+        .command()
+        .args(["share", "pets.com", "--to", &bob_key])
+        .output()
+        .unwrap();
+    assert!(shared.status.success());
+    assert!(shared
+        .stdout
+        .starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----"));
+
+    // Alice can't receive her own share, since it's encrypted to Bob.
+    alice
+        .command()
+        .args(["receive", "--as", "shared"])
+        .write_stdin(shared.stdout.clone())
+        .assert()
+        .failure();
+
+    bob.command()
+        .args(["receive", "--as", "alice-pets.com"])
+        .write_stdin(shared.stdout.clone())
+        .assert()
+        .success();
+
+    let dump = bob
+        .command()
+        .args(["dump", "--json", "alice-pets.com"])
+        .output()
+        .unwrap()
+        .json();
+
+    assert_eq!(dump["label"], "alice-pets.com");
+    assert_eq!(dump["body"]["fields"]["username"], "fakeuser");
+    assert_eq!(dump["body"]["fields"]["password"], "fakepass");
+
+    // Receiving over an existing record requires --force.
+    bob.command()
+        .args(["receive", "--as", "alice-pets.com"])
+        .write_stdin(shared.stdout.clone())
+        .assert()
+        .failure();
+
+    bob.command()
+        .args(["receive", "--force", "--as", "alice-pets.com"])
+        .write_stdin(shared.stdout)
+        .assert()
+        .success();
+
+    // Invalid recipients are rejected.
+    alice
+        .command()
+        .args(["share", "pets.com", "--to", "not-a-key"])
+        .assert()
+        .failure();
+}
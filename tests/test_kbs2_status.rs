@@ -0,0 +1,50 @@
+mod common;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_status() {
+    let session = CliSession::new();
+
+    for label in ["foo", "bar"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("jdoe\x01hunter2")
+            .assert()
+            .success();
+    }
+
+    let output = session
+        .command()
+        .args(["status", "--porcelain"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let fields = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let (key, value) = line.split_once('\t').unwrap();
+            (key.to_string(), value.to_string())
+        })
+        .collect::<Vec<_>>();
+
+    let keys = fields.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>();
+    assert_eq!(keys, ["store", "records", "wrapped", "agent", "unlocked"]);
+
+    let value = |key: &str| fields.iter().find(|(k, _)| k == key).unwrap().1.as_str();
+    assert_eq!(value("store"), session.store_dir.path().to_str().unwrap());
+    assert_eq!(value("records"), "2");
+
+    // The test key is bare, so it's always usable without the agent.
+    assert_eq!(value("wrapped"), "0");
+    assert_eq!(value("unlocked"), "1");
+
+    let output = session.command().arg("status").output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("(2 records)"));
+    assert!(stdout.contains("key: bare"));
+}
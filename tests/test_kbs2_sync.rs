@@ -0,0 +1,174 @@
+mod common;
+
+use std::fs;
+
+use common::CliSession;
+
+fn configure_sync(session: &CliSession, auto: bool) {
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = fs::read_to_string(&config_path).unwrap();
+
+    // `kbs2 init` already writes a `[sync]` table, so we fill it in rather than
+    // adding another.
+    let dir = session.config_dir.path();
+    fs::write(
+        &config_path,
+        config.replace(
+            "[sync]\nauto = false\n",
+            &format!(
+                "[sync]\npull = {:?}\npush = {:?}\nauto = {auto}\n",
+                dir.join("pull.sh"),
+                dir.join("push.sh")
+            ),
+        ),
+    )
+    .unwrap();
+
+    // NOTE(ww): The scripts and their marks live outside of the store, so that commands
+    // that decrypt every record don't trip over them.
+    for (script, mark) in [("pull.sh", "pulled"), ("push.sh", "pushed")] {
+        let path = dir.join(script);
+        fs::write(&path, format!("#!/bin/sh\necho >> {:?}\n", dir.join(mark))).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+}
+
+fn marks(session: &CliSession, mark: &str) -> usize {
+    fs::read_to_string(session.config_dir.path().join(mark))
+        .map(|m| m.lines().count())
+        .unwrap_or(0)
+}
+
+#[test]
+fn test_kbs2_sync() {
+    let session = CliSession::new();
+
+    // Without any sync commands, `kbs2 sync` fails.
+    session.command().arg("sync").assert().failure();
+
+    configure_sync(&session, false);
+
+    session.command().arg("sync").assert().success();
+    assert_eq!(marks(&session, "pulled"), 1);
+    assert_eq!(marks(&session, "pushed"), 1);
+
+    session
+        .command()
+        .args(["sync", "--pull"])
+        .assert()
+        .success();
+    assert_eq!(marks(&session, "pulled"), 2);
+    assert_eq!(marks(&session, "pushed"), 1);
+
+    // Without `sync.auto`, other commands don't sync.
+    session.command().arg("list").assert().success();
+    assert_eq!(marks(&session, "pulled"), 2);
+}
+
+#[test]
+fn test_kbs2_sync_auto() {
+    let session = CliSession::new();
+    configure_sync(&session, true);
+
+    // Reads pull, but don't push.
+    session.command().arg("list").assert().success();
+    assert_eq!(marks(&session, "pulled"), 1);
+    assert_eq!(marks(&session, "pushed"), 0);
+
+    // Mutations pull, and then push.
+    session
+        .command()
+        .args(["new", "-k", "login", "test-record"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+    assert_eq!(marks(&session, "pulled"), 2);
+    assert_eq!(marks(&session, "pushed"), 1);
+
+    // Listing duplicates only reads, but walking through them can remove records.
+    session.command().arg("dedupe").assert().success();
+    assert_eq!(marks(&session, "pulled"), 3);
+    assert_eq!(marks(&session, "pushed"), 1);
+
+    session
+        .command()
+        .args(["dedupe", "--interactive"])
+        .assert()
+        .success();
+    assert_eq!(marks(&session, "pulled"), 4);
+    assert_eq!(marks(&session, "pushed"), 2);
+
+    // Migrating the store rewrites every record.
+    session
+        .command()
+        .args(["migrate-store", "--to", "escaped", "--yes", "--no-backup"])
+        .assert()
+        .success();
+    assert_eq!(marks(&session, "pulled"), 5);
+    assert_eq!(marks(&session, "pushed"), 3);
+
+    // Reading a burn-after-reading record removes it, so that read pushes too.
+    session
+        .command()
+        .args(["new", "-k", "login", "--burn-after-reading", "burned"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+    assert_eq!(marks(&session, "pushed"), 4);
+
+    for cmd in ["pass", "dump"] {
+        session
+            .command()
+            .args([cmd, "test-record"])
+            .assert()
+            .success();
+        assert_eq!(marks(&session, "pushed"), 4, "{cmd}");
+    }
+
+    session
+        .command()
+        .args(["pass", "burned"])
+        .assert()
+        .success();
+    assert_eq!(marks(&session, "pulled"), 9);
+    assert_eq!(marks(&session, "pushed"), 5);
+
+    // `kbs2 get` is a read like `kbs2 pass`, and burns the same way.
+    session
+        .command()
+        .args(["new", "-k", "login", "--burn-after-reading", "burned"])
+        .write_stdin("fakeuser\x01fakepass")
+        .assert()
+        .success();
+    session
+        .command()
+        .args(["get", "test-record"])
+        .assert()
+        .success();
+    assert_eq!(marks(&session, "pulled"), 11);
+    assert_eq!(marks(&session, "pushed"), 6);
+
+    session.command().args(["get", "burned"]).assert().success();
+    assert_eq!(marks(&session, "pulled"), 12);
+    assert_eq!(marks(&session, "pushed"), 7);
+}
+
+#[test]
+fn test_kbs2_sync_auto_rekey() {
+    let session = CliSession::new_wrapped();
+    configure_sync(&session, true);
+
+    // Rekeying re-encrypts every record, so it pushes like any other mutation.
+    session
+        .command()
+        .args(["rekey", "--yes", "--no-backup"])
+        .assert()
+        .success();
+    assert_eq!(marks(&session, "pulled"), 1);
+    assert_eq!(marks(&session, "pushed"), 1);
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_timings() {
+    let session = CliSession::new();
+
+    session
+        .command()
+        .args(["new", "-k", "login", "foo"])
+        .write_stdin("jdoe\x01hunter2")
+        .assert()
+        .success();
+
+    // Without --timings, nothing extra is printed.
+    let output = session.command().args(["pass", "foo"]).output().unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    let output = session
+        .command()
+        .args(["--timings", "pass", "foo"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"hunter2");
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("Timings:\n"));
+    for phase in ["config load", "record read", "decrypt", "total"] {
+        assert!(stderr.lines().any(|l| l.trim_start().starts_with(phase)));
+    }
+
+    // The breakdown is printed even when the subcommand fails.
+    let output = session
+        .command()
+        .args(["--timings", "pass", "nonexistent"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Timings:"));
+}
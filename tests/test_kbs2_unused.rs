@@ -0,0 +1,76 @@
+mod common;
+
+use std::fs;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_unused() {
+    let session = CliSession::new();
+
+    let config_path = session.config_dir.path().join("config.toml");
+    let config = fs::read_to_string(&config_path).unwrap();
+    fs::write(
+        &config_path,
+        config.replace("track-access = false", "track-access = true"),
+    )
+    .unwrap();
+
+    for label in ["a", "b"] {
+        session
+            .command()
+            .args(["new", "-k", "login", label])
+            .write_stdin("fakeuser\x01fakepass")
+            .assert()
+            .success();
+    }
+
+    // Reading the whole store doesn't access anything.
+    for args in [&["list", "--details"][..], &["dump", "--all"], &["fsck"]] {
+        session.command().args(args).assert().success();
+    }
+
+    // Neither record has been accessed yet.
+    session
+        .command()
+        .arg("unused")
+        .assert()
+        .success()
+        .stdout("a\nb\n");
+
+    session.command().args(["pass", "a"]).assert().success();
+
+    session
+        .command()
+        .arg("unused")
+        .assert()
+        .success()
+        .stdout("b\n");
+
+    // `a` was accessed within the last year, so it isn't listed.
+    session
+        .command()
+        .args(["unused", "--since", "1y"])
+        .assert()
+        .success()
+        .stdout("b\n");
+
+    session
+        .command()
+        .args(["unused", "--since", "bogus"])
+        .assert()
+        .failure();
+
+    session
+        .command()
+        .args(["unused", "--reset"])
+        .assert()
+        .success();
+
+    session
+        .command()
+        .arg("unused")
+        .assert()
+        .success()
+        .stdout("a\nb\n");
+}
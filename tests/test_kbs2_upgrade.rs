@@ -0,0 +1,25 @@
+mod common;
+
+use common::CliSession;
+
+#[test]
+fn test_kbs2_upgrade() {
+    let session = CliSession::new();
+
+    // kbs2 never upgrades itself, so `--check` is the only thing that `upgrade` does.
+    session.command().arg("upgrade").assert().failure();
+
+    // Without the network feature, there's nothing to check with.
+    #[cfg(not(feature = "network"))]
+    {
+        let output = session
+            .command()
+            .args(["upgrade", "--check"])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("built with the network feature"));
+    }
+}